@@ -0,0 +1,38 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+#[allow(unused)]
+#[path = "../src/convolution.rs"]
+mod convolution;
+
+/// Kernel lengths of the lowpass filter at 44.1 kHz, for the default and a narrow transition band.
+const FILTER_LENGTHS: [usize; 3] = [64, 400, 1400];
+/// 20 ms of stereo audio at 44.1 kHz.
+const FRAME_SIZE: usize = 1764;
+
+fn bench_convolve(c: &mut Criterion) {
+    let mut group = c.benchmark_group("convolve");
+    for filter_len in FILTER_LENGTHS {
+        let filter = (0..filter_len)
+            .map(|i| (i as f32 * 0.1).sin() / filter_len as f32)
+            .collect::<Vec<_>>();
+        let input = (0..FRAME_SIZE + filter_len)
+            .map(|i| (i as f32 * 0.37).sin())
+            .collect::<Vec<_>>();
+        let mut output = vec![0.0; FRAME_SIZE];
+
+        group.bench_with_input(
+            BenchmarkId::new("optimized", filter_len),
+            &filter_len,
+            |b, _| b.iter(|| convolution::convolve(&filter, &input, &mut output)),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("reference", filter_len),
+            &filter_len,
+            |b, _| b.iter(|| convolution::convolve_reference(&filter, &input, &mut output)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_convolve);
+criterion_main!(benches);
@@ -1,103 +1,130 @@
 use chrono::{DateTime, TimeDelta, Utc};
-use log::info;
+use log::{info, warn};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rodio::source::UniformSourceIterator;
 use rodio::{Sink, Source};
-use symphonia::core::codecs::DecoderOptions;
-use symphonia::core::formats::FormatOptions;
-use symphonia::core::io::MediaSourceStream;
-use symphonia::core::meta::MetadataOptions;
+use serde::Deserialize;
 
-use std::{ffi::OsStr, thread, time};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::{path::Path, path::PathBuf};
+use std::{thread, time};
 
-use crate::filtered_source::dynamic_filter;
+use crate::analysis::{self, SoundAnalysis};
+use crate::config::{Config, EscalationStage, LowpassConfig, SpeakerConfig};
+use crate::crossfade_source::crossfade_queue;
+use crate::decode_cache::{self, DecodedAudio};
+use crate::downmix_source::Downmix;
+use crate::events::PlaybackEventKind;
+use crate::fade::{fadein, fadeout};
+use crate::filtered_source::{dynamic_filter, sanitize};
+use crate::history::AlarmHistoryEntry;
+use crate::latency::{self, Latency, LatencyTrace};
+use crate::output::{self, Beeper, OutputHealth, OutputMonitor, ResumableSource};
+use crate::playback::{PlaybackLease, PlaybackPriority};
+use crate::shutdown;
+#[cfg(feature = "motion")]
+use crate::sleep_monitor::BedOrientation;
+use crate::sounds::{self, random_alarm_sound, AlarmSoundError};
+#[cfg(feature = "motion")]
+use crate::wake::{snooze_decision, SnoozeChain, SnoozeCheck, SnoozeDecision};
+use crate::wake::{BedExit, SleepSignals, WakeReason};
+use crate::watchdog::{self, Subsystem};
 use crate::AlarmState;
-use rand::prelude::*;
 use symphonia::core::audio::SampleBuffer;
 use thiserror::Error;
 use time::{Duration, Instant};
 
-fn frequency_cutoff_lowpass(t: f32) -> f32 {
-    let clamped_t = (t - 10.0).max(0.0);
-    100000.0f32.min(800.0 + clamped_t.powf(2.5) * 1.0)
+fn frequency_cutoff_lowpass(t: f32, curve: &LowpassConfig) -> f32 {
+    let clamped_t = (t - curve.delay_seconds).max(0.0);
+    curve
+        .max_hz
+        .min(curve.base_hz + clamped_t.powf(curve.exponent) * curve.scale)
+}
+
+/// Lowpass cutoff frequency as a function of the time since playback started.
+pub type CutoffCurve = Box<dyn Fn(f64) -> f64 + Send + Sync>;
+
+/// The usual cutoff curve, which gradually opens up the filter. Or no filtering at all if `lowpass` is false.
+pub(crate) fn cutoff_curve(lowpass: bool, curve: &LowpassConfig) -> CutoffCurve {
+    let curve = curve.clone();
+    Box::new(move |t| {
+        if lowpass {
+            frequency_cutoff_lowpass(t as f32, &curve) as f64
+        } else {
+            100_000.0
+        }
+    })
 }
 
 pub fn fadein_slow(t: f32) -> f32 {
     1.0f32.min(0.007 * t + 0.0f32.max(t - 5.0) * 0.013)
 }
 
-pub fn smoothstep(x: f32) -> f32 {
-    3.0 * x.powi(2) - 2.0 * x.powi(3)
-}
+#[derive(Error, Debug)]
+#[error("Decoding was cancelled")]
+pub struct DecodeCancelled;
 
-pub fn fadein(t: f32, duration: f32) -> f32 {
-    smoothstep((t.max(0.0) / duration).min(1.0))
+/// How far decoding has come, in frames.
+pub struct DecodeProgress {
+    pub frames: u64,
+    pub total_frames: Option<u64>,
 }
 
-pub fn fadeout(t: f32, duration: f32) -> f32 {
-    smoothstep((1.0 - (t.max(0.0) / duration)).max(0.0))
+/// Why an alarm sound could not be used.
+#[derive(Error, Debug)]
+pub enum TrackError {
+    #[error(transparent)]
+    Cancelled(#[from] DecodeCancelled),
+    #[error("`{0}` is empty")]
+    Empty(PathBuf),
+    #[error("`{0}` could not be decoded: {1}")]
+    Decode(PathBuf, #[source] symphonia::core::errors::Error),
+    /// Seconds which would be played, after trimming the sound and skipping the silence at its start.
+    #[error("Only {1:.1} seconds of `{0}` would be played")]
+    TooShort(PathBuf, f32),
+    #[error(transparent)]
+    NoSound(#[from] AlarmSoundError),
 }
 
-/// Decode Mp3 using symphonia.
+/// How often to check for cancellation and report progress while decoding.
+const DECODE_CHECK_PACKETS: usize = 32;
+
+/// Decode an audio file (mp3, flac, wav or ogg) using symphonia.
+///
+/// Every few packets, `cancelled` is checked and `progress` is called. Fails if the file is missing or broken.
 ///
 /// rodio's built-in mp3 decodeer (minimp3) seems to trigger out of range asserts in debug mode, and possibly does pretty unsafe things in release mode.
 /// It's also just a c++ blob. Which is also not very nice.
 ///
 /// Hopefully symphonia is more robust.
-fn decode_mp3(path: &Path) -> rodio::buffer::SamplesBuffer<f32> {
-    // Open the media source.
-    let src = std::fs::File::open(path).expect("failed to open media");
-
-    // Create the media source stream.
-    let mss = MediaSourceStream::new(Box::new(src), Default::default());
-
-    // Create a probe hint using the file's extension. [Optional]
-    let mut hint = symphonia::core::probe::Hint::new();
-    hint.with_extension("mp3");
-
-    // Use the default options for metadata and format readers.
-    let meta_opts: MetadataOptions = Default::default();
-    let fmt_opts: FormatOptions = Default::default();
-
-    // Probe the media source.
-    let probed = symphonia::default::get_probe()
-        .format(&hint, mss, &fmt_opts, &meta_opts)
-        .expect("unsupported format");
-
-    // Get the instantiated format reader.
-    let mut format = probed.format;
-
-    // Find the first audio track with a known (decodeable) codec.
-    let track = format
-        .tracks()
-        .iter()
-        .find(|t| t.codec_params.codec != symphonia::core::codecs::CODEC_TYPE_NULL)
-        .expect("no supported audio tracks");
+fn decode_mp3(
+    path: &Path,
+    cancelled: &dyn Fn() -> bool,
+    progress: &mut dyn FnMut(DecodeProgress),
+) -> Result<DecodedAudio, TrackError> {
+    let error = |e| TrackError::Decode(path.to_owned(), e);
 
-    // Use the default options for the decoder.
-    let dec_opts: DecoderOptions = Default::default();
+    let analysis::ProbedSound {
+        mut format,
+        mut decoder,
+        track_id,
+        params,
+    } = analysis::probe(path).map_err(error)?;
 
-    // Create a decoder for the track.
-    let mut decoder = symphonia::default::get_codecs()
-        .make(&track.codec_params, &dec_opts)
-        .expect("unsupported codec");
-
-    // Store the track identifier, it will be used to filter packets.
-    let track_id = track.id;
     let mut all_samples: Vec<f32> = vec![];
-    let sample_rate = track.codec_params.sample_rate.unwrap();
+    // Some containers only know the sample rate and channel layout after the first packet has been decoded
+    let mut sample_rate = params.sample_rate;
+    let mut channels = params.channels.map(|c| c.count() as u16);
+    let total_frames = params.n_frames;
 
     // The decode loop.
-    loop {
+    for packet_index in 0usize.. {
         // Get the next packet from the media format.
         let packet = match format.next_packet() {
             Ok(packet) => packet,
-            Err(symphonia::core::errors::Error::ResetRequired) => {
-                // The track list has been changed. Re-examine it and create a new set of decoders,
-                // then restart the decode loop. This is an advanced feature and it is not
-                // unreasonable to consider this "the end." As of v0.5.0, the only usage of this is
-                // for chained OGG physical streams.
-                unimplemented!();
-            }
             Err(symphonia::core::errors::Error::IoError(er))
                 if er.kind() == std::io::ErrorKind::UnexpectedEof =>
             {
@@ -105,8 +132,9 @@ fn decode_mp3(path: &Path) -> rodio::buffer::SamplesBuffer<f32> {
                 break;
             }
             Err(err) => {
-                // A unrecoverable error occurred, halt decoding.
-                panic!("{}", err);
+                // A unrecoverable error occurred, halt decoding. This includes a changed track list
+                // (`ResetRequired`), which as of v0.5.0 only happens for chained OGG physical streams.
+                return Err(error(err));
             }
         };
 
@@ -123,16 +151,30 @@ fn decode_mp3(path: &Path) -> rodio::buffer::SamplesBuffer<f32> {
             continue;
         }
 
+        if packet_index.is_multiple_of(DECODE_CHECK_PACKETS) {
+            if cancelled() {
+                return Err(DecodeCancelled.into());
+            }
+            progress(DecodeProgress {
+                frames: packet.ts(),
+                total_frames,
+            });
+        }
+
         // Decode the packet into audio samples.
         match decoder.decode(&packet) {
             Ok(decoded) => {
                 // Consume the decoded audio samples (see below).
-                let mut sample_buf =
-                    SampleBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
+                let spec = *decoded.spec();
+                sample_rate.get_or_insert(spec.rate);
+                channels.get_or_insert(spec.channels.count() as u16);
+                // Converts from any sample format (e.g. 24 bit integers, or 64 bit floats) to f32 in the range [-1, 1],
+                // so that all formats end up with the same full scale level.
+                let mut sample_buf = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
                 sample_buf.copy_interleaved_ref(decoded);
                 // let buf = decoded.make_equivalent::<f32>();
                 // all_samples.extend(buf.chan(0).iter().cloned());
-                all_samples.extend(sample_buf.samples());
+                all_samples.extend(sample_buf.samples().iter().map(|&x| sanitize(x)));
             }
             Err(symphonia::core::errors::Error::IoError(er))
                 if er.kind() == std::io::ErrorKind::UnexpectedEof =>
@@ -140,65 +182,503 @@ fn decode_mp3(path: &Path) -> rodio::buffer::SamplesBuffer<f32> {
                 // End of file
                 break;
             }
-            Err(symphonia::core::errors::Error::IoError(err)) => {
-                // The packet failed to decode due to an IO error, skip the packet.
-                panic!("{:#?}", err);
+            Err(err) => {
+                // An IO error, invalid data, or something unrecoverable. Halt decoding.
+                return Err(error(err));
             }
-            Err(symphonia::core::errors::Error::DecodeError(err)) => {
-                // The packet failed to decode due to invalid data, skip the packet.
-                panic!("{:#?}", err);
+        }
+    }
+
+    let sample_rate = sample_rate.ok_or_else(|| {
+        error(symphonia::core::errors::Error::Unsupported(
+            "unknown sample rate",
+        ))
+    })?;
+    Ok(DecodedAudio::new(
+        channels.unwrap_or(2),
+        sample_rate,
+        all_samples,
+    ))
+}
+
+/// How often to log the progress of slow decodes.
+const DECODE_PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Decodes `path`, reusing the samples from an earlier play if possible.
+///
+/// Stops early if `cancelled` returns true, or if the server is shutting down.
+fn decode(
+    path: &Path,
+    config: &Config,
+    cancelled: &dyn Fn() -> bool,
+) -> Result<DecodedAudio, TrackError> {
+    let max_bytes = config.playback.decode_cache_mb as usize * 1024 * 1024;
+    let mut last_log = Instant::now();
+    let mut log_progress = |progress: DecodeProgress| {
+        if last_log.elapsed() > DECODE_PROGRESS_INTERVAL {
+            last_log = Instant::now();
+            match progress.total_frames {
+                Some(total) if total > 0 => info!(
+                    "Decoding {}: {:.0}%",
+                    path.display(),
+                    100.0 * progress.frames as f64 / total as f64
+                ),
+                _ => info!("Decoding {}: {} frames", path.display(), progress.frames),
             }
-            Err(err) => {
-                // An unrecoverable error occurred, halt decoding.
-                panic!("{:#?}", err);
+        }
+    };
+    decode_cache::decode_cached(path, max_bytes, |path| {
+        decode_mp3(
+            path,
+            // Also stops any ongoing decoding at shutdown
+            &|| shutdown::requested() || cancelled(),
+            &mut log_progress,
+        )
+    })
+}
+
+/// Decodes an alarm track, skipping any silence at the start if enabled in the config.
+///
+/// Fails if the file is empty, broken, or if the part which is played is shorter than the configured minimum duration.
+fn decode_alarm_track(
+    path: &Path,
+    root_dir: &Path,
+    config: &Config,
+    cancelled: &dyn Fn() -> bool,
+) -> Result<DecodedAudio, TrackError> {
+    let name = sounds::sound_name(root_dir, path);
+    let min_duration_seconds = config.sounds.min_duration_seconds;
+    if std::fs::metadata(path).is_ok_and(|m| m.len() == 0) {
+        return Err(TrackError::Empty(path.to_owned()));
+    }
+    let decoded = match decode(path, config, cancelled) {
+        Ok(decoded) => decoded,
+        Err(e @ TrackError::Decode(..)) => {
+            analysis::record(
+                root_dir,
+                &name,
+                SoundAnalysis {
+                    undecodable: true,
+                    ..Default::default()
+                },
+            );
+            return Err(e);
+        }
+        Err(e) => return Err(e),
+    };
+
+    let channels = decoded.channels();
+    let sample_rate = decoded.sample_rate();
+    let duration_seconds = decoded.len() as f32 / (channels.max(1) as f32 * sample_rate as f32);
+    let mut samples = decoded.collect::<Vec<_>>();
+    let fade = (SILENCE_SKIP_FADE.as_secs_f32() * sample_rate as f32) as usize;
+
+    // An explicit start point replaces the automatic silence skipping
+    let total_frames = samples.len() / channels.max(1) as usize;
+    let trimmed_start = match sounds::SoundTrims::load(root_dir)
+        .get(&name)
+        .map(|trim| trim.frames(total_frames, sample_rate))
+    {
+        Some(Ok(frames)) => {
+            analysis::trim_end(&mut samples, channels, frames.end, fade);
+            analysis::trim_start(&mut samples, channels, frames.start, fade);
+            frames.start > 0
+        }
+        Some(Err(e)) => {
+            warn!("Ignoring the trim of {}: {}", path.display(), e);
+            false
+        }
+        None => false,
+    };
+
+    let config = &config.alarm;
+    let skip = if config.skip_leading_silence && !trimmed_start {
+        let max_skip = (config.max_silence_skip_seconds * sample_rate as f32) as usize;
+        analysis::leading_silence(
+            &samples,
+            channels,
+            sample_rate,
+            config.silence_threshold_db,
+            max_skip,
+        )
+    } else {
+        0
+    };
+    analysis::trim_start(&mut samples, channels, skip, fade);
+
+    let skipped_seconds = skip as f32 / sample_rate as f32;
+    if skip > 0 {
+        info!(
+            "Skipping {:.1} seconds of silence at the start of {}",
+            skipped_seconds,
+            path.display()
+        );
+    }
+    analysis::record(
+        root_dir,
+        &name,
+        SoundAnalysis {
+            leading_silence_seconds: skipped_seconds,
+            duration_seconds: Some(duration_seconds),
+            undecodable: false,
+            probe: None,
+        },
+    );
+
+    let played_seconds = samples.len() as f32 / (channels.max(1) as f32 * sample_rate as f32);
+    if played_seconds < min_duration_seconds {
+        return Err(TrackError::TooShort(path.to_owned(), played_seconds));
+    }
+    Ok(DecodedAudio::new(channels, sample_rate, samples))
+}
+
+/// Decodes the alarm sound at `path`. If it cannot be used, other sounds from `category` are tried instead.
+///
+/// The names of the sounds which could not be used are added to `rejected`.
+fn decode_usable_track(
+    path: &Path,
+    category: Option<&str>,
+    config: &Config,
+    cancelled: &dyn Fn() -> bool,
+    rejected: &mut Vec<String>,
+) -> Result<(PathBuf, DecodedAudio), TrackError> {
+    let root_dir = config.sounds.directory.as_path();
+    let mut path = path.to_path_buf();
+    loop {
+        match decode_alarm_track(&path, root_dir, config, cancelled) {
+            Ok(track) => return Ok((path, track)),
+            Err(e @ TrackError::Cancelled(_)) => return Err(e),
+            Err(e) => {
+                warn!("{}. Picking another sound.", e);
+                rejected.push(sounds::sound_name(root_dir, &path));
+                path = random_alarm_sound(root_dir, &config.sounds, category, &[], rejected)?;
             }
         }
     }
+}
+
+/// Prepends the configured intro chime to `track`, joined at the sample level so that there is no gap between them.
+///
+/// The chime is converted to the channel count and sample rate of `track`. It is skipped if it cannot be decoded.
+fn with_intro_chime(
+    track: DecodedAudio,
+    config: &Config,
+    cancelled: &dyn Fn() -> bool,
+) -> Result<DecodedAudio, DecodeCancelled> {
+    let Some(chime_path) = &config.alarm.intro_chime else {
+        return Ok(track);
+    };
+
+    let chime = match decode(chime_path, config, cancelled) {
+        Ok(chime) => chime,
+        Err(TrackError::Cancelled(e)) => return Err(e),
+        Err(e) => {
+            warn!("Could not decode the intro chime: {}. Skipping it.", e);
+            return Ok(track);
+        }
+    };
+
+    let channels = track.channels();
+    let sample_rate = track.sample_rate();
+    let mut samples =
+        UniformSourceIterator::<_, f32>::new(chime, channels, sample_rate).collect::<Vec<_>>();
+    samples.extend(track);
+    Ok(DecodedAudio::new(channels, sample_rate, samples))
+}
+
+/// Fade-in applied after skipping silence, so that playback does not start with a click.
+const SILENCE_SKIP_FADE: Duration = Duration::from_millis(30);
+
+pub fn play_audio(
+    path: &Path,
+    vol: impl FnMut(f32) -> Option<f32>,
+    lowpass: bool,
+    max_duration: Option<Duration>,
+    config: &Config,
+    trace: &mut LatencyTrace,
+    lease: &PlaybackLease,
+) -> Result<(), PlaybackError> {
+    let source = match decode(path, config, &|| lease.is_cancelled()) {
+        Ok(source) => source,
+        Err(TrackError::Cancelled(_)) => {
+            info!(
+                "Not playing {}, since decoding was cancelled",
+                path.display()
+            );
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    trace.mark_decoded();
+    play_source(
+        source,
+        vol,
+        cutoff_curve(lowpass, &config.lowpass),
+        max_duration,
+        config,
+        trace,
+        lease,
+    )
+}
+
+#[derive(Error, Debug)]
+pub enum PlaybackError {
+    #[error("The audio output failed and could not be recovered after {0} attempts. Played the fallback beeper instead.")]
+    FellBackToBeeper(u32),
+    #[error("The audio output failed and no fallback device was available")]
+    NoOutput,
+    #[error("No usable alarm sound: {0}")]
+    NoUsableSound(String),
+    #[error(transparent)]
+    Undecodable(#[from] TrackError),
+}
+
+/// How long the audio output may play too slowly (or not at all) before we consider it broken.
+const OUTPUT_STALL_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Lowpass cutoff frequency which replaces the time based one for everything that is played. Only used for debugging.
+static CUTOFF_OVERRIDE: Mutex<Option<f64>> = Mutex::new(None);
+
+/// Sets a fixed lowpass cutoff frequency in Hz for all playing sounds, or goes back to the normal one if `null`.
+#[put("/debug/cutoff", data = "<freq>")]
+pub fn put_cutoff_override(freq: Json<Option<f64>>) -> Result<Json<Option<f64>>, (Status, String)> {
+    if let Some(freq) = freq.0 {
+        if !freq.is_finite() || freq <= 0.0 {
+            return Err((
+                Status::BadRequest,
+                "cutoff must be a positive number".to_owned(),
+            ));
+        }
+    }
+    info!("Setting cutoff override to {:?}", freq.0);
+    *CUTOFF_OVERRIDE.lock().unwrap() = freq.0;
+    Ok(freq)
+}
+
+/// Length of the ramp down to silence before the output is stopped.
+const STOP_FADE: Duration = Duration::from_millis(50);
+
+/// Length of the fade out when something more important starts playing.
+const DUCK_FADE: Duration = Duration::from_secs(1);
+
+/// Time it takes to fade out when reaching the maximum play duration.
+const MAX_DURATION_FADEOUT_SECONDS: f32 = 5.0;
+
+/// How far the current playback has come.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct PlaybackProgress {
+    /// Seconds since the first audible sample.
+    pub elapsed_seconds: f32,
+    /// Length of the sound, if known.
+    pub total_seconds: Option<f32>,
+    /// When playback will start to fade out, if it is limited.
+    pub max_seconds: Option<f32>,
+}
+
+/// Progress of the most recently started playback which is still playing.
+static PROGRESS: Mutex<Option<PlaybackProgress>> = Mutex::new(None);
+
+#[get("/playback")]
+pub fn get_playback() -> Json<Option<PlaybackProgress>> {
+    Json(PROGRESS.lock().unwrap().clone())
+}
+
+/// Plays a sound once at a constant volume, so that it can be tried out.
+///
+/// Fails with 409 Conflict while the alarm is playing.
+#[post("/sounds/<name>/preview")]
+pub async fn preview_sound(state: &rocket::State<AlarmState>, name: &str) -> Result<(), Status> {
+    let config = state.config.get();
+    let root_dir = config.sounds.directory.as_path();
+    let path = sounds::list_sounds(root_dir, &config.sounds)
+        .map_err(|_| Status::InternalServerError)?
+        .into_iter()
+        .find(|path| sounds::sound_name(root_dir, path) == name)
+        .ok_or(Status::NotFound)?;
+    if state.is_playing.get().unwrap_or(false) {
+        return Err(Status::Conflict);
+    }
+
+    info!("Previewing {}", path.display());
+    let max_duration = Duration::from_secs_f32(config.playback.max_preview_seconds);
+    let playback = state.playback.clone();
+    tokio::task::spawn_blocking(move || {
+        let Some(lease) = playback.request(PlaybackPriority::Preview) else {
+            info!("Not previewing, since something more important is playing");
+            return;
+        };
+        let mut trace = LatencyTrace::new("preview");
+        if let Err(e) = play_audio(
+            &path,
+            |_| Some(0.5),
+            false,
+            Some(max_duration),
+            &config,
+            &mut trace,
+            &lease,
+        ) {
+            error!("{}", e);
+        }
+    });
+    Ok(())
+}
+
+/// Longest tone that can be played using [`play_tone`].
+const MAX_TONE_SECONDS: f32 = 60.0;
+
+#[derive(Deserialize, Debug)]
+pub struct ToneRequest {
+    frequency_hz: u32,
+    seconds: f32,
+    volume: f32,
+}
 
-    println!("Decoded {} samples", all_samples.len());
+/// Plays a sine wave, e.g. to try out where to put the speakers. It can be stopped like a preview.
+///
+/// Fails with 409 Conflict while the alarm is playing.
+#[post("/tone", data = "<tone>")]
+pub fn play_tone(
+    state: &rocket::State<AlarmState>,
+    tone: Json<ToneRequest>,
+) -> Result<(), (Status, String)> {
+    let tone = tone.into_inner();
+    if !(20..=20_000).contains(&tone.frequency_hz) {
+        return Err((
+            Status::BadRequest,
+            "frequency_hz must be between 20 and 20000".to_owned(),
+        ));
+    }
+    if !(tone.seconds > 0.0 && tone.seconds <= MAX_TONE_SECONDS) {
+        return Err((
+            Status::BadRequest,
+            format!("seconds must be between 0 and {MAX_TONE_SECONDS}"),
+        ));
+    }
+    if !(0.0..=1.0).contains(&tone.volume) {
+        return Err((
+            Status::BadRequest,
+            "volume must be between 0 and 1".to_owned(),
+        ));
+    }
+    if state.is_playing.get().unwrap_or(false) {
+        return Err((Status::Conflict, "the alarm is playing".to_owned()));
+    }
 
-    rodio::buffer::SamplesBuffer::new(2, sample_rate, all_samples)
+    info!("Playing a {} Hz tone", tone.frequency_hz);
+    let config = state.config.get();
+    let playback = state.playback.clone();
+    tokio::task::spawn_blocking(move || {
+        let Some(lease) = playback.request(PlaybackPriority::Preview) else {
+            info!("Not playing the tone, since something more important is playing");
+            return;
+        };
+        let sine = rodio::source::SineWave::new(tone.frequency_hz)
+            .take_duration(Duration::from_secs_f32(tone.seconds));
+        if let Err(e) = play_source(
+            sine,
+            |_| Some(tone.volume),
+            cutoff_curve(false, &config.lowpass),
+            None,
+            &config,
+            &mut LatencyTrace::new("tone"),
+            &lease,
+        ) {
+            error!("{}", e);
+        }
+    });
+    Ok(())
 }
 
-pub fn play_audio(path: &Path, mut vol: impl FnMut(f32) -> Option<f32>, lowpass: bool) {
-    let device = rodio::default_output_device().unwrap();
+/// Stops the sound preview or tone that is currently playing.
+#[delete("/sounds/preview")]
+pub fn stop_preview(state: &rocket::State<AlarmState>) {
+    info!("Stopping sound preview");
+    state.playback.cancel(PlaybackPriority::Preview);
+}
 
-    let sink = Sink::new(&device);
+/// Plays `source_samples` until it ends or `vol` returns `None`.
+///
+/// `vol` is called regularly with the time since playback started, and returns the volume to play at.
+/// The volume is applied by the output sink, so it changes smoothly regardless of the filter's frame size.
+/// If `max_duration` is given, playback fades out once it has played that long, not counting the speaker wakeup tone.
+///
+/// If the output device stops consuming samples, or keeps underrunning, playback is restarted on the default device
+/// from the same position.
+/// After too many failed attempts, we give up and play a beeper on the fallback device for the rest of the playback.
+///
+/// The time until the sink is started and the first sound is heard is recorded in `trace`.
+/// Playback fades out and stops if `lease` is cancelled. The volume never goes above the configured maximum.
+pub fn play_source<S>(
+    source_samples: S,
+    mut vol: impl FnMut(f32) -> Option<f32>,
+    lowpass: CutoffCurve,
+    max_duration: Option<Duration>,
+    config: &Config,
+    trace: &mut LatencyTrace,
+    lease: &PlaybackLease,
+) -> Result<(), PlaybackError>
+where
+    S: Source<Item = f32> + Send + 'static,
+{
+    let speaker = &config.speaker;
 
-    // Add a dummy source of the sake of the example.
-    let source_samples = decode_mp3(path);
     let total_duration = source_samples.total_duration();
 
-    let (source, controller) = dynamic_filter(
-        source_samples,
-        Box::new(move |t| {
-            if lowpass {
-                frequency_cutoff_lowpass(t as f32) as f64
-            } else {
-                100_000.0
-            }
-        }),
+    let source_samples: Box<dyn Source<Item = f32> + Send> = if config.playback.mono {
+        Box::new(Downmix::new(source_samples))
+    } else {
+        Box::new(source_samples)
+    };
+
+    let (source, controller) = dynamic_filter(source_samples, lowpass);
+
+    controller.set_limiter(
+        config
+            .playback
+            .limiter
+            .then_some(config.playback.limiter_threshold),
     );
+    controller.set_balance(config.playback.balance);
+    controller.set_cutoff_slew(Some(config.lowpass.max_octaves_per_second as f64));
+    controller.set_dc_blocker(config.playback.dc_blocker);
+    controller.set_frame_duration(Duration::from_secs_f32(
+        config.playback.filter_frame_ms / 1000.0,
+    ));
+    controller.set_eq(config.playback.eq);
 
     let mut sources: Vec<Box<dyn rodio::source::Source<Item = f32> + Send>> = vec![];
+    let mut lead_in = 0.0;
 
-    let speaker_has_standby_mode = false;
-    if speaker_has_standby_mode {
-        let sine = rodio::source::SineWave::new(30).amplify(0.7);
+    if speaker.standby_wakeup {
+        let sine = rodio::source::SineWave::new(speaker.wakeup_tone_hz)
+            .amplify(speaker.wakeup_tone_amplitude);
+        let duration = Duration::from_secs_f32(speaker.wakeup_tone_seconds);
+        lead_in = duration.as_secs_f32();
         sources.push(Box::new(
             // Play sine wave for a few seconds to make the speakers wake up
-            sine.take_duration(Duration::from_millis(5000))
+            sine.take_duration(duration)
                 // Fade in sine wave over one second to avoid speaker pop
-                .fade_in(Duration::from_millis(1000)),
+                .fade_in(duration.min(Duration::from_millis(1000))),
         ))
     }
 
     sources.push(Box::new(source));
 
-    let source = rodio::source::from_iter(sources);
+    let source = ResumableSource::new(rodio::source::from_iter(sources));
 
-    sink.append(source);
+    let mut sink = output::find_output_device(None)
+        .and_then(|device| output::open_sink(&device, source.resume(), 0.0));
+    if sink.is_some() {
+        trace.mark_sink_started();
+    }
+    let mut first_audible: Option<Instant> = None;
+    let mut beeper: Option<Sink> = None;
+    let mut result = Ok(());
+    let mut recovery_attempts = 0;
+    let mut monitor =
+        OutputMonitor::new(OUTPUT_STALL_TIMEOUT, source.samples_read(), Instant::now());
 
+    let mut stop_fade = STOP_FADE;
     let t0 = Instant::now();
     loop {
         let t = Instant::now().duration_since(t0).as_secs_f32();
@@ -208,171 +688,1124 @@ pub fn play_audio(path: &Path, mut vol: impl FnMut(f32) -> Option<f32>, lowpass:
             }
         }
 
-        if let Some(v) = vol(t) {
-            controller.set_volume(v);
-            thread::sleep(Duration::from_millis(40));
-        } else {
+        let Some(mut v) = vol(t) else {
+            break;
+        };
+        if lease.is_cancelled() {
+            info!("Something more important is playing. Fading out.");
+            stop_fade = DUCK_FADE;
             break;
         }
+        v = v.min(config.playback.max_volume);
+
+        let elapsed = (t - lead_in).max(0.0);
+        if let Some(max_duration) = max_duration {
+            let over = elapsed - max_duration.as_secs_f32();
+            if over > MAX_DURATION_FADEOUT_SECONDS {
+                info!("Reached the maximum play duration of {:?}", max_duration);
+                break;
+            }
+            v *= fadeout(over, MAX_DURATION_FADEOUT_SECONDS);
+        }
+        *PROGRESS.lock().unwrap() = Some(PlaybackProgress {
+            elapsed_seconds: elapsed,
+            total_seconds: total_duration.map(|d| d.as_secs_f32()),
+            max_seconds: max_duration.map(|d| d.as_secs_f32()),
+        });
+
+        if let Some(beeper) = &beeper {
+            beeper.set_volume(v);
+        } else {
+            // The wakeup tone has its own amplitude, and should not be affected by the volume
+            let sink_volume = if t < lead_in { 1.0 } else { v };
+            if let Some(sink) = &sink {
+                sink.set_volume(sink_volume);
+            }
+            controller.set_cutoff_override(*CUTOFF_OVERRIDE.lock().unwrap());
+
+            if v > 0.0 {
+                first_audible.get_or_insert_with(Instant::now);
+            }
+            // The sound is heard once the filter has produced a nonzero sample, and the volume has been turned up
+            if let (Some(first_sound), Some(first_audible)) =
+                (controller.first_sound(), first_audible)
+            {
+                trace.mark_first_sound(first_sound.max(first_audible));
+            }
+
+            let samples_read = source.samples_read();
+            let rate = source.sample_rate() as f32 * source.channels() as f32;
+            let health = monitor.update(samples_read, rate, source.is_exhausted(), Instant::now());
+
+            if sink.is_none() || health == OutputHealth::Stalled {
+                if recovery_attempts < config.playback.max_recovery_attempts {
+                    recovery_attempts += 1;
+                    warn!(
+                        "Audio output is not playing. Reopening the output device (attempt {}).",
+                        recovery_attempts
+                    );
+                    // Dropping the old sink stops it
+                    output::record_recovery();
+                    sink = output::find_output_device(None).and_then(|device| {
+                        output::open_sink(&device, source.resume(), sink_volume)
+                    });
+                    if sink.is_some() {
+                        trace.mark_sink_started();
+                    }
+                    monitor.reset(samples_read, Instant::now());
+                } else {
+                    error!(
+                        "Audio output could not be recovered. Falling back to the beeper on {}.",
+                        config
+                            .playback
+                            .fallback_device
+                            .as_deref()
+                            .unwrap_or("the default device")
+                    );
+                    sink = None;
+                    beeper = output::find_output_device(config.playback.fallback_device.as_deref())
+                        .and_then(|device| output::open_sink(&device, Beeper::new(880.0), v));
+                    result = match beeper {
+                        Some(_) => Err(PlaybackError::FellBackToBeeper(recovery_attempts)),
+                        None => {
+                            error!("Could not open the fallback device");
+                            return Err(PlaybackError::NoOutput);
+                        }
+                    };
+                }
+            }
+        }
+
+        thread::sleep(Duration::from_millis(40));
     }
 
-    controller.set_volume(0.0);
-    sink.stop();
+    *PROGRESS.lock().unwrap() = None;
+    if let Some(sink) = sink {
+        // Ramp down before stopping, since cutting off the sound abruptly makes the speakers pop.
+        // Don't wait for too long if the output is not consuming any samples.
+        controller.fade_to_silence(stop_fade);
+        let deadline = Instant::now() + stop_fade + Duration::from_millis(500);
+        while !controller.is_silent() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        sink.stop();
+    }
+    if let Some(beeper) = beeper {
+        beeper.stop();
+    }
+    result
 }
 
+/// How often the user is checked on while the alarm is snoozed.
 #[cfg(feature = "motion")]
-async fn snooze(alarm_state: AlarmState, trigger_time: DateTime<Utc>) {
-    // In a few minutes, check if the user is still in bed, and if so, re-enable the alarm
+const SNOOZE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The alarm which is ringing again after it was snoozed.
+#[cfg_attr(not(feature = "motion"), allow(unused_variables))]
+fn snoozed_alarm(alarm_state: &AlarmState) -> Option<DateTime<Utc>> {
+    #[cfg(feature = "motion")]
+    return alarm_state
+        .snooze_chain
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|c| c.last_rang);
+    #[cfg(not(feature = "motion"))]
+    None
+}
+
+/// The movement events before the snoozed alarm rang again.
+#[cfg_attr(not(feature = "motion"), allow(unused_variables))]
+fn snooze_movement_events(alarm_state: &AlarmState) -> Vec<u64> {
+    #[cfg(feature = "motion")]
+    return alarm_state
+        .snooze_chain
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|c| c.movement_events.clone())
+        .unwrap_or_default();
+    #[cfg(not(feature = "motion"))]
+    vec![]
+}
 
+/// Checks on the user every minute after the alarm stopped, and re-enables the alarm if they are still in bed.
+///
+/// See [`snooze_decision`] for when.
+#[cfg(feature = "motion")]
+async fn snooze(alarm_state: AlarmState, trigger_time: DateTime<Utc>) {
     use chrono::TimeDelta;
-    tokio::time::sleep(time::Duration::from_secs(15 * 60)).await;
-    let prev_state = alarm_state.inner.get().clone().unwrap();
-    if prev_state.enabled
-        && prev_state.next_alarm == trigger_time
-        && alarm_state
-            .sleep_monitor
-            .lock()
-            .await
-            .sleep_monitor
-            .is_present()
-    {
-        alarm_state
-            .last_played
-            .update(|s| {
-                // We must do a minimum here, because if the alarm started early because of motion, then this could otherwise fail to play the alarm again, because we
-                // set the 'last played time' to trigger time, which might be after the alarm retry time (now).
-                s.last_played_time = s
-                    .last_played_time
-                    .min(Some(Utc::now() - TimeDelta::seconds(1)));
-            })
-            .await;
-        alarm_state
-            .inner
-            .update(|s| {
-                s.enabled = true;
-                s.next_alarm = Utc::now();
-            })
-            .await;
+    let first_rang = match &*alarm_state.snooze_chain.lock().unwrap() {
+        Some(chain) if chain.last_rang == trigger_time => chain.first_rang,
+        _ => trigger_time,
+    };
+    let stopped = Utc::now();
+    let earlier = (stopped - first_rang).to_std().unwrap_or_default();
+    let mut checks = vec![];
+    loop {
+        tokio::time::sleep(SNOOZE_CHECK_INTERVAL).await;
+        let prev_state = alarm_state.inner.get().clone().unwrap();
+        if !prev_state.enabled || prev_state.next_alarm != trigger_time {
+            // The alarm was changed
+            return;
+        }
+        let config = alarm_state.config.get().snooze;
+        checks.push({
+            let s = alarm_state.sleep_monitor.lock().await;
+            if s.sleep_monitor.is_stale() {
+                // Without a sensor, the alarm never rings again
+                info!("The sensor data is unusable. Not ringing again.");
+                return;
+            }
+            // Without a reference orientation, only the presence is known
+            let sitting_up = s.sleep_monitor.orientation() == Some(BedOrientation::Tilted);
+            SnoozeCheck {
+                presence: if config.require_lying_down && sitting_up {
+                    0.0
+                } else {
+                    s.sleep_monitor.presence_confidence().unwrap_or(0.0)
+                },
+                moving: s.sleep_monitor.is_significant_movement(),
+            }
+        });
+        let elapsed = (Utc::now() - stopped).to_std().unwrap_or_default();
+        match snooze_decision(&checks, SNOOZE_CHECK_INTERVAL, elapsed, earlier, &config) {
+            SnoozeDecision::Wait => {}
+            SnoozeDecision::Ring => break,
+            SnoozeDecision::Stop => return,
+        }
+    }
+
+    let next_alarm = Utc::now();
+    let movement_events = alarm_state
+        .sleep_monitor
+        .lock()
+        .await
+        .movement_events
+        .between(stopped, next_alarm);
+    info!(
+        "The user is still in bed. Ringing again. Movement events since the alarm stopped: {:?}",
+        movement_events
+    );
+    *alarm_state.snooze_chain.lock().unwrap() = Some(SnoozeChain {
+        first_rang,
+        last_rang: next_alarm,
+        movement_events,
+    });
+    alarm_state
+        .last_played
+        .update(|s| {
+            // We must do a minimum here, because if the alarm started early because of motion, then this could otherwise fail to play the alarm again, because we
+            // set the 'last played time' to trigger time, which might be after the alarm retry time (now).
+            s.last_played_time = s
+                .last_played_time
+                .min(Some(next_alarm - TimeDelta::seconds(1)));
+        })
+        .await;
+    alarm_state
+        .inner
+        .update(|s| {
+            s.enabled = true;
+            s.next_alarm = next_alarm;
+        })
+        .await;
+}
+
+/// Time before the current track ends at which the next playlist track starts decoding.
+const PLAYLIST_PREPARE_AHEAD: Duration = Duration::from_secs(30);
+
+/// How the alarm ended.
+pub struct AlarmOutcome {
+    /// Index of the escalation stage that was playing when the alarm stopped, if an escalation plan is configured.
+    pub escalation_stage: Option<usize>,
+    pub result: Result<(), PlaybackError>,
+    pub latency: Latency,
+    /// The sound that the alarm started with. Differs from the picked one if that could not be used.
+    pub sound: Option<PathBuf>,
+    /// Sounds which could not be used.
+    pub rejected_sounds: Vec<String>,
+}
+
+/// Time it takes to fade from the volume of one escalation stage to the next.
+const STAGE_TRANSITION_SECONDS: f32 = 10.0;
+
+/// The index of the stage that is active `t` seconds after the start of `stages`, and the volume to play at.
+///
+/// Returns `None` once all stages are over.
+fn escalation_volume(stages: &[EscalationStage], t: f32) -> Option<(usize, f32)> {
+    let mut stage_start = 0.0;
+    let mut previous_volume = 0.0;
+    for (i, stage) in stages.iter().enumerate() {
+        if t < stage_start + stage.duration_seconds {
+            let transition = STAGE_TRANSITION_SECONDS.min(stage.duration_seconds);
+            let v = previous_volume
+                + (stage.volume - previous_volume) * fadein(t - stage_start, transition);
+            return Some((i, v));
+        }
+        stage_start += stage.duration_seconds;
+        previous_volume = stage.volume;
+    }
+    None
+}
+
+/// The stage of `plan` active `t` seconds after the start of the plan and its volume, while that stage is one of `group`.
+///
+/// Each group is played from where the previous one ended, so the volume fades across the boundary instead of starting over from silence.
+fn group_volume(plan: &[EscalationStage], group: Range<usize>, t: f32) -> Option<(usize, f32)> {
+    escalation_volume(plan, t).filter(|(i, _)| group.contains(i))
+}
+
+/// Seconds from the start of `plan` until stage `index` starts.
+fn stage_start_seconds(plan: &[EscalationStage], index: usize) -> f32 {
+    plan[..index]
+        .iter()
+        .map(|stage| stage.duration_seconds)
+        .sum()
+}
+
+/// Lowpass cutoff frequency for `t` seconds after the start of `stages`.
+fn escalation_cutoff(stages: &[EscalationStage], curve: &LowpassConfig, t: f64) -> f64 {
+    let stage = escalation_volume(stages, t as f32).map(|(i, _)| &stages[i]);
+    match stage {
+        Some(stage) if !stage.lowpass => 100_000.0,
+        _ => frequency_cutoff_lowpass(t as f32, curve) as f64,
     }
 }
 
-fn play_alarm(path: &Path, trigger_time: DateTime<Utc>, alarm_state: &AlarmState) {
+/// Plays the alarm, starting with the sound at `path`.
+///
+/// `prepared` is the already decoded sound, if it was decoded ahead of time.
+/// If an intro chime is configured, it is played right before the first sound.
+/// `trace` was started when the alarm was triggered, and measures how long it took until the sound started.
+///
+/// If an escalation plan is configured, its stages are played in order.
+/// Consecutive stages with the same category keep playing the same music, only changing the volume and lowpass filter.
+///
+/// An alarm which was interrupted by a restart continues `resume_from` into the volume and cutoff curves of the plan,
+/// and has that much less left of the maximum duration.
+fn play_alarm(
+    path: &Path,
+    prepared: Option<DecodedAudio>,
+    trigger_time: DateTime<Utc>,
+    resume_from: Duration,
+    mut trace: LatencyTrace,
+    alarm_state: &AlarmState,
+) -> AlarmOutcome {
+    let config = alarm_state.config.get();
+    let alarm_category = alarm_state.inner.get().and_then(|s| s.category);
+    let plan = &config.alarm.escalation;
     let alarm_timeout = 5.0 * 60.0;
+    let offset = resume_from.as_secs_f32();
+
+    let mut outcome = AlarmOutcome {
+        escalation_stage: None,
+        result: Ok(()),
+        latency: Latency::default(),
+        sound: None,
+        rejected_sounds: vec![],
+    };
+
+    // Waits for anything else that is playing to fade out
+    let lease = alarm_state
+        .playback
+        .request(PlaybackPriority::Alarm)
+        .expect("nothing has a higher priority than the alarm");
+    if shutdown::requested() {
+        return outcome;
+    }
+
+    // Stop decoding if the alarm is dismissed before it has even started playing
+    let cancelled = || !alarm_state.is_trigger_time(trigger_time);
+    let root_dir = config.sounds.directory.as_path();
+    // With an escalation plan, the first sound is played in the first stage
+    let first_category = plan
+        .first()
+        .and_then(|stage| stage.category.clone())
+        .or(alarm_category.clone());
+    let first_track = match prepared {
+        Some(track) => Ok((path.to_path_buf(), track)),
+        None => decode_usable_track(
+            path,
+            first_category.as_deref(),
+            &config,
+            &cancelled,
+            &mut outcome.rejected_sounds,
+        ),
+    };
+    let (path, first_track) = match first_track
+        .and_then(|(path, track)| Ok((path, with_intro_chime(track, &config, &cancelled)?)))
+    {
+        Ok(track) => track,
+        Err(TrackError::Cancelled(_)) => {
+            info!("The alarm was cancelled while decoding");
+            futures::executor::block_on(alarm_state.on_alarm_finished(trigger_time));
+            return outcome;
+        }
+        Err(e) => {
+            outcome.result = Err(PlaybackError::NoUsableSound(e.to_string()));
+            futures::executor::block_on(alarm_state.on_alarm_finished(trigger_time));
+            return outcome;
+        }
+    };
+    outcome.sound = Some(path.clone());
+    let mut prepared = Some((path, first_track));
+
+    if plan.is_empty() {
+        let cutoff = cutoff_curve(true, &config.lowpass);
+        let (path, first_track) = prepared.take().unwrap();
+        outcome.result = play_tracks(
+            &path,
+            first_track,
+            alarm_category.as_deref(),
+            trigger_time,
+            &mut trace,
+            &lease,
+            alarm_state,
+            &config,
+            max_alarm_duration(&config, offset),
+            Box::new(move |t| cutoff(t + offset as f64)),
+            |t| (t + offset <= alarm_timeout).then(|| fadein_slow(t + offset)),
+        );
+    } else {
+        let mut group_start = 0;
+        while group_start < plan.len() && alarm_state.is_trigger_time(trigger_time) {
+            let category = plan[group_start]
+                .category
+                .as_ref()
+                .or(alarm_category.as_ref());
+            let group_end = plan[group_start..]
+                .iter()
+                .position(|stage| stage.category.as_ref().or(alarm_category.as_ref()) != category)
+                .map_or(plan.len(), |len| group_start + len);
+            info!(
+                "Starting escalation stages {}..{} with category {:?}",
+                group_start, group_end, category
+            );
+
+            // The first sound was already decoded for the category of the first stage
+            let group_track = match prepared.take() {
+                Some(track) => Ok(track),
+                None => random_alarm_sound(
+                    root_dir,
+                    &config.sounds,
+                    category.map(|c| c.as_str()),
+                    &[],
+                    &outcome.rejected_sounds,
+                )
+                .map_err(TrackError::from)
+                .and_then(|path| {
+                    decode_usable_track(
+                        &path,
+                        category.map(|c| c.as_str()),
+                        &config,
+                        &cancelled,
+                        &mut outcome.rejected_sounds,
+                    )
+                }),
+            };
+
+            match group_track {
+                Ok((group_path, group_track)) => {
+                    let cutoff_stages = plan.clone();
+                    let curve = config.lowpass.clone();
+                    let mut stage_reached = group_start;
+                    // Volume and cutoff continue from where the previous group ended, or from where a resumed alarm was interrupted
+                    let offset = stage_start_seconds(plan, group_start).max(offset);
+                    // Only the first group is measured from the alarm trigger
+                    let mut group_trace = LatencyTrace::new("escalation stage");
+                    let result = play_tracks(
+                        &group_path,
+                        group_track,
+                        category.map(|c| c.as_str()),
+                        trigger_time,
+                        if group_start == 0 {
+                            &mut trace
+                        } else {
+                            &mut group_trace
+                        },
+                        &lease,
+                        alarm_state,
+                        &config,
+                        max_alarm_duration(&config, offset),
+                        Box::new(move |t| {
+                            escalation_cutoff(&cutoff_stages, &curve, t + offset as f64)
+                        }),
+                        |t| {
+                            group_volume(plan, group_start..group_end, t + offset).map(|(i, v)| {
+                                stage_reached = i;
+                                v
+                            })
+                        },
+                    );
+                    outcome.escalation_stage = Some(stage_reached);
+                    if result.is_err() {
+                        outcome.result = result;
+                        break;
+                    }
+                }
+                Err(TrackError::Cancelled(_)) => break,
+                Err(e) => {
+                    error!(
+                        "Skipping escalation stages {}..{}: {}",
+                        group_start, group_end, e
+                    );
+                }
+            }
+
+            group_start = group_end;
+        }
+    }
+
+    outcome.latency = trace.latency();
+    // Not finished, so that it continues where it was interrupted after a restart
+    if shutdown::requested() {
+        info!("The alarm was interrupted by the shutdown");
+        return outcome;
+    }
+    let manually_cancelled = !alarm_state.is_trigger_time(trigger_time);
+
+    futures::executor::block_on(alarm_state.on_alarm_finished(trigger_time));
+
+    #[cfg(feature = "motion")]
+    {
+        if !manually_cancelled {
+            let alarm_state = alarm_state.clone();
+            tokio::spawn(snooze(alarm_state, trigger_time));
+        }
+    }
+
+    outcome
+}
+
+/// Plays `first_track`, decoded from `path`, followed by more sounds from `category` if the playlist is enabled.
+///
+/// `volume` is called with the time since the start, and returns the volume to play at, or `None` to fade out and stop.
+/// Playback also fades out and stops as soon as the alarm is dismissed, or once it has played for `max_duration`.
+#[allow(clippy::too_many_arguments)]
+fn play_tracks(
+    path: &Path,
+    first_track: DecodedAudio,
+    category: Option<&str>,
+    trigger_time: DateTime<Utc>,
+    trace: &mut LatencyTrace,
+    lease: &PlaybackLease,
+    alarm_state: &AlarmState,
+    config: &Config,
+    max_duration: Duration,
+    lowpass: CutoffCurve,
+    mut volume: impl FnMut(f32) -> Option<f32>,
+) -> Result<(), PlaybackError> {
     let mut fadeout_start = None;
     let fadeout_duration = 5.0;
+    let mut last_volume = 0.0;
+    // Volume of the curve, and how far it is turned up from the dismissed volume, from 0 to 1
+    let mut curve_volume = 0.0;
+    let mut turned_up = 1.0;
+    let mut prev_t = 0.0;
+    let out_of_bed = Duration::from_secs_f32(config.alarm.out_of_bed_minutes * 60.0);
+
+    let crossfade = Duration::from_secs_f32(config.alarm.crossfade_seconds);
+    let root_dir = config.sounds.directory.as_path();
+    trace.mark_decoded();
+    let (source, playlist) = crossfade_queue(first_track, crossfade);
+    let mut played = vec![sounds::sound_name(root_dir, path)];
+    let mut decoder: Option<thread::JoinHandle<()>> = None;
+    let mut playlist_failed = false;
 
-    play_audio(
-        path,
+    play_source(
+        source,
         |t| {
-            let v = fadein_slow(t);
+            // The alarm loop waits for the alarm to finish
+            watchdog::beat(Subsystem::AlarmLoop);
+            if playlist.is_finished() {
+                return None;
+            }
+
             if let Some(fadeout_start) = fadeout_start {
                 let t_fadeout = t - fadeout_start;
                 if t_fadeout > fadeout_duration {
                     return None;
                 }
-                Some(v * fadeout(t_fadeout, fadeout_duration))
+                Some(last_volume * fadeout(t_fadeout, fadeout_duration))
             } else {
-                if t > alarm_timeout || !alarm_state.is_trigger_time(trigger_time) {
+                // A dismissed alarm plays quietly, and does not time out, until the user has been out of bed for long enough
+                let dismissed = alarm_state.update_bed_exit(out_of_bed);
+                let step = (t - prev_t) / fadeout_duration;
+                prev_t = t;
+                turned_up = if dismissed {
+                    (turned_up - step).max(0.0)
+                } else {
+                    (turned_up + step).min(1.0)
+                };
+                let curve = volume(t);
+                if let Some(v) = curve {
+                    curve_volume = v;
+                }
+                if !alarm_state.is_trigger_time(trigger_time) || (curve.is_none() && !dismissed) {
                     fadeout_start = Some(t);
+                } else {
+                    let quiet = curve_volume.min(config.alarm.dismissed_volume);
+                    last_volume = quiet + (curve_volume - quiet) * turned_up;
+                }
+
+                if decoder.as_ref().is_some_and(|d| d.is_finished())
+                    && decoder.take().unwrap().join().is_err()
+                {
+                    error!("Failed to decode the next track. Not queueing any more tracks.");
+                    playlist_failed = true;
                 }
-                Some(v)
+
+                // Decode the next track in the background, so that it is ready to be crossfaded in when the current one ends
+                if config.alarm.playlist
+                    && !playlist_failed
+                    && decoder.is_none()
+                    && playlist.queued() == 0
+                    && playlist.current_remaining() < crossfade + PLAYLIST_PREPARE_AHEAD
+                {
+                    match random_alarm_sound(root_dir, &config.sounds, category, &played, &[]) {
+                        Ok(next_path) => {
+                            info!("Queueing {}", next_path.display());
+                            played.push(sounds::sound_name(root_dir, &next_path));
+                            let playlist = playlist.clone();
+                            let config = config.clone();
+                            let alarm_state = alarm_state.clone();
+                            decoder = Some(thread::spawn(move || {
+                                // If the track cannot be used, another one is picked the next time around
+                                match decode_alarm_track(
+                                    &next_path,
+                                    &config.sounds.directory,
+                                    &config,
+                                    &|| !alarm_state.is_trigger_time(trigger_time),
+                                ) {
+                                    Ok(track) => playlist.push(track),
+                                    Err(TrackError::Cancelled(_)) => {}
+                                    Err(e) => warn!("{}", e),
+                                }
+                            }));
+                        }
+                        Err(e) => {
+                            error!("{}", e);
+                            playlist_failed = true;
+                        }
+                    }
+                }
+                Some(last_volume)
             }
         },
-        true,
-    );
+        lowpass,
+        Some(max_duration),
+        config,
+        trace,
+        lease,
+    )
+}
 
-    let manually_cancelled = !alarm_state.is_trigger_time(trigger_time);
+/// What is left of [`crate::config::PlaybackConfig::max_alarm_seconds`] when the alarm continues `offset` seconds in,
+/// like after a restart.
+fn max_alarm_duration(config: &Config, offset: f32) -> Duration {
+    Duration::from_secs_f32((config.playback.max_alarm_seconds - offset).max(0.0))
+}
 
-    futures::executor::block_on(alarm_state.on_alarm_finished(trigger_time));
+/// How long before the alarm to pick and decode its sound, so that it can start playing immediately.
+const PREPARE_AHEAD: TimeDelta = TimeDelta::minutes(10);
 
-    #[cfg(feature = "motion")]
-    {
-        if !manually_cancelled {
-            let alarm_state = alarm_state.clone();
-            tokio::spawn(snooze(alarm_state, trigger_time));
-        }
-    }
+/// An alarm sound which was decoded ahead of time.
+struct PreparedAlarm {
+    trigger_time: DateTime<Utc>,
+    path: PathBuf,
+    decoded: tokio::task::JoinHandle<Result<DecodedAudio, TrackError>>,
+    cancelled: Arc<AtomicBool>,
 }
 
-#[derive(Error, Debug)]
-pub enum AlarmSoundError {
-    #[error("Could not read directory `{0}`: {1}")]
-    CouldNotReadDir(PathBuf, std::io::Error),
-    #[error("There were no sound files in the sound directory")]
-    NoFiles,
-}
-
-pub fn random_alarm_sound(root_dir: &Path) -> Result<PathBuf, AlarmSoundError> {
-    let valid_extensions = ["mp3", "ogg", "flac", "wav"];
-    match root_dir.read_dir() {
-        Ok(iter) => iter
-            .filter_map(|x| x.ok().map(|x| x.path()))
-            .filter(|path| {
-                path.extension()
-                    .and_then(OsStr::to_str)
-                    .map(|x| valid_extensions.contains(&x))
-                    .unwrap_or_default()
-            })
-            .choose(&mut rand::thread_rng())
-            .ok_or(AlarmSoundError::NoFiles),
-        Err(e) => Err(AlarmSoundError::CouldNotReadDir(root_dir.to_path_buf(), e)),
+impl PreparedAlarm {
+    /// Stops decoding, since the sound will not be used.
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
     }
 }
 
-pub async fn start_alarm_thread(alarm_state: AlarmState) {
-    info!("Starting alarm thread");
-    loop {
-        #[allow(unused_mut)]
-        let mut trigger_time = alarm_state.should_start_alarm();
+fn pick_alarm_sound(
+    alarm_state: &AlarmState,
+    config: &Config,
+    rejected: &[String],
+) -> Result<PathBuf, AlarmSoundError> {
+    // With an escalation plan, the first sound is played in the first stage
+    let category = config
+        .alarm
+        .escalation
+        .first()
+        .and_then(|stage| stage.category.clone())
+        .or_else(|| alarm_state.inner.get().and_then(|s| s.category));
+    let recently_played = alarm_state
+        .history
+        .recent(config.sounds.avoid_repeat_count)
+        .into_iter()
+        .filter_map(|entry| entry.sound)
+        .collect::<Vec<_>>();
+    random_alarm_sound(
+        &config.sounds.directory,
+        &config.sounds,
+        category.as_deref(),
+        &recently_played,
+        rejected,
+    )
+}
 
-        // If the alarm should start soon, and there is significant movement, start the alarm.
-        // Movement may indicate REM sleep, and it is desirable to wake up the user during REM sleep.
+/// `interrupted_alarm` is an alarm which was playing when the program was stopped. It continues where it was interrupted.
+pub async fn start_alarm_thread(
+    alarm_state: AlarmState,
+    mut interrupted_alarm: Option<DateTime<Utc>>,
+) {
+    info!("Starting alarm thread");
+    let mut prepared: Option<PreparedAlarm> = None;
+    while !shutdown::requested() {
+        watchdog::beat(Subsystem::AlarmLoop);
+        // Within the wake window, start the alarm when the user has come up from deep sleep or is moving.
+        // It is easier to wake up from light sleep.
         #[cfg(feature = "motion")]
-        if let Some(t) = alarm_state.should_start_alarm_soon(TimeDelta::minutes(30)) {
-            if alarm_state
-                .sleep_monitor
-                .lock()
-                .await
-                .sleep_monitor
-                .is_significant_movement()
-            {
-                trigger_time = Some(t);
-            }
+        let (signals, movement_event) = {
+            let s = alarm_state.sleep_monitor.lock().await;
+            // Without usable sensor data, the alarm starts at the start of the window as if there was no sensor
+            let signals = (!s.sleep_monitor.is_stale()).then(|| SleepSignals {
+                in_bed: s.sleep_monitor.is_present(),
+                light_sleep_transition: s.sleep_monitor.is_light_sleep_transition(),
+                significant_movement: s.sleep_monitor.is_significant_movement(),
+            });
+            (signals, s.movement_events.current().map(|e| e.id))
         };
+        #[cfg(not(feature = "motion"))]
+        let (signals, movement_event): (Option<SleepSignals>, Option<u64>) = (None, None);
+        // An alarm which was interrupted by a restart continues right away, and so does a snoozed one
+        let wake = interrupted_alarm
+            .filter(|&t| alarm_state.is_trigger_time(t))
+            .map(|t| (t, WakeReason::Resumed))
+            .or_else(|| {
+                snoozed_alarm(&alarm_state)
+                    .filter(|&t| alarm_state.is_trigger_time(t))
+                    .map(|t| (t, WakeReason::Snoozed))
+            })
+            .or_else(|| alarm_state.should_wake(signals.as_ref()));
+
+        let window = alarm_state.wake_window();
+        let upcoming = alarm_state.should_start_alarm_soon(
+            PREPARE_AHEAD + TimeDelta::minutes(window.earliest_minutes as i64),
+        );
+        if prepared
+            .as_ref()
+            .is_some_and(|p| Some(p.trigger_time) != upcoming)
+        {
+            info!("The alarm was changed. Dropping the prepared alarm sound.");
+            prepared.take().unwrap().cancel();
+        }
+        if let (None, Some(upcoming)) = (&prepared, upcoming) {
+            let config = alarm_state.config.get();
+            match pick_alarm_sound(&alarm_state, &config, &[]) {
+                Ok(path) => {
+                    info!("Preparing {} for the next alarm", path.display());
+                    let decode_path = path.clone();
+                    let cancelled = Arc::new(AtomicBool::new(false));
+                    let decode_cancelled = cancelled.clone();
+                    prepared = Some(PreparedAlarm {
+                        trigger_time: upcoming,
+                        path,
+                        decoded: tokio::task::spawn_blocking(move || {
+                            decode_alarm_track(
+                                &decode_path,
+                                &config.sounds.directory,
+                                &config,
+                                &|| decode_cancelled.load(Ordering::SeqCst),
+                            )
+                        }),
+                        cancelled,
+                    });
+                }
+                // Will be reported again when the alarm is triggered
+                Err(e) => warn!("Could not prepare the next alarm: {}", e),
+            }
+        }
+
+        if let Some((trigger_time, wake_reason)) = wake {
+            let movement_events = match wake_reason {
+                WakeReason::Movement => movement_event.into_iter().collect(),
+                WakeReason::Snoozed => snooze_movement_events(&alarm_state),
+                _ => vec![],
+            };
+            if movement_events.is_empty() {
+                info!("Starting alarm ({:?})...", wake_reason);
+            } else {
+                info!(
+                    "Starting alarm ({:?}, movement events {:?})...",
+                    wake_reason, movement_events
+                );
+            }
+            // Fades out a lucid cue right away, rather than after the alarm sound has been decoded
+            alarm_state.playback.cancel(PlaybackPriority::LucidCue);
+            alarm_state.lucid_budget.lock().unwrap().reset();
+            let started_at = Utc::now();
+            let trace = LatencyTrace::new("alarm");
+            let resume_from = (interrupted_alarm.take() == Some(trigger_time)).then(|| {
+                (started_at - trigger_time)
+                    .to_std()
+                    .unwrap_or(Duration::ZERO)
+            });
+            let config = alarm_state.config.get();
+            let root_dir = config.sounds.directory.as_path();
 
-        if let Some(trigger_time) = trigger_time {
-            info!("Starting alarm...");
-            match random_alarm_sound(Path::new("./sounds")) {
+            let mut rejected = vec![];
+            let (sound, prepared_audio) = match prepared.take() {
+                Some(p) if p.trigger_time == trigger_time && p.path.exists() => {
+                    match p.decoded.await {
+                        Ok(Ok(audio)) => (Ok(p.path), Some(audio)),
+                        // Nothing is wrong with the sound, so decode it again while playing instead of picking another one
+                        Ok(Err(TrackError::Cancelled(_))) => {
+                            info!("Decoding the prepared alarm sound was cancelled");
+                            (Ok(p.path), None)
+                        }
+                        Ok(Err(e)) => {
+                            warn!("{}. Picking another sound.", e);
+                            rejected.push(sounds::sound_name(root_dir, &p.path));
+                            (pick_alarm_sound(&alarm_state, &config, &rejected), None)
+                        }
+                        Err(e) => {
+                            warn!("Decoding the prepared alarm sound failed: {}", e);
+                            (pick_alarm_sound(&alarm_state, &config, &[]), None)
+                        }
+                    }
+                }
+                Some(p) => {
+                    warn!(
+                        "The prepared alarm sound {} can no longer be used",
+                        p.path.display()
+                    );
+                    p.cancel();
+                    (pick_alarm_sound(&alarm_state, &config, &[]), None)
+                }
+                None => (pick_alarm_sound(&alarm_state, &config, &[]), None),
+            };
+
+            let mut history_entry = AlarmHistoryEntry {
+                trigger_time,
+                started_at,
+                sound: sound
+                    .as_ref()
+                    .ok()
+                    .map(|path| sounds::sound_name(root_dir, path)),
+                playback_error: None,
+                escalation_stage: None,
+                latency: None,
+                resumed: resume_from.is_some(),
+                rejected_sounds: rejected,
+                wake_reason: Some(wake_reason),
+                early: started_at < trigger_time,
+                movement_events,
+            };
+            match sound {
                 Ok(path) => {
                     info!("Playing {}", path.to_str().unwrap());
                     #[cfg(feature = "motion")]
                     {
                         alarm_state.sleep_monitor.lock().await.alarm_is_playing = true;
                     }
+                    *alarm_state.bed_exit.lock().unwrap() = BedExit::Ringing;
                     alarm_state.is_playing.set(true).await;
-                    {
+                    alarm_state
+                        .events
+                        .now_playing(history_entry.sound.as_deref())
+                        .await;
+                    alarm_state
+                        .events
+                        .publish(PlaybackEventKind::AlarmStarted, history_entry.sound.clone())
+                        .await;
+                    let outcome = {
                         let alarm_state = alarm_state.clone();
                         tokio::task::spawn_blocking(move || {
                             // TODO: Make into async function
-                            play_alarm(&path, trigger_time, &alarm_state);
+                            play_alarm(
+                                &path,
+                                prepared_audio,
+                                trigger_time,
+                                resume_from.unwrap_or(Duration::ZERO),
+                                trace,
+                                &alarm_state,
+                            )
                         })
                         .await
-                        .unwrap();
+                        .unwrap()
+                    };
+                    history_entry.escalation_stage = outcome.escalation_stage;
+                    if let Some(sound) = &outcome.sound {
+                        history_entry.sound = Some(sounds::sound_name(root_dir, sound));
+                    }
+                    history_entry
+                        .rejected_sounds
+                        .extend(outcome.rejected_sounds);
+                    latency::record_alarm(&outcome.latency);
+                    history_entry.latency = Some(outcome.latency);
+                    if let Err(e) = outcome.result {
+                        error!("{}", e);
+                        history_entry.playback_error = Some(e.to_string());
                     }
                     #[cfg(feature = "motion")]
                     {
                         alarm_state.sleep_monitor.lock().await.alarm_is_playing = false;
                     }
                     alarm_state.is_playing.set(false).await;
+                    alarm_state.events.now_playing(None).await;
+                    alarm_state
+                        .events
+                        .publish(PlaybackEventKind::AlarmStopped, history_entry.sound.clone())
+                        .await;
                 }
                 Err(e) => {
                     error!("{}", e);
                     alarm_state.on_alarm_finished(trigger_time).await;
                 }
             }
+            // Recorded when it continues after a restart instead
+            if shutdown::requested() {
+                break;
+            }
+            alarm_state.history.record(&history_entry);
             info!("Alarm finished...");
         }
 
         tokio::time::sleep(Duration::from_millis(500)).await;
     }
 }
+
+/// Plays a quiet, low frequency tone to keep speakers with a standby mode awake.
+///
+/// Stops early if anything else wants to play. Skipped if there is no output device, like a disconnected speaker.
+fn play_keep_alive_tone(speaker: &SpeakerConfig, lease: PlaybackLease) {
+    let duration = Duration::from_secs_f32(speaker.keep_alive_seconds);
+    let tone = rodio::source::SineWave::new(speaker.keep_alive_tone_hz)
+        .amplify(speaker.keep_alive_amplitude)
+        .take_duration(duration)
+        .fade_in(duration.min(Duration::from_millis(1000)));
+    let Some(sink) =
+        output::find_output_device(None).and_then(|device| output::open_sink(&device, tone, 1.0))
+    else {
+        warn!("No audio output device. Skipping the keep-alive tone.");
+        return;
+    };
+    // The tone is too quiet to pop when it is stopped
+    while !sink.empty() && !lease.is_cancelled() {
+        thread::sleep(Duration::from_millis(40));
+    }
+    sink.stop();
+}
+
+/// During the time before the alarm, regularly plays an inaudible tone so that the speakers are awake when the alarm starts.
+pub async fn keep_speaker_awake(alarm_state: AlarmState) {
+    let mut last_keep_alive: Option<Instant> = None;
+    loop {
+        tokio::time::sleep(Duration::from_secs(10)).await;
+
+        let speaker = alarm_state.config.get().speaker;
+        if !speaker.keep_alive {
+            continue;
+        }
+
+        let alarm_is_near = alarm_state
+            .should_start_alarm_soon(TimeDelta::minutes(speaker.keep_alive_window_minutes as i64))
+            .is_some();
+        let is_due = last_keep_alive
+            .map(|t| {
+                t.elapsed() >= Duration::from_secs(speaker.keep_alive_interval_minutes as u64 * 60)
+            })
+            .unwrap_or(true);
+        let is_playing =
+            alarm_state.is_playing.get().unwrap_or(false) || alarm_state.playback.is_playing();
+
+        if alarm_is_near && is_due && !is_playing {
+            last_keep_alive = Some(Instant::now());
+            let playback = alarm_state.playback.clone();
+            tokio::task::spawn_blocking(move || {
+                if let Some(lease) = playback.request(PlaybackPriority::KeepAlive) {
+                    play_keep_alive_tone(&speaker, lease);
+                }
+            })
+            .await
+            .unwrap();
+        }
+    }
+}
+
+#[test]
+fn test_escalation_volume() {
+    let stage = |volume, duration_seconds| EscalationStage {
+        category: None,
+        volume,
+        duration_seconds,
+        lowpass: true,
+    };
+    let stages = [stage(0.2, 120.0), stage(0.5, 60.0), stage(1.0, 60.0)];
+
+    assert_eq!(escalation_volume(&stages, 0.0), Some((0, 0.0)));
+    assert_eq!(escalation_volume(&stages, 60.0), Some((0, 0.2)));
+    // Fades from the volume of the previous stage
+    assert_eq!(escalation_volume(&stages, 120.0), Some((1, 0.2)));
+    assert_eq!(escalation_volume(&stages, 150.0), Some((1, 0.5)));
+    assert_eq!(escalation_volume(&stages, 239.0), Some((2, 1.0)));
+    assert_eq!(escalation_volume(&stages, 240.0), None);
+}
+
+#[test]
+fn test_group_volume_is_continuous() {
+    let stage = |category: &str, volume, duration_seconds| EscalationStage {
+        category: Some(category.to_owned()),
+        volume,
+        duration_seconds,
+        lowpass: true,
+    };
+    let plan = [stage("soft", 0.2, 120.0), stage("loud", 1.0, 60.0)];
+
+    let first_end = stage_start_seconds(&plan, 1);
+    assert_eq!(first_end, 120.0);
+    assert_eq!(group_volume(&plan, 0..1, first_end - 1.0), Some((0, 0.2)));
+    assert_eq!(group_volume(&plan, 0..1, first_end), None);
+    // The second group fades from the volume the first group ended at
+    assert_eq!(group_volume(&plan, 1..2, first_end), Some((1, 0.2)));
+    assert_eq!(group_volume(&plan, 1..2, first_end + 30.0), Some((1, 1.0)));
+    // The cutoff keeps opening up instead of restarting
+    let curve = LowpassConfig::default();
+    assert_eq!(
+        escalation_cutoff(&plan, &curve, first_end as f64),
+        frequency_cutoff_lowpass(first_end, &curve) as f64
+    );
+}
+
+#[test]
+fn test_decode_high_bit_depth() {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    for (file, sample_rate) in [("sine_24bit_96k.flac", 96000), ("sine_f32_48k.wav", 48000)] {
+        let decoded = decode_mp3(&fixtures.join(file), &|| false, &mut |_| {}).unwrap();
+        assert_eq!(decoded.channels(), 2, "{file}");
+        assert_eq!(decoded.sample_rate(), sample_rate, "{file}");
+
+        // Both fixtures are a 1 kHz sine wave with an amplitude of 0.5
+        let samples = decoded.collect::<Vec<_>>();
+        assert_eq!(samples.len(), 2000, "{file}");
+        let peak = samples.iter().fold(0.0f32, |a, &b| a.max(b.abs()));
+        assert!((peak - 0.5).abs() < 0.01, "{file}: {peak}");
+
+        let cancelled = decode_mp3(&fixtures.join(file), &|| true, &mut |_| {});
+        assert!(cancelled.is_err(), "{file}");
+    }
+}
+
+#[test]
+fn test_intro_chime() {
+    let track = DecodedAudio::new(2, 44100, vec![0.25; 100]);
+    let mut config = Config::default();
+    assert_eq!(
+        with_intro_chime(track.clone(), &config, &|| false)
+            .unwrap()
+            .count(),
+        100
+    );
+
+    // The chime is a 48 kHz stereo file with 1000 frames
+    config.alarm.intro_chime =
+        Some(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/sine_f32_48k.wav"));
+    let joined = with_intro_chime(track.clone(), &config, &|| false)
+        .unwrap()
+        .collect::<Vec<_>>();
+    let chime_samples = 1000 * 2 * 44100 / 48000;
+    assert!(
+        joined.len().abs_diff(chime_samples + 100) <= 4,
+        "{}",
+        joined.len()
+    );
+    assert!(joined[joined.len() - 100..].iter().all(|&s| s == 0.25));
+
+    config.alarm.intro_chime = Some(PathBuf::from("does/not/exist.mp3"));
+    assert_eq!(
+        with_intro_chime(track, &config, &|| false).unwrap().count(),
+        100
+    );
+}
+
+#[test]
+fn test_reject_broken_tracks() {
+    let root_dir = std::env::temp_dir().join(format!("alarm-test-{}", std::process::id()));
+    std::fs::create_dir_all(&root_dir).unwrap();
+    let empty = root_dir.join("empty.mp3");
+    std::fs::write(&empty, []).unwrap();
+    let truncated = root_dir.join("truncated.flac");
+    std::fs::write(&truncated, b"fLaC").unwrap();
+    let short = root_dir.join("short.wav");
+    std::fs::copy(
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/sine_f32_48k.wav"),
+        &short,
+    )
+    .unwrap();
+
+    let mut config = Config::default();
+    let decode =
+        |path: &Path, config: &Config| decode_alarm_track(path, &root_dir, config, &|| false);
+    assert!(matches!(decode(&empty, &config), Err(TrackError::Empty(_))));
+    // Played without the checks of an alarm track, like a preview
+    assert!(matches!(
+        decode_mp3(&empty, &|| false, &mut |_| {}),
+        Err(TrackError::Decode(..))
+    ));
+    assert!(matches!(
+        decode(&truncated, &config),
+        Err(TrackError::Decode(..))
+    ));
+    assert!(matches!(
+        decode(&short, &config),
+        Err(TrackError::TooShort(_, d)) if (d - 1000.0 / 48000.0).abs() < 1e-4
+    ));
+    config.sounds.min_duration_seconds = 0.0;
+    assert!(decode(&short, &config).is_ok());
+
+    let min_duration = Config::default().sounds.min_duration_seconds;
+    let analysis = analysis::load(&root_dir);
+    let unplayed = SoundAnalysis::default;
+    assert!(sounds::sound_problem(&empty, &mut unplayed(), None, min_duration).is_some());
+    let problem = |name: &str, trim, min_duration| {
+        let mut analysis = analysis.get(name).cloned().unwrap();
+        sounds::sound_problem(&root_dir.join(name), &mut analysis, trim, min_duration)
+    };
+    assert!(problem("truncated.flac", None, 0.0).is_some());
+    assert!(problem("short.wav", None, min_duration).is_some());
+    assert!(problem("short.wav", None, 0.0).is_none());
+
+    // Sounds which have not been played yet are probed
+    let probed = |path: &Path, min_duration| {
+        sounds::sound_problem(path, &mut unplayed(), None, min_duration)
+    };
+    assert!(probed(&truncated, 0.0).is_some());
+    assert!(probed(&short, min_duration).is_some());
+    assert!(probed(&short, 0.0).is_none());
+    // The probe is cached until the file is modified
+    let mut cached = unplayed();
+    assert!(sounds::sound_problem(&short, &mut cached, None, 0.0).is_none());
+    assert!(!cached.is_decoded());
+    let probe = cached.probe.as_mut().unwrap();
+    assert!(probe.error.is_none());
+    probe.duration_seconds = Some(100.0);
+    assert!(sounds::sound_problem(&short, &mut cached, None, min_duration).is_none());
+    // As if the file was modified after it was probed
+    cached.probe.as_mut().unwrap().modified_ms -= 1;
+    assert!(sounds::sound_problem(&short, &mut cached, None, min_duration).is_some());
+
+    // Only the trimmed part is played
+    let trim = sounds::Trim {
+        start_seconds: 0.015,
+        end_seconds: None,
+    };
+    config.sounds.min_duration_seconds = 0.01;
+    assert!(decode(&short, &config).is_ok());
+    assert!(problem("short.wav", Some(trim), 0.01).is_some());
+    let mut trims = sounds::SoundTrims::default();
+    trims.set("short.wav", Some(trim));
+    trims.save(&root_dir).unwrap();
+    assert!(matches!(
+        decode(&short, &config),
+        Err(TrackError::TooShort(_, d)) if d < 0.01
+    ));
+    std::fs::remove_dir_all(&root_dir).unwrap();
+}
@@ -0,0 +1,290 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+/// Name of the file in a sound directory which stores the results of analysing each sound.
+pub const ANALYSIS_FILE: &str = "analysis.toml";
+
+/// Length of the windows that the loudness is measured over when looking for silence.
+const SILENCE_WINDOW_SECONDS: f32 = 0.05;
+
+/// Number of frames at the start of `samples` before the first window whose RMS exceeds `threshold_db` (in dBFS).
+///
+/// At most `max_skip_frames` are skipped. If the whole track is quiet, nothing is skipped.
+pub fn leading_silence(
+    samples: &[f32],
+    channels: u16,
+    sample_rate: u32,
+    threshold_db: f32,
+    max_skip_frames: usize,
+) -> usize {
+    let channels = channels.max(1) as usize;
+    let window_frames = ((SILENCE_WINDOW_SECONDS * sample_rate as f32) as usize).max(1);
+    let threshold = 10f32.powf(threshold_db / 20.0);
+
+    for (i, window) in samples.chunks(window_frames * channels).enumerate() {
+        let mean_square = window.iter().map(|x| x * x).sum::<f32>() / window.len() as f32;
+        if mean_square.sqrt() > threshold {
+            return (i * window_frames).min(max_skip_frames);
+        }
+    }
+
+    0
+}
+
+/// Removes the first `frames` frames of `samples`, and fades in what remains over `fade_frames` to avoid a click.
+pub fn trim_start(samples: &mut Vec<f32>, channels: u16, frames: usize, fade_frames: usize) {
+    let channels = channels.max(1) as usize;
+    samples.drain(..(frames * channels).min(samples.len()));
+    if frames == 0 {
+        return;
+    }
+    for (i, frame) in samples.chunks_mut(channels).take(fade_frames).enumerate() {
+        let gain = i as f32 / fade_frames as f32;
+        for s in frame {
+            *s *= gain;
+        }
+    }
+}
+
+/// Keeps only the first `frames` frames of `samples`, and fades out the end over `fade_frames` to avoid a click.
+pub fn trim_end(samples: &mut Vec<f32>, channels: u16, frames: usize, fade_frames: usize) {
+    let channels = channels.max(1) as usize;
+    if frames * channels >= samples.len() {
+        return;
+    }
+    samples.truncate(frames * channels);
+    let fade_frames = fade_frames.min(frames);
+    for (i, frame) in samples
+        .chunks_mut(channels)
+        .rev()
+        .take(fade_frames)
+        .enumerate()
+    {
+        let gain = i as f32 / fade_frames as f32;
+        for s in frame {
+            *s *= gain;
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SoundAnalysis {
+    /// Seconds of near-silence that were skipped at the start of the sound the last time it was played.
+    pub leading_silence_seconds: f32,
+    /// Length of the decoded sound.
+    #[serde(default)]
+    pub duration_seconds: Option<f32>,
+    /// The sound could not be decoded.
+    #[serde(default)]
+    pub undecodable: bool,
+    /// The header of the sound, read when it was listed before it had been played.
+    #[serde(default)]
+    pub probe: Option<ProbedDuration>,
+}
+
+impl SoundAnalysis {
+    /// The sound has been decoded, or failed to, rather than only had its header read.
+    pub fn is_decoded(&self) -> bool {
+        let decoded = SoundAnalysis {
+            probe: None,
+            ..self.clone()
+        };
+        decoded != SoundAnalysis::default()
+    }
+}
+
+/// The result of [`probed_duration`], which is valid as long as the file is not modified.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProbedDuration {
+    /// When the file was last modified, in milliseconds since the Unix epoch.
+    pub modified_ms: u64,
+    /// Length of the sound, if the header contains it.
+    pub duration_seconds: Option<f32>,
+    /// Why the header could not be read.
+    pub error: Option<String>,
+}
+
+/// A sound whose header has been read, ready to be decoded.
+#[cfg(feature = "audio")]
+pub struct ProbedSound {
+    pub format: Box<dyn symphonia::core::formats::FormatReader>,
+    pub decoder: Box<dyn symphonia::core::codecs::Decoder>,
+    /// The first track with a known codec.
+    pub track_id: u32,
+    /// Of the track, as far as the header tells.
+    pub params: symphonia::core::codecs::CodecParameters,
+}
+
+/// Reads the header of the sound at `path`, and creates a decoder for its first audio track.
+///
+/// Fails if the file is missing, or is not a sound which can be decoded.
+#[cfg(feature = "audio")]
+pub fn probe(path: &Path) -> Result<ProbedSound, symphonia::core::errors::Error> {
+    use symphonia::core::codecs::CODEC_TYPE_NULL;
+    use symphonia::core::io::MediaSourceStream;
+    use symphonia::core::probe::Hint;
+
+    let src = std::fs::File::open(path)?;
+    let mss = MediaSourceStream::new(Box::new(src), Default::default());
+    // The extension is only a hint, the contents decide the format
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|x| x.to_str()) {
+        hint.with_extension(extension);
+    }
+    let probed = symphonia::default::get_probe().format(
+        &hint,
+        mss,
+        &Default::default(),
+        &Default::default(),
+    )?;
+    let track = probed
+        .format
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or(symphonia::core::errors::Error::Unsupported(
+            "no supported audio tracks",
+        ))?;
+    // Fails if there is no decoder for the codec
+    let decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &Default::default())?;
+    let (track_id, params) = (track.id, track.codec_params.clone());
+    Ok(ProbedSound {
+        format: probed.format,
+        decoder,
+        track_id,
+        params,
+    })
+}
+
+/// Reads the header of the sound at `path` without decoding it, and returns its length if the header contains it.
+///
+/// Fails if the file is not a sound which can be decoded.
+#[cfg(feature = "audio")]
+fn probe_duration(path: &Path) -> Result<Option<f32>, String> {
+    let params = probe(path)
+        .map_err(|e| format!("The file could not be decoded: {e}"))?
+        .params;
+    Ok(params
+        .n_frames
+        .zip(params.sample_rate)
+        .map(|(frames, sample_rate)| frames as f32 / sample_rate as f32))
+}
+
+/// Sounds cannot be decoded without audio support, so nothing is known about them until they are played.
+#[cfg(not(feature = "audio"))]
+fn probe_duration(_path: &Path) -> Result<Option<f32>, String> {
+    Ok(None)
+}
+
+/// Milliseconds since the Unix epoch at which the file at `path` was last modified.
+fn modified_ms(path: &Path) -> Option<u64> {
+    let modified = std::fs::metadata(path).ok()?.modified().ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_millis() as u64)
+}
+
+/// Length of the sound at `path` from its header, see [`probe_duration`].
+///
+/// The result is cached in `analysis` until the file is modified, since listing the sounds probes all of them.
+pub fn probed_duration(path: &Path, analysis: &mut SoundAnalysis) -> Result<Option<f32>, String> {
+    let modified_ms = modified_ms(path);
+    if let Some(probe) = analysis
+        .probe
+        .as_ref()
+        .filter(|p| Some(p.modified_ms) == modified_ms)
+    {
+        return match &probe.error {
+            Some(e) => Err(e.clone()),
+            None => Ok(probe.duration_seconds),
+        };
+    }
+    let result = probe_duration(path);
+    if let Some(modified_ms) = modified_ms {
+        analysis.probe = Some(ProbedDuration {
+            modified_ms,
+            duration_seconds: result.as_ref().ok().copied().flatten(),
+            error: result.as_ref().err().cloned(),
+        });
+    }
+    result
+}
+
+/// Serializes writes to the analysis file, since tracks may be decoded on several threads.
+static ANALYSIS_LOCK: Mutex<()> = Mutex::new(());
+
+/// Loads the analysis of all sounds in `root_dir` which have been analysed so far.
+pub fn load(root_dir: &Path) -> BTreeMap<String, SoundAnalysis> {
+    let path = root_dir.join(ANALYSIS_FILE);
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return BTreeMap::new(),
+        Err(e) => {
+            warn!("Could not read {}: {}", path.display(), e);
+            return BTreeMap::new();
+        }
+    };
+    toml::from_str(&contents).unwrap_or_else(|e| {
+        warn!("Could not parse {}: {}", path.display(), e);
+        BTreeMap::new()
+    })
+}
+
+/// Stores the analysis of the sound called `name` in the analysis file of `root_dir`.
+pub fn record(root_dir: &Path, name: &str, analysis: SoundAnalysis) {
+    record_all(root_dir, [(name.to_owned(), analysis)]);
+}
+
+/// Stores the analysis of several sounds at once, by name. The file is only written if anything changed.
+pub fn record_all(root_dir: &Path, analyses: impl IntoIterator<Item = (String, SoundAnalysis)>) {
+    let _guard = ANALYSIS_LOCK.lock().unwrap();
+    let mut all = load(root_dir);
+    let mut changed = false;
+    for (name, analysis) in analyses {
+        if all.get(&name) != Some(&analysis) {
+            all.insert(name, analysis);
+            changed = true;
+        }
+    }
+    if !changed {
+        return;
+    }
+
+    let path = root_dir.join(ANALYSIS_FILE);
+    let result = toml::to_string(&all)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+        .and_then(|contents| std::fs::write(&path, contents));
+    if let Err(e) = result {
+        warn!("Could not save {}: {}", path.display(), e);
+    }
+}
+
+#[test]
+fn test_leading_silence() {
+    let sample_rate = 1000;
+    // Two seconds of faint noise followed by a loud tone, in stereo
+    let mut samples = vec![0.001f32; 2 * 2000];
+    samples.extend((0..2 * 1000).map(|i| if i % 4 < 2 { 0.5 } else { -0.5 }));
+
+    assert_eq!(
+        leading_silence(&samples, 2, sample_rate, -40.0, 10_000),
+        2000
+    );
+    assert_eq!(leading_silence(&samples, 2, sample_rate, -40.0, 500), 500);
+    // Nothing is loud enough, so nothing is skipped
+    assert_eq!(leading_silence(&samples, 2, sample_rate, 0.0, 10_000), 0);
+
+    trim_start(&mut samples, 2, 2000, 10);
+    assert_eq!(samples.len(), 2 * 1000);
+    assert_eq!(samples[0], 0.0);
+    assert_eq!(samples[2 * 20], 0.5);
+
+    trim_end(&mut samples, 2, 500, 10);
+    assert_eq!(samples.len(), 2 * 500);
+    assert_eq!(samples[samples.len() - 1], 0.0);
+    assert_eq!(samples[samples.len() - 2 * 20], 0.5);
+}
@@ -0,0 +1,331 @@
+//! Long-term archive of the accelerometer logs, as per-minute aggregates named `minutes-YYYY-MM-DD.csv`.
+//!
+//! A day of readings is a few megabytes, while its aggregates are less than a hundred kilobytes,
+//! so the aggregates are kept after the raw logs are deleted. Only the readings of the user's sensor are archived.
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeDelta, Utc};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::histogram;
+use crate::motion_log::{self, in_hours, LogSample, SleepStats};
+use crate::respiration::Respiration;
+
+const PREFIX: &str = "minutes-";
+const DATE_FORMAT: &str = "%Y-%m-%d";
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M";
+
+/// The readings of one minute.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MinuteAggregate {
+    /// Start of the minute.
+    pub time: DateTime<Utc>,
+    pub readings: u32,
+    /// Change in acceleration between readings, in g. `None` if no reading has a previous one.
+    pub mean_movement: Option<f32>,
+    pub max_movement: Option<f32>,
+    /// Fraction of the readings when someone was in bed. `None` if unknown for all of them.
+    pub present_fraction: Option<f32>,
+    /// Mean temperature of the sensor, in °C.
+    pub temp: f32,
+    /// Breathing rate during the minute. `None` if it could not be estimated, or in archives from before it was.
+    pub respiration: Option<Respiration>,
+}
+
+impl MinuteAggregate {
+    fn to_csv(&self) -> String {
+        let optional = |v: Option<f32>| v.map_or(String::new(), |v| v.to_string());
+        format!(
+            "{},{},{},{},{},{},{},{}\n",
+            self.time.format(TIME_FORMAT),
+            self.readings,
+            optional(self.mean_movement),
+            optional(self.max_movement),
+            optional(self.present_fraction),
+            self.temp,
+            optional(self.respiration.map(|r| r.breaths_per_minute)),
+            optional(self.respiration.map(|r| r.confidence)),
+        )
+    }
+
+    fn parse_csv(line: &str) -> Option<MinuteAggregate> {
+        let mut fields = line.trim_end().split(',');
+        let time = NaiveDateTime::parse_from_str(fields.next()?, TIME_FORMAT)
+            .ok()?
+            .and_utc();
+        let readings = fields.next()?.parse().ok()?;
+        let mut optional = || -> Option<Option<f32>> {
+            match fields.next()? {
+                "" => Some(None),
+                v => v.parse().ok().map(Some),
+            }
+        };
+        let (mean_movement, max_movement, present_fraction) =
+            (optional()?, optional()?, optional()?);
+        let temp = fields.next()?.parse().ok()?;
+        // Added later, so older archives don't have it
+        let respiration = match (fields.next(), fields.next()) {
+            (Some(rate), Some(confidence)) if !rate.is_empty() => Some(Respiration {
+                breaths_per_minute: rate.parse().ok()?,
+                confidence: confidence.parse().ok()?,
+            }),
+            _ => None,
+        };
+        Some(MinuteAggregate {
+            time,
+            readings,
+            mean_movement,
+            max_movement,
+            present_fraction,
+            temp,
+            respiration,
+        })
+    }
+}
+
+/// Sums up the samples per minute, oldest first. The movement must already be filled in, see [`motion_log::with_movement`].
+///
+/// The breathing rate of a minute is logged with the first reading after it, so the one of the last minute before
+/// the samples end is not known.
+pub fn aggregate(samples: &[LogSample]) -> Vec<MinuteAggregate> {
+    let mut minutes: BTreeMap<DateTime<Utc>, Vec<&LogSample>> = BTreeMap::new();
+    let mut respiration = BTreeMap::new();
+    for s in samples {
+        let minute = histogram::bucket_start(s.time, TimeDelta::minutes(1), &Utc);
+        minutes.entry(minute).or_default().push(s);
+        if let Some(r) = s.respiration {
+            respiration.insert(minute - TimeDelta::minutes(1), r);
+        }
+    }
+    let mean = |values: &[f32]| {
+        (!values.is_empty()).then(|| values.iter().sum::<f32>() / values.len() as f32)
+    };
+    minutes
+        .into_iter()
+        .map(|(time, samples)| {
+            let movements = samples
+                .iter()
+                .filter_map(|s| s.movement)
+                .collect::<Vec<_>>();
+            let present = samples
+                .iter()
+                .filter_map(|s| s.present.map(|p| p as u32 as f32))
+                .collect::<Vec<_>>();
+            let temps = samples.iter().map(|s| s.data.temp).collect::<Vec<_>>();
+            MinuteAggregate {
+                time,
+                readings: samples.len() as u32,
+                mean_movement: mean(&movements),
+                max_movement: movements.iter().copied().reduce(f32::max),
+                present_fraction: mean(&present),
+                temp: mean(&temps).unwrap(),
+                respiration: respiration.get(&time).copied(),
+            }
+        })
+        .collect()
+}
+
+/// Statistics of the minutes which are within the local hours `start_hour..end_hour`. See [`motion_log::get_stats`].
+pub fn stats(minutes: &[MinuteAggregate], start_hour: u32, end_hour: u32) -> SleepStats {
+    let minutes = minutes
+        .iter()
+        .filter(|m| in_hours(m.time, start_hour, end_hour))
+        .collect::<Vec<_>>();
+    // Weighted by the number of readings, so that it is the same as the mean of the readings
+    let weighted_mean = |value: fn(&MinuteAggregate) -> Option<f32>| {
+        let (sum, readings) = minutes
+            .iter()
+            .filter_map(|m| Some((value(m)? * m.readings as f32, m.readings)))
+            .fold((0.0, 0), |(sum, n), (v, r)| (sum + v, n + r));
+        (readings > 0).then(|| sum / readings as f32)
+    };
+    SleepStats {
+        samples: minutes.iter().map(|m| m.readings as u64).sum(),
+        mean_movement: weighted_mean(|m| m.mean_movement),
+        present_fraction: weighted_mean(|m| m.present_fraction),
+        ..Default::default()
+    }
+}
+
+fn archive_path(dir: &Path, date: NaiveDate) -> PathBuf {
+    dir.join(format!("{PREFIX}{}.csv", date.format(DATE_FORMAT)))
+}
+
+pub fn is_archived(dir: &Path, date: NaiveDate) -> bool {
+    archive_path(dir, date).exists()
+}
+
+fn write_archive(dir: &Path, date: NaiveDate, minutes: &[MinuteAggregate]) -> io::Result<()> {
+    let path = archive_path(dir, date);
+    let tmp = path.with_extension("csv.tmp");
+    let mut file = BufWriter::new(File::create(&tmp)?);
+    for m in minutes {
+        file.write_all(m.to_csv().as_bytes())?;
+    }
+    file.into_inner()?.sync_all()?;
+    fs::rename(&tmp, &path)
+}
+
+fn read_archive(dir: &Path, date: NaiveDate) -> io::Result<Vec<MinuteAggregate>> {
+    let path = archive_path(dir, date);
+    let file = match File::open(&path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        file => file?,
+    };
+    let mut minutes = vec![];
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        match MinuteAggregate::parse_csv(&line) {
+            Some(m) => minutes.push(m),
+            None => warn!("Skipping invalid line in {}: {}", path.display(), line),
+        }
+    }
+    Ok(minutes)
+}
+
+/// Archives the daily log `log` of `date`, which may be gzipped.
+pub fn archive_log(dir: &Path, date: NaiveDate, log: &Path) -> io::Result<()> {
+    // A read error, like a truncated gzip file, fails the whole archive so that the log is not deleted
+    let lines = motion_log::open(log)?
+        .lines()
+        .collect::<io::Result<Vec<_>>>()?;
+    write_archive(dir, date, &aggregate(&motion_log::parse_my_side(lines)))
+}
+
+/// Archives a log which may span many days, like the single log which was used before the daily logs.
+///
+/// Days which are already archived are skipped. Returns the number of archived days.
+pub fn backfill(dir: &Path, csv: &Path) -> io::Result<usize> {
+    let mut days: BTreeMap<NaiveDate, Vec<LogSample>> = BTreeMap::new();
+    for line in motion_log::open(csv)?.lines() {
+        let line = line?;
+        match LogSample::parse_csv(&line) {
+            Some(sample) if sample.sensor != motion_log::MY_SENSOR => {}
+            Some(sample) => days
+                .entry(sample.time.date_naive())
+                .or_default()
+                .push(sample),
+            None => warn!("Skipping invalid line in {}: {}", csv.display(), line),
+        }
+    }
+
+    fs::create_dir_all(dir)?;
+    let mut count = 0;
+    for (date, samples) in days {
+        if is_archived(dir, date) {
+            info!("Skipping {}, which is already archived", date);
+            continue;
+        }
+        write_archive(dir, date, &aggregate(&motion_log::with_movement(samples)))?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// The minutes between `from` and `to`. Uses the raw logs for the days which still have them, and the archive for older days.
+pub fn read(
+    dir: &Path,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> io::Result<Vec<MinuteAggregate>> {
+    let mut minutes = vec![];
+    let mut date = from.date_naive();
+    while date <= to.date_naive() {
+        if motion_log::has_log(dir, date) {
+            let start = date.and_hms_opt(0, 0, 0).unwrap().and_utc();
+            let end = start + TimeDelta::days(1) - TimeDelta::milliseconds(1);
+            let lines = motion_log::read(dir, from.max(start), to.min(end))?;
+            minutes.extend(aggregate(&motion_log::parse_my_side(lines)));
+        } else {
+            minutes.extend(
+                read_archive(dir, date)?
+                    .into_iter()
+                    .filter(|m| from <= m.time && m.time <= to),
+            );
+        }
+        date = date.succ_opt().unwrap();
+    }
+    Ok(minutes)
+}
+
+#[test]
+fn test_archive() {
+    use crate::sleep_monitor::AccelerometerData;
+    use chrono::TimeZone;
+
+    let dir = std::env::temp_dir().join(format!("archive-test-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let start = Utc.with_ymd_and_hms(2024, 3, 1, 23, 59, 0).unwrap();
+    let sample = |seconds, acc, present| LogSample {
+        sensor: motion_log::MY_SENSOR,
+        time: start + TimeDelta::seconds(seconds),
+        samples: 10,
+        alarm_playing: false,
+        present,
+        movement: None,
+        data: AccelerometerData {
+            acc: (acc, 0.0, -1.0),
+            gyro: (0.0, 0.0, 0.0),
+            temp: 20.0 + seconds as f32 / 60.0,
+        },
+        respiration: None,
+    };
+    // Two minutes on two days. The CSV logs before the presence was logged don't have it.
+    let samples = [
+        sample(0, 0.0, Some(true)),
+        sample(20, 0.1, Some(true)),
+        sample(40, 0.1, Some(false)),
+        sample(60, 0.4, None),
+        sample(90, 0.4, None),
+    ];
+    let csv = dir.join("accelerometer.csv");
+    fs::write(
+        &csv,
+        samples.iter().map(LogSample::to_csv).collect::<String>(),
+    )
+    .unwrap();
+
+    assert_eq!(backfill(&dir, &csv).unwrap(), 2);
+    assert_eq!(backfill(&dir, &csv).unwrap(), 0);
+    let minutes = read(&dir, start, start + TimeDelta::minutes(5)).unwrap();
+    assert_eq!(minutes.len(), 2);
+    assert_eq!(minutes[0].time, start);
+    assert_eq!(minutes[0].readings, 3);
+    // The readings are 20 seconds apart, so about half of the change is removed as drift
+    assert!((minutes[0].mean_movement.unwrap() - 0.0382).abs() < 1e-4);
+    assert!((minutes[0].max_movement.unwrap() - 0.0513).abs() < 1e-4);
+    assert!((minutes[0].present_fraction.unwrap() - 2.0 / 3.0).abs() < 1e-6);
+    // The change from the last reading of the first day is not known, so the mean is only over the second reading,
+    // which did not move. Counting the change across the days would have given a large movement.
+    assert_eq!(minutes[1].mean_movement, Some(0.0));
+    assert_eq!(minutes[1].present_fraction, None);
+    assert_eq!(minutes[1].temp, 21.25);
+
+    // Gives the same as the raw readings
+    let raw = aggregate(&motion_log::with_movement(samples[..3].to_vec()));
+    assert_eq!(raw[0], minutes[0]);
+    // The breathing rate is logged with the first reading of the next minute
+    let breathing = Respiration {
+        breaths_per_minute: 15.0,
+        confidence: 0.5,
+    };
+    let mut logged = samples.to_vec();
+    logged[3].respiration = Some(breathing);
+    let raw = aggregate(&logged);
+    assert_eq!(
+        (raw[0].respiration, raw[1].respiration),
+        (Some(breathing), None)
+    );
+    assert_eq!(
+        MinuteAggregate::parse_csv(&raw[0].to_csv()).as_ref(),
+        Some(&raw[0])
+    );
+    let stats = stats(&minutes, 0, 24);
+    assert_eq!(stats.samples, 5);
+    assert!((stats.present_fraction.unwrap() - 2.0 / 3.0).abs() < 1e-6);
+    fs::remove_dir_all(&dir).unwrap();
+}
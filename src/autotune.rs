@@ -0,0 +1,250 @@
+//! Proposes the sleep monitor thresholds from the changes in acceleration while the bed is known to be empty or occupied.
+//!
+//! The user marks the bed as empty or occupied, for example over the first night. The changes are collected for each,
+//! and the thresholds are proposed from the two distributions. The proposal is only used once it has been accepted.
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use thiserror::Error;
+
+use crate::config::{self, SleepMonitorConfig};
+use crate::AlarmState;
+
+pub const AUTOTUNE_FILE: &str = "autotune.json";
+/// Both distributions need at least this many changes, about a minute of readings.
+const MIN_SAMPLES: u64 = 600;
+/// Saved after this many changes, about ten minutes of readings.
+const SAVE_EVERY: u64 = 6000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BedLabel {
+    Empty,
+    Occupied,
+}
+
+/// Mean and standard deviation of the changes in acceleration, updated one change at a time.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct DeltaStats {
+    pub count: u64,
+    pub mean: f64,
+    /// Sum of the squared differences from the mean.
+    m2: f64,
+}
+
+impl DeltaStats {
+    pub fn add(&mut self, delta: f32) {
+        self.count += 1;
+        let diff = delta as f64 - self.mean;
+        self.mean += diff / self.count as f64;
+        self.m2 += diff * (delta as f64 - self.mean);
+    }
+
+    pub fn std_dev(&self) -> f64 {
+        if self.count < 2 {
+            return 0.0;
+        }
+        (self.m2 / (self.count - 1) as f64).sqrt()
+    }
+}
+
+/// Thresholds which can replace the ones in [`SleepMonitorConfig`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Proposal {
+    pub noise_threshold: f32,
+    pub movement_threshold: f32,
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum AutoTuneError {
+    #[error("Only {count} changes while the bed was {label:?}, at least {MIN_SAMPLES} are needed")]
+    TooFewSamples { label: BedLabel, count: u64 },
+    #[error("The empty and the occupied bed are too similar to tell apart with {std_devs} standard deviations")]
+    Overlap { std_devs: f32 },
+}
+
+/// Proposes the thresholds `std_devs` standard deviations above each distribution.
+///
+/// The noise threshold is that far above the changes of the empty bed, and must be below the mean of the occupied bed.
+/// The movement threshold is that far above the changes of the occupied bed, so that breathing is not movement.
+pub fn propose(
+    empty: &DeltaStats,
+    occupied: &DeltaStats,
+    std_devs: f32,
+) -> Result<Proposal, AutoTuneError> {
+    for (label, stats) in [(BedLabel::Empty, empty), (BedLabel::Occupied, occupied)] {
+        if stats.count < MIN_SAMPLES {
+            return Err(AutoTuneError::TooFewSamples {
+                label,
+                count: stats.count,
+            });
+        }
+    }
+    let k = std_devs as f64;
+    let noise_threshold = empty.mean + k * empty.std_dev();
+    if noise_threshold >= occupied.mean {
+        return Err(AutoTuneError::Overlap { std_devs });
+    }
+    Ok(Proposal {
+        noise_threshold: noise_threshold as f32,
+        movement_threshold: (occupied.mean + k * occupied.std_dev()) as f32,
+    })
+}
+
+/// Collected changes, and the last proposal. Saved in [`AUTOTUNE_FILE`], so that a restart during the night loses at most a few minutes.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AutoTune {
+    /// What the bed currently is, according to the user. Nothing is collected while it is `None`.
+    pub label: Option<BedLabel>,
+    pub empty: DeltaStats,
+    pub occupied: DeltaStats,
+    pub proposal: Option<Proposal>,
+    /// Why there is no proposal.
+    pub error: Option<String>,
+}
+
+impl AutoTune {
+    pub fn load(path: &Path) -> AutoTune {
+        std::fs::read_to_string(path)
+            .ok()
+            .and_then(|s| {
+                serde_json::from_str(&s)
+                    .map_err(|e| warn!("Could not parse {}: {}", path.display(), e))
+                    .ok()
+            })
+            .unwrap_or_default()
+    }
+
+    fn save(&self, path: &Path) {
+        let result = serde_json::to_string_pretty(self)
+            .map_err(std::io::Error::from)
+            .and_then(|json| std::fs::write(path, json));
+        if let Err(e) = result {
+            warn!("Could not save {}: {}", path.display(), e);
+        }
+    }
+
+    /// Adds a change in acceleration to the distribution of the current label.
+    pub fn add(&mut self, delta: f32) {
+        let stats = match self.label {
+            Some(BedLabel::Empty) => &mut self.empty,
+            Some(BedLabel::Occupied) => &mut self.occupied,
+            None => return,
+        };
+        stats.add(delta);
+        if stats.count.is_multiple_of(SAVE_EVERY) {
+            self.save(Path::new(AUTOTUNE_FILE));
+        }
+    }
+
+    /// Changes the label. The proposal is updated, and everything is saved.
+    fn set_label(&mut self, label: Option<BedLabel>, std_devs: f32) {
+        self.label = label;
+        match propose(&self.empty, &self.occupied, std_devs) {
+            Ok(proposal) => {
+                self.proposal = Some(proposal);
+                self.error = None;
+            }
+            Err(e) => {
+                self.proposal = None;
+                self.error = Some(e.to_string());
+            }
+        }
+        self.save(Path::new(AUTOTUNE_FILE));
+    }
+}
+
+#[get("/sleep/autotune")]
+pub async fn get_autotune(state: &State<AlarmState>) -> Json<AutoTune> {
+    Json(state.sleep_monitor.lock().await.autotune.clone())
+}
+
+/// Marks the bed as empty or occupied from now on, or stops collecting with `null`.
+#[put("/sleep/autotune/label", data = "<label>")]
+pub async fn put_autotune_label(
+    state: &State<AlarmState>,
+    label: Json<Option<BedLabel>>,
+) -> Json<AutoTune> {
+    let std_devs = state.config.get().sleep_monitor.autotune_std_devs;
+    let mut monitor = state.sleep_monitor.lock().await;
+    monitor.autotune.set_label(label.0, std_devs);
+    info!("Auto-tuning with the bed marked as {:?}", label.0);
+    Json(monitor.autotune.clone())
+}
+
+/// Starts over, forgetting the collected changes and the proposal.
+#[delete("/sleep/autotune")]
+pub async fn reset_autotune(state: &State<AlarmState>) {
+    let mut monitor = state.sleep_monitor.lock().await;
+    monitor.autotune = AutoTune::default();
+    monitor.autotune.save(Path::new(AUTOTUNE_FILE));
+}
+
+/// Uses the proposed thresholds from now on.
+#[post("/sleep/config/accept-autotune")]
+pub async fn accept_autotune(
+    state: &State<AlarmState>,
+) -> Result<Json<SleepMonitorConfig>, (Status, String)> {
+    let autotune = state.sleep_monitor.lock().await.autotune.clone();
+    let Some(proposal) = autotune.proposal else {
+        let reason = autotune
+            .error
+            .unwrap_or_else(|| "Nothing has been collected".to_owned());
+        return Err((Status::NotFound, format!("There is no proposal. {reason}")));
+    };
+    let mut config = state.config.get();
+    config.sleep_monitor.noise_threshold = proposal.noise_threshold;
+    config.sleep_monitor.movement_threshold = proposal.movement_threshold;
+    match state.config.set(config) {
+        Ok(()) => {
+            info!(
+                "Accepted the auto-tuned thresholds: noise {} g, movement {} g",
+                proposal.noise_threshold, proposal.movement_threshold
+            );
+            Ok(Json(state.config.get().sleep_monitor))
+        }
+        Err(e @ config::ConfigError::Invalid(_)) => Err((Status::BadRequest, e.to_string())),
+        Err(e) => {
+            error!("{}", e);
+            Err((Status::InternalServerError, e.to_string()))
+        }
+    }
+}
+
+#[test]
+fn test_propose() {
+    // Deterministic samples with the given mean, evenly spread over ±`spread`
+    let stats = |mean: f32, spread: f32, count: u32| {
+        let mut stats = DeltaStats::default();
+        for i in 0..count {
+            stats.add(mean + spread * (2.0 * i as f32 / (count - 1) as f32 - 1.0));
+        }
+        stats
+    };
+    // A uniform distribution over ±a has the standard deviation a / √3
+    let empty = stats(0.004, 0.003, 1000);
+    assert!((empty.mean - 0.004).abs() < 1e-6);
+    assert!((empty.std_dev() - 0.003 / 3f64.sqrt()).abs() < 1e-5);
+    let occupied = stats(0.03, 0.02, 2000);
+
+    let proposal = propose(&empty, &occupied, 3.0).unwrap();
+    let expected_noise = 0.004 + 3.0 * 0.003 / 3f32.sqrt();
+    let expected_movement = 0.03 + 3.0 * 0.02 / 3f32.sqrt();
+    assert!((proposal.noise_threshold - expected_noise).abs() < 1e-4);
+    assert!((proposal.movement_threshold - expected_movement).abs() < 1e-4);
+
+    // More standard deviations than the distributions are apart
+    assert_eq!(
+        propose(&empty, &stats(0.008, 0.002, 1000), 3.0),
+        Err(AutoTuneError::Overlap { std_devs: 3.0 })
+    );
+    assert_eq!(
+        propose(&empty, &stats(0.03, 0.02, 10), 3.0),
+        Err(AutoTuneError::TooFewSamples {
+            label: BedLabel::Occupied,
+            count: 10
+        })
+    );
+}
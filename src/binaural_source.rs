@@ -0,0 +1,124 @@
+use rodio::Source;
+
+use std::f64::consts::TAU;
+use std::time::Duration;
+
+use crate::lucid::{cue_envelope, Fade};
+
+const SAMPLE_RATE: u32 = 44_100;
+
+/// Binaural beats: a sine wave at the carrier frequency in the left channel, and one at the carrier plus the beat
+/// frequency in the right channel. The beat is only perceived with the two channels kept apart, so it must not be downmixed.
+///
+/// Fades in and out like the other lucid cues, see [`cue_envelope`], and ends after `duration`.
+pub struct BinauralSource {
+    frequencies: [f64; 2],
+    duration: Duration,
+    fade: Fade,
+    total_frames: u64,
+    frame: u64,
+    channel: usize,
+}
+
+impl BinauralSource {
+    pub fn new(carrier_hz: f32, beat_hz: f32, duration: Duration, fade: Fade) -> Self {
+        BinauralSource {
+            frequencies: [carrier_hz as f64, (carrier_hz + beat_hz) as f64],
+            duration,
+            fade,
+            total_frames: (duration.as_secs_f64() * SAMPLE_RATE as f64) as u64,
+            frame: 0,
+            channel: 0,
+        }
+    }
+}
+
+impl Iterator for BinauralSource {
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<f32> {
+        if self.frame >= self.total_frames {
+            return None;
+        }
+        let t = self.frame as f64 / SAMPLE_RATE as f64;
+        // The phase is computed from the frame, rather than accumulated, so that it does not drift
+        let phase = (self.frequencies[self.channel] * t).fract() * TAU;
+        // Silent past the end, which the last frame may be by less than a frame
+        let envelope =
+            cue_envelope(t as f32, self.duration.as_secs_f32(), self.fade).unwrap_or(0.0);
+        let sample = phase.sin() as f32 * envelope;
+        self.channel += 1;
+        if self.channel == 2 {
+            self.channel = 0;
+            self.frame += 1;
+        }
+        Some(sample)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = (2 * (self.total_frames - self.frame)) as usize - self.channel;
+        (remaining, Some(remaining))
+    }
+}
+
+impl Source for BinauralSource {
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        2
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        Some(self.duration)
+    }
+}
+
+#[test]
+fn test_binaural_source() {
+    let source = BinauralSource::new(
+        200.0,
+        4.0,
+        Duration::from_secs(2),
+        Fade {
+            in_seconds: 0.5,
+            out_seconds: 0.5,
+        },
+    );
+    assert_eq!(source.channels(), 2);
+    assert_eq!(
+        source.size_hint(),
+        (4 * SAMPLE_RATE as usize, Some(4 * SAMPLE_RATE as usize))
+    );
+    let samples = source.collect::<Vec<_>>();
+    assert_eq!(samples.len(), 4 * SAMPLE_RATE as usize);
+    let (left, right): (Vec<f32>, Vec<f32>) = samples.chunks(2).map(|f| (f[0], f[1])).unzip();
+
+    // Counts the upward zero crossings during the second in the middle, where the envelope is 1
+    let crossings = |channel: &[f32]| {
+        let middle = &channel[SAMPLE_RATE as usize / 2..3 * SAMPLE_RATE as usize / 2];
+        middle
+            .windows(2)
+            .filter(|w| w[0] < 0.0 && w[1] >= 0.0)
+            .count()
+    };
+    assert!((199..=201).contains(&crossings(&left)));
+    assert!((203..=205).contains(&crossings(&right)));
+
+    // Starts and ends silently
+    assert_eq!(left[0], 0.0);
+    assert!(right.last().unwrap().abs() < 1e-3);
+    let peak = left.iter().fold(0.0f32, |a, &b| a.max(b.abs()));
+    assert!((peak - 1.0).abs() < 1e-3);
+}
@@ -0,0 +1,935 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use log::{info, warn};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+use thiserror::Error;
+
+use crate::equalizer::EqPreset;
+use crate::{sounds, AlarmState};
+
+pub const CONFIG_FILE: &str = "./config.json";
+
+/// Settings which can be changed at runtime through the API.
+///
+/// All fields have defaults, so a partial (or missing) config file is fine.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct Config {
+    pub sounds: SoundsConfig,
+    pub alarm: AlarmConfig,
+    pub speaker: SpeakerConfig,
+    pub playback: PlaybackConfig,
+    pub lowpass: LowpassConfig,
+    pub motion: MotionConfig,
+    #[serde(deserialize_with = "deserialize_sleep_monitor")]
+    pub sleep_monitor: SleepMonitorConfig,
+    pub snooze: SnoozeConfig,
+    pub tap_to_dismiss: TapConfig,
+    pub haptic: HapticConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct SoundsConfig {
+    /// The alarm sounds. Sounds are named relative to it, in the API, the weights file and the history.
+    pub directory: PathBuf,
+    /// The sounds of the lucid cues, see [`crate::lucid::LucidCue`]. Never picked as alarm sounds.
+    pub lucid_music_dir: PathBuf,
+    pub lucid_sfx_dir: PathBuf,
+    pub lucid_voice_dir: PathBuf,
+    /// Chimes or voice prompts for the reality checks, see [`crate::reality_check`].
+    pub reality_check_dir: PathBuf,
+    /// How many levels of subdirectories to descend into when looking for sounds.
+    pub max_scan_depth: usize,
+    /// Subdirectories (relative to the sound directory) which never contain alarm sounds.
+    pub excluded_dirs: Vec<String>,
+    /// Number of most recent alarms whose sounds should not be picked again.
+    pub avoid_repeat_count: usize,
+    /// Sounds shorter than this many seconds are considered broken (e.g. a failed download), and are never played as alarms.
+    pub min_duration_seconds: f32,
+}
+
+impl Default for SoundsConfig {
+    fn default() -> Self {
+        SoundsConfig {
+            directory: PathBuf::from("./sounds"),
+            lucid_music_dir: PathBuf::from("./sounds/lucid"),
+            lucid_sfx_dir: PathBuf::from("./sounds/lucid_sfx"),
+            lucid_voice_dir: PathBuf::from("./sounds/lucid_voice"),
+            reality_check_dir: PathBuf::from("./sounds/reality_check"),
+            max_scan_depth: 3,
+            excluded_dirs: vec!["lucid".to_owned(), "lucid_sfx".to_owned()],
+            avoid_repeat_count: 3,
+            min_duration_seconds: 5.0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct AlarmConfig {
+    /// Keep playing more tracks if the first one ends before the alarm is over.
+    pub playlist: bool,
+    /// Duration of the crossfade between consecutive tracks in the playlist, in seconds.
+    pub crossfade_seconds: f32,
+    /// Start alarm tracks at the first audible sound instead of at the very beginning.
+    pub skip_leading_silence: bool,
+    /// RMS level in dBFS below which the start of a track counts as silence.
+    pub silence_threshold_db: f32,
+    /// Never skip more than this many seconds at the start of a track.
+    pub max_silence_skip_seconds: f32,
+    /// Stages that the alarm goes through in order. If empty, the alarm slowly fades in a single sound.
+    pub escalation: Vec<EscalationStage>,
+    /// Sound file which is played right before the first alarm track, without any gap.
+    pub intro_chime: Option<PathBuf>,
+    /// If the program starts at most this many minutes after an alarm which never finished, that alarm continues where it was interrupted.
+    pub resume_window_minutes: f32,
+    pub dismissal: DismissalPolicy,
+    /// With [`DismissalPolicy::MustLeaveBed`], how long the user must be out of bed before the alarm finishes.
+    pub out_of_bed_minutes: f32,
+    /// With [`DismissalPolicy::MustLeaveBed`], the volume between 0 and 1 that a stopped alarm keeps playing at.
+    pub dismissed_volume: f32,
+}
+
+/// What stopping the alarm does.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DismissalPolicy {
+    /// The alarm stops right away.
+    #[default]
+    Stop,
+    /// The alarm only turns down, and finishes once the user has been out of bed for a while.
+    /// Stops right away if it is not known whether the user is in bed, like without a sensor.
+    /// The sounds or `playback.max_alarm_seconds` running out still end it, after which the snooze takes over.
+    MustLeaveBed,
+}
+
+impl Default for AlarmConfig {
+    fn default() -> Self {
+        AlarmConfig {
+            playlist: false,
+            crossfade_seconds: 3.0,
+            skip_leading_silence: true,
+            silence_threshold_db: -40.0,
+            max_silence_skip_seconds: 20.0,
+            escalation: vec![],
+            intro_chime: None,
+            resume_window_minutes: 10.0,
+            dismissal: DismissalPolicy::Stop,
+            out_of_bed_minutes: 2.0,
+            dismissed_volume: 0.05,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EscalationStage {
+    /// Sound category to play from. Uses the category of the alarm if not set.
+    #[serde(default)]
+    pub category: Option<String>,
+    /// Volume between 0 and 1 that the stage fades to.
+    pub volume: f32,
+    pub duration_seconds: f32,
+    /// Gradually open up the lowpass filter, instead of playing unfiltered.
+    #[serde(default = "default_true")]
+    pub lowpass: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Workarounds for speakers which go into standby and take a while to wake up.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct SpeakerConfig {
+    /// Play a tone before every sound to wake the speakers up.
+    pub standby_wakeup: bool,
+    pub wakeup_tone_hz: u32,
+    pub wakeup_tone_amplitude: f32,
+    pub wakeup_tone_seconds: f32,
+    /// Regularly play an inaudible tone during the time before the alarm, so that the speakers never go into standby.
+    pub keep_alive: bool,
+    pub keep_alive_tone_hz: u32,
+    pub keep_alive_amplitude: f32,
+    pub keep_alive_seconds: f32,
+    pub keep_alive_interval_minutes: u32,
+    /// How long before the alarm to start keeping the speakers awake.
+    pub keep_alive_window_minutes: u32,
+}
+
+impl Default for SpeakerConfig {
+    fn default() -> Self {
+        SpeakerConfig {
+            standby_wakeup: false,
+            wakeup_tone_hz: 30,
+            wakeup_tone_amplitude: 0.7,
+            wakeup_tone_seconds: 5.0,
+            keep_alive: false,
+            keep_alive_tone_hz: 20,
+            keep_alive_amplitude: 0.3,
+            keep_alive_seconds: 3.0,
+            keep_alive_interval_minutes: 5,
+            keep_alive_window_minutes: 60,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct PlaybackConfig {
+    /// How many times to try reopening the output device if it stops working during playback.
+    pub max_recovery_attempts: u32,
+    /// Output device (a substring of its name) to play the beeper on when the normal output cannot be recovered.
+    /// Uses the default device if not set.
+    pub fallback_device: Option<String>,
+    /// Smoothly turn down the gain of loud passages instead of clipping them.
+    pub limiter: bool,
+    /// Peak level that the limiter keeps the output below, between 0 and 1.
+    pub limiter_threshold: f32,
+    /// Mix all channels down to mono, for setups with a single speaker.
+    pub mono: bool,
+    /// Maximum size of the decoded sounds which are kept in memory to avoid decoding them again, in MiB.
+    pub decode_cache_mb: u32,
+    /// Longest time that an alarm plays before fading out, counted from the first audible sample.
+    pub max_alarm_seconds: f32,
+    /// Longest time that a lucid dreaming cue plays.
+    pub max_lucid_cue_seconds: f32,
+    /// Longest time that a sound preview plays.
+    pub max_preview_seconds: f32,
+    /// Nothing is ever played louder than this volume between 0 and 1.
+    pub max_volume: f32,
+    /// Length of the chunks that the filters process at a time. Longer chunks use less CPU, but add latency.
+    pub filter_frame_ms: f32,
+    /// Balance between the left (-1) and right (1) speaker.
+    pub balance: f32,
+    /// Remove DC offsets from sounds before filtering them.
+    pub dc_blocker: bool,
+    pub eq: EqPreset,
+}
+
+impl Default for PlaybackConfig {
+    fn default() -> Self {
+        PlaybackConfig {
+            max_recovery_attempts: 3,
+            fallback_device: None,
+            limiter: true,
+            limiter_threshold: 0.9,
+            mono: false,
+            decode_cache_mb: 64,
+            max_alarm_seconds: 10.0 * 60.0,
+            max_lucid_cue_seconds: 500.0,
+            max_preview_seconds: 30.0,
+            max_volume: 1.0,
+            filter_frame_ms: 20.0,
+            balance: 0.0,
+            dc_blocker: true,
+            eq: EqPreset::Flat,
+        }
+    }
+}
+
+/// Shape of the lowpass filter cutoff curve which makes the alarm start out muffled.
+///
+/// The cutoff frequency is `base_hz + max(t - delay_seconds, 0)^exponent * scale`, limited to `max_hz`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct LowpassConfig {
+    pub delay_seconds: f32,
+    pub base_hz: f32,
+    pub exponent: f32,
+    pub scale: f32,
+    pub max_hz: f32,
+    /// The cutoff never changes faster than this, so that the sound does not brighten in audible steps.
+    pub max_octaves_per_second: f32,
+}
+
+impl Default for LowpassConfig {
+    fn default() -> Self {
+        LowpassConfig {
+            delay_seconds: 10.0,
+            base_hz: 800.0,
+            exponent: 2.5,
+            scale: 1.0,
+            max_hz: 100_000.0,
+            max_octaves_per_second: 1.0,
+        }
+    }
+}
+
+/// Settings for the accelerometer. Only read at startup.
+///
+/// The sensor is on the side of the bed of the user, which the alarm follows.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct MotionConfig {
+    /// `mpu6050`, `bmi160`, or `auto` to probe the known addresses for any supported sensor.
+    pub sensor: String,
+    pub i2c_bus: PathBuf,
+    /// 7-bit I2C address of the sensor. Defaults to 104 (0x68), which is the usual address of both supported sensors.
+    /// They use 105 (0x69) if their address pin is pulled high.
+    pub i2c_address: Option<u8>,
+    /// File which stores the offsets of the sensor, measured by calibrating it while the bed is empty.
+    pub calibration_file: PathBuf,
+    /// Directory of the daily accelerometer logs.
+    pub log_dir: PathBuf,
+    /// Readings older than this many days are deleted, once they are archived as per-minute aggregates.
+    pub log_retention_days: u32,
+    /// Movement events older than this many days are deleted from the event log, see [`crate::movement_events`].
+    pub movement_event_retention_days: u32,
+    /// SQLite database to store the readings in, instead of the CSV logs. Requires the `sqlite` feature.
+    pub database: Option<PathBuf>,
+    /// Added to the temperature of the sensor, in °C. The chip is usually a few degrees warmer than the room.
+    pub temperature_offset: f32,
+    /// Each reading is the mean of this many samples.
+    pub samples_per_reading: usize,
+    /// Time between the samples of a reading, in milliseconds.
+    pub sample_interval_ms: u64,
+    /// Time from the start of one reading to the start of the next, in milliseconds.
+    /// Readings are never closer than `samples_per_reading * sample_interval_ms`.
+    pub reading_interval_ms: u64,
+    /// The sensor counts as disconnected if every value it returns stays exactly the same for this many seconds.
+    /// Some sensors keep returning their last values when the cable comes loose, instead of failing.
+    pub frozen_after_seconds: f32,
+    /// At most this many readings are kept in the window of the sleep monitor, even if they come faster than expected.
+    /// Defaults to twice the readings expected in the window at the rate which the program was started with.
+    pub max_window_readings: Option<usize>,
+    /// A second sensor on the other side of the bed, so that the movements of a partner are not mistaken for the user's.
+    pub partner: Option<PartnerSensorConfig>,
+}
+
+/// The sensor on the partner's side of the bed. Uses the same settings as the user's sensor, except for where it is.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PartnerSensorConfig {
+    /// See [`MotionConfig::sensor`].
+    #[serde(default = "default_sensor")]
+    pub sensor: String,
+    pub i2c_bus: PathBuf,
+    pub i2c_address: Option<u8>,
+    /// Separate from [`MotionConfig::calibration_file`], since every sensor has offsets of its own.
+    #[serde(default = "default_partner_calibration_file")]
+    pub calibration_file: PathBuf,
+}
+
+fn default_sensor() -> String {
+    MotionConfig::default().sensor
+}
+
+fn default_partner_calibration_file() -> PathBuf {
+    PathBuf::from("partner_accelerometer_calibration.json")
+}
+
+impl MotionConfig {
+    /// The config of the partner's sensor, if there is one.
+    pub fn partner_config(&self) -> Option<MotionConfig> {
+        let partner = self.partner.as_ref()?;
+        Some(MotionConfig {
+            sensor: partner.sensor.clone(),
+            i2c_bus: partner.i2c_bus.clone(),
+            i2c_address: partner.i2c_address,
+            calibration_file: partner.calibration_file.clone(),
+            ..self.clone()
+        })
+    }
+
+    /// Time between the readings which are pushed to the sleep monitor.
+    pub fn reading_period(&self) -> Duration {
+        Duration::from_millis(
+            self.reading_interval_ms
+                .max(self.samples_per_reading as u64 * self.sample_interval_ms),
+        )
+    }
+}
+
+impl Default for MotionConfig {
+    fn default() -> Self {
+        MotionConfig {
+            sensor: "mpu6050".to_owned(),
+            i2c_bus: PathBuf::from("/dev/i2c-1"),
+            i2c_address: None,
+            calibration_file: PathBuf::from("accelerometer_calibration.json"),
+            log_dir: PathBuf::from("."),
+            log_retention_days: 30,
+            movement_event_retention_days: 90,
+            database: None,
+            temperature_offset: 0.0,
+            samples_per_reading: 10,
+            sample_interval_ms: 10,
+            reading_interval_ms: 100,
+            frozen_after_seconds: 30.0,
+            max_window_readings: None,
+            partner: None,
+        }
+    }
+}
+
+/// When the accelerometer readings count as someone being in bed, or moving.
+///
+/// Changes in acceleration are measured in g, between consecutive readings (see [`MotionConfig::reading_interval_ms`]),
+/// after removing the slow drift (see `drift_time_constant_seconds`). Durations are converted to a number of readings, so that they mean the same thing at any reading rate.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct SleepMonitorConfig {
+    /// Smaller changes are sensor noise. Larger ones are breathing or movement.
+    pub noise_threshold: f32,
+    /// Someone may be in bed if the changes within the window are above the noise threshold for more than this many seconds.
+    pub noise_threshold_seconds: f32,
+    /// Someone may have left the bed if the changes within the window are above the noise threshold for at most this many seconds.
+    /// At most `noise_threshold_seconds`. Durations in between keep the current presence.
+    pub absent_threshold_seconds: f32,
+    /// How long the changes must indicate presence before someone counts as in bed.
+    pub present_after_seconds: f32,
+    /// How long the changes must indicate absence before the bed counts as empty. Long enough to ignore short bathroom breaks.
+    pub absent_after_seconds: f32,
+    /// Changes larger than this are movement, rather than breathing.
+    pub movement_threshold: f32,
+    /// There is significant movement if the changes within the window are above the movement threshold for more than this many seconds.
+    pub movement_threshold_seconds: f32,
+    /// The mattress counts as tilted, as when someone sits on the edge, if gravity points more than this many degrees away from when the bed was empty.
+    pub tilt_threshold_degrees: f32,
+    /// Time constant of the moving average which is subtracted from each axis before the changes are measured.
+    /// Removes gravity and the slow, temperature dependent drift of the sensor, while keeping faster movements.
+    pub drift_time_constant_seconds: f32,
+    /// How many standard deviations above the observed changes the auto-tuned thresholds are. See [`crate::autotune`].
+    pub autotune_std_devs: f32,
+    /// The user falls asleep at the start of the first time asleep for this many minutes in a row. See [`crate::nights::summarize`].
+    pub sleep_onset_minutes: u32,
+}
+
+impl Default for SleepMonitorConfig {
+    fn default() -> Self {
+        SleepMonitorConfig {
+            noise_threshold: 0.015,
+            noise_threshold_seconds: 0.1,
+            absent_threshold_seconds: 0.0,
+            present_after_seconds: 5.0,
+            absent_after_seconds: 180.0,
+            movement_threshold: 0.02,
+            movement_threshold_seconds: 0.2,
+            tilt_threshold_degrees: 3.0,
+            drift_time_constant_seconds: 30.0,
+            autotune_std_devs: 3.0,
+            sleep_onset_minutes: 10,
+        }
+    }
+}
+
+/// Time between readings back when the thresholds were counted in readings, as `*_samples`.
+const LEGACY_READING_SECONDS: f64 = 0.1;
+
+/// Reads a [`SleepMonitorConfig`], converting the thresholds of older configs from a number of readings to seconds.
+fn deserialize_sleep_monitor<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<SleepMonitorConfig, D::Error> {
+    let mut value = serde_json::Value::deserialize(deserializer)?;
+    if let Some(fields) = value.as_object_mut() {
+        for name in ["noise_threshold", "absent_threshold", "movement_threshold"] {
+            let Some(samples) = fields.remove(&format!("{name}_samples")) else {
+                continue;
+            };
+            let seconds = format!("{name}_seconds");
+            if fields.contains_key(&seconds) {
+                warn!("Ignoring `{name}_samples`, since `{seconds}` is also set");
+                continue;
+            }
+            let samples = samples
+                .as_f64()
+                .ok_or_else(|| D::Error::custom(format!("`{name}_samples` must be a number")))?;
+            warn!("`{name}_samples` is deprecated. Converted it to `{seconds}`.");
+            fields.insert(seconds, (samples * LEGACY_READING_SECONDS).into());
+        }
+    }
+    serde_json::from_value(value).map_err(D::Error::custom)
+}
+
+/// When the alarm rings again if the user is still in bed after it stopped.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct SnoozeConfig {
+    /// Rings again this soon at the earliest, if the user keeps moving.
+    pub min_minutes: f32,
+    /// Rings again this late at the latest, if the user is still in bed.
+    pub max_minutes: f32,
+    /// How long the user must have been moving for the alarm to ring again before `max_minutes`.
+    pub movement_minutes: f32,
+    /// The alarm never rings again once this long has passed since it first rang.
+    pub max_total_minutes: f32,
+    /// Only count the user as in bed while the mattress is level, so that sitting on the edge of the bed counts as up.
+    pub require_lying_down: bool,
+    /// The user counts as in bed when the presence confidence is at least this.
+    pub present_confidence: f32,
+    /// The user counts as out of bed when the presence confidence is at most this. Values in between are unclear.
+    pub absent_confidence: f32,
+}
+
+impl Default for SnoozeConfig {
+    fn default() -> Self {
+        SnoozeConfig {
+            min_minutes: 5.0,
+            max_minutes: 20.0,
+            movement_minutes: 2.0,
+            max_total_minutes: 60.0,
+            require_lying_down: false,
+            present_confidence: 0.7,
+            absent_confidence: 0.3,
+        }
+    }
+}
+
+/// Stopping the alarm by tapping the bed twice.
+///
+/// The spikes of the taps are logged at the debug level, to help with picking the thresholds.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct TapConfig {
+    /// Off by default, so that moving around in bed cannot stop the alarm by accident.
+    pub enabled: bool,
+    /// Changes in acceleration between samples larger than this (in g) are taps.
+    pub acc_threshold: f32,
+    /// Rotations faster than this (in rad/s) are taps.
+    pub gyro_threshold: f32,
+    /// The second tap must come at least this many seconds after the first one.
+    pub min_gap_seconds: f32,
+    /// The second tap must come at most this many seconds after the first one.
+    pub max_gap_seconds: f32,
+}
+
+impl Default for TapConfig {
+    fn default() -> Self {
+        TapConfig {
+            enabled: false,
+            acc_threshold: 0.3,
+            gyro_threshold: 1.0,
+            min_gap_seconds: 0.2,
+            max_gap_seconds: 1.0,
+        }
+    }
+}
+
+/// The vibration motor for haptic lucid cues, see [`crate::haptic`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct HapticConfig {
+    /// BCM number of the GPIO pin which switches the motor. No haptic cues are played if it is not set. Read at startup.
+    pub pin: Option<u8>,
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Invalid config: {0}")]
+    Invalid(String),
+    #[error("Could not save config to `{0}`: {1}")]
+    CouldNotSave(PathBuf, std::io::Error),
+}
+
+fn is_non_negative(x: f32) -> bool {
+    x.is_finite() && x >= 0.0
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        for dir in &self.sounds.excluded_dirs {
+            if !sounds::is_relative_subpath(dir) {
+                return Err(ConfigError::Invalid(format!(
+                    "excluded directory `{dir}` must be a relative path inside the sound directory"
+                )));
+            }
+        }
+        if !is_non_negative(self.sounds.min_duration_seconds) {
+            return Err(ConfigError::Invalid(
+                "min_duration_seconds must be a non-negative number".to_owned(),
+            ));
+        }
+        if !is_non_negative(self.alarm.resume_window_minutes) {
+            return Err(ConfigError::Invalid(
+                "resume_window_minutes must be a non-negative number".to_owned(),
+            ));
+        }
+        if !is_non_negative(self.alarm.crossfade_seconds) {
+            return Err(ConfigError::Invalid(
+                "crossfade_seconds must be a non-negative number".to_owned(),
+            ));
+        }
+        if !self.alarm.silence_threshold_db.is_finite() || self.alarm.silence_threshold_db > 0.0 {
+            return Err(ConfigError::Invalid(
+                "silence_threshold_db must be at most 0 dBFS".to_owned(),
+            ));
+        }
+        if !is_non_negative(self.alarm.max_silence_skip_seconds) {
+            return Err(ConfigError::Invalid(
+                "max_silence_skip_seconds must be a non-negative number".to_owned(),
+            ));
+        }
+        if !is_non_negative(self.alarm.out_of_bed_minutes) {
+            return Err(ConfigError::Invalid(
+                "out_of_bed_minutes must be a non-negative number".to_owned(),
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.alarm.dismissed_volume) {
+            return Err(ConfigError::Invalid(
+                "dismissed_volume must be between 0 and 1".to_owned(),
+            ));
+        }
+        for stage in &self.alarm.escalation {
+            if let Some(category) = &stage.category {
+                if !sounds::is_relative_subpath(category) {
+                    return Err(ConfigError::Invalid(format!(
+                        "escalation category `{category}` must be a relative path inside the sound directory"
+                    )));
+                }
+            }
+            if !(0.0..=1.0).contains(&stage.volume) {
+                return Err(ConfigError::Invalid(
+                    "escalation stage volumes must be between 0 and 1".to_owned(),
+                ));
+            }
+            if !stage.duration_seconds.is_finite() || stage.duration_seconds <= 0.0 {
+                return Err(ConfigError::Invalid(
+                    "escalation stage durations must be positive".to_owned(),
+                ));
+            }
+        }
+        let playback = &self.playback;
+        if !is_non_negative(playback.max_alarm_seconds)
+            || !is_non_negative(playback.max_lucid_cue_seconds)
+            || !is_non_negative(playback.max_preview_seconds)
+        {
+            return Err(ConfigError::Invalid(
+                "maximum play durations must be non-negative".to_owned(),
+            ));
+        }
+        if !(1.0..=500.0).contains(&playback.filter_frame_ms) {
+            return Err(ConfigError::Invalid(
+                "filter_frame_ms must be between 1 and 500".to_owned(),
+            ));
+        }
+        if !(0.0..=1.0).contains(&playback.max_volume) {
+            return Err(ConfigError::Invalid(
+                "max_volume must be between 0 and 1".to_owned(),
+            ));
+        }
+        if !(-1.0..=1.0).contains(&playback.balance) {
+            return Err(ConfigError::Invalid(
+                "balance must be between -1 and 1".to_owned(),
+            ));
+        }
+        let lowpass = &self.lowpass;
+        if !is_non_negative(lowpass.delay_seconds) || !is_non_negative(lowpass.scale) {
+            return Err(ConfigError::Invalid(
+                "lowpass delay_seconds and scale must be non-negative".to_owned(),
+            ));
+        }
+        if !(lowpass.base_hz > 0.0
+            && lowpass.base_hz < lowpass.max_hz
+            && lowpass.max_hz.is_finite())
+        {
+            return Err(ConfigError::Invalid(
+                "lowpass base_hz must be positive and less than max_hz".to_owned(),
+            ));
+        }
+        if !(1.0..=4.0).contains(&lowpass.exponent) {
+            return Err(ConfigError::Invalid(
+                "lowpass exponent must be between 1 and 4".to_owned(),
+            ));
+        }
+        if !lowpass.max_octaves_per_second.is_finite() || lowpass.max_octaves_per_second <= 0.0 {
+            return Err(ConfigError::Invalid(
+                "lowpass max_octaves_per_second must be positive".to_owned(),
+            ));
+        }
+        let speaker = &self.speaker;
+        if !(1..=20_000).contains(&speaker.wakeup_tone_hz)
+            || !(1..=20_000).contains(&speaker.keep_alive_tone_hz)
+        {
+            return Err(ConfigError::Invalid(
+                "speaker tone frequencies must be between 1 and 20000 Hz".to_owned(),
+            ));
+        }
+        if !(0.0..=1.0).contains(&speaker.wakeup_tone_amplitude)
+            || !(0.0..=1.0).contains(&speaker.keep_alive_amplitude)
+        {
+            return Err(ConfigError::Invalid(
+                "speaker tone amplitudes must be between 0 and 1".to_owned(),
+            ));
+        }
+        if !is_non_negative(speaker.wakeup_tone_seconds)
+            || !is_non_negative(speaker.keep_alive_seconds)
+        {
+            return Err(ConfigError::Invalid(
+                "speaker tone durations must be non-negative".to_owned(),
+            ));
+        }
+        if speaker.keep_alive_interval_minutes == 0 {
+            return Err(ConfigError::Invalid(
+                "keep_alive_interval_minutes must be positive".to_owned(),
+            ));
+        }
+        if !(self.playback.limiter_threshold > 0.0 && self.playback.limiter_threshold <= 1.0) {
+            return Err(ConfigError::Invalid(
+                "limiter_threshold must be greater than 0 and at most 1".to_owned(),
+            ));
+        }
+        let is_positive = |x: f32| x.is_finite() && x > 0.0;
+        if !is_positive(self.sleep_monitor.noise_threshold)
+            || !is_positive(self.sleep_monitor.movement_threshold)
+        {
+            return Err(ConfigError::Invalid(
+                "sleep monitor thresholds must be positive numbers".to_owned(),
+            ));
+        }
+        if !is_non_negative(self.sleep_monitor.noise_threshold_seconds)
+            || !is_non_negative(self.sleep_monitor.absent_threshold_seconds)
+            || !is_non_negative(self.sleep_monitor.movement_threshold_seconds)
+        {
+            return Err(ConfigError::Invalid(
+                "sleep monitor threshold durations must be non-negative numbers".to_owned(),
+            ));
+        }
+        if self.sleep_monitor.absent_threshold_seconds > self.sleep_monitor.noise_threshold_seconds
+        {
+            return Err(ConfigError::Invalid(
+                "absent_threshold_seconds must be at most noise_threshold_seconds".to_owned(),
+            ));
+        }
+        if !is_positive(self.sleep_monitor.autotune_std_devs) {
+            return Err(ConfigError::Invalid(
+                "autotune_std_devs must be a positive number".to_owned(),
+            ));
+        }
+        if self.sleep_monitor.sleep_onset_minutes == 0 {
+            return Err(ConfigError::Invalid(
+                "sleep_onset_minutes must be positive".to_owned(),
+            ));
+        }
+        if !is_positive(self.sleep_monitor.drift_time_constant_seconds) {
+            return Err(ConfigError::Invalid(
+                "drift_time_constant_seconds must be a positive number".to_owned(),
+            ));
+        }
+        if !is_non_negative(self.sleep_monitor.tilt_threshold_degrees) {
+            return Err(ConfigError::Invalid(
+                "tilt_threshold_degrees must be a non-negative number".to_owned(),
+            ));
+        }
+        if !is_non_negative(self.sleep_monitor.present_after_seconds)
+            || !is_non_negative(self.sleep_monitor.absent_after_seconds)
+        {
+            return Err(ConfigError::Invalid(
+                "present_after_seconds and absent_after_seconds must be non-negative numbers"
+                    .to_owned(),
+            ));
+        }
+        let snooze = &self.snooze;
+        if !is_non_negative(snooze.min_minutes)
+            || !is_non_negative(snooze.movement_minutes)
+            || !is_non_negative(snooze.max_total_minutes)
+            || !snooze.max_minutes.is_finite()
+            || snooze.max_minutes < snooze.min_minutes
+        {
+            return Err(ConfigError::Invalid(
+                "snooze durations must be non-negative numbers, and max_minutes must be at least min_minutes"
+                    .to_owned(),
+            ));
+        }
+        if !(0.0..=1.0).contains(&snooze.absent_confidence)
+            || !(0.0..=1.0).contains(&snooze.present_confidence)
+            || snooze.absent_confidence > snooze.present_confidence
+        {
+            return Err(ConfigError::Invalid(
+                "snooze confidences must be between 0 and 1, and absent_confidence must be at most present_confidence"
+                    .to_owned(),
+            ));
+        }
+        let tap = &self.tap_to_dismiss;
+        if !is_positive(tap.acc_threshold)
+            || !is_positive(tap.gyro_threshold)
+            || !is_non_negative(tap.min_gap_seconds)
+            || !tap.max_gap_seconds.is_finite()
+            || tap.max_gap_seconds < tap.min_gap_seconds
+        {
+            return Err(ConfigError::Invalid(
+                "tap thresholds must be positive numbers, and max_gap_seconds must be at least min_gap_seconds"
+                    .to_owned(),
+            ));
+        }
+        if self.motion.samples_per_reading == 0 || self.motion.reading_period().is_zero() {
+            return Err(ConfigError::Invalid(
+                "samples_per_reading and the time between readings must be positive".to_owned(),
+            ));
+        }
+        if self.motion.max_window_readings == Some(0) {
+            return Err(ConfigError::Invalid(
+                "max_window_readings must be positive".to_owned(),
+            ));
+        }
+        if !is_positive(self.motion.frozen_after_seconds) {
+            return Err(ConfigError::Invalid(
+                "frozen_after_seconds must be a positive number".to_owned(),
+            ));
+        }
+        if let Some(partner) = &self.motion.partner {
+            if partner.i2c_bus == self.motion.i2c_bus
+                && partner.i2c_address == self.motion.i2c_address
+            {
+                return Err(ConfigError::Invalid(
+                    "the partner's sensor must be on a different I2C bus or address".to_owned(),
+                ));
+            }
+            if partner.calibration_file == self.motion.calibration_file {
+                return Err(ConfigError::Invalid(
+                    "the partner's sensor must have a calibration file of its own".to_owned(),
+                ));
+            }
+        }
+        let partner_address = self.motion.partner.as_ref().and_then(|p| p.i2c_address);
+        if self.motion.i2c_address.is_some_and(|a| a > 0x7f)
+            || partner_address.is_some_and(|a| a > 0x7f)
+        {
+            return Err(ConfigError::Invalid(
+                "i2c_address must be a 7-bit address".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The current config, backed by a json file.
+pub struct ConfigStore {
+    path: PathBuf,
+    config: RwLock<Config>,
+    /// Increases every time the config is set, see [`ConfigStore::generation`].
+    generation: AtomicU64,
+}
+
+impl ConfigStore {
+    /// Loads the config from `path`, falling back to the defaults if it is missing or invalid.
+    pub fn load(path: &Path) -> ConfigStore {
+        let config = match std::fs::read_to_string(path) {
+            Ok(contents) => match serde_json::from_str::<Config>(&contents) {
+                Ok(config) => match config.validate() {
+                    Ok(()) => config,
+                    Err(e) => {
+                        warn!("{} in `{}`. Using defaults.", e, path.display());
+                        Config::default()
+                    }
+                },
+                Err(e) => {
+                    warn!(
+                        "Could not parse `{}`: {}. Using defaults.",
+                        path.display(),
+                        e
+                    );
+                    Config::default()
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!(
+                    "No config file found at `{}`. Using defaults.",
+                    path.display()
+                );
+                Config::default()
+            }
+            Err(e) => {
+                warn!(
+                    "Could not read `{}`: {}. Using defaults.",
+                    path.display(),
+                    e
+                );
+                Config::default()
+            }
+        };
+
+        ConfigStore {
+            path: path.to_path_buf(),
+            config: RwLock::new(config),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self) -> Config {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Changes whenever the config is set, so that values which are derived from it can be cached until then.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Validates and stores a new config, both in memory and on disk.
+    pub fn set(&self, config: Config) -> Result<(), ConfigError> {
+        config.validate()?;
+        let contents = serde_json::to_string_pretty(&config).unwrap();
+        std::fs::write(&self.path, contents)
+            .map_err(|e| ConfigError::CouldNotSave(self.path.clone(), e))?;
+        *self.config.write().unwrap() = config;
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        Ok(())
+    }
+}
+
+#[get("/config")]
+pub fn get_config(state: &State<AlarmState>) -> Json<Config> {
+    Json(state.config.get())
+}
+
+#[put("/config", data = "<config>")]
+pub fn put_config(
+    state: &State<AlarmState>,
+    config: Json<Config>,
+) -> Result<Json<Config>, (Status, String)> {
+    match state.config.set(config.0) {
+        Ok(()) => {
+            info!("Updated config");
+            let config = state.config.get();
+            sounds::check_directories(&config.sounds);
+            Ok(Json(config))
+        }
+        Err(e @ ConfigError::Invalid(_)) => Err((Status::BadRequest, e.to_string())),
+        Err(e) => {
+            error!("{}", e);
+            Err((Status::InternalServerError, e.to_string()))
+        }
+    }
+}
+
+#[test]
+fn test_validate_lowpass() {
+    let mut config = Config::default();
+    assert!(config.validate().is_ok());
+
+    config.lowpass.base_hz = config.lowpass.max_hz;
+    assert!(config.validate().is_err());
+
+    config.lowpass = LowpassConfig {
+        exponent: 5.0,
+        ..LowpassConfig::default()
+    };
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_legacy_sleep_monitor_thresholds() {
+    let config: Config = serde_json::from_str(
+        r#"{"sleep_monitor": {"noise_threshold_samples": 3, "absent_threshold_samples": 1, "movement_threshold_samples": 4, "movement_threshold_seconds": 0.5}}"#,
+    )
+    .unwrap();
+    let thresholds = config.sleep_monitor;
+    assert!((thresholds.noise_threshold_seconds - 0.3).abs() < 1e-6);
+    assert!((thresholds.absent_threshold_seconds - 0.1).abs() < 1e-6);
+    // The new key takes precedence
+    assert_eq!(thresholds.movement_threshold_seconds, 0.5);
+    assert_eq!(
+        thresholds.noise_threshold,
+        SleepMonitorConfig::default().noise_threshold
+    );
+
+    let config: Config = serde_json::from_str("{}").unwrap();
+    assert_eq!(config.sleep_monitor, SleepMonitorConfig::default());
+}
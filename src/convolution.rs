@@ -0,0 +1,155 @@
+//! The FIR convolution used by the lowpass filter.
+//!
+//! This module has no dependencies on the rest of the crate, so that `benches/convolve.rs` can include it directly.
+
+/// Number of independent accumulators. Lets the compiler keep a full SIMD register busy without reordering float additions itself.
+const LANES: usize = 8;
+
+/// Flushes denormal numbers to zero, since they are very slow to compute with.
+#[inline]
+pub fn flush_denormal(x: f32) -> f32 {
+    if x.abs() < f32::MIN_POSITIVE {
+        0.0
+    } else {
+        x
+    }
+}
+
+fn check_sizes(filter_len: usize, input_len: usize, output_len: usize) {
+    assert!(
+        input_len >= filter_len,
+        "input must be at least as long as filter"
+    );
+    assert_eq!(output_len, input_len - filter_len, "output size are only the inner valid samples. filter.len()/2 samples on each side are skipped.");
+    assert_eq!(filter_len % 2, 0, "filter must have an even length");
+}
+
+#[inline]
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    debug_assert_eq!(a.len(), b.len());
+    let a_chunks = a.chunks_exact(LANES);
+    let b_chunks = b.chunks_exact(LANES);
+    let tail = a_chunks
+        .remainder()
+        .iter()
+        .zip(b_chunks.remainder())
+        .map(|(x, y)| x * y)
+        .sum::<f32>();
+
+    let mut acc = [0.0f32; LANES];
+    for (a, b) in a_chunks.zip(b_chunks) {
+        for k in 0..LANES {
+            acc[k] += a[k] * b[k];
+        }
+    }
+    acc.iter().sum::<f32>() + tail
+}
+
+/// Filters `input` with `filter`. Only the samples where the filter fully overlaps the input are written to `output`.
+///
+/// Sums in a different order than [`convolve_reference`], so the results may differ by rounding errors.
+pub fn convolve(filter: &[f32], input: &[f32], output: &mut [f32]) {
+    check_sizes(filter.len(), input.len(), output.len());
+
+    for (out, window) in output.iter_mut().zip(input.windows(filter.len())) {
+        *out = flush_denormal(dot(window, filter));
+    }
+}
+
+/// Straightforward version of [`convolve`], used to test and benchmark it.
+#[allow(unused)]
+pub fn convolve_reference(filter: &[f32], input: &[f32], output: &mut [f32]) {
+    check_sizes(filter.len(), input.len(), output.len());
+
+    let h_len = filter.len() / 2;
+    for i in h_len..input.len() - h_len {
+        let mut v = 0.0;
+        for j in 0..filter.len() {
+            v += input[i + j - h_len] * filter[j];
+        }
+        output[i - h_len] = flush_denormal(v);
+    }
+}
+
+#[allow(unused)]
+pub fn convolve_f64(filter: &[f64], input: &[f64], output: &mut [f64]) {
+    assert_eq!(output.len(), input.len() - filter.len(), "output size are only the inner valid samples. filter.len()/2 samples on each side are skipped.");
+    assert_eq!(filter.len() % 2, 0, "filter must have an even length");
+    assert!(
+        input.len() >= filter.len(),
+        "input must be at least as long as filter"
+    );
+
+    let h_len = filter.len() / 2;
+    for i in h_len..input.len() - h_len {
+        let mut v = 0.0;
+        for j in 0..filter.len() {
+            v += input[i + j - h_len] * filter[j];
+        }
+        output[i - h_len] = v;
+    }
+}
+
+#[cfg(test)]
+fn random_signal(rng: &mut impl rand::Rng, len: usize) -> Vec<f32> {
+    (0..len).map(|_| rng.gen_range(-1.0..1.0)).collect()
+}
+
+#[cfg(test)]
+fn run(convolve: fn(&[f32], &[f32], &mut [f32]), filter: &[f32], input: &[f32]) -> Vec<f32> {
+    let mut output = vec![0.0; input.len() - filter.len()];
+    convolve(filter, input, &mut output);
+    output
+}
+
+#[cfg(test)]
+fn assert_close(a: &[f32], b: &[f32]) {
+    assert_eq!(a.len(), b.len());
+    for (i, (x, y)) in a.iter().zip(b).enumerate() {
+        assert!((x - y).abs() <= 1e-4 * (1.0 + y.abs()), "{i}: {x} != {y}");
+    }
+}
+
+#[test]
+fn test_convolve_matches_reference() {
+    use rand::SeedableRng;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+    // Kernel lengths that are, and are not, multiples of the number of lanes
+    for filter_len in [2, 6, 8, 18, 64, 130, 1026] {
+        let filter = random_signal(&mut rng, filter_len);
+        let input = random_signal(&mut rng, filter_len + 883);
+        assert_close(
+            &run(convolve, &filter, &input),
+            &run(convolve_reference, &filter, &input),
+        );
+    }
+}
+
+#[test]
+fn test_convolve_properties() {
+    use rand::{Rng, SeedableRng};
+    let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+    let filter = random_signal(&mut rng, 42);
+
+    // An impulse reproduces the kernel, back to front
+    let mut impulse = vec![0.0; 2 * filter.len()];
+    impulse[filter.len()] = 1.0;
+    let mut reversed = filter.clone();
+    reversed.reverse();
+    assert_close(
+        &run(convolve, &filter, &impulse)[1..],
+        &reversed[..filter.len() - 1],
+    );
+
+    // Linearity, and commutation with scaling
+    let x = random_signal(&mut rng, 500);
+    let y = random_signal(&mut rng, 500);
+    let a = rng.gen_range(-4.0..4.0);
+    let combined = x.iter().zip(&y).map(|(x, y)| a * x + y).collect::<Vec<_>>();
+    let expected = run(convolve, &filter, &x)
+        .iter()
+        .zip(run(convolve, &filter, &y))
+        .map(|(x, y)| a * x + y)
+        .collect::<Vec<_>>();
+    assert_close(&run(convolve, &filter, &combined), &expected);
+}
@@ -0,0 +1,224 @@
+use rodio::source::UniformSourceIterator;
+use rodio::{Sample, Source};
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time;
+use time::Duration;
+
+use crate::fade::smoothstep;
+
+struct Track {
+    samples: Vec<f32>,
+    position: usize,
+}
+
+impl Track {
+    fn remaining(&self) -> usize {
+        self.samples.len() - self.position
+    }
+
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.samples.get(self.position).copied();
+        self.position += 1;
+        sample
+    }
+}
+
+struct Shared {
+    queue: Mutex<VecDeque<Track>>,
+    queued: AtomicUsize,
+    current_remaining: AtomicUsize,
+    finished: AtomicBool,
+}
+
+/// Plays a queue of tracks back to back, crossfading the end of each track into the start of the next one.
+///
+/// More tracks can be queued while playing using the [`CrossfadeController`].
+/// The source ends when the last queued track has been played.
+pub struct CrossfadeSource {
+    current: Track,
+    /// The track that is being faded in, and the number of samples the fade lasts.
+    next: Option<(Track, usize)>,
+    channels: u16,
+    sample_rate: u32,
+    overlap_samples: usize,
+    shared: Arc<Shared>,
+}
+
+#[derive(Clone)]
+pub struct CrossfadeController {
+    channels: u16,
+    sample_rate: u32,
+    shared: Arc<Shared>,
+}
+
+/// Builds a `CrossfadeSource` which starts with `first`.
+///
+/// All tracks are converted to the channel count and sample rate of the first one.
+pub fn crossfade_queue<I>(first: I, overlap: Duration) -> (CrossfadeSource, CrossfadeController)
+where
+    I: Source,
+    I::Item: Sample,
+{
+    let channels = first.channels();
+    let sample_rate = first.sample_rate();
+    let current = Track {
+        samples: UniformSourceIterator::<I, f32>::new(first, channels, sample_rate).collect(),
+        position: 0,
+    };
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(VecDeque::new()),
+        queued: AtomicUsize::new(0),
+        current_remaining: AtomicUsize::new(current.remaining()),
+        finished: AtomicBool::new(false),
+    });
+
+    let overlap_frames = (overlap.as_secs_f64() * sample_rate as f64) as usize;
+    let source = CrossfadeSource {
+        current,
+        next: None,
+        channels,
+        sample_rate,
+        overlap_samples: overlap_frames * channels as usize,
+        shared: shared.clone(),
+    };
+    let controller = CrossfadeController {
+        channels,
+        sample_rate,
+        shared,
+    };
+
+    (source, controller)
+}
+
+impl CrossfadeController {
+    /// Queues a track to be played after the currently queued ones.
+    ///
+    /// This converts the track to the output format, so it is best called from a background thread.
+    pub fn push<I>(&self, track: I)
+    where
+        I: Source,
+        I::Item: Sample,
+    {
+        let samples =
+            UniformSourceIterator::<I, f32>::new(track, self.channels, self.sample_rate).collect();
+        self.shared.queue.lock().unwrap().push_back(Track {
+            samples,
+            position: 0,
+        });
+        self.shared.queued.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Number of queued tracks which have not started playing yet.
+    pub fn queued(&self) -> usize {
+        self.shared.queued.load(Ordering::SeqCst)
+    }
+
+    /// Time left until the currently playing track ends.
+    pub fn current_remaining(&self) -> Duration {
+        let samples = self.shared.current_remaining.load(Ordering::Relaxed);
+        Duration::from_secs_f64(samples as f64 / (self.channels as f64 * self.sample_rate as f64))
+    }
+
+    /// True when all queued tracks have been played.
+    pub fn is_finished(&self) -> bool {
+        self.shared.finished.load(Ordering::SeqCst)
+    }
+}
+
+impl CrossfadeSource {
+    fn pop_queued(&self) -> Option<Track> {
+        let track = self.shared.queue.lock().unwrap().pop_front();
+        if track.is_some() {
+            self.shared.queued.fetch_sub(1, Ordering::SeqCst);
+        }
+        track
+    }
+}
+
+impl Iterator for CrossfadeSource {
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.current.remaining() == 0 {
+            // Either the crossfade is complete, or there was no track ready in time and we have to cut
+            let next = match self.next.take() {
+                Some((next, _)) => Some(next),
+                None => self.pop_queued(),
+            };
+            match next {
+                Some(next) => self.current = next,
+                None => {
+                    self.shared.finished.store(true, Ordering::SeqCst);
+                    return None;
+                }
+            }
+        }
+
+        // Only start the crossfade at a frame boundary, so that the channels of both tracks line up
+        if self.next.is_none()
+            && self.current.remaining() <= self.overlap_samples
+            && self.current.position.is_multiple_of(self.channels as usize)
+            && self.shared.queued.load(Ordering::SeqCst) > 0
+        {
+            let fade_samples = self.current.remaining();
+            self.next = self.pop_queued().map(|track| (track, fade_samples));
+        }
+
+        self.shared
+            .current_remaining
+            .store(self.current.remaining(), Ordering::Relaxed);
+
+        let sample = self.current.next().unwrap_or(0.0);
+        match &mut self.next {
+            Some((next, fade_samples)) => {
+                let t = 1.0 - self.current.remaining() as f32 / *fade_samples as f32;
+                let next_sample = next.next().unwrap_or(0.0);
+                Some(sample * smoothstep(1.0 - t) + next_sample * smoothstep(t))
+            }
+            None => Some(sample),
+        }
+    }
+}
+
+impl Source for CrossfadeSource {
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[test]
+fn test_crossfade_queue() {
+    let first = rodio::buffer::SamplesBuffer::new(1, 100, vec![1.0f32; 100]);
+    let (source, controller) = crossfade_queue(first, Duration::from_millis(100));
+    controller.push(rodio::buffer::SamplesBuffer::new(1, 100, vec![-1.0f32; 50]));
+
+    let output = source.collect::<Vec<_>>();
+    // The two tracks overlap by 10 samples
+    assert_eq!(output.len(), 140);
+    assert!(output[..90].iter().all(|&x| x == 1.0));
+    // The fade is monotonic and stays within the range of the inputs
+    assert!(output[90..100].windows(2).all(|w| w[1] <= w[0]));
+    assert!(output[90..100].iter().all(|&x| (-1.0..=1.0).contains(&x)));
+    assert!(output[100..].iter().all(|&x| x == -1.0));
+    assert!(controller.is_finished());
+}
@@ -0,0 +1,232 @@
+use rodio::Source;
+
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{self, SystemTime};
+use time::Duration;
+
+/// Decoded audio which can be played any number of times without copying the samples.
+#[derive(Clone)]
+pub struct DecodedAudio {
+    samples: Arc<Vec<f32>>,
+    position: usize,
+    channels: u16,
+    sample_rate: u32,
+}
+
+impl DecodedAudio {
+    pub fn new(channels: u16, sample_rate: u32, samples: Vec<f32>) -> Self {
+        DecodedAudio {
+            samples: Arc::new(samples),
+            position: 0,
+            channels,
+            sample_rate,
+        }
+    }
+
+    fn size_bytes(&self) -> usize {
+        self.samples.len() * std::mem::size_of::<f32>()
+    }
+}
+
+impl Iterator for DecodedAudio {
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let sample = self.samples.get(self.position).copied();
+        self.position += 1;
+        sample
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.samples.len().saturating_sub(self.position);
+        (remaining, Some(remaining))
+    }
+}
+
+impl ExactSizeIterator for DecodedAudio {}
+
+impl Source for DecodedAudio {
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        let frames = self.samples.len() / self.channels.max(1) as usize;
+        Some(Duration::from_secs_f64(
+            frames as f64 / self.sample_rate as f64,
+        ))
+    }
+}
+
+struct Entry {
+    path: PathBuf,
+    modified: SystemTime,
+    audio: DecodedAudio,
+}
+
+/// Least recently used cache of decoded files, limited by the total size of the samples.
+pub struct DecodeCache {
+    /// Least recently used first.
+    entries: VecDeque<Entry>,
+    total_bytes: usize,
+    hits: u64,
+    misses: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub bytes: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl DecodeCache {
+    pub const fn new() -> Self {
+        DecodeCache {
+            entries: VecDeque::new(),
+            total_bytes: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// The cached audio for `path`, if it was decoded when the file had the modification time `modified`.
+    ///
+    /// Entries for older versions of the file are dropped.
+    pub fn get(&mut self, path: &Path, modified: SystemTime) -> Option<DecodedAudio> {
+        let Some(index) = self.entries.iter().position(|e| e.path == path) else {
+            self.misses += 1;
+            return None;
+        };
+
+        let entry = self.entries.remove(index).unwrap();
+        if entry.modified != modified {
+            self.total_bytes -= entry.audio.size_bytes();
+            self.misses += 1;
+            return None;
+        }
+
+        self.hits += 1;
+        let audio = entry.audio.clone();
+        self.entries.push_back(entry);
+        Some(audio)
+    }
+
+    /// Adds `audio` to the cache, evicting the least recently used entries until the cache fits in `max_bytes`.
+    pub fn insert(
+        &mut self,
+        path: &Path,
+        modified: SystemTime,
+        audio: DecodedAudio,
+        max_bytes: usize,
+    ) {
+        if let Some(index) = self.entries.iter().position(|e| e.path == path) {
+            let old = self.entries.remove(index).unwrap();
+            self.total_bytes -= old.audio.size_bytes();
+        }
+
+        let size = audio.size_bytes();
+        if size > max_bytes {
+            return;
+        }
+
+        while self.total_bytes + size > max_bytes {
+            let evicted = self.entries.pop_front().unwrap();
+            self.total_bytes -= evicted.audio.size_bytes();
+        }
+
+        self.total_bytes += size;
+        self.entries.push_back(Entry {
+            path: path.to_path_buf(),
+            modified,
+            audio,
+        });
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entries.len(),
+            bytes: self.total_bytes,
+            hits: self.hits,
+            misses: self.misses,
+        }
+    }
+}
+
+static CACHE: Mutex<DecodeCache> = Mutex::new(DecodeCache::new());
+
+/// Decodes `path` using `decode`, or reuses the result from an earlier call if the file has not changed since.
+///
+/// The cache is limited to `max_bytes` of samples. Decoding happens without holding the cache lock.
+/// Nothing is cached if decoding fails.
+pub fn decode_cached<E>(
+    path: &Path,
+    max_bytes: usize,
+    decode: impl FnOnce(&Path) -> Result<DecodedAudio, E>,
+) -> Result<DecodedAudio, E> {
+    let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let Some(modified) = modified else {
+        return decode(path);
+    };
+
+    if let Some(audio) = CACHE.lock().unwrap().get(path, modified) {
+        return Ok(audio);
+    }
+
+    let audio = decode(path)?;
+    CACHE
+        .lock()
+        .unwrap()
+        .insert(path, modified, audio.clone(), max_bytes);
+    Ok(audio)
+}
+
+pub fn stats() -> CacheStats {
+    CACHE.lock().unwrap().stats()
+}
+
+#[test]
+fn test_decode_cache() {
+    let audio = |len: usize| DecodedAudio::new(1, 100, vec![0.0; len]);
+    let t0 = SystemTime::UNIX_EPOCH;
+    let t1 = t0 + Duration::from_secs(1);
+    let mut cache = DecodeCache::new();
+
+    cache.insert(Path::new("a"), t0, audio(10), 100);
+    cache.insert(Path::new("b"), t0, audio(10), 100);
+    assert_eq!(cache.stats().bytes, 80);
+    assert!(cache.get(Path::new("a"), t0).is_some());
+
+    // "b" is the least recently used, so it gets evicted first
+    cache.insert(Path::new("c"), t0, audio(10), 100);
+    assert!(cache.get(Path::new("b"), t0).is_none());
+    assert!(cache.get(Path::new("a"), t0).is_some());
+    assert!(cache.get(Path::new("c"), t0).is_some());
+    assert_eq!(cache.stats().bytes, 80);
+
+    // A modified file is not served from the cache
+    assert!(cache.get(Path::new("a"), t1).is_none());
+    assert_eq!(cache.stats().entries, 1);
+
+    // Too large to cache at all
+    cache.insert(Path::new("d"), t0, audio(100), 100);
+    assert!(cache.get(Path::new("d"), t0).is_none());
+    assert_eq!(cache.stats().bytes, 40);
+}
@@ -0,0 +1,88 @@
+use rodio::{Sample, Source};
+
+use std::time;
+use time::Duration;
+
+/// Mixes all channels of the input down to a single channel by averaging them.
+pub struct Downmix<I> {
+    input: I,
+}
+
+impl<I> Downmix<I>
+where
+    I: Source<Item = f32>,
+{
+    pub fn new(input: I) -> Self {
+        Downmix { input }
+    }
+}
+
+impl<I> Iterator for Downmix<I>
+where
+    I: Source<Item = f32>,
+    I::Item: Sample,
+{
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        // Always consume whole frames, so that we stay in sync with the interleaving of the input
+        let channels = self.input.channels().max(1);
+        let mut sum = self.input.next()?;
+        for _ in 1..channels {
+            sum += self.input.next().unwrap_or(0.0);
+        }
+        Some(sum / channels as f32)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let channels = self.input.channels().max(1) as usize;
+        let inner = self.input.size_hint();
+        (inner.0 / channels, inner.1.map(|x| x.div_ceil(channels)))
+    }
+}
+
+impl<I> Source for Downmix<I>
+where
+    I: Source<Item = f32>,
+    I::Item: Sample,
+{
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        let channels = self.input.channels().max(1) as usize;
+        self.input.current_frame_len().map(|x| x / channels)
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
+
+#[test]
+fn test_downmix() {
+    // One second hard panned left, followed by one second hard panned right
+    let mut samples = vec![];
+    samples.extend([0.8f32, 0.0].repeat(100));
+    samples.extend([0.0f32, -0.6].repeat(100));
+    let stereo = rodio::buffer::SamplesBuffer::new(2, 100, samples);
+
+    let mono = Downmix::new(stereo);
+    assert_eq!(mono.channels(), 1);
+    assert_eq!(mono.sample_rate(), 100);
+    let output = mono.collect::<Vec<_>>();
+    assert_eq!(output.len(), 200);
+    assert!(output[..100].iter().all(|&x| (x - 0.4).abs() < 1e-6));
+    assert!(output[100..].iter().all(|&x| (x + 0.3).abs() < 1e-6));
+}
@@ -0,0 +1,186 @@
+use std::f32::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum EqPreset {
+    #[default]
+    Flat,
+    /// Removes the bass that a small speaker cannot play anyway, and tames harsh highs.
+    SmallSpeaker,
+    BassBoost,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BandKind {
+    HighPass,
+    LowShelf,
+    HighShelf,
+    Peak,
+}
+
+/// One filter in an equalizer preset.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EqBand {
+    pub kind: BandKind,
+    pub frequency: f32,
+    /// Ignored for high pass filters.
+    pub gain_db: f32,
+    pub q: f32,
+}
+
+const fn band(kind: BandKind, frequency: f32, gain_db: f32, q: f32) -> EqBand {
+    EqBand {
+        kind,
+        frequency,
+        gain_db,
+        q,
+    }
+}
+
+const SMALL_SPEAKER: &[EqBand] = &[
+    band(BandKind::HighPass, 150.0, 0.0, 0.707),
+    band(BandKind::Peak, 3000.0, -4.0, 1.0),
+    band(BandKind::HighShelf, 8000.0, -6.0, 0.707),
+];
+
+const BASS_BOOST: &[EqBand] = &[band(BandKind::LowShelf, 120.0, 6.0, 0.707)];
+
+impl EqPreset {
+    pub fn bands(self) -> &'static [EqBand] {
+        match self {
+            EqPreset::Flat => &[],
+            EqPreset::SmallSpeaker => SMALL_SPEAKER,
+            EqPreset::BassBoost => BASS_BOOST,
+        }
+    }
+}
+
+/// Second order IIR filter, with coefficients from the Audio EQ Cookbook.
+#[derive(Debug, Clone)]
+struct Biquad {
+    b: [f32; 3],
+    a: [f32; 2],
+}
+
+impl Biquad {
+    fn new(band: &EqBand, sample_rate: u32) -> Self {
+        // Keep the frequency below the nyquist frequency, otherwise the filter becomes unstable
+        let frequency = band.frequency.min(0.45 * sample_rate as f32);
+        let w0 = 2.0 * PI * frequency / sample_rate as f32;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * band.q);
+        let a = 10f32.powf(band.gain_db / 40.0);
+        let sqrt_a_alpha = 2.0 * a.sqrt() * alpha;
+
+        let (b, a) = match band.kind {
+            BandKind::HighPass => (
+                [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+                [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            ),
+            BandKind::Peak => (
+                [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a],
+                [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a],
+            ),
+            BandKind::LowShelf => (
+                [
+                    a * ((a + 1.0) - (a - 1.0) * cos + sqrt_a_alpha),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                    a * ((a + 1.0) - (a - 1.0) * cos - sqrt_a_alpha),
+                ],
+                [
+                    (a + 1.0) + (a - 1.0) * cos + sqrt_a_alpha,
+                    -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                    (a + 1.0) + (a - 1.0) * cos - sqrt_a_alpha,
+                ],
+            ),
+            BandKind::HighShelf => (
+                [
+                    a * ((a + 1.0) + (a - 1.0) * cos + sqrt_a_alpha),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                    a * ((a + 1.0) + (a - 1.0) * cos - sqrt_a_alpha),
+                ],
+                [
+                    (a + 1.0) - (a - 1.0) * cos + sqrt_a_alpha,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                    (a + 1.0) - (a - 1.0) * cos - sqrt_a_alpha,
+                ],
+            ),
+        };
+
+        Biquad {
+            b: [b[0] / a[0], b[1] / a[0], b[2] / a[0]],
+            a: [a[1] / a[0], a[2] / a[0]],
+        }
+    }
+}
+
+/// Applies the bands of an [`EqPreset`] to an interleaved signal.
+pub struct Equalizer {
+    preset: EqPreset,
+    filters: Vec<Biquad>,
+    /// Filter state `[x1, x2, y1, y2]` for every filter and channel.
+    state: Vec<[f32; 4]>,
+    channels: usize,
+    channel: usize,
+}
+
+impl Equalizer {
+    pub fn new(preset: EqPreset, sample_rate: u32, channels: u16) -> Self {
+        let filters = preset
+            .bands()
+            .iter()
+            .map(|band| Biquad::new(band, sample_rate))
+            .collect::<Vec<_>>();
+        let channels = channels.max(1) as usize;
+        Equalizer {
+            preset,
+            state: vec![[0.0; 4]; filters.len() * channels],
+            filters,
+            channels,
+            channel: 0,
+        }
+    }
+
+    pub fn preset(&self) -> EqPreset {
+        self.preset
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        let mut x = sample;
+        for (i, filter) in self.filters.iter().enumerate() {
+            let state = &mut self.state[i * self.channels + self.channel];
+            let y = filter.b[0] * x + filter.b[1] * state[0] + filter.b[2] * state[1]
+                - filter.a[0] * state[2]
+                - filter.a[1] * state[3];
+            *state = [x, state[0], y, state[2]];
+            x = y;
+        }
+        self.channel = (self.channel + 1) % self.channels;
+        x
+    }
+}
+
+#[test]
+fn test_small_speaker_preset() {
+    let sample_rate = 44100;
+    let rms_after_eq = |frequency: f32| {
+        let mut eq = Equalizer::new(EqPreset::SmallSpeaker, sample_rate, 1);
+        let output = (0..sample_rate)
+            .map(|i| eq.process((2.0 * PI * frequency * i as f32 / sample_rate as f32).sin()))
+            .skip(sample_rate as usize / 2)
+            .collect::<Vec<_>>();
+        (output.iter().map(|x| x * x).sum::<f32>() / output.len() as f32).sqrt()
+    };
+
+    let low = rms_after_eq(60.0);
+    let mid = rms_after_eq(1000.0);
+    // At least 12 dB quieter
+    assert!(low < mid / 4.0, "{low} vs {mid}");
+    // 1 kHz is mostly untouched
+    assert!((mid - std::f32::consts::FRAC_1_SQRT_2).abs() < 0.1, "{mid}");
+
+    let mut flat = Equalizer::new(EqPreset::Flat, sample_rate, 2);
+    assert_eq!(flat.process(0.25), 0.25);
+}
@@ -0,0 +1,81 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::lucid::{LucidCue, LucidTiming};
+use crate::offline::Container;
+
+/// Value of `alarm/now_playing` when nothing is playing.
+pub const NOT_PLAYING: &str = "none";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum PlaybackEventKind {
+    AlarmStarted,
+    AlarmStopped,
+    LucidCuePlayed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PlaybackEvent {
+    pub kind: PlaybackEventKind,
+    pub time: DateTime<Utc>,
+    /// The sound involved, relative to the sound directory.
+    pub sound: Option<String>,
+    /// The kind of lucid cue which was played.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cue: Option<LucidCue>,
+    /// Whether the lucid cue was timed to REM sleep, or random.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timing: Option<LucidTiming>,
+}
+
+/// Publishes what is currently playing, and playback events, to the synced storage.
+#[derive(Clone)]
+pub struct PlaybackEvents {
+    now_playing: Arc<Container<String>>,
+    last_event: Arc<Container<Option<PlaybackEvent>>>,
+}
+
+impl PlaybackEvents {
+    pub fn new(
+        now_playing: Arc<Container<String>>,
+        last_event: Arc<Container<Option<PlaybackEvent>>>,
+    ) -> Self {
+        PlaybackEvents {
+            now_playing,
+            last_event,
+        }
+    }
+
+    pub async fn now_playing(&self, sound: Option<&str>) {
+        self.now_playing
+            .set(sound.unwrap_or(NOT_PLAYING).to_owned())
+            .await;
+    }
+
+    pub async fn publish(&self, kind: PlaybackEventKind, sound: Option<String>) {
+        self.last_event
+            .set(Some(PlaybackEvent {
+                kind,
+                time: Utc::now(),
+                sound,
+                cue: None,
+                timing: None,
+            }))
+            .await;
+    }
+
+    pub async fn publish_lucid_cue(&self, cue: LucidCue, timing: LucidTiming, sound: String) {
+        self.last_event
+            .set(Some(PlaybackEvent {
+                kind: PlaybackEventKind::LucidCuePlayed,
+                time: Utc::now(),
+                sound: Some(sound),
+                cue: Some(cue),
+                timing: Some(timing),
+            }))
+            .await;
+    }
+}
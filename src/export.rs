@@ -0,0 +1,300 @@
+//! Exports the sleep data as Parquet, for analysis in for example pandas or polars.
+//!
+//! Usage: `alarm export --format parquet --from 2024-03-01 [--to 2024-03-08] [--minutes] [--output sleep.parquet]`.
+//! The times are dates, which start at local midnight, or RFC 3339 timestamps. `--to` defaults to now.
+//! Every reading of both sensors is exported, or with `--minutes` the per-minute aggregates of the user's sensor.
+use arrow_array::{
+    ArrayRef, BooleanArray, Float32Array, RecordBatch, TimestampMillisecondArray, UInt32Array,
+    UInt8Array,
+};
+use arrow_schema::{ArrowError, DataType, Field, Schema, SchemaRef, TimeUnit};
+use chrono::{DateTime, Local, NaiveDate, TimeDelta, TimeZone, Utc};
+use parquet::{
+    arrow::ArrowWriter, basic::Compression, errors::ParquetError,
+    file::properties::WriterProperties,
+};
+use std::{fs::File, io, path::Path, sync::Arc};
+use thiserror::Error;
+
+use crate::archive::{self, MinuteAggregate};
+use crate::config::MotionConfig;
+use crate::motion_log::{self, LogSample, MovementTracker};
+
+#[derive(Error, Debug)]
+pub enum ExportError {
+    #[error("{0}")]
+    Usage(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Arrow(#[from] ArrowError),
+    #[error(transparent)]
+    Parquet(#[from] ParquetError),
+    #[cfg(feature = "sqlite")]
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+}
+
+fn time_field() -> Field {
+    Field::new(
+        "time",
+        DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+        false,
+    )
+}
+
+fn samples_schema() -> SchemaRef {
+    let float = |name| Field::new(name, DataType::Float32, false);
+    Arc::new(Schema::new(vec![
+        time_field(),
+        Field::new("sensor", DataType::UInt8, false),
+        Field::new("samples", DataType::UInt32, false),
+        Field::new("alarm_playing", DataType::Boolean, false),
+        Field::new("present", DataType::Boolean, true),
+        Field::new("movement", DataType::Float32, true),
+        float("acc_x"),
+        float("acc_y"),
+        float("acc_z"),
+        float("gyro_x"),
+        float("gyro_y"),
+        float("gyro_z"),
+        float("temp"),
+    ]))
+}
+
+fn minutes_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        time_field(),
+        Field::new("readings", DataType::UInt32, false),
+        Field::new("mean_movement", DataType::Float32, true),
+        Field::new("max_movement", DataType::Float32, true),
+        Field::new("present_fraction", DataType::Float32, true),
+        Field::new("temp", DataType::Float32, false),
+        Field::new("breaths_per_minute", DataType::Float32, true),
+        Field::new("respiration_confidence", DataType::Float32, true),
+    ]))
+}
+
+fn times<T>(rows: &[T], time: impl Fn(&T) -> DateTime<Utc>) -> ArrayRef {
+    Arc::new(
+        TimestampMillisecondArray::from_iter_values(
+            rows.iter().map(|r| time(r).timestamp_millis()),
+        )
+        .with_timezone("UTC"),
+    )
+}
+
+fn floats<T>(rows: &[T], value: impl Fn(&T) -> Option<f32>) -> ArrayRef {
+    Arc::new(rows.iter().map(value).collect::<Float32Array>())
+}
+
+pub fn samples_batch(samples: &[LogSample]) -> Result<RecordBatch, ArrowError> {
+    RecordBatch::try_new(
+        samples_schema(),
+        vec![
+            times(samples, |s| s.time),
+            Arc::new(UInt8Array::from_iter_values(
+                samples.iter().map(|s| s.sensor),
+            )),
+            Arc::new(UInt32Array::from_iter_values(
+                samples.iter().map(|s| s.samples as u32),
+            )),
+            Arc::new(
+                samples
+                    .iter()
+                    .map(|s| Some(s.alarm_playing))
+                    .collect::<BooleanArray>(),
+            ),
+            Arc::new(samples.iter().map(|s| s.present).collect::<BooleanArray>()),
+            floats(samples, |s| s.movement),
+            floats(samples, |s| Some(s.data.acc.0)),
+            floats(samples, |s| Some(s.data.acc.1)),
+            floats(samples, |s| Some(s.data.acc.2)),
+            floats(samples, |s| Some(s.data.gyro.0)),
+            floats(samples, |s| Some(s.data.gyro.1)),
+            floats(samples, |s| Some(s.data.gyro.2)),
+            floats(samples, |s| Some(s.data.temp)),
+        ],
+    )
+}
+
+pub fn minutes_batch(minutes: &[MinuteAggregate]) -> Result<RecordBatch, ArrowError> {
+    RecordBatch::try_new(
+        minutes_schema(),
+        vec![
+            times(minutes, |m| m.time),
+            Arc::new(UInt32Array::from_iter_values(
+                minutes.iter().map(|m| m.readings),
+            )),
+            floats(minutes, |m| m.mean_movement),
+            floats(minutes, |m| m.max_movement),
+            floats(minutes, |m| m.present_fraction),
+            floats(minutes, |m| Some(m.temp)),
+            floats(minutes, |m| m.respiration.map(|r| r.breaths_per_minute)),
+            floats(minutes, |m| m.respiration.map(|r| r.confidence)),
+        ],
+    )
+}
+
+fn writer(path: &Path, schema: SchemaRef) -> Result<ArrowWriter<File>, ExportError> {
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    Ok(ArrowWriter::try_new(
+        File::create(path)?,
+        schema,
+        Some(properties),
+    )?)
+}
+
+/// The samples of all sensors between `from` and `to`, with their movement.
+fn read_samples(
+    motion: &MotionConfig,
+    tracker: &mut MovementTracker,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<LogSample>, ExportError> {
+    #[cfg(feature = "sqlite")]
+    if let Some(database) = &motion.database {
+        return Ok(crate::sleep_db::read(database, from, to)?);
+    }
+    Ok(motion_log::read(&motion.log_dir, from, to)?
+        .iter()
+        .filter_map(|l| LogSample::parse_csv(l))
+        .map(|mut sample| {
+            sample.movement = tracker.update(&sample);
+            sample
+        })
+        .collect())
+}
+
+/// Writes the samples, or the minutes if `minutes` is true, between `from` and `to` to `path`. Returns the number of rows.
+///
+/// One day is read and written at a time, so that long periods don't have to fit in memory.
+pub fn export(
+    motion: &MotionConfig,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    minutes: bool,
+    path: &Path,
+) -> Result<usize, ExportError> {
+    let schema = if minutes {
+        minutes_schema()
+    } else {
+        samples_schema()
+    };
+    let mut writer = writer(path, schema)?;
+    let mut tracker = MovementTracker::default();
+    let mut rows = 0;
+    let mut date = from.date_naive();
+    while date <= to.date_naive() {
+        let start = date.and_hms_opt(0, 0, 0).unwrap().and_utc();
+        let end = start + TimeDelta::days(1) - TimeDelta::milliseconds(1);
+        let (start, end) = (from.max(start), to.min(end));
+        let batch = if minutes && motion.database.is_none() {
+            minutes_batch(&archive::read(&motion.log_dir, start, end)?)?
+        } else if minutes {
+            let samples = read_samples(motion, &mut tracker, start, end)?
+                .into_iter()
+                .filter(|s| s.sensor == motion_log::MY_SENSOR)
+                .collect::<Vec<_>>();
+            minutes_batch(&archive::aggregate(&samples))?
+        } else {
+            samples_batch(&read_samples(motion, &mut tracker, start, end)?)?
+        };
+        rows += batch.num_rows();
+        writer.write(&batch)?;
+        date = date.succ_opt().unwrap();
+    }
+    writer.close()?;
+    Ok(rows)
+}
+
+/// Parses an RFC 3339 timestamp, or a date which starts at local midnight.
+fn parse_time(time: &str) -> Option<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(time) {
+        return Some(time.with_timezone(&Utc));
+    }
+    let date = NaiveDate::parse_from_str(time, "%Y-%m-%d").ok()?;
+    Local
+        .from_local_datetime(&date.and_hms_opt(0, 0, 0)?)
+        .earliest()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+/// Runs the `export` command with the arguments after it.
+pub fn run(motion: &MotionConfig, args: &[String]) -> Result<(), ExportError> {
+    let value = |name: &str| {
+        args.iter()
+            .skip_while(|a| *a != name)
+            .nth(1)
+            .map(String::as_str)
+    };
+    let time = |name: &str| {
+        value(name)
+            .map(|t| parse_time(t).ok_or_else(|| ExportError::Usage(format!("Invalid time `{t}`"))))
+            .transpose()
+    };
+    let format = value("--format").unwrap_or("parquet");
+    if format != "parquet" {
+        return Err(ExportError::Usage(format!(
+            "Unsupported format `{format}`, only parquet is supported"
+        )));
+    }
+    let Some(from) = time("--from")? else {
+        return Err(ExportError::Usage("--from is required".to_owned()));
+    };
+    let to = time("--to")?.unwrap_or_else(Utc::now);
+    let minutes = args.iter().any(|a| a == "--minutes");
+    let path = Path::new(value("--output").unwrap_or("sleep.parquet"));
+    let rows = export(motion, from, to, minutes, path)?;
+    info!("Exported {} rows to {}", rows, path.display());
+    Ok(())
+}
+
+#[test]
+fn test_parquet_round_trip() {
+    use crate::sleep_monitor::AccelerometerData;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    let path = std::env::temp_dir().join(format!("export-test-{}.parquet", std::process::id()));
+    let start = Utc.with_ymd_and_hms(2024, 3, 1, 23, 59, 0).unwrap();
+    let sample = |seconds, sensor, present, movement| LogSample {
+        sensor,
+        time: start + TimeDelta::seconds(seconds),
+        samples: 10,
+        alarm_playing: seconds > 30,
+        present,
+        movement,
+        data: AccelerometerData {
+            acc: (0.01, -0.02, -1.0),
+            gyro: (0.5, 0.0, -0.25),
+            temp: 21.5,
+        },
+        respiration: None,
+    };
+    let samples = [
+        sample(0, motion_log::MY_SENSOR, None, None),
+        sample(0, motion_log::PARTNER_SENSOR, Some(false), None),
+        sample(20, motion_log::MY_SENSOR, Some(true), Some(0.03)),
+    ];
+    let batch = samples_batch(&samples).unwrap();
+    let mut w = writer(&path, batch.schema()).unwrap();
+    w.write(&batch).unwrap();
+    w.close().unwrap();
+
+    let read = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap())
+        .unwrap()
+        .build()
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(read, vec![batch]);
+    let times = read[0]
+        .column(0)
+        .as_any()
+        .downcast_ref::<TimestampMillisecondArray>()
+        .unwrap();
+    assert_eq!(times.value(2), samples[2].time.timestamp_millis());
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,14 @@
+//! Volume curves for fading sounds in and out. Kept apart from [`crate::alarm`], so that they are also available
+//! without the audio feature.
+
+pub fn smoothstep(x: f32) -> f32 {
+    3.0 * x.powi(2) - 2.0 * x.powi(3)
+}
+
+pub fn fadein(t: f32, duration: f32) -> f32 {
+    smoothstep((t.max(0.0) / duration).min(1.0))
+}
+
+pub fn fadeout(t: f32, duration: f32) -> f32 {
+    smoothstep((1.0 - (t.max(0.0) / duration)).max(0.0))
+}
@@ -1,23 +1,95 @@
+use cpal::Sample as CpalSample;
+use log::debug;
 use rodio::{Sample, Source};
 
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::{sync::Arc, sync::Mutex, time};
 use synthrs::filter::{cutoff_from_frequency, lowpass_filter};
+use time::Instant;
+
+use crate::convolution::{convolve, flush_denormal};
+use crate::equalizer::{EqPreset, Equalizer};
 use time::Duration;
 
+/// Lowest lowpass cutoff frequency. Lower frequencies would make the filter kernel degenerate.
+const MIN_CUTOFF_HZ: f64 = 10.0;
+
+/// Default length of the audio processed at a time. Longer frames use less CPU, but add latency.
+pub const DEFAULT_FRAME_DURATION: Duration = Duration::from_millis(20);
+
+/// Width of the lowpass filter's transition band, relative to the sample rate. Narrower bands need longer kernels.
+const TRANSITION_BAND: f64 = 0.01;
+
+/// How often to log filter performance statistics, in frames.
+const STATS_INTERVAL_FRAMES: usize = 250;
+
+/// Number of interleaved samples in a frame of the given duration.
+fn frame_samples(duration: Duration, sample_rate: u32, channels: u16) -> usize {
+    let frames = (duration.as_secs_f64() * sample_rate as f64) as usize;
+    frames.max(1) * channels.max(1) as usize
+}
+
+/// The filter kernel is only recalculated once the cutoff has changed by at least this much.
+const MIN_CUTOFF_CHANGE_OCTAVES: f64 = 1.0 / 48.0;
+
+/// Number of NaN or infinite samples that have been replaced by silence.
+static NON_FINITE_SAMPLES: AtomicU64 = AtomicU64::new(0);
+
+pub fn non_finite_samples() -> u64 {
+    NON_FINITE_SAMPLES.load(Ordering::Relaxed)
+}
+
+/// Replaces NaN and infinite samples with silence, since they would otherwise get stuck in the filter state forever.
+#[inline]
+pub fn sanitize(sample: f32) -> f32 {
+    if sample.is_finite() {
+        sample
+    } else {
+        NON_FINITE_SAMPLES.fetch_add(1, Ordering::Relaxed);
+        0.0
+    }
+}
+
+/// Clamps a cutoff frequency to the range the lowpass filter can handle at the given sample rate.
+fn safe_cutoff(freq: f64, sample_rate: u32) -> f64 {
+    let nyquist = sample_rate as f64 / 2.0;
+    if freq.is_nan() {
+        return nyquist;
+    }
+    freq.clamp(MIN_CUTOFF_HZ, nyquist)
+}
+
 /// Internal function that builds a `FilteredSource` object.
+///
+/// Works with any sample type. The samples are converted to f32 for filtering, and back again afterwards.
 pub fn dynamic_filter<I>(
     input: I,
     lowpass_freq: Box<dyn Fn(f64) -> f64 + Send + Sync>,
 ) -> (FilteredSource<I>, Controller)
 where
-    I: Source<Item = f32>,
+    I: Source,
+    I::Item: Sample,
 {
     let sample_rate = input.sample_rate();
+    let channels = input.channels();
+    let limiter = Limiter::new(sample_rate, input.channels());
+    let dc_blocker = DcBlocker::new(sample_rate, input.channels());
+    let equalizer = Equalizer::new(EqPreset::Flat, sample_rate, input.channels());
     let source = FilteredSource {
         input,
         settings: Arc::new(Mutex::new(Settings {
             lowpass: vec![],
-            volume: 1.0,
+            cutoff_override: None,
+            max_cutoff_slew: None,
+            limiter_threshold: None,
+            balance: 0.0,
+            dc_blocker: false,
+            fade_out: None,
+            silent: false,
+            eq: EqPreset::Flat,
+            first_sound: None,
+            frame_size: frame_samples(DEFAULT_FRAME_DURATION, sample_rate, channels),
         })),
         current_buffer: vec![],
         current_buffer_index: 0,
@@ -25,11 +97,20 @@ where
         trailing_samples: vec![],
         lowpass_freq,
         sample_count: 0,
-        last_lowpass_recalculation: 0,
+        last_cutoff_update: 0,
+        cutoff: None,
+        kernel_cutoff: None,
+        transition_band: TRANSITION_BAND,
+        effective_frame_size: 0,
+        frame_stats: (0, Duration::ZERO),
+        limiter,
+        dc_blocker,
+        equalizer,
     };
 
     let controller = Controller {
         sample_rate,
+        channels: source.input.channels(),
         settings: source.settings.clone(),
     };
 
@@ -40,7 +121,21 @@ where
 
 pub struct Settings {
     lowpass: Vec<f32>,
-    volume: f32,
+    cutoff_override: Option<f64>,
+    /// Maximum change of the cutoff frequency, in octaves per second.
+    max_cutoff_slew: Option<f64>,
+    limiter_threshold: Option<f32>,
+    balance: f32,
+    dc_blocker: bool,
+    /// Remaining and total number of samples of an ongoing fade to silence.
+    fade_out: Option<(usize, usize)>,
+    /// True once a fade to silence has completed. Everything after it is silent.
+    silent: bool,
+    eq: EqPreset,
+    /// When the first nonzero sample was produced.
+    first_sound: Option<Instant>,
+    /// Target number of new samples to process per frame.
+    frame_size: usize,
 }
 
 /// Filter that modifies reduces the volume to silence over a time period.
@@ -53,104 +148,279 @@ pub struct FilteredSource<I> {
     current_buffer: Vec<f32>,
     lowpass_freq: Box<dyn Fn(f64) -> f64 + Send + Sync>,
     sample_count: usize,
-    last_lowpass_recalculation: usize,
+    last_cutoff_update: usize,
+    /// Cutoff frequency, after slew limiting.
+    cutoff: Option<f64>,
+    /// Cutoff frequency of the current filter kernel.
+    kernel_cutoff: Option<f64>,
+    transition_band: f64,
+    /// Number of new samples processed per frame, after making sure it is not shorter than the kernel.
+    effective_frame_size: usize,
+    /// Number of frames and total time spent processing them, since the statistics were last logged.
+    frame_stats: (usize, Duration),
+    limiter: Limiter,
+    dc_blocker: DcBlocker,
+    equalizer: Equalizer,
 }
 
+#[derive(Clone)]
 pub struct Controller {
-    #[allow(unused)]
     sample_rate: u32,
+    channels: u16,
     settings: Arc<Mutex<Settings>>,
 }
 
 impl Controller {
-    pub fn set_volume(&self, v: f32) {
-        self.settings.lock().unwrap().volume = v;
+    /// Uses a fixed lowpass cutoff frequency instead of the time based one, or goes back to it if `None`.
+    ///
+    /// The filter moves towards it at the rate allowed by [`Controller::set_cutoff_slew`].
+    pub fn set_cutoff_override(&self, freq: Option<f64>) {
+        self.settings.lock().unwrap().cutoff_override = freq;
+    }
+
+    /// Limits how fast the cutoff frequency may change, in octaves per second. Unlimited if `None`.
+    pub fn set_cutoff_slew(&self, octaves_per_second: Option<f64>) {
+        self.settings.lock().unwrap().max_cutoff_slew = octaves_per_second;
+    }
+
+    /// Limits the output to `threshold` using a [`Limiter`], or just clips it to [-1, 1] if `None`.
+    pub fn set_limiter(&self, threshold: Option<f32>) {
+        self.settings.lock().unwrap().limiter_threshold = threshold;
+    }
+
+    /// Shifts the sound towards the left (-1) or right (1) channel, by turning down the other one.
+    pub fn set_balance(&self, balance: f32) {
+        self.settings.lock().unwrap().balance = balance.clamp(-1.0, 1.0);
+    }
+
+    /// Ramps the output down to silence over `duration`, sample by sample.
+    ///
+    /// Use [`Controller::is_silent`] to find out when the ramp has been played.
+    pub fn fade_to_silence(&self, duration: Duration) {
+        let samples = (duration.as_secs_f64() * self.sample_rate as f64) as usize
+            * self.channels.max(1) as usize;
+        let mut settings = self.settings.lock().unwrap();
+        if settings.fade_out.is_none() && !settings.silent {
+            settings.fade_out = Some((samples.max(1), samples.max(1)));
+        }
+    }
+
+    /// True when a fade started by [`Controller::fade_to_silence`] has completed.
+    pub fn is_silent(&self) -> bool {
+        self.settings.lock().unwrap().silent
+    }
+
+    /// Equalizer preset which is applied after the lowpass filter.
+    pub fn set_eq(&self, preset: EqPreset) {
+        self.settings.lock().unwrap().eq = preset;
+    }
+
+    /// Removes any DC offset from the input before filtering, to avoid thumps when the volume changes.
+    pub fn set_dc_blocker(&self, enabled: bool) {
+        self.settings.lock().unwrap().dc_blocker = enabled;
+    }
+
+    /// How much audio to process at a time. The frame is made longer if it would be shorter than the filter kernel.
+    pub fn set_frame_duration(&self, duration: Duration) {
+        self.settings.lock().unwrap().frame_size =
+            frame_samples(duration, self.sample_rate, self.channels);
+    }
+
+    /// When the first nonzero sample was produced, if any.
+    pub fn first_sound(&self) -> Option<Instant> {
+        self.settings.lock().unwrap().first_sound
     }
 }
 
-#[allow(unused)]
-pub fn convolve(filter: &[f32], input: &[f32], output: &mut [f32]) {
-    assert_eq!(output.len(), input.len() - filter.len(), "output size are only the inner valid samples. filter.len()/2 samples on each side are skipped.");
-    assert_eq!(filter.len() % 2, 0, "filter must have an even length");
-    assert!(
-        input.len() >= filter.len(),
-        "input must be at least as long as filter"
-    );
+/// One-pole highpass filter which removes DC offsets from an interleaved signal.
+pub struct DcBlocker {
+    r: f32,
+    previous_input: Vec<f32>,
+    previous_output: Vec<f32>,
+    channel: usize,
+}
 
-    let h_len = filter.len() / 2;
-    for i in h_len..input.len() - h_len {
-        let mut v = 0.0;
-        for j in 0..filter.len() {
-            v += input[i + j - h_len] * filter[j];
+impl DcBlocker {
+    const CUTOFF_HZ: f32 = 20.0;
+
+    pub fn new(sample_rate: u32, channels: u16) -> Self {
+        let channels = channels.max(1) as usize;
+        DcBlocker {
+            r: 1.0 - 2.0 * std::f32::consts::PI * Self::CUTOFF_HZ / sample_rate as f32,
+            previous_input: vec![0.0; channels],
+            previous_output: vec![0.0; channels],
+            channel: 0,
         }
-        output[i - h_len] = v;
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        let c = self.channel;
+        let output =
+            flush_denormal(sample - self.previous_input[c] + self.r * self.previous_output[c]);
+        self.previous_input[c] = sample;
+        self.previous_output[c] = output;
+        self.channel = (c + 1) % self.previous_input.len();
+        output
     }
 }
 
-#[allow(unused)]
-pub fn convolve_f64(filter: &[f64], input: &[f64], output: &mut [f64]) {
-    assert_eq!(output.len(), input.len() - filter.len(), "output size are only the inner valid samples. filter.len()/2 samples on each side are skipped.");
-    assert_eq!(filter.len() % 2, 0, "filter must have an even length");
-    assert!(
-        input.len() >= filter.len(),
-        "input must be at least as long as filter"
-    );
+/// Gain of the left and right channel for a given balance between -1 and 1.
+fn balance_gains(balance: f32) -> [f32; 2] {
+    [(1.0 - balance).min(1.0), (1.0 + balance).min(1.0)]
+}
 
-    let h_len = filter.len() / 2;
-    for i in h_len..input.len() - h_len {
-        let mut v = 0.0;
-        for j in 0..filter.len() {
-            v += input[i + j - h_len] * filter[j];
+/// Lookahead peak limiter.
+///
+/// Delays the signal by a few milliseconds, so that the gain can be lowered before a peak arrives
+/// instead of clipping it. The gain then slowly recovers once the peak has passed.
+pub struct Limiter {
+    delay: VecDeque<f32>,
+    /// Required gains of the samples in `delay` that may still be the smallest one, as `(index, gain)`.
+    window_min: VecDeque<(usize, f32)>,
+    sample_index: usize,
+    lookahead: usize,
+    gain: f32,
+    release: f32,
+}
+
+impl Limiter {
+    const LOOKAHEAD: Duration = Duration::from_millis(5);
+    const RELEASE: Duration = Duration::from_millis(200);
+
+    pub fn new(sample_rate: u32, channels: u16) -> Self {
+        let samples_per_second = sample_rate as f32 * channels as f32;
+        let lookahead = (Self::LOOKAHEAD.as_secs_f32() * samples_per_second) as usize;
+        Limiter {
+            delay: VecDeque::with_capacity(lookahead + 1),
+            window_min: VecDeque::new(),
+            sample_index: 0,
+            lookahead,
+            gain: 1.0,
+            release: 1.0 - (-1.0 / (Self::RELEASE.as_secs_f32() * samples_per_second)).exp(),
         }
-        output[i - h_len] = v;
+    }
+
+    /// Takes the next input sample, and returns the output sample from [`Limiter::LOOKAHEAD`] ago.
+    pub fn process(&mut self, sample: f32, threshold: f32) -> f32 {
+        let required_gain = if sample.abs() > threshold {
+            threshold / sample.abs()
+        } else {
+            1.0
+        };
+
+        while self
+            .window_min
+            .back()
+            .is_some_and(|&(_, gain)| gain >= required_gain)
+        {
+            self.window_min.pop_back();
+        }
+        self.window_min
+            .push_back((self.sample_index, required_gain));
+        self.delay.push_back(sample);
+        self.sample_index += 1;
+
+        let output = if self.delay.len() > self.lookahead {
+            self.delay.pop_front().unwrap()
+        } else {
+            0.0
+        };
+
+        // The window covers both the output sample and everything up to `lookahead` samples after it
+        while self.window_min.front().unwrap().0 + self.lookahead + 1 < self.sample_index {
+            self.window_min.pop_front();
+        }
+        let target = self.window_min.front().unwrap().1;
+        self.gain = target.min(self.gain + (1.0 - self.gain) * self.release);
+
+        (output * self.gain).clamp(-1.0, 1.0)
     }
 }
 
 impl<I> Iterator for FilteredSource<I>
 where
-    I: Source<Item = f32>,
+    I: Source,
     I::Item: Sample,
 {
-    type Item = f32;
+    type Item = I::Item;
 
     #[inline]
     fn next(&mut self) -> Option<Self::Item> {
         if self.current_buffer_index < self.current_buffer.len() {
             self.current_buffer_index += 1;
             self.sample_count += 1;
-            return Some(self.current_buffer[self.current_buffer_index - 1]);
+            // A no-op for f32
+            return Some(CpalSample::from(
+                &self.current_buffer[self.current_buffer_index - 1],
+            ));
         }
 
         let t = self.sample_count as f64 / (self.channels() as f64 * self.sample_rate() as f64);
 
         {
             let mut settings = self.settings.lock().unwrap();
+            let cutoff_override = settings.cutoff_override;
+            let max_cutoff_slew = settings.max_cutoff_slew;
+            let dc_blocker = settings.dc_blocker;
+            let target_frame_size = settings.frame_size;
             let lowpass = &mut settings.lowpass;
 
-            if lowpass.is_empty() || self.sample_count > self.last_lowpass_recalculation + 8192 {
-                self.last_lowpass_recalculation = self.sample_count;
-                let freq = (self.lowpass_freq)(t);
+            let target = safe_cutoff(
+                cutoff_override.unwrap_or_else(|| (self.lowpass_freq)(t)),
+                self.sample_rate(),
+            );
+            // Move towards the target, but not faster than the slew limit allows
+            let freq = match (self.cutoff, max_cutoff_slew) {
+                (Some(current), Some(max_slew)) => {
+                    let elapsed = (self.sample_count - self.last_cutoff_update) as f64
+                        / (self.channels() as f64 * self.sample_rate() as f64);
+                    let max_step = max_slew * elapsed;
+                    current * (target / current).log2().clamp(-max_step, max_step).exp2()
+                }
+                _ => target,
+            };
+
+            self.cutoff = Some(freq);
+            self.last_cutoff_update = self.sample_count;
+
+            let changed = self
+                .kernel_cutoff
+                .is_none_or(|current| (freq / current).log2().abs() >= MIN_CUTOFF_CHANGE_OCTAVES);
+            if lowpass.is_empty() || changed {
+                self.kernel_cutoff = Some(freq);
                 let lowpass64 = lowpass_filter(
-                    cutoff_from_frequency(
-                        freq.min((self.sample_rate() / 2) as f64),
-                        self.sample_rate() as usize,
-                    ),
-                    0.01,
+                    cutoff_from_frequency(freq, self.sample_rate() as usize),
+                    self.transition_band,
                 );
                 *lowpass = lowpass64.iter().map(|&x| x as f32).collect();
             }
 
             // Must be at least the same size as the filter
-            let frame_size = 1024 - lowpass.len();
+            let frame_size = target_frame_size.max(lowpass.len());
+            if frame_size != self.effective_frame_size {
+                self.effective_frame_size = frame_size;
+                debug!(
+                    "Filter frame size is {} samples ({:.1} ms), with a kernel of {} taps",
+                    frame_size,
+                    1000.0 * frame_size as f64
+                        / (self.input.channels() as f64 * self.input.sample_rate() as f64),
+                    lowpass.len()
+                );
+            }
+            let frame_start = Instant::now();
 
             let input_samples = &mut self.input_buffer;
             input_samples.clear();
             input_samples.append(&mut self.trailing_samples);
+            let blocker = &mut self.dc_blocker;
             input_samples.extend(
                 self.input
                     .by_ref()
+                    .map(|x| x.to_f32())
                     .chain(std::iter::repeat(0.0))
-                    .take(frame_size),
+                    .take(frame_size)
+                    .map(sanitize)
+                    .map(|x| if dc_blocker { blocker.process(x) } else { x }),
             );
 
             assert!(
@@ -167,9 +437,53 @@ where
             buffer.resize(input_samples.len() - lowpass.len(), 0.0);
             convolve(lowpass, input_samples, buffer);
 
-            for s in buffer {
-                *s *= settings.volume;
-                *s = s.clamp(-1.0, 1.0);
+            if self.equalizer.preset() != settings.eq {
+                self.equalizer =
+                    Equalizer::new(settings.eq, self.input.sample_rate(), self.input.channels());
+            }
+
+            let channels = self.input.channels() as usize;
+            let balance = balance_gains(settings.balance);
+            for (i, s) in buffer.iter_mut().enumerate() {
+                *s = self.equalizer.process(*s);
+                // Only the first two channels are left and right. Other channels are not affected by the balance.
+                if channels >= 2 {
+                    *s *= balance
+                        .get((self.sample_count + i) % channels)
+                        .unwrap_or(&1.0);
+                }
+                *s = match settings.limiter_threshold {
+                    Some(threshold) => self.limiter.process(*s, threshold),
+                    None => s.clamp(-1.0, 1.0),
+                };
+                if let Some((remaining, total)) = &mut settings.fade_out {
+                    *s *= *remaining as f32 / *total as f32;
+                    *remaining = remaining.saturating_sub(1);
+                    if *remaining == 0 {
+                        settings.fade_out = None;
+                        settings.silent = true;
+                    }
+                } else if settings.silent {
+                    *s = 0.0;
+                }
+            }
+
+            if settings.first_sound.is_none() && buffer.iter().any(|&s| s != 0.0) {
+                settings.first_sound = Some(Instant::now());
+            }
+
+            self.frame_stats.0 += 1;
+            self.frame_stats.1 += frame_start.elapsed();
+            if self.frame_stats.0 >= STATS_INTERVAL_FRAMES {
+                let (frames, time) = std::mem::take(&mut self.frame_stats);
+                let frame_duration = frame_size as f64
+                    / (self.input.channels() as f64 * self.input.sample_rate() as f64);
+                let average = time.as_secs_f64() / frames as f64;
+                debug!(
+                    "Filter frames take {:.2} ms on average to process ({:.1}% of real time)",
+                    1000.0 * average,
+                    100.0 * average / frame_duration
+                );
             }
 
             self.current_buffer_index = 0;
@@ -190,14 +504,14 @@ where
 
 impl<I> ExactSizeIterator for FilteredSource<I>
 where
-    I: Source<Item = f32> + ExactSizeIterator,
+    I: Source + ExactSizeIterator,
     I::Item: Sample,
 {
 }
 
 impl<I> Source for FilteredSource<I>
 where
-    I: Source<Item = f32>,
+    I: Source,
     I::Item: Sample,
 {
     #[inline]
@@ -222,3 +536,266 @@ where
         self.input.total_duration()
     }
 }
+
+#[test]
+fn test_limiter() {
+    // Amplitude of each harmonic of a signal with exactly `cycles` periods of the fundamental
+    let harmonic = |signal: &[f32], cycles: usize, h: usize| {
+        let (mut re, mut im) = (0.0f64, 0.0f64);
+        for (i, &x) in signal.iter().enumerate() {
+            let phase =
+                2.0 * std::f64::consts::PI * (h * cycles) as f64 * i as f64 / signal.len() as f64;
+            re += x as f64 * phase.cos();
+            im += x as f64 * phase.sin();
+        }
+        re.hypot(im)
+    };
+    let thd = |signal: &[f32], cycles: usize| {
+        let distortion = (2..20)
+            .map(|h| harmonic(signal, cycles, h).powi(2))
+            .sum::<f64>()
+            .sqrt();
+        distortion / harmonic(signal, cycles, 1)
+    };
+
+    let sample_rate = 44100;
+    let input = (0..sample_rate * 2)
+        .map(|i| 2.0 * (2.0 * std::f32::consts::PI * 441.0 * i as f32 / sample_rate as f32).sin())
+        .collect::<Vec<_>>();
+
+    let mut limiter = Limiter::new(sample_rate as u32, 1);
+    let limited = input
+        .iter()
+        .map(|&x| limiter.process(x, 0.9))
+        .collect::<Vec<_>>();
+    let clipped = input.iter().map(|x| x.clamp(-1.0, 1.0)).collect::<Vec<_>>();
+
+    assert!(limited.iter().all(|x| x.abs() <= 1.0));
+
+    // Skip the first second to let the limiter settle
+    let cycles = 441;
+    let limited_thd = thd(&limited[sample_rate..], cycles);
+    let clipped_thd = thd(&clipped[sample_rate..], cycles);
+    assert!(clipped_thd > 0.1, "{clipped_thd}");
+    assert!(
+        limited_thd < clipped_thd / 10.0,
+        "{limited_thd} vs {clipped_thd}"
+    );
+}
+
+#[test]
+fn test_balance() {
+    let stereo = rodio::buffer::SamplesBuffer::new(2, 44100, vec![0.5f32; 8000]);
+    let (source, controller) = dynamic_filter(stereo, Box::new(|_| 100_000.0));
+    controller.set_balance(0.5);
+
+    let output = source.take(4000).collect::<Vec<_>>();
+    for frame in output.chunks(2) {
+        // The left channel is attenuated, the right one is left alone
+        assert!((frame[0] - 0.25).abs() < 1e-3, "{:?}", frame);
+        assert!((frame[1] - 0.5).abs() < 1e-3, "{:?}", frame);
+    }
+}
+
+#[test]
+fn test_dc_blocker() {
+    let sample_rate = 44100;
+    let mut blocker = DcBlocker::new(sample_rate, 1);
+    let offset = (0..sample_rate)
+        .map(|_| blocker.process(0.3))
+        .collect::<Vec<_>>();
+    let tail = &offset[sample_rate as usize / 2..];
+    assert!((tail.iter().sum::<f32>() / tail.len() as f32).abs() < 1e-3);
+
+    let mut blocker = DcBlocker::new(sample_rate, 1);
+    let tone = (0..sample_rate)
+        .map(|i| (2.0 * std::f32::consts::PI * 440.0 * i as f32 / sample_rate as f32).sin())
+        .collect::<Vec<_>>();
+    let filtered = tone.iter().map(|&x| blocker.process(x)).collect::<Vec<_>>();
+    let rms = |x: &[f32]| (x.iter().map(|x| x * x).sum::<f32>() / x.len() as f32).sqrt();
+    let half = sample_rate as usize / 2;
+    assert!(rms(&filtered[half..]) / rms(&tone[half..]) > 0.99);
+}
+
+#[test]
+fn test_fade_to_silence() {
+    let mono = rodio::buffer::SamplesBuffer::new(1, 1000, vec![0.5f32; 4000]);
+    let (mut source, controller) = dynamic_filter(mono, Box::new(|_| 100_000.0));
+    assert!(source.by_ref().take(100).all(|x| (x - 0.5).abs() < 1e-3));
+
+    controller.fade_to_silence(Duration::from_millis(500));
+    assert!(!controller.is_silent());
+    let output = source.take(2000).collect::<Vec<_>>();
+    assert!(controller.is_silent());
+
+    // The ramp starts at the next buffer, and then decreases smoothly without any jumps
+    assert!(output.windows(2).all(|w| w[1] <= w[0] + 1e-6));
+    assert!(output.windows(2).all(|w| (w[1] - w[0]).abs() < 0.01));
+    assert!(output[1500..].iter().all(|&x| x == 0.0));
+}
+
+#[test]
+fn test_volume_envelope() {
+    // The volume is applied the same way as by `rodio::Sink`, which updates it every 5 ms
+    let mono = rodio::buffer::SamplesBuffer::new(1, 1000, vec![0.5f32; 3000]);
+    let (source, _controller) = dynamic_filter(mono, Box::new(|_| 100_000.0));
+    let mut t = 0.0;
+    let output = source
+        .amplify(1.0)
+        .periodic_access(Duration::from_millis(5), move |s| {
+            s.set_factor(crate::fade::fadein(t, 1.0));
+            t += 0.005;
+        })
+        .take(2000)
+        .collect::<Vec<_>>();
+
+    for (i, x) in output.iter().enumerate() {
+        let expected = 0.5 * crate::fade::fadein(i as f32 / 1000.0, 1.0);
+        // Only off by at most one 5 ms step of the fade
+        assert!((x - expected).abs() < 0.5 * 0.02, "{i}: {x} vs {expected}");
+    }
+
+    // The envelope starts silent, is halfway up in the middle of the ramp, and then stays at full volume
+    use crate::fade::{fadein, fadeout};
+    assert_eq!(fadein(-1.0, 1.0), 0.0);
+    assert_eq!(fadein(0.0, 1.0), 0.0);
+    assert_eq!(fadein(0.5, 1.0), 0.5);
+    assert!(fadein(0.25, 1.0) < 0.25 && fadein(0.75, 1.0) > 0.75);
+    assert_eq!(fadein(1.0, 1.0), 1.0);
+    assert_eq!(fadein(2.0, 1.0), 1.0);
+    assert_eq!(fadeout(0.0, 1.0), 1.0);
+    assert_eq!(fadeout(0.5, 1.0), 0.5);
+    assert_eq!(fadeout(1.0, 1.0), 0.0);
+    assert_eq!(fadeout(2.0, 1.0), 0.0);
+    assert_eq!(output[0], 0.0);
+    assert!((output[500] - 0.25).abs() < 0.5 * 0.02, "{}", output[500]);
+    // One 5 ms step after the end of the ramp
+    assert!(output[1005..].iter().all(|&x| x == 0.5));
+}
+
+#[test]
+fn test_non_finite_input() {
+    let sample_rate = 44100;
+    let mut input = (0..sample_rate)
+        .map(|i| 0.5 * (2.0 * std::f32::consts::PI * 440.0 * i as f32 / sample_rate as f32).sin())
+        .collect::<Vec<_>>();
+    let nan_index = 10_000;
+    input[nan_index] = f32::NAN;
+    input[nan_index + 1] = f32::INFINITY;
+
+    let source = rodio::buffer::SamplesBuffer::new(1, sample_rate, input);
+    let (source, controller) = dynamic_filter(source, Box::new(|_| 5000.0));
+    controller.set_dc_blocker(true);
+    controller.set_eq(EqPreset::SmallSpeaker);
+    controller.set_limiter(Some(0.9));
+    let output = source.take(sample_rate as usize).collect::<Vec<_>>();
+    assert!(output.iter().all(|x| x.is_finite()));
+
+    // The signal is back to normal one frame after the broken samples
+    let after = &output[nan_index + 2048..nan_index + 4096];
+    let rms = (after.iter().map(|x| x * x).sum::<f32>() / after.len() as f32).sqrt();
+    assert!(rms > 0.3, "{rms}");
+}
+
+#[test]
+fn test_degenerate_cutoff() {
+    for cutoff in [0.0, -100.0, f64::NAN, f64::INFINITY] {
+        let source = rodio::buffer::SamplesBuffer::new(1, 44100, vec![0.5f32; 4096]);
+        let (source, _) = dynamic_filter(source, Box::new(move |_| cutoff));
+        assert!(source.take(2048).all(|x| x.is_finite()), "{cutoff}");
+    }
+}
+
+#[test]
+fn test_cutoff_slew() {
+    // Lowpass filtered white noise has a power proportional to the cutoff frequency,
+    // so the effective -3 dB point of each frame can be estimated from the output power.
+    let sample_rate = 44100;
+    let mut seed = 1u32;
+    let noise = (0..sample_rate * 3)
+        .map(|_| {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            (seed >> 8) as f32 / (1 << 24) as f32 - 0.5
+        })
+        .collect::<Vec<_>>();
+    let frame = sample_rate as usize / 10;
+
+    let cutoffs = |slew: Option<f64>| {
+        let source = rodio::buffer::SamplesBuffer::new(1, sample_rate, noise.clone());
+        // The cutoff jumps by four octaves after one second
+        let (source, controller) =
+            dynamic_filter(source, Box::new(|t| if t < 1.0 { 1000.0 } else { 16000.0 }));
+        controller.set_cutoff_slew(slew);
+        let output = source.take(noise.len()).collect::<Vec<_>>();
+        let power = |x: &[f32]| x.iter().map(|x| x * x).sum::<f32>();
+        output
+            .chunks_exact(frame)
+            .zip(noise.chunks_exact(frame))
+            .map(|(output, input)| power(output) / power(input) * (sample_rate / 2) as f32)
+            .collect::<Vec<_>>()
+    };
+    let max_jump = |cutoffs: &[f32]| {
+        cutoffs
+            .windows(2)
+            .map(|w| (w[1] / w[0]).log2().abs())
+            .fold(0.0, f32::max)
+    };
+
+    let unlimited = cutoffs(None);
+    assert!(max_jump(&unlimited) > 3.0, "{:?}", unlimited);
+
+    // At 4 octaves per second, each 100 ms frame may only change by 0.4 octaves
+    let limited = cutoffs(Some(4.0));
+    assert!(max_jump(&limited) < 0.8, "{:?}", limited);
+    assert!(*limited.last().unwrap() > 12000.0, "{:?}", limited);
+}
+
+#[test]
+fn test_long_kernel() {
+    let source = rodio::buffer::SamplesBuffer::new(1, 44100, vec![0.5f32; 44100]);
+    let (mut source, controller) = dynamic_filter(source, Box::new(|_| 1000.0));
+    // A narrow transition band needs a kernel with more than 1024 taps
+    source.transition_band = 0.003;
+    controller.set_frame_duration(Duration::from_millis(5));
+
+    let output = source.by_ref().take(20_000).collect::<Vec<_>>();
+    assert_eq!(output.len(), 20_000);
+    assert!(source.settings.lock().unwrap().lowpass.len() > 1024);
+    assert!(source.effective_frame_size >= source.settings.lock().unwrap().lowpass.len());
+    // The kernel is normalized, so a constant signal passes through unchanged once the filter has filled up
+    assert!((output[10_000] - 0.5).abs() < 1e-3, "{}", output[10_000]);
+}
+
+#[test]
+fn test_i16_matches_f32() {
+    let samples = (0..8820)
+        .map(|i| 0.8 * (i as f32 * 440.0 / 44100.0 * std::f32::consts::TAU).sin())
+        .collect::<Vec<_>>();
+    let render =
+        |source: Box<dyn Source<Item = f32> + Send>| source.take(samples.len()).collect::<Vec<_>>();
+    let filter = |controller: &Controller| {
+        controller.set_limiter(Some(0.5));
+        controller.set_balance(0.25);
+    };
+
+    let (f32_source, controller) = dynamic_filter(
+        rodio::buffer::SamplesBuffer::new(2, 44100, samples.clone()),
+        Box::new(|_| 2000.0),
+    );
+    filter(&controller);
+    let expected = render(Box::new(f32_source));
+
+    let quantized = samples.iter().map(|x| x.to_i16()).collect::<Vec<_>>();
+    let (i16_source, controller) = dynamic_filter(
+        rodio::buffer::SamplesBuffer::new(2, 44100, quantized),
+        Box::new(|_| 2000.0),
+    );
+    filter(&controller);
+    let output = render(Box::new(i16_source.convert_samples()));
+
+    assert_eq!(output.len(), expected.len());
+    for (a, b) in output.iter().zip(&expected) {
+        // Two roundings to 16 bits, plus the filter gain
+        assert!((a - b).abs() < 1e-3, "{} != {}", a, b);
+    }
+}
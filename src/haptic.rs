@@ -0,0 +1,226 @@
+//! Haptic lucid cues: pulses of a vibration motor under the pillow, driven by a GPIO pin.
+//!
+//! Unlike the sound cues, they are only noticed by the one lying on the motor.
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::shutdown;
+
+/// Pulses of the motor, like three pulses of 500 ms.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(default)]
+pub struct PulsePattern {
+    pub pulses: u32,
+    pub pulse_ms: u32,
+    /// Time between the pulses.
+    pub gap_ms: u32,
+}
+
+impl Default for PulsePattern {
+    fn default() -> Self {
+        PulsePattern {
+            pulses: 3,
+            pulse_ms: 500,
+            gap_ms: 500,
+        }
+    }
+}
+
+impl PulsePattern {
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(
+            self.pulses as u64 * self.pulse_ms as u64
+                + self.pulses.saturating_sub(1) as u64 * self.gap_ms as u64,
+        )
+    }
+}
+
+/// Turns a vibration motor on and off.
+pub trait Motor: Send {
+    fn set(&mut self, on: bool);
+}
+
+#[derive(Error, Debug)]
+pub enum HapticError {
+    #[cfg(feature = "haptic")]
+    #[error("Could not open GPIO pin {0}: {1}")]
+    Gpio(u8, rppal::gpio::Error),
+    #[cfg(not(feature = "haptic"))]
+    #[error("Haptic cues require the haptic feature")]
+    Unavailable,
+}
+
+/// A motor which is switched by a transistor on a GPIO pin of the Raspberry Pi.
+#[cfg(feature = "haptic")]
+struct GpioMotor(rppal::gpio::OutputPin);
+
+#[cfg(feature = "haptic")]
+impl Motor for GpioMotor {
+    fn set(&mut self, on: bool) {
+        if on {
+            self.0.set_high();
+        } else {
+            self.0.set_low();
+        }
+    }
+}
+
+/// Turns the motor off when dropped, also when unwinding from a panic.
+struct MotorOff<'a>(&'a mut dyn Motor);
+
+impl Drop for MotorOff<'_> {
+    fn drop(&mut self) {
+        self.0.set(false);
+    }
+}
+
+/// Plays the pattern, using `sleep` to wait. Returns false if it was stopped early, since `cancelled` returned true.
+///
+/// The motor is off afterwards, however this returns.
+fn play_pattern(
+    motor: &mut dyn Motor,
+    pattern: &PulsePattern,
+    cancelled: &dyn Fn() -> bool,
+    sleep: &mut dyn FnMut(Duration),
+) -> bool {
+    let motor = MotorOff(motor);
+    for i in 0..pattern.pulses {
+        if i > 0 {
+            sleep(Duration::from_millis(pattern.gap_ms as u64));
+        }
+        if cancelled() {
+            return false;
+        }
+        motor.0.set(true);
+        sleep(Duration::from_millis(pattern.pulse_ms as u64));
+        motor.0.set(false);
+    }
+    true
+}
+
+/// The vibration motor, shared by the cues.
+pub struct Haptics {
+    motor: Mutex<Box<dyn Motor>>,
+}
+
+impl Haptics {
+    #[cfg_attr(not(feature = "haptic"), allow(dead_code))]
+    pub fn new(mut motor: Box<dyn Motor>) -> Haptics {
+        motor.set(false);
+        Haptics {
+            motor: Mutex::new(motor),
+        }
+    }
+
+    /// Opens the motor on the GPIO pin with the BCM number `pin`.
+    #[cfg(feature = "haptic")]
+    pub fn open(pin: u8) -> Result<Haptics, HapticError> {
+        let output = rppal::gpio::Gpio::new()
+            .and_then(|gpio| gpio.get(pin))
+            .map_err(|e| HapticError::Gpio(pin, e))?
+            .into_output_low();
+        Ok(Haptics::new(Box::new(GpioMotor(output))))
+    }
+
+    #[cfg(not(feature = "haptic"))]
+    pub fn open(_pin: u8) -> Result<Haptics, HapticError> {
+        Err(HapticError::Unavailable)
+    }
+
+    /// Plays the pattern, and blocks until it is done. Returns false if it was stopped early by `cancelled`, or by a
+    /// shutdown.
+    pub fn play(&self, pattern: &PulsePattern, cancelled: &dyn Fn() -> bool) -> bool {
+        // A panic during a pattern has already turned the motor off
+        let mut motor = self.motor.lock().unwrap_or_else(|e| e.into_inner());
+        play_pattern(
+            &mut **motor,
+            pattern,
+            &|| shutdown::requested() || cancelled(),
+            &mut std::thread::sleep,
+        )
+    }
+
+    /// Turns the motor off, like at shutdown.
+    pub fn off(&self) {
+        self.motor
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .set(false);
+    }
+}
+
+#[test]
+fn test_play_pattern() {
+    use std::sync::Arc;
+
+    #[derive(Default)]
+    struct MockState {
+        now: Duration,
+        switches: Vec<(Duration, bool)>,
+    }
+    /// Records when the motor was switched, by the mock clock.
+    #[derive(Clone, Default)]
+    struct MockMotor(Arc<Mutex<MockState>>);
+    impl Motor for MockMotor {
+        fn set(&mut self, on: bool) {
+            let mut state = self.0.lock().unwrap();
+            let now = state.now;
+            state.switches.push((now, on));
+        }
+    }
+    let ms = Duration::from_millis;
+
+    let motor = MockMotor::default();
+    let pattern = PulsePattern::default();
+    assert_eq!(pattern.duration(), ms(2500));
+    let mut sleep = |d| motor.0.lock().unwrap().now += d;
+    assert!(play_pattern(
+        &mut motor.clone(),
+        &pattern,
+        &|| false,
+        &mut sleep
+    ));
+    assert_eq!(
+        motor.0.lock().unwrap().switches,
+        [
+            (ms(0), true),
+            (ms(500), false),
+            (ms(1000), true),
+            (ms(1500), false),
+            (ms(2000), true),
+            (ms(2500), false),
+            (ms(2500), false),
+        ]
+    );
+
+    // Cancelled after the first pulse
+    let motor = MockMotor::default();
+    let mut sleep = |d| motor.0.lock().unwrap().now += d;
+    let cancelled = || motor.0.lock().unwrap().now > ms(0);
+    assert!(!play_pattern(
+        &mut motor.clone(),
+        &pattern,
+        &cancelled,
+        &mut sleep
+    ));
+    assert_eq!(
+        motor.0.lock().unwrap().switches.last().copied(),
+        Some((ms(1000), false))
+    );
+
+    // The motor is turned off by a panic in the middle of a pulse
+    let motor = MockMotor::default();
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        play_pattern(&mut motor.clone(), &pattern, &|| false, &mut |_| {
+            panic!("interrupted")
+        })
+    }));
+    assert!(result.is_err());
+    assert_eq!(
+        motor.0.lock().unwrap().switches,
+        [(ms(0), true), (ms(0), false)]
+    );
+}
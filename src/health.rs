@@ -0,0 +1,67 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+
+use crate::sync_health::SyncHealth;
+use crate::AlarmState;
+
+/// State of the accelerometer used by the sleep monitor.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum SensorHealth {
+    Ok,
+    /// Reads are failing, but the presence data is still recent enough to use.
+    Failing,
+    /// The sensor has been unreadable for so long that the presence data is not used.
+    Stale,
+    /// The sensor returns exactly the same values all the time, so it is probably disconnected.
+    /// The presence data is not used until the values change again.
+    Frozen,
+}
+
+impl SensorHealth {
+    /// True if the presence data should not be used.
+    pub fn is_stale(self) -> bool {
+        matches!(self, SensorHealth::Stale | SensorHealth::Frozen)
+    }
+}
+
+/// `None` if there is no sensor, or it has not been read yet.
+static SENSOR_HEALTH: Mutex<Option<SensorHealth>> = Mutex::new(None);
+
+pub fn set_sensor_health(health: SensorHealth) {
+    *SENSOR_HEALTH.lock().unwrap() = Some(health);
+}
+
+/// Set when the accelerometer log could not be written, and the logging has been turned off.
+static MOTION_LOG_FAILED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_motion_log_failed() {
+    MOTION_LOG_FAILED.store(true, Ordering::Relaxed);
+}
+
+#[derive(Serialize, Debug)]
+pub struct Health {
+    /// False if anything is degraded.
+    ok: bool,
+    sensor: Option<SensorHealth>,
+    /// True if the accelerometer readings are no longer logged, for example because the disk is full.
+    motion_log_failed: bool,
+    sync: SyncHealth,
+}
+
+#[get("/healthz")]
+pub fn get_health(state: &State<AlarmState>) -> Json<Health> {
+    let sensor = *SENSOR_HEALTH.lock().unwrap();
+    let motion_log_failed = MOTION_LOG_FAILED.load(Ordering::Relaxed);
+    let sync = state.sync_health.lock().unwrap().clone();
+    Json(Health {
+        ok: sensor.is_none_or(|s| s == SensorHealth::Ok) && !motion_log_failed && sync.is_ok(),
+        sensor,
+        motion_log_failed,
+        sync,
+    })
+}
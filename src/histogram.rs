@@ -0,0 +1,180 @@
+//! Distribution of the movement over time, split into buckets of fixed length.
+use chrono::{DateTime, NaiveDate, TimeDelta, TimeZone, Utc};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::Serialize;
+
+use crate::motion_log;
+use crate::nights;
+use crate::AlarmState;
+
+/// Start of the bucket of length `width` which `time` is in. Buckets start at local midnight.
+pub fn bucket_start<Tz: TimeZone>(time: DateTime<Utc>, width: TimeDelta, tz: &Tz) -> DateTime<Utc> {
+    let local = time.with_timezone(tz).naive_local();
+    let midnight = local.date().and_hms_opt(0, 0, 0).unwrap();
+    let width_ms = width.num_milliseconds().max(1);
+    let offset = (local - midnight).num_milliseconds() / width_ms * width_ms;
+    let start = midnight + TimeDelta::milliseconds(offset);
+    tz.from_local_datetime(&start)
+        .earliest()
+        .map(|t| t.with_timezone(&Utc))
+        // The start is skipped by a daylight saving time change
+        .unwrap_or_else(|| time - (local - start))
+}
+
+/// The value at `p` (0 to 1) of `sorted`, using the nearest rank.
+pub fn percentile(sorted: &[f32], p: f32) -> Option<f32> {
+    let index = ((sorted.len() as f32 - 1.0) * p).round() as usize;
+    sorted.get(index).copied()
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Bucket {
+    pub start: DateTime<Utc>,
+    /// Number of readings with a change since the previous reading.
+    pub samples: usize,
+    /// Number of readings which changed more than the movement threshold.
+    pub above_threshold: usize,
+    /// Percentiles of the change in acceleration, in g.
+    pub p50: f32,
+    pub p90: f32,
+    pub p99: f32,
+}
+
+/// Splits the changes in acceleration into buckets, oldest first. `movements` must be in order. Empty buckets are left out.
+pub fn histogram<Tz: TimeZone>(
+    movements: &[(DateTime<Utc>, f32)],
+    width: TimeDelta,
+    threshold: f32,
+    tz: &Tz,
+) -> Vec<Bucket> {
+    let mut buckets: Vec<(DateTime<Utc>, Vec<f32>)> = vec![];
+    for &(time, movement) in movements {
+        let start = bucket_start(time, width, tz);
+        match buckets.last_mut() {
+            Some((last, values)) if *last == start => values.push(movement),
+            _ => buckets.push((start, vec![movement])),
+        }
+    }
+    buckets
+        .into_iter()
+        .map(|(start, mut values)| {
+            values.sort_by(f32::total_cmp);
+            let at = |p| percentile(&values, p).unwrap();
+            Bucket {
+                start,
+                samples: values.len(),
+                above_threshold: values.iter().filter(|&&v| v > threshold).count(),
+                p50: at(0.5),
+                p90: at(0.9),
+                p99: at(0.99),
+            }
+        })
+        .collect()
+}
+
+/// Distribution of the movement during the night of `date`, from noon to noon, in buckets of `bucket_minutes` (default 60).
+///
+/// Uses the stored readings of the user's sensor, so it is only available for the days within the retention period.
+#[get("/sleep/histogram?<date>&<bucket_minutes>")]
+pub async fn get_histogram(
+    state: &State<AlarmState>,
+    date: &str,
+    bucket_minutes: Option<u32>,
+) -> Result<Json<Vec<Bucket>>, (Status, String)> {
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|e| (Status::BadRequest, format!("Invalid date `{date}`: {e}")))?;
+    let bucket_minutes = bucket_minutes.unwrap_or(60);
+    if !(1..=24 * 60).contains(&bucket_minutes) {
+        return Err((
+            Status::BadRequest,
+            "bucket_minutes must be between 1 and 1440".to_owned(),
+        ));
+    }
+    let to = nights::end_of_night(date, &chrono::Local);
+    let from = to - TimeDelta::days(1);
+    let config = state.config.get();
+    let (motion, threshold) = (config.motion, config.sleep_monitor.movement_threshold);
+    let samples = tokio::task::spawn_blocking(move || {
+        #[cfg(feature = "sqlite")]
+        if let Some(database) = &motion.database {
+            return crate::sleep_db::read(database, from, to).map_err(|e| e.to_string());
+        }
+        let lines = motion_log::read(&motion.log_dir, from, to).map_err(|e| e.to_string())?;
+        Ok(motion_log::parse_my_side(lines))
+    })
+    .await
+    .unwrap()
+    .map_err(|e: String| (Status::InternalServerError, e))?;
+
+    let movements = samples
+        .iter()
+        .filter(|s| s.sensor == motion_log::MY_SENSOR)
+        .filter_map(|s| Some((s.time, s.movement?)))
+        .collect::<Vec<_>>();
+    Ok(Json(histogram(
+        &movements,
+        TimeDelta::minutes(bucket_minutes as i64),
+        threshold,
+        &chrono::Local,
+    )))
+}
+
+#[test]
+fn test_histogram() {
+    let tz = chrono::FixedOffset::east_opt(2 * 3600 + 1800).unwrap();
+    let local = |h, m, s| {
+        tz.with_ymd_and_hms(2024, 3, 1, h, m, s)
+            .unwrap()
+            .with_timezone(&Utc)
+    };
+    // Buckets follow the local time, also with an offset which is not whole hours
+    assert_eq!(
+        bucket_start(local(23, 59, 59), TimeDelta::hours(1), &tz),
+        local(23, 0, 0)
+    );
+    assert_eq!(
+        bucket_start(local(3, 17, 0), TimeDelta::minutes(15), &tz),
+        local(3, 15, 0)
+    );
+
+    // The first hour has the movements 0.01, 0.02 … 1.00, once per 36 seconds. The next hour is empty,
+    // and the one after it has a single movement.
+    let mut movements = (0..100)
+        .map(|i| {
+            (
+                local(1, 0, 0) + TimeDelta::seconds(36 * i),
+                (i + 1) as f32 / 100.0,
+            )
+        })
+        .collect::<Vec<_>>();
+    movements.push((local(3, 30, 0), 0.5));
+    let buckets = histogram(&movements, TimeDelta::hours(1), 0.25, &tz);
+    assert_eq!(
+        buckets,
+        [
+            Bucket {
+                start: local(1, 0, 0),
+                samples: 100,
+                above_threshold: 75,
+                p50: 0.51,
+                p90: 0.90,
+                p99: 0.99,
+            },
+            Bucket {
+                start: local(3, 0, 0),
+                samples: 1,
+                above_threshold: 1,
+                p50: 0.5,
+                p90: 0.5,
+                p99: 0.5,
+            },
+        ]
+    );
+    let halves = histogram(&movements, TimeDelta::minutes(30), 0.25, &tz);
+    assert_eq!(
+        halves.iter().map(|b| b.samples).collect::<Vec<_>>(),
+        [50, 50, 1]
+    );
+}
@@ -0,0 +1,199 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::latency::Latency;
+use crate::wake::WakeReason;
+
+pub const HISTORY_FILE: &str = "./alarm_history.jsonl";
+
+/// One alarm that was triggered.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AlarmHistoryEntry {
+    /// The time the alarm was scheduled for.
+    pub trigger_time: DateTime<Utc>,
+    /// The time the alarm actually started.
+    pub started_at: DateTime<Utc>,
+    /// The sound that was played, relative to the sound directory.
+    pub sound: Option<String>,
+    /// Set if the audio output failed while the alarm was playing.
+    #[serde(default)]
+    pub playback_error: Option<String>,
+    /// The escalation stage which was playing when the alarm stopped.
+    #[serde(default)]
+    pub escalation_stage: Option<usize>,
+    /// How long it took from the trigger until the sound could be heard.
+    #[serde(default)]
+    pub latency: Option<Latency>,
+    /// The alarm was interrupted by a restart, and continued where it left off.
+    #[serde(default)]
+    pub resumed: bool,
+    /// Sounds which were picked, but could not be used because they were empty, broken or too short.
+    #[serde(default)]
+    pub rejected_sounds: Vec<String>,
+    /// Why the alarm started when it did.
+    #[serde(default)]
+    pub wake_reason: Option<WakeReason>,
+    /// The alarm started before `trigger_time`, because the user seemed to be sleeping lightly.
+    #[serde(default)]
+    pub early: bool,
+    /// Ids of the movement events which the alarm acted on. See `GET /sleep/events`.
+    #[serde(default)]
+    pub movement_events: Vec<u64>,
+}
+
+/// Append-only log of alarms, stored as one json object per line.
+pub struct AlarmHistory {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl AlarmHistory {
+    pub fn new(path: &Path) -> AlarmHistory {
+        AlarmHistory {
+            path: path.to_path_buf(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn record(&self, entry: &AlarmHistoryEntry) {
+        let _guard = self.lock.lock().unwrap();
+        let result = std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)
+            .and_then(|mut file| {
+                let line = serde_json::to_string(entry).unwrap();
+                writeln!(file, "{line}")
+            });
+
+        if let Err(e) = result {
+            warn!(
+                "Could not write to alarm history `{}`: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+
+    /// The last `count` entries, oldest first. Only the end of the file is read, so that this stays fast as the history grows.
+    pub fn recent(&self, count: usize) -> Vec<AlarmHistoryEntry> {
+        if count == 0 {
+            return vec![];
+        }
+        let _guard = self.lock.lock().unwrap();
+        let lines = match read_last_lines(&self.path, count) {
+            Ok(lines) => lines,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return vec![],
+            Err(e) => {
+                warn!(
+                    "Could not read alarm history `{}`: {}",
+                    self.path.display(),
+                    e
+                );
+                return vec![];
+            }
+        };
+
+        lines
+            .iter()
+            .filter_map(|line| match serde_json::from_str(line) {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    warn!("Skipping invalid alarm history entry: {}", e);
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// The history is read backwards from the end in blocks of this many bytes.
+const READ_BLOCK_SIZE: u64 = 16 * 1024;
+
+/// The last `count` non-empty lines of the file at `path`, oldest first.
+fn read_last_lines(path: &Path, count: usize) -> std::io::Result<Vec<String>> {
+    let mut file = File::open(path)?;
+    let mut start = file.metadata()?.len();
+    let mut tail = vec![];
+    loop {
+        let size = start.min(READ_BLOCK_SIZE);
+        start -= size;
+        let mut block = vec![0; size as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut block)?;
+        block.extend_from_slice(&tail);
+        tail = block;
+
+        // The first line may be cut off, unless the whole file has been read
+        let complete = match tail.iter().position(|&b| b == b'\n') {
+            Some(newline) if start > 0 => &tail[newline + 1..],
+            None if start > 0 => &[],
+            _ => &tail[..],
+        };
+        let text = String::from_utf8_lossy(complete);
+        let mut lines = text
+            .lines()
+            .rev()
+            .filter(|line| !line.trim().is_empty())
+            .take(count)
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        if lines.len() == count || start == 0 {
+            lines.reverse();
+            return Ok(lines);
+        }
+    }
+}
+
+#[test]
+fn test_recent() {
+    let path = std::env::temp_dir().join(format!("history-test-{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let history = AlarmHistory::new(&path);
+    assert!(history.recent(3).is_empty());
+
+    let entry = |i: usize| AlarmHistoryEntry {
+        trigger_time: Utc::now(),
+        started_at: Utc::now(),
+        sound: Some(format!("{i}.mp3")),
+        playback_error: None,
+        escalation_stage: None,
+        latency: None,
+        resumed: false,
+        rejected_sounds: vec![],
+        wake_reason: None,
+        early: false,
+        movement_events: vec![],
+    };
+    // Several blocks, so that lines are cut off at the block boundaries
+    for i in 0..200 {
+        history.record(&entry(i));
+    }
+    let sounds = |entries: Vec<AlarmHistoryEntry>| {
+        entries
+            .into_iter()
+            .map(|e| e.sound.unwrap())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(sounds(history.recent(3)), ["197.mp3", "198.mp3", "199.mp3"]);
+    assert!(history.recent(0).is_empty());
+    let all = sounds(history.recent(1000));
+    assert_eq!(all.len(), 200);
+    assert_eq!(all[0], "0.mp3");
+
+    // Empty lines are skipped
+    std::fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .unwrap()
+        .write_all(b"\n\n")
+        .unwrap();
+    assert_eq!(sounds(history.recent(1)), ["199.mp3"]);
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,282 @@
+//! Picks the accelerometer from the config, and drivers for the sensors which have no driver crate.
+use embedded_hal::i2c::I2c;
+use linux_embedded_hal::{I2CError, I2cdev};
+use std::{fmt, str::FromStr, time::Duration};
+
+use crate::config::MotionConfig;
+use crate::sleep_monitor::{
+    Accelerometer, AccelerometerData, AccelerometerInitError, AccelerometerSource, Calibration,
+    CalibrationError, GyroBiasLearner, SensorError,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SensorType {
+    /// Probes the known addresses for any supported sensor.
+    Auto,
+    Mpu6050,
+    Bmi160,
+}
+
+impl SensorType {
+    const ALL: [SensorType; 3] = [SensorType::Auto, SensorType::Mpu6050, SensorType::Bmi160];
+
+    fn name(self) -> &'static str {
+        match self {
+            SensorType::Auto => "auto",
+            SensorType::Mpu6050 => "mpu6050",
+            SensorType::Bmi160 => "bmi160",
+        }
+    }
+}
+
+impl fmt::Display for SensorType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+fn supported_sensors() -> String {
+    SensorType::ALL.map(SensorType::name).join(", ")
+}
+
+impl FromStr for SensorType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        SensorType::ALL
+            .into_iter()
+            .find(|t| t.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                format!(
+                    "Unknown sensor `{s}`. Supported sensors are: {}",
+                    supported_sensors()
+                )
+            })
+    }
+}
+
+/// Addresses at which the supported sensors can be found.
+const KNOWN_ADDRESSES: [u8; 2] = [0x68, 0x69];
+
+const MPU6050_WHO_AM_I: u8 = 0x75;
+const MPU6050_WHO_AM_I_VALUE: u8 = 0x68;
+
+const BMI160_CHIP_ID: u8 = 0x00;
+const BMI160_CHIP_ID_VALUE: u8 = 0xD1;
+/// Rotation x, y, z followed by acceleration x, y, z. Little endian.
+const BMI160_DATA: u8 = 0x0C;
+const BMI160_TEMPERATURE: u8 = 0x20;
+const BMI160_ACC_CONF: u8 = 0x40;
+const BMI160_ACC_RANGE: u8 = 0x41;
+const BMI160_GYR_CONF: u8 = 0x42;
+const BMI160_GYR_RANGE: u8 = 0x43;
+const BMI160_CMD: u8 = 0x7E;
+const BMI160_CMD_SOFT_RESET: u8 = 0xB6;
+const BMI160_CMD_ACC_NORMAL: u8 = 0x11;
+const BMI160_CMD_GYR_NORMAL: u8 = 0x15;
+/// 100 Hz output data rate, with normal filtering.
+const BMI160_CONF_100HZ: u8 = 0x28;
+/// ±2 g, the same range as the MPU6050 uses.
+const BMI160_ACC_RANGE_2G: u8 = 0x03;
+const BMI160_ACC_LSB_PER_G: f32 = 16384.0;
+/// ±250 °/s, the same range as the MPU6050 uses.
+const BMI160_GYR_RANGE_250: u8 = 0x03;
+const BMI160_GYR_LSB_PER_DEG: f32 = 131.2;
+
+fn read_register(i2c: &mut I2cdev, address: u8, register: u8) -> Result<u8, I2CError> {
+    let mut buf = [0];
+    i2c.write_read(address, &[register], &mut buf)?;
+    Ok(buf[0])
+}
+
+/// Identifies the sensor at `address` from its chip id.
+fn identify(i2c: &mut I2cdev, address: u8) -> Option<SensorType> {
+    if read_register(i2c, address, BMI160_CHIP_ID).ok()? == BMI160_CHIP_ID_VALUE {
+        Some(SensorType::Bmi160)
+    } else if read_register(i2c, address, MPU6050_WHO_AM_I).ok()? == MPU6050_WHO_AM_I_VALUE {
+        Some(SensorType::Mpu6050)
+    } else {
+        None
+    }
+}
+
+fn detect(config: &MotionConfig) -> Result<(SensorType, u8), String> {
+    let mut i2c = I2cdev::new(&config.i2c_bus).map_err(|e| format!("{:?}", e))?;
+    let addresses = match config.i2c_address {
+        Some(address) => vec![address],
+        None => KNOWN_ADDRESSES.to_vec(),
+    };
+    for address in addresses {
+        if let Some(sensor) = identify(&mut i2c, address) {
+            info!("Found a {} at address {:#04x}", sensor, address);
+            return Ok((sensor, address));
+        }
+    }
+    Err(format!(
+        "No supported sensor found. Supported sensors are: {}",
+        supported_sensors()
+    ))
+}
+
+/// Opens the sensor selected in the config.
+pub fn open(config: &MotionConfig) -> Result<Box<dyn AccelerometerSource>, AccelerometerInitError> {
+    let error = |cause| AccelerometerInitError {
+        sensor: "accelerometer".to_owned(),
+        bus: config.i2c_bus.clone(),
+        address: config.i2c_address,
+        cause,
+    };
+    let (sensor, address) = match config.sensor.parse().map_err(error)? {
+        SensorType::Auto => {
+            let (sensor, address) = detect(config).map_err(error)?;
+            (sensor, Some(address))
+        }
+        sensor => (sensor, config.i2c_address),
+    };
+    let config = MotionConfig {
+        i2c_address: address,
+        ..config.clone()
+    };
+    match sensor {
+        SensorType::Mpu6050 => Ok(Box::new(Accelerometer::new(&config)?)),
+        SensorType::Bmi160 => Ok(Box::new(Bmi160::new(&config)?)),
+        SensorType::Auto => unreachable!(),
+    }
+}
+
+/// A Bosch BMI160 connected over I2C.
+pub struct Bmi160 {
+    i2c: I2cdev,
+    address: u8,
+    calibration: Calibration,
+    gyro_bias: GyroBiasLearner,
+    config: MotionConfig,
+}
+
+fn i2c_error(e: I2CError) -> SensorError {
+    SensorError::Read(format!("{:?}", e))
+}
+
+/// Converts the data and temperature registers to the same units as the MPU6050 driver uses.
+fn bmi160_data(data: [u8; 12], temperature: [u8; 2]) -> AccelerometerData {
+    let value = |i: usize| i16::from_le_bytes([data[2 * i], data[2 * i + 1]]) as f32;
+    let gyro = |i| (value(i) / BMI160_GYR_LSB_PER_DEG).to_radians();
+    let acc = |i| value(i) / BMI160_ACC_LSB_PER_G;
+    AccelerometerData {
+        acc: (acc(3), acc(4), acc(5)),
+        gyro: (gyro(0), gyro(1), gyro(2)),
+        // 0 is 23 °C, and every LSB is 1/512 °C
+        temp: 23.0 + i16::from_le_bytes(temperature) as f32 / 512.0,
+    }
+}
+
+impl Bmi160 {
+    pub fn new(config: &MotionConfig) -> Result<Self, AccelerometerInitError> {
+        let address = config.i2c_address.unwrap_or(KNOWN_ADDRESSES[0]);
+        let init = || -> Result<I2cdev, String> {
+            let mut i2c = I2cdev::new(&config.i2c_bus).map_err(|e| format!("{:?}", e))?;
+            let id =
+                read_register(&mut i2c, address, BMI160_CHIP_ID).map_err(|e| format!("{:?}", e))?;
+            if id != BMI160_CHIP_ID_VALUE {
+                return Err(format!(
+                    "Unexpected chip id {id:#04x}, expected {BMI160_CHIP_ID_VALUE:#04x}. Is it a different sensor?"
+                ));
+            }
+
+            let mut write = |register, value, delay_ms| -> Result<(), String> {
+                i2c.write(address, &[register, value])
+                    .map_err(|e| format!("{:?}", e))?;
+                std::thread::sleep(Duration::from_millis(delay_ms));
+                Ok(())
+            };
+            // The sensors start suspended, and take a while to power up
+            write(BMI160_CMD, BMI160_CMD_SOFT_RESET, 10)?;
+            write(BMI160_CMD, BMI160_CMD_ACC_NORMAL, 5)?;
+            write(BMI160_CMD, BMI160_CMD_GYR_NORMAL, 81)?;
+            write(BMI160_ACC_CONF, BMI160_CONF_100HZ, 0)?;
+            write(BMI160_ACC_RANGE, BMI160_ACC_RANGE_2G, 0)?;
+            write(BMI160_GYR_CONF, BMI160_CONF_100HZ, 0)?;
+            write(BMI160_GYR_RANGE, BMI160_GYR_RANGE_250, 0)?;
+            Ok(i2c)
+        };
+        let i2c = init().map_err(|cause| AccelerometerInitError {
+            sensor: "BMI160".to_owned(),
+            bus: config.i2c_bus.clone(),
+            address: Some(address),
+            cause,
+        })?;
+        Ok(Bmi160 {
+            i2c,
+            address,
+            calibration: Calibration::load(&config.calibration_file),
+            gyro_bias: GyroBiasLearner::default(),
+            config: config.clone(),
+        })
+    }
+
+    fn get_raw_data(&mut self) -> Result<AccelerometerData, SensorError> {
+        let mut data = [0; 12];
+        self.i2c
+            .write_read(self.address, &[BMI160_DATA], &mut data)
+            .map_err(i2c_error)?;
+        let mut temperature = [0; 2];
+        self.i2c
+            .write_read(self.address, &[BMI160_TEMPERATURE], &mut temperature)
+            .map_err(i2c_error)?;
+        Ok(bmi160_data(data, temperature))
+    }
+}
+
+impl AccelerometerSource for Bmi160 {
+    fn sample(&mut self) -> Result<AccelerometerData, SensorError> {
+        let data = self.get_raw_data()?;
+        self.gyro_bias
+            .add(&mut self.calibration, &self.config.calibration_file, &data);
+        Ok(self.calibration.apply(data))
+    }
+
+    fn reinit(&mut self) -> Result<(), SensorError> {
+        let calibration = std::mem::take(&mut self.calibration);
+        let gyro_bias = std::mem::take(&mut self.gyro_bias);
+        *self = Bmi160::new(&self.config)?;
+        self.calibration = calibration;
+        self.gyro_bias = gyro_bias;
+        Ok(())
+    }
+
+    fn calibrate(&mut self) -> Result<Calibration, CalibrationError> {
+        let previous = self.calibration.clone();
+        let path = self.config.calibration_file.clone();
+        let calibration = Calibration::measure(&path, &previous, || self.get_raw_data())?;
+        self.calibration = calibration.clone();
+        Ok(calibration)
+    }
+
+    fn set_at_rest(&mut self, at_rest: bool) {
+        self.gyro_bias.set_at_rest(
+            &mut self.calibration,
+            &self.config.calibration_file,
+            at_rest,
+        );
+    }
+}
+
+#[test]
+fn test_sensor_type() {
+    assert_eq!("BMI160".parse(), Ok(SensorType::Bmi160));
+    assert_eq!("auto".parse(), Ok(SensorType::Auto));
+    let error = "adxl345".parse::<SensorType>().unwrap_err();
+    assert!(error.contains("mpu6050, bmi160"), "{error}");
+}
+
+#[test]
+fn test_bmi160_data() {
+    // 1 g along -z, 125 °/s around x, and 25 °C
+    let mut data = [0; 12];
+    data[2 * 5..2 * 5 + 2].copy_from_slice(&(-16384i16).to_le_bytes());
+    data[0..2].copy_from_slice(&16400i16.to_le_bytes());
+    let data = bmi160_data(data, 1024i16.to_le_bytes());
+    assert_eq!(data.acc, (0.0, 0.0, -1.0));
+    assert!((data.gyro.0 - 125f32.to_radians()).abs() < 1e-6);
+    assert_eq!(data.temp, 25.0);
+}
@@ -0,0 +1,92 @@
+use std::io::Write;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use log::warn;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Append-only log, stored as one json object per line.
+pub struct JsonLog<T> {
+    path: PathBuf,
+    lock: Mutex<()>,
+    entries: PhantomData<fn() -> T>,
+}
+
+impl<T: Serialize + DeserializeOwned> JsonLog<T> {
+    pub fn new(path: &Path) -> JsonLog<T> {
+        JsonLog {
+            path: path.to_path_buf(),
+            lock: Mutex::new(()),
+            entries: PhantomData,
+        }
+    }
+
+    pub fn record(&self, entry: &T) {
+        let _guard = self.lock.lock().unwrap();
+        let result = std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)
+            .and_then(|mut file| {
+                let line = serde_json::to_string(entry).unwrap();
+                writeln!(file, "{line}")
+            });
+
+        if let Err(e) = result {
+            warn!("Could not write to `{}`: {}", self.path.display(), e);
+        }
+    }
+
+    /// Removes the entries for which `keep` returns false, by rewriting the file. Returns the number of removed entries.
+    ///
+    /// Invalid entries are dropped when the file is rewritten.
+    pub fn retain(&self, mut keep: impl FnMut(&T) -> bool) -> usize {
+        let _guard = self.lock.lock().unwrap();
+        let entries = self.read();
+        let total = entries.len();
+        let kept = entries.into_iter().filter(|e| keep(e)).collect::<Vec<_>>();
+        if kept.len() == total {
+            return 0;
+        }
+        let contents = kept
+            .iter()
+            .map(|entry| serde_json::to_string(entry).unwrap() + "\n")
+            .collect::<String>();
+        if let Err(e) = std::fs::write(&self.path, contents) {
+            warn!("Could not write to `{}`: {}", self.path.display(), e);
+            return 0;
+        }
+        total - kept.len()
+    }
+
+    /// All entries, oldest first.
+    pub fn entries(&self) -> Vec<T> {
+        let _guard = self.lock.lock().unwrap();
+        self.read()
+    }
+
+    /// Reads all entries. The lock must be held.
+    fn read(&self) -> Vec<T> {
+        let contents = match std::fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return vec![],
+            Err(e) => {
+                warn!("Could not read `{}`: {}", self.path.display(), e);
+                return vec![];
+            }
+        };
+
+        contents
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| match serde_json::from_str(line) {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    warn!("Skipping invalid entry in `{}`: {}", self.path.display(), e);
+                    None
+                }
+            })
+            .collect()
+    }
+}
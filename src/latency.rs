@@ -0,0 +1,103 @@
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+/// Milliseconds from deciding to play a sound until each step on the way to the speaker was reached.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Latency {
+    /// The sound was decoded and ready to play.
+    pub decoded_ms: Option<u64>,
+    /// The sound was appended to the output sink.
+    pub sink_ms: Option<u64>,
+    /// The first nonzero sample was played at a nonzero volume.
+    pub first_sound_ms: Option<u64>,
+}
+
+impl fmt::Display for Latency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |v: Option<u64>| v.map_or("-".to_owned(), |v| format!("{v} ms"));
+        write!(
+            f,
+            "decoded after {}, sink started after {}, first sound after {}",
+            ms(self.decoded_ms),
+            ms(self.sink_ms),
+            ms(self.first_sound_ms)
+        )
+    }
+}
+
+/// Records when a sound reaches each step of the playback path. Each step is only recorded the first time.
+pub struct LatencyTrace {
+    label: &'static str,
+    start: Instant,
+    latency: Latency,
+}
+
+impl LatencyTrace {
+    /// Starts measuring from now. `label` is used when logging, e.g. "alarm".
+    pub fn new(label: &'static str) -> Self {
+        LatencyTrace {
+            label,
+            start: Instant::now(),
+            latency: Latency::default(),
+        }
+    }
+
+    fn since_start(&self, at: Instant) -> Option<u64> {
+        Some(at.saturating_duration_since(self.start).as_millis() as u64)
+    }
+
+    pub fn mark_decoded(&mut self) {
+        if self.latency.decoded_ms.is_none() {
+            self.latency.decoded_ms = self.since_start(Instant::now());
+        }
+    }
+
+    pub fn mark_sink_started(&mut self) {
+        if self.latency.sink_ms.is_none() {
+            self.latency.sink_ms = self.since_start(Instant::now());
+        }
+    }
+
+    /// Records the first sound, and logs all steps.
+    pub fn mark_first_sound(&mut self, at: Instant) {
+        if self.latency.first_sound_ms.is_none() {
+            self.latency.first_sound_ms = self.since_start(at);
+            info!("Latency of {}: {}", self.label, self.latency);
+        }
+    }
+
+    pub fn latency(&self) -> Latency {
+        self.latency.clone()
+    }
+}
+
+/// Latency of the most recent alarm, exported as metrics.
+static LAST_ALARM: Mutex<Option<Latency>> = Mutex::new(None);
+
+pub fn record_alarm(latency: &Latency) {
+    *LAST_ALARM.lock().unwrap() = Some(latency.clone());
+}
+
+pub fn last_alarm() -> Option<Latency> {
+    LAST_ALARM.lock().unwrap().clone()
+}
+
+#[test]
+fn test_latency_trace() {
+    let mut trace = LatencyTrace::new("test");
+    trace.mark_decoded();
+    let decoded = trace.latency().decoded_ms;
+    assert!(decoded.is_some());
+    assert_eq!(trace.latency().first_sound_ms, None);
+
+    std::thread::sleep(std::time::Duration::from_millis(5));
+    trace.mark_decoded();
+    assert_eq!(trace.latency().decoded_ms, decoded);
+
+    // Sounds which were produced before the trace started count as immediate
+    trace.mark_first_sound(trace.start - std::time::Duration::from_millis(1));
+    assert_eq!(trace.latency().first_sound_ms, Some(0));
+}
@@ -0,0 +1,283 @@
+//! Local copy of the synced state, so that it survives a restart while the MQTT broker is unavailable.
+//!
+//! The containers are saved to [`LOCAL_STATE_FILE`] when they change, see [`crate::offline`], and are created with the
+//! saved values, so that they have them before (and whether or not) the sync completes.
+use std::collections::BTreeMap;
+use std::hash::Hash;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use futures::future::BoxFuture;
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::offline::Container;
+
+pub const LOCAL_STATE_FILE: &str = "./local_state.json";
+/// Bumped when the format of the file changes, together with a step in [`migrate`].
+const SCHEMA_VERSION: u64 = 1;
+
+/// Values of the containers by name.
+pub type Snapshot = BTreeMap<String, Value>;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct LocalStateFile {
+    version: u64,
+    containers: Snapshot,
+}
+
+#[derive(Error, Debug)]
+pub enum LocalStateError {
+    #[error("Could not read the local state `{0}`: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Invalid local state `{0}`: {1}")]
+    Parse(PathBuf, serde_json::Error),
+    #[error("The local state `{0}` has version {1}, which is newer than {SCHEMA_VERSION}")]
+    Newer(PathBuf, u64),
+}
+
+/// Brings the contents of a file of any earlier version up to [`SCHEMA_VERSION`].
+fn migrate(mut file: Value) -> Result<Value, u64> {
+    let mut version = file.get("version").and_then(Value::as_u64).unwrap_or(0);
+    if version == 0 {
+        // Only the containers, from before there was a version
+        file = serde_json::json!({ "version": 1, "containers": file });
+        version = 1;
+    }
+    if version > SCHEMA_VERSION {
+        return Err(version);
+    }
+    Ok(file)
+}
+
+/// A synced container, seen as JSON.
+trait LocalCopy: Send + Sync {
+    fn get_value(&self) -> Option<Value>;
+    fn set_value(&self, value: Value) -> BoxFuture<'_, Result<(), serde_json::Error>>;
+}
+
+impl<T> LocalCopy for Container<T>
+where
+    T: Serialize + DeserializeOwned + Clone + Hash + Send + Sync + 'static,
+{
+    fn get_value(&self) -> Option<Value> {
+        serde_json::to_value(self.get()?).ok()
+    }
+
+    fn set_value(&self, value: Value) -> BoxFuture<'_, Result<(), serde_json::Error>> {
+        Box::pin(async move {
+            self.set(serde_json::from_value(value)?).await;
+            Ok(())
+        })
+    }
+}
+
+/// The containers which are saved locally, and the values which were last saved.
+///
+/// Clones share the saved values, so that the state can also be saved at shutdown.
+#[derive(Clone)]
+pub struct LocalState {
+    path: PathBuf,
+    /// Locked while saving, so that saves do not interleave.
+    saved: Arc<Mutex<Snapshot>>,
+    containers: Vec<(&'static str, Arc<dyn LocalCopy>)>,
+}
+
+impl LocalState {
+    /// Continues from the values saved in `path`. Starts empty if it is missing or can not be used.
+    pub fn load(path: &Path) -> LocalState {
+        let saved = read(path).unwrap_or_else(|e| {
+            warn!("{e}");
+            Snapshot::new()
+        });
+        LocalState {
+            path: path.to_owned(),
+            saved: Arc::new(Mutex::new(saved)),
+            containers: Vec::new(),
+        }
+    }
+
+    /// The saved value of the container `name`, or `default` if there is none.
+    pub fn initial<T: DeserializeOwned>(&self, name: &str, default: T) -> T {
+        let Some(value) = self.saved.lock().unwrap().get(name).cloned() else {
+            return default;
+        };
+        // Like after the type of the container changed
+        serde_json::from_value(value).unwrap_or_else(|e| {
+            warn!("Ignoring the local value of {name}: {e}");
+            default
+        })
+    }
+
+    /// Saves `container` from now on. It should have been created with [`LocalState::initial`], unless it is only
+    /// saved to see what it was when the alarm stopped, like `alarm/is_playing`.
+    pub fn add<T>(&mut self, name: &'static str, container: &Arc<Container<T>>)
+    where
+        T: Serialize + DeserializeOwned + Clone + Hash + Send + Sync + 'static,
+    {
+        self.containers.push((name, container.clone()));
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The values which were last saved.
+    pub fn saved(&self) -> Snapshot {
+        self.saved.lock().unwrap().clone()
+    }
+
+    /// The current values of the containers.
+    pub fn snapshot(&self) -> Snapshot {
+        self.containers
+            .iter()
+            .filter_map(|(name, c)| Some((name.to_string(), c.get_value()?)))
+            .collect()
+    }
+
+    /// Saves `snapshot`, through a temporary file, so that a crash while writing never leaves a partial file.
+    pub fn save(&self, snapshot: Snapshot) -> std::io::Result<()> {
+        let mut saved = self.saved.lock().unwrap();
+        let file = LocalStateFile {
+            version: SCHEMA_VERSION,
+            containers: snapshot,
+        };
+        let tmp = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(&file)?)?;
+        std::fs::rename(tmp, &self.path)?;
+        *saved = file.containers;
+        Ok(())
+    }
+
+    /// Saves the containers if they changed since the last save. Returns true if they were saved.
+    pub fn save_changes(&self) -> std::io::Result<bool> {
+        let snapshot = self.snapshot();
+        if snapshot == *self.saved.lock().unwrap() {
+            return Ok(false);
+        }
+        self.save(snapshot)?;
+        Ok(true)
+    }
+
+    /// Sets the containers to the values in `values`.
+    pub async fn set(&self, values: &Snapshot) {
+        for (name, container) in &self.containers {
+            if let Some(value) = values.get(*name) {
+                if let Err(e) = container.set_value(value.clone()).await {
+                    warn!("Ignoring the local value of {name}: {e}");
+                }
+            }
+        }
+    }
+}
+
+fn read(path: &Path) -> Result<Snapshot, LocalStateError> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Snapshot::new()),
+        Err(e) => return Err(LocalStateError::Read(path.to_owned(), e)),
+    };
+    let parse = |e| LocalStateError::Parse(path.to_owned(), e);
+    let file = serde_json::from_str(&contents).map_err(parse)?;
+    let file = migrate(file).map_err(|v| LocalStateError::Newer(path.to_owned(), v))?;
+    Ok(serde_json::from_value::<LocalStateFile>(file)
+        .map_err(parse)?
+        .containers)
+}
+
+#[test]
+fn test_local_state_restart() {
+    use crate::{lucid::LucidConfig, InnerAlarmState, LastPlayed};
+    use chrono::{TimeZone, Utc};
+
+    let path = std::env::temp_dir().join(format!("local-state-test-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let default_state = InnerAlarmState {
+        next_alarm: Utc.timestamp_opt(0, 0).unwrap(),
+        enabled: false,
+        category: None,
+        wake_window: Default::default(),
+    };
+
+    let local = LocalState::load(&path);
+    assert_eq!(
+        local.initial("alarm/state", default_state.clone()),
+        default_state
+    );
+
+    let state = InnerAlarmState {
+        next_alarm: Utc.with_ymd_and_hms(2024, 3, 2, 6, 30, 15).unwrap(),
+        enabled: true,
+        category: Some("birds".to_owned()),
+        wake_window: Default::default(),
+    };
+    let last_played = LastPlayed {
+        last_played_time: Some(Utc.with_ymd_and_hms(2024, 3, 1, 6, 31, 2).unwrap()),
+    };
+    let lucid_config = LucidConfig {
+        max_cues_per_night: 7,
+        ..LucidConfig::default()
+    };
+    local
+        .save(Snapshot::from([
+            (
+                "alarm/state".to_owned(),
+                serde_json::to_value(&state).unwrap(),
+            ),
+            (
+                "alarm/last_played".to_owned(),
+                serde_json::to_value(&last_played).unwrap(),
+            ),
+            (
+                "alarm/lucid_config".to_owned(),
+                serde_json::to_value(&lucid_config).unwrap(),
+            ),
+        ]))
+        .unwrap();
+    // Killed after saving, nothing else is written
+    drop(local);
+
+    let local = LocalState::load(&path);
+    assert_eq!(local.initial("alarm/state", default_state.clone()), state);
+    assert_eq!(
+        local.initial("alarm/last_played", LastPlayed::default()),
+        last_played
+    );
+    assert_eq!(
+        local.initial("alarm/lucid_config", LucidConfig::default()),
+        lucid_config
+    );
+    assert!(!path.with_extension("json.tmp").exists());
+
+    // A file from before the version, and one from a newer version which is not understood
+    std::fs::write(&path, r#"{"alarm/lucid_sfx_volume": 40}"#).unwrap();
+    assert_eq!(
+        LocalState::load(&path).initial("alarm/lucid_sfx_volume", 50),
+        40
+    );
+    std::fs::write(
+        &path,
+        r#"{"version": 99, "containers": {"alarm/lucid_sfx_volume": 40}}"#,
+    )
+    .unwrap();
+    assert!(matches!(read(&path), Err(LocalStateError::Newer(_, 99))));
+    assert_eq!(
+        LocalState::load(&path).initial("alarm/lucid_sfx_volume", 50),
+        50
+    );
+
+    // A value of the wrong type is ignored
+    std::fs::write(
+        &path,
+        r#"{"version": 1, "containers": {"alarm/lucid_sfx_volume": "loud"}}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        LocalState::load(&path).initial("alarm/lucid_sfx_volume", 50),
+        50
+    );
+    std::fs::remove_file(&path).unwrap();
+}
@@ -1,252 +1,2256 @@
 // At random times before waking up, play low volume music
 // At random times before waking up, play low volume sfx
+// At random times before waking up, play low volume voice prompts or binaural beats
 
 use std::{
     path::Path,
-    sync::{Arc, Mutex},
+    str::FromStr,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
-use brevduva::SyncedContainer;
-use chrono::TimeDelta;
-use rand::{rngs::StdRng, Rng, SeedableRng};
+use chrono::{DateTime, Local, NaiveTime, TimeDelta, Utc};
+use rand::{
+    distributions::{Distribution, WeightedIndex},
+    rngs::StdRng,
+    Rng, SeedableRng,
+};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
 
+#[cfg(feature = "audio")]
+use crate::{binaural_source::BinauralSource, latency::LatencyTrace};
 use crate::{
-    alarm::{fadein, fadeout, random_alarm_sound},
-    AlarmState,
+    config::{Config, ConfigError, SoundsConfig},
+    fade::{fadein, fadeout},
+    haptic::PulsePattern,
+    lucid_log::{find_disruption, Awakening, BinauralParams, LucidCueRecord},
+    offline::Container,
+    playback::{PlaybackLease, PlaybackPriority},
+    reality_check::RealityCheckConfig,
+    sounds::random_alarm_sound,
+    AlarmState, Confidence,
 };
 
+/// Lucid sounds are only played when it is this likely that the user is in bed, so that they don't play to an empty room.
+const MIN_PRESENCE_CONFIDENCE: f32 = 0.8;
+/// How often the config is checked while the lucid cues are disabled.
+const DISABLED_POLL_INTERVAL: Duration = Duration::from_secs(60);
+/// How often the sleep stages are checked for REM sleep.
+const REM_POLL_INTERVAL: Duration = Duration::from_secs(60);
+/// REM sleep shows as small movements in at least this many of the last [`REM_RECENT_MINUTES`].
+const REM_MOVING_MINUTES: usize = 2;
+const REM_RECENT_MINUTES: usize = 5;
+/// The quiet sleep before REM sleep may have movement in at most this fraction of the minutes.
+const MAX_QUIET_MOVING_FRACTION: f32 = 0.1;
+/// A cue must be able to finish at least this long before the alarm could start.
+const ALARM_MARGIN: Duration = Duration::from_secs(5 * 60);
+/// How often a voice cue checks whether it should stop, while waiting between the repetitions.
+const VOICE_GAP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// A new night starts for the cue budget when the user has been out of bed for this long.
+const BUDGET_RESET_OUT_OF_BED: TimeDelta = TimeDelta::minutes(30);
+
+/// When lucid cues are played.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LucidTiming {
+    /// When the sleep stages look like REM sleep, see [`is_rem_window`]. Random if the stages are unknown.
+    #[default]
+    RemTargeted,
+    /// At a random time within each period.
+    Random,
+    /// Played on request, with `--lucid-test` or [`trigger_lucid_cue`]. Cannot be used in the config.
+    Manual,
+}
+
+/// One minute of sleep, as seen by the sleep monitor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SleepMinute {
+    pub asleep: bool,
+    pub moving: bool,
+}
+
+/// True if the minutes, oldest first, end with a pattern consistent with REM sleep.
+///
+/// That is small movements while asleep during the last few minutes, after at least `quiet_minutes` of quiet sleep.
+pub fn is_rem_window(minutes: &[SleepMinute], quiet_minutes: usize) -> bool {
+    let Some(start) = minutes
+        .len()
+        .checked_sub(quiet_minutes + REM_RECENT_MINUTES)
+    else {
+        return false;
+    };
+    let (quiet, recent) = minutes[start..].split_at(quiet_minutes);
+    let moving = |minutes: &[SleepMinute]| minutes.iter().filter(|m| m.moving).count();
+    minutes[start..].iter().all(|m| m.asleep)
+        && moving(recent) >= REM_MOVING_MINUTES
+        && moving(quiet) as f32 <= MAX_QUIET_MOVING_FRACTION * quiet_minutes as f32
+}
+
+/// The recorded minutes of sleep, oldest first. `None` if they are unknown.
+#[cfg(feature = "motion")]
+async fn sleep_minutes(alarm_state: &AlarmState) -> Option<Vec<SleepMinute>> {
+    use crate::sleep_monitor::SleepStage;
+    let staged = alarm_state
+        .sleep_monitor
+        .lock()
+        .await
+        .sleep_monitor
+        .staged_movement()?;
+    Some(
+        staged
+            .into_iter()
+            .map(|(stage, movement)| SleepMinute {
+                asleep: stage != SleepStage::Wake,
+                moving: movement > 0,
+            })
+            .collect(),
+    )
+}
+
+#[cfg(not(feature = "motion"))]
+async fn sleep_minutes(_alarm_state: &AlarmState) -> Option<Vec<SleepMinute>> {
+    None
+}
+
+/// The kinds of lucid cues.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum LucidCue {
+    Music,
+    Sfx,
+    /// Short spoken prompts, like "you are dreaming".
+    Voice,
+    /// Generated, see [`BinauralSource`].
+    Binaural,
+    /// Pulses of a vibration motor, see [`crate::haptic`].
+    Haptic,
+}
+
+impl LucidCue {
+    pub const ALL: [LucidCue; 5] = [
+        LucidCue::Music,
+        LucidCue::Sfx,
+        LucidCue::Voice,
+        LucidCue::Binaural,
+        LucidCue::Haptic,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LucidCue::Music => "music",
+            LucidCue::Sfx => "sfx",
+            LucidCue::Voice => "voice",
+            LucidCue::Binaural => "binaural",
+            LucidCue::Haptic => "haptic",
+        }
+    }
+
+    /// Where the sounds of the cue are, unless they are generated.
+    fn directory(self, config: &SoundsConfig) -> Option<&Path> {
+        match self {
+            LucidCue::Music => Some(&config.lucid_music_dir),
+            LucidCue::Sfx => Some(&config.lucid_sfx_dir),
+            LucidCue::Voice => Some(&config.lucid_voice_dir),
+            LucidCue::Binaural | LucidCue::Haptic => None,
+        }
+    }
+}
+
+impl FromStr for LucidCue {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        LucidCue::ALL
+            .into_iter()
+            .find(|c| c.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                format!(
+                    "Unknown lucid cue `{s}`. The cues are: {}",
+                    LucidCue::ALL.map(LucidCue::name).join(", ")
+                )
+            })
+    }
+}
+
+/// A time of day window, in the local time of the server. Wraps around midnight if `start` is after `end`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TimeWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl TimeWindow {
+    /// True if `time` is in `start..end`. An empty window, where `start == end`, contains all times.
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        match self.start.cmp(&self.end) {
+            std::cmp::Ordering::Less => self.start <= time && time < self.end,
+            std::cmp::Ordering::Greater => time >= self.start || time < self.end,
+            std::cmp::Ordering::Equal => true,
+        }
+    }
+
+    /// The length of the window. An empty window lasts the whole day.
+    pub fn duration(&self) -> TimeDelta {
+        let duration = self.end - self.start;
+        if duration <= TimeDelta::zero() {
+            duration + TimeDelta::days(1)
+        } else {
+            duration
+        }
+    }
+}
+
+/// How long a cue fades in at the start, and out at the end.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Fade {
+    pub in_seconds: f32,
+    pub out_seconds: f32,
+}
+
+impl std::hash::Hash for Fade {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.in_seconds.to_bits().hash(state);
+        self.out_seconds.to_bits().hash(state);
+    }
+}
+
+/// A point of [`LucidConfig::volume_schedule`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct VolumePoint {
+    pub hours: f32,
+    pub multiplier: f32,
+}
+
+impl std::hash::Hash for VolumePoint {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.hours.to_bits().hash(state);
+        self.multiplier.to_bits().hash(state);
+    }
+}
+
+/// Volume multiplier `hours` after the user fell asleep, interpolated linearly between the points of `schedule`.
+///
+/// Constant before the first and after the last point. 1 if the schedule is empty, or the time is unknown.
+fn volume_multiplier(schedule: &[VolumePoint], hours: Option<f32>) -> f32 {
+    let (Some(first), Some(last), Some(hours)) = (schedule.first(), schedule.last(), hours) else {
+        return 1.0;
+    };
+    if hours <= first.hours {
+        return first.multiplier;
+    }
+    schedule
+        .windows(2)
+        .find(|w| hours < w[1].hours)
+        .map_or(last.multiplier, |w| {
+            let f = (hours - w[0].hours) / (w[1].hours - w[0].hours);
+            w[0].multiplier + (w[1].multiplier - w[0].multiplier) * f
+        })
+}
+
+/// Volume factor at `t` seconds into a cue which lasts `duration` seconds. `None` once the cue has ended.
+///
+/// The fade out ends at `duration`, so that the cue never ends abruptly.
+pub fn cue_envelope(t: f32, duration: f32, fade: Fade) -> Option<f32> {
+    if t >= duration {
+        return None;
+    }
+    let fade_in = if fade.in_seconds > 0.0 {
+        fadein(t, fade.in_seconds)
+    } else {
+        1.0
+    };
+    let fade_out = if fade.out_seconds > 0.0 {
+        fadeout(t - (duration - fade.out_seconds), fade.out_seconds)
+    } else {
+        1.0
+    };
+    Some(fade_in * fade_out)
+}
+
+/// When and what lucid cues are played. Stored in the `alarm/lucid_config` container, so that it can be changed during an experiment.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct LucidConfig {
+    /// No cues are played, and the time the user fell asleep is not tracked, while this is false.
+    pub enabled: bool,
+    /// How often each kind of cue is chosen, relative to the others.
+    pub music_weight: f32,
+    pub sfx_weight: f32,
+    pub voice_weight: f32,
+    pub binaural_weight: f32,
+    pub haptic_weight: f32,
+    /// Music plays for a random duration up to this long.
+    pub max_music_seconds: f32,
+    /// Sound effects play for this long.
+    pub sfx_seconds: f32,
+    pub music_fade: Fade,
+    pub sfx_fade: Fade,
+    pub binaural_fade: Fade,
+    /// A voice cue plays its clip this many times.
+    pub voice_repetitions: u32,
+    /// Silence between the repetitions of a voice cue.
+    pub voice_gap_seconds: f32,
+    /// Frequency of the left channel of binaural beats.
+    pub binaural_carrier_hz: f32,
+    /// The right channel is this much higher than the left one.
+    pub binaural_beat_hz: f32,
+    /// Binaural beats play for this long.
+    pub binaural_seconds: f32,
+    pub haptic_pattern: PulsePattern,
+    /// Also plays the haptic pattern at the start of every sound cue.
+    pub haptic_with_audio: bool,
+    /// Cues are only played after the user has been asleep for this long.
+    pub min_sleep_minutes: f32,
+    /// A cue is considered at a random time within each period of this length. With REM targeting, at most one cue is played per period.
+    pub period_minutes: f32,
+    pub timing: LucidTiming,
+    /// REM sleep must come after at least this many minutes of quiet sleep.
+    pub rem_quiet_minutes: u32,
+    /// Cues are only played during this time of day, like `{"start": "03:00", "end": "06:30"}`. Any time if not set.
+    pub window: Option<TimeWindow>,
+    /// Multiplies the volumes, by the hours since the user fell asleep, like
+    /// `[{"hours": 2, "multiplier": 0.5}, {"hours": 6, "multiplier": 1}]`, since sleep gets lighter during the night.
+    /// The volumes are used as they are if it is empty.
+    pub volume_schedule: Vec<VolumePoint>,
+    /// A cue which is followed by significant movement or a bed exit within this many minutes is marked as disruptive,
+    /// as is one during which the user starts moving.
+    pub disruption_window_minutes: f32,
+    /// At most this many cues are played during one night, see [`CueBudget`]. Manual cues are not counted.
+    pub max_cues_per_night: u32,
+    /// Least time from the end of one cue to the start of the next.
+    pub min_cue_spacing_minutes: f32,
+    /// Prompts during the day, see [`crate::reality_check`].
+    pub reality_checks: RealityCheckConfig,
+}
+
+impl Default for LucidConfig {
+    fn default() -> Self {
+        LucidConfig {
+            enabled: true,
+            music_weight: 0.2,
+            sfx_weight: 0.8,
+            voice_weight: 0.0,
+            binaural_weight: 0.0,
+            haptic_weight: 0.0,
+            max_music_seconds: 150.0,
+            sfx_seconds: 500.0,
+            music_fade: Fade {
+                in_seconds: 5.0,
+                out_seconds: 10.0,
+            },
+            sfx_fade: Fade {
+                in_seconds: 5.0,
+                out_seconds: 10.0,
+            },
+            binaural_fade: Fade {
+                in_seconds: 5.0,
+                out_seconds: 5.0,
+            },
+            voice_repetitions: 2,
+            voice_gap_seconds: 20.0,
+            binaural_carrier_hz: 200.0,
+            binaural_beat_hz: 4.0,
+            binaural_seconds: 300.0,
+            haptic_pattern: PulsePattern::default(),
+            haptic_with_audio: false,
+            min_sleep_minutes: 90.0,
+            period_minutes: 60.0,
+            timing: LucidTiming::RemTargeted,
+            rem_quiet_minutes: 60,
+            window: None,
+            volume_schedule: vec![],
+            disruption_window_minutes: 3.0,
+            max_cues_per_night: 3,
+            min_cue_spacing_minutes: 45.0,
+            reality_checks: RealityCheckConfig::default(),
+        }
+    }
+}
+
+// Containers must be hashable
+impl std::hash::Hash for LucidConfig {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.enabled.hash(state);
+        self.music_weight.to_bits().hash(state);
+        self.sfx_weight.to_bits().hash(state);
+        self.voice_weight.to_bits().hash(state);
+        self.binaural_weight.to_bits().hash(state);
+        self.haptic_weight.to_bits().hash(state);
+        self.max_music_seconds.to_bits().hash(state);
+        self.sfx_seconds.to_bits().hash(state);
+        self.music_fade.hash(state);
+        self.sfx_fade.hash(state);
+        self.binaural_fade.hash(state);
+        self.voice_repetitions.hash(state);
+        self.voice_gap_seconds.to_bits().hash(state);
+        self.binaural_carrier_hz.to_bits().hash(state);
+        self.binaural_beat_hz.to_bits().hash(state);
+        self.binaural_seconds.to_bits().hash(state);
+        self.haptic_pattern.hash(state);
+        self.haptic_with_audio.hash(state);
+        self.min_sleep_minutes.to_bits().hash(state);
+        self.period_minutes.to_bits().hash(state);
+        self.timing.hash(state);
+        self.rem_quiet_minutes.hash(state);
+        self.window.hash(state);
+        self.volume_schedule.hash(state);
+        self.disruption_window_minutes.to_bits().hash(state);
+        self.max_cues_per_night.hash(state);
+        self.min_cue_spacing_minutes.to_bits().hash(state);
+        self.reality_checks.hash(state);
+    }
+}
+
+impl LucidConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let weights = self.weights();
+        if weights.iter().any(|w| !w.is_finite() || *w < 0.0) || weights.iter().sum::<f32>() <= 0.0
+        {
+            return Err(ConfigError::Invalid(
+                "lucid cue weights must be non-negative numbers, and not all 0".to_owned(),
+            ));
+        }
+        if self.haptic_pattern.pulses == 0 || self.haptic_pattern.pulse_ms == 0 {
+            return Err(ConfigError::Invalid(
+                "the haptic pattern must have at least one pulse".to_owned(),
+            ));
+        }
+        if self.timing == LucidTiming::Manual {
+            return Err(ConfigError::Invalid(
+                "the lucid timing must be rem_targeted or random".to_owned(),
+            ));
+        }
+        if self.voice_repetitions == 0 {
+            return Err(ConfigError::Invalid(
+                "voice_repetitions must be positive".to_owned(),
+            ));
+        }
+        let fades = [self.music_fade, self.sfx_fade, self.binaural_fade];
+        if fades.iter().any(|f| {
+            !f.in_seconds.is_finite()
+                || !f.out_seconds.is_finite()
+                || f.in_seconds < 0.0
+                || f.out_seconds < 0.0
+        }) {
+            return Err(ConfigError::Invalid(
+                "lucid cue fades must be non-negative numbers".to_owned(),
+            ));
+        }
+        if !self.voice_gap_seconds.is_finite() || self.voice_gap_seconds < 0.0 {
+            return Err(ConfigError::Invalid(
+                "voice_gap_seconds must be a non-negative number".to_owned(),
+            ));
+        }
+        let is_positive = |x: f32| x.is_finite() && x > 0.0;
+        if !is_positive(self.max_music_seconds)
+            || !is_positive(self.sfx_seconds)
+            || !is_positive(self.binaural_seconds)
+            || !is_positive(self.period_minutes)
+        {
+            return Err(ConfigError::Invalid(
+                "lucid cue durations and period must be positive numbers".to_owned(),
+            ));
+        }
+        if !(20.0..=20_000.0).contains(&self.binaural_carrier_hz) {
+            return Err(ConfigError::Invalid(
+                "binaural_carrier_hz must be between 20 and 20000".to_owned(),
+            ));
+        }
+        if !(self.binaural_beat_hz > 0.0 && self.binaural_beat_hz <= 100.0) {
+            return Err(ConfigError::Invalid(
+                "binaural_beat_hz must be between 0 and 100".to_owned(),
+            ));
+        }
+        if self
+            .volume_schedule
+            .iter()
+            .any(|p| !p.hours.is_finite() || !p.multiplier.is_finite() || p.multiplier < 0.0)
+            || self
+                .volume_schedule
+                .windows(2)
+                .any(|w| w[0].hours >= w[1].hours)
+        {
+            return Err(ConfigError::Invalid(
+                "the volume schedule must have increasing hours and non-negative multipliers"
+                    .to_owned(),
+            ));
+        }
+        if !self.disruption_window_minutes.is_finite() || self.disruption_window_minutes < 0.0 {
+            return Err(ConfigError::Invalid(
+                "disruption_window_minutes must be a non-negative number".to_owned(),
+            ));
+        }
+        if !self.min_cue_spacing_minutes.is_finite() || self.min_cue_spacing_minutes < 0.0 {
+            return Err(ConfigError::Invalid(
+                "min_cue_spacing_minutes must be a non-negative number".to_owned(),
+            ));
+        }
+        self.reality_checks.validate()?;
+        if !self.min_sleep_minutes.is_finite() || self.min_sleep_minutes < 0.0 {
+            return Err(ConfigError::Invalid(
+                "min_sleep_minutes must be a non-negative number".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn weights(&self) -> [f32; 5] {
+        [
+            self.music_weight,
+            self.sfx_weight,
+            self.voice_weight,
+            self.binaural_weight,
+            self.haptic_weight,
+        ]
+    }
+
+    /// The longest that a cue of any of the kinds which can be chosen may play, when no cue plays longer than `max_cue_seconds`.
+    ///
+    /// The length of voice clips is not known in advance, so each repetition is assumed to play for the maximum time.
+    fn max_cue_duration(&self, max_cue_seconds: f32) -> Duration {
+        let seconds = [
+            self.max_music_seconds.min(max_cue_seconds),
+            self.sfx_seconds.min(max_cue_seconds),
+            self.voice_repetitions as f32 * max_cue_seconds
+                + self.voice_repetitions.saturating_sub(1) as f32 * self.voice_gap_seconds,
+            self.binaural_seconds.min(max_cue_seconds),
+            self.haptic_pattern.duration().as_secs_f32(),
+        ];
+        let longest = seconds
+            .iter()
+            .zip(self.weights())
+            .filter(|(_, weight)| *weight > 0.0)
+            .fold(0.0f32, |a, (&b, _)| a.max(b));
+        Duration::from_secs_f32(longest)
+    }
+
+    /// Picks the kind of a cue at random, by the weights.
+    fn choose_cue(&self, rng: &mut impl Rng) -> LucidCue {
+        match WeightedIndex::new(self.weights()) {
+            Ok(index) => LucidCue::ALL[index.sample(rng)],
+            Err(_) => LucidCue::Sfx,
+        }
+    }
+}
+
+/// Reads the current config. An invalid config is replaced by the last valid one, which is `current`.
+async fn read_lucid_config(
+    lucid_config: &Container<LucidConfig>,
+    current: &mut LucidConfig,
+) -> LucidConfig {
+    let Some(config) = lucid_config.get() else {
+        return current.clone();
+    };
+    match config.validate() {
+        Ok(()) => *current = config,
+        Err(e) => {
+            warn!("Ignoring the lucid config {:?}: {}", config, e);
+            lucid_config.set(current.clone()).await;
+        }
+    }
+    current.clone()
+}
+
+/// The multiplier of [`LucidConfig::volume_schedule`] for a cue which starts now.
+fn scheduled_volume_multiplier(
+    alarm_state: &AlarmState,
+    config: &LucidConfig,
+    cue: LucidCue,
+) -> f32 {
+    let hours = alarm_state
+        .asleep_since
+        .borrow()
+        .map(|t| (Utc::now() - t).num_seconds() as f32 / 3600.0);
+    let multiplier = volume_multiplier(&config.volume_schedule, hours);
+    match hours {
+        Some(hours) => info!(
+            "The lucid {:?} cue plays at {:.2} times the volume, {:.1} hours after falling asleep",
+            cue, multiplier, hours
+        ),
+        None => info!(
+            "The lucid {:?} cue plays at {:.2} times the volume, since it is unknown when the user fell asleep",
+            cue, multiplier
+        ),
+    }
+    multiplier
+}
+
+/// A lucid cue which is playing.
+struct PlayingCue {
+    record: LucidCueRecord,
+    started: Instant,
+}
+
+/// Publishes that a lucid cue called `name` has started playing.
+fn start_cue(
+    alarm_state: &AlarmState,
+    cue: LucidCue,
+    timing: LucidTiming,
+    name: String,
+    binaural: Option<BinauralParams>,
+    volume: f32,
+) -> PlayingCue {
+    info!("Starting a {:?} lucid {:?} cue: {}", timing, cue, name);
+    alarm_state.lucid_status.lock().unwrap().playing = Some(cue);
+    futures::executor::block_on(async {
+        alarm_state.events.now_playing(Some(&name)).await;
+        alarm_state
+            .events
+            .publish_lucid_cue(cue, timing, name.clone())
+            .await;
+    });
+    PlayingCue {
+        record: LucidCueRecord {
+            id: alarm_state.lucid_log.next_id(),
+            time: chrono::Utc::now(),
+            cue,
+            timing,
+            sound: (binaural.is_none() && cue != LucidCue::Haptic).then_some(name),
+            binaural,
+            duration_seconds: 0.0,
+            volume,
+            aborted: false,
+            skipped: None,
+            note: None,
+            disruption: None,
+        },
+        started: Instant::now(),
+    }
+}
+
+/// Records the cue in the lucid log, and starts watching for whether it woke the user up.
+fn finish_cue(
+    alarm_state: &AlarmState,
+    mut playing: PlayingCue,
+    aborted: bool,
+    config: &LucidConfig,
+) {
+    futures::executor::block_on(alarm_state.events.now_playing(None));
+    alarm_state.lucid_status.lock().unwrap().playing = None;
+    playing.record.duration_seconds = playing.started.elapsed().as_secs_f32();
+    playing.record.aborted = aborted;
+    info!(
+        "Lucid cue {} {} after {:.0} s",
+        playing.record.id,
+        if aborted { "was aborted" } else { "ended" },
+        playing.record.duration_seconds
+    );
+    alarm_state.lucid_log.record(&playing.record);
+
+    // Manual cues are usually played while awake
+    if playing.record.timing != LucidTiming::Manual {
+        tokio::spawn(watch_for_disruption(
+            alarm_state.clone(),
+            playing.record.id,
+            playing.record.time,
+            Utc::now(),
+            TimeDelta::milliseconds((config.disruption_window_minutes * 60_000.0) as i64),
+        ));
+    }
+}
+
+/// Waits until `window` after the cue has ended, and then marks the cue as disruptive if the user seems to have woken up.
+async fn watch_for_disruption(
+    alarm_state: AlarmState,
+    id: u64,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    window: TimeDelta,
+) {
+    tokio::time::sleep(window.to_std().unwrap_or_default()).await;
+    let awakenings = awakenings(&alarm_state, start, end + window).await;
+    if let Some(awakening) = find_disruption(start, end, window, &awakenings) {
+        info!(
+            "Lucid cue {} seems to have woken the user up, with a {:?} at {}",
+            id, awakening.kind, awakening.time
+        );
+        alarm_state.lucid_log.mark_disruptive(id, awakening);
+    }
+}
+
+/// Significant movements and bed exits which started in `from..=to`.
+#[cfg(feature = "motion")]
+async fn awakenings(
+    alarm_state: &AlarmState,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Vec<Awakening> {
+    let mut awakenings = alarm_state
+        .sleep_monitor
+        .lock()
+        .await
+        .movement_events
+        .overlapping(from, to)
+        .map(|e| Awakening {
+            kind: crate::lucid_log::AwakeningKind::Movement,
+            time: e.start,
+        })
+        .collect::<Vec<_>>();
+    awakenings.extend(
+        alarm_state
+            .bed_log
+            .entries()
+            .into_iter()
+            .filter(|t| !t.in_bed && from <= t.time && t.time <= to)
+            .map(|t| Awakening {
+                kind: crate::lucid_log::AwakeningKind::BedExit,
+                time: t.time,
+            }),
+    );
+    awakenings
+}
+
+#[cfg(not(feature = "motion"))]
+async fn awakenings(
+    _alarm_state: &AlarmState,
+    _from: DateTime<Utc>,
+    _to: DateTime<Utc>,
+) -> Vec<Awakening> {
+    vec![]
+}
+
+/// The confidence that the user is in bed, or 0 if it is unknown.
+fn get_presence_confidence(presence_confidence: &Container<Option<Confidence>>) -> f32 {
+    presence_confidence.get().flatten().map_or(0.0, |c| c.0)
+}
+
+/// The cues which have been played during the current night, for [`LucidConfig::max_cues_per_night`].
+///
+/// The night ends when the alarm goes off, or when the user has been out of bed for [`BUDGET_RESET_OUT_OF_BED`].
+#[derive(Debug, Default)]
+pub struct CueBudget {
+    used: u32,
+    out_of_bed_since: Option<DateTime<Utc>>,
+}
+
+impl CueBudget {
+    pub fn update(&mut self, in_bed: bool, now: DateTime<Utc>) {
+        if in_bed {
+            self.out_of_bed_since = None;
+            return;
+        }
+        let since = *self.out_of_bed_since.get_or_insert(now);
+        if self.used > 0 && now - since >= BUDGET_RESET_OUT_OF_BED {
+            info!("The user left the bed, so the lucid cue budget is reset");
+            self.used = 0;
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.used = 0;
+    }
+
+    pub fn spend(&mut self) {
+        self.used += 1;
+    }
+
+    pub fn remaining(&self, max_cues: u32) -> u32 {
+        max_cues.saturating_sub(self.used)
+    }
+
+    pub fn used(&self) -> u32 {
+        self.used
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LucidDecision {
+    Wait,
+    Play,
+    Decline,
+}
+
+/// What the lucid loop is doing. Part of `GET /status`.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct LucidStatus {
+    /// The loop has started. It never does without the audio feature.
+    pub running: bool,
+    pub enabled: bool,
+    pub last_evaluation: Option<DateTime<Utc>>,
+    pub last_decision: Option<LucidDecision>,
+    /// Why the last evaluation did not play a cue.
+    pub declined: Option<Declined>,
+    pub next_evaluation: Option<DateTime<Utc>>,
+    /// See [`CueBudget`].
+    pub cues_tonight: u32,
+    pub playing: Option<LucidCue>,
+}
+
+impl LucidStatus {
+    /// Records an evaluation at `now`, after which the loop waits for `wait`.
+    fn evaluated(
+        &mut self,
+        now: DateTime<Utc>,
+        decision: LucidDecision,
+        declined: Option<Declined>,
+        wait: Duration,
+    ) {
+        if declined != self.declined {
+            match declined {
+                Some(declined) => debug!("Not playing a lucid cue: {:?}", declined),
+                None => debug!("Lucid cues are no longer declined"),
+            }
+        }
+        self.last_evaluation = Some(now);
+        self.last_decision = Some(decision);
+        self.declined = declined;
+        self.next_evaluation = TimeDelta::from_std(wait).ok().map(|wait| now + wait);
+    }
+}
+
+/// The status of the lucid loop, with the cues of tonight.
+pub fn lucid_status(alarm_state: &AlarmState) -> LucidStatus {
+    let mut status = alarm_state.lucid_status.lock().unwrap().clone();
+    status.cues_tonight = alarm_state.lucid_budget.lock().unwrap().used();
+    status
+}
+
+/// Keeps [`AlarmState::asleep_since`] up to date. Returns when the lucid cues are disabled.
 async fn monitor_sleeping_duration(
     alarm_state: AlarmState,
-    sleeping_start_time: Arc<Mutex<Option<Instant>>>,
-    is_user_in_bed: Arc<SyncedContainer<bool>>,
-    is_significant_movement_in_bed: Arc<SyncedContainer<bool>>,
+    lucid_config: Arc<Container<LucidConfig>>,
+    presence_confidence: Arc<Container<Option<Confidence>>>,
+    is_significant_movement_in_bed: Arc<Container<bool>>,
 ) {
-    loop {
-        let is_user_in_bed = is_user_in_bed.get().unwrap_or(false);
+    while lucid_config.get().is_none_or(|c| c.enabled) {
+        let is_user_in_bed =
+            get_presence_confidence(&presence_confidence) >= MIN_PRESENCE_CONFIDENCE;
         let is_awake = is_significant_movement_in_bed.get().unwrap_or(false);
 
         let alarm_is_active = alarm_state
             .should_start_alarm_soon(TimeDelta::hours(12))
             .is_some();
+        alarm_state
+            .lucid_budget
+            .lock()
+            .unwrap()
+            .update(is_user_in_bed, Utc::now());
 
         if alarm_is_active && is_user_in_bed && !is_awake {
-            let mut data = sleeping_start_time.lock().unwrap();
-            if data.is_none() {
-                println!("Asleep at {}", chrono::Local::now());
-                data.replace(Instant::now());
-            }
+            alarm_state.asleep_since.send_if_modified(|since| {
+                if since.is_some() {
+                    return false;
+                }
+                info!("Asleep at {}", chrono::Local::now());
+                *since = Some(Utc::now());
+                true
+            });
         } else if !alarm_is_active {
-            sleeping_start_time.lock().unwrap().take();
+            alarm_state.asleep_since.send_replace(None);
         }
         tokio::time::sleep(Duration::from_secs(60)).await;
     }
+    info!("Lucid cues are disabled. No longer tracking when the user falls asleep.");
+    alarm_state.asleep_since.send_replace(None);
 }
 
+/// Why a lucid cue was not played.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Declined {
+    Disabled,
+    NotInBed,
+    AlarmPlaying,
+    /// There is no alarm tonight.
+    NoAlarm,
+    /// The cue would not end well before the alarm, see [`alarm_margin`].
+    TooCloseToAlarm,
+    NotAsleepLongEnough,
+    OutsideWindow,
+    /// See [`LucidConfig::min_cue_spacing_minutes`].
+    TooSoonAfterLastCue,
+    /// Random cues wait for the user to move, except at the last attempt.
+    NoMovement,
+    NotRem,
+    OverBudget,
+}
+
+/// Decides whether a cue may start at `now`, or why not. Only if the presence confidence is at least `min_presence_confidence`.
+///
+/// `next_alarm` is the alarm of tonight, if any. No cue is started within `alarm_margin` of it, so that the cue has ended
+/// before the alarm starts, and none while the alarm is playing.
+#[allow(clippy::too_many_arguments)]
 fn should_start_lucid_sounds(
-    is_user_in_bed: bool,
-    alarm_is_active: bool,
-    user_is_waking_up_soon: bool,
+    presence_confidence: f32,
+    min_presence_confidence: f32,
+    now: DateTime<Utc>,
+    next_alarm: Option<DateTime<Utc>>,
+    alarm_is_playing: bool,
+    alarm_margin: TimeDelta,
     sleeping_time: Option<Duration>,
     minimum_sleeping_time: Duration,
-) -> bool {
-    is_user_in_bed
-        && alarm_is_active
-        && !user_is_waking_up_soon
-        && sleeping_time
-            .map(|d| d > minimum_sleeping_time)
-            .unwrap_or(false)
+) -> Result<(), Declined> {
+    if presence_confidence < min_presence_confidence {
+        return Err(Declined::NotInBed);
+    }
+    if alarm_is_playing {
+        return Err(Declined::AlarmPlaying);
+    }
+    match next_alarm {
+        None => return Err(Declined::NoAlarm),
+        Some(t) if now + alarm_margin >= t => return Err(Declined::TooCloseToAlarm),
+        Some(_) => {}
+    }
+    if sleeping_time.is_none_or(|d| d <= minimum_sleeping_time) {
+        return Err(Declined::NotAsleepLongEnough);
+    }
+    Ok(())
+}
+
+/// Time to keep free before the alarm: the longest cue, the margin, and how early the alarm may start to wake the user
+/// at a good moment.
+fn alarm_margin(config: &LucidConfig, max_cue_seconds: f32, earliest_minutes: u32) -> TimeDelta {
+    TimeDelta::from_std(config.max_cue_duration(max_cue_seconds) + ALARM_MARGIN).unwrap()
+        + TimeDelta::minutes(earliest_minutes as i64)
 }
 
 #[test]
 fn test_should_start_lucid_sounds() {
-    assert!(!should_start_lucid_sounds(
-        false,
-        false,
-        false,
-        None,
-        Duration::from_secs(0)
-    ));
+    let now = DateTime::parse_from_rfc3339("2024-03-02T04:00:00Z")
+        .unwrap()
+        .with_timezone(&Utc);
+    let alarm = Some(now + TimeDelta::hours(3));
+    let margin = TimeDelta::minutes(50);
+    let asleep = Some(Duration::from_secs(60 * 60 * 2));
+    let min_asleep = Duration::from_secs(60 * 60);
+    let min_confidence = 0.8;
 
-    assert!(should_start_lucid_sounds(
-        true,
-        true,
-        false,
-        Some(Duration::from_secs(60 * 60 * 2)),
-        Duration::from_secs(60 * 60)
-    ));
+    assert_eq!(
+        should_start_lucid_sounds(
+            0.0,
+            min_confidence,
+            now,
+            None,
+            false,
+            margin,
+            None,
+            Duration::from_secs(0)
+        ),
+        Err(Declined::NotInBed)
+    );
+    assert_eq!(
+        should_start_lucid_sounds(
+            1.0,
+            min_confidence,
+            now,
+            alarm,
+            false,
+            margin,
+            asleep,
+            min_asleep
+        ),
+        Ok(())
+    );
+    assert_eq!(
+        should_start_lucid_sounds(
+            1.0,
+            min_confidence,
+            now,
+            alarm,
+            false,
+            margin,
+            Some(Duration::from_secs(60)),
+            min_asleep
+        ),
+        Err(Declined::NotAsleepLongEnough)
+    );
+    // No alarm tonight
+    assert_eq!(
+        should_start_lucid_sounds(
+            1.0,
+            min_confidence,
+            now,
+            None,
+            false,
+            margin,
+            asleep,
+            min_asleep
+        ),
+        Err(Declined::NoAlarm)
+    );
 
-    assert!(!should_start_lucid_sounds(
-        true,
-        true,
-        false,
-        Some(Duration::from_secs(60)),
-        Duration::from_secs(60 * 60)
-    ));
+    // Not when it is unclear whether anyone is in bed
+    for (confidence, start) in [(0.5, false), (0.79, false), (0.8, true)] {
+        assert_eq!(
+            should_start_lucid_sounds(
+                confidence,
+                min_confidence,
+                now,
+                alarm,
+                false,
+                margin,
+                asleep,
+                min_asleep
+            )
+            .is_ok(),
+            start
+        );
+    }
 
-    assert!(!should_start_lucid_sounds(
-        true,
-        true,
-        true,
-        Some(Duration::from_secs(60 * 60 * 2)),
-        Duration::from_secs(60 * 60)
-    ));
+    // The threshold is up to the caller
+    assert_eq!(
+        should_start_lucid_sounds(0.5, 0.4, now, alarm, false, margin, asleep, min_asleep),
+        Ok(())
+    );
+
+    // Never while the alarm is playing, and only if the longest cue ends well before the alarm
+    let config = LucidConfig::default();
+    let margin = alarm_margin(&config, 500.0, 20);
+    assert_eq!(margin, TimeDelta::seconds(500 + 5 * 60 + 20 * 60));
+    let alarm = now + margin;
+    for (start, expected) in [
+        (now - TimeDelta::seconds(1), true),
+        (now, false),
+        (alarm, false),
+        (alarm + TimeDelta::minutes(1), false),
+    ] {
+        assert_eq!(
+            should_start_lucid_sounds(
+                1.0,
+                min_confidence,
+                start,
+                Some(alarm),
+                false,
+                margin,
+                asleep,
+                min_asleep
+            )
+            .is_ok(),
+            expected
+        );
+    }
+    assert_eq!(
+        should_start_lucid_sounds(
+            1.0,
+            min_confidence,
+            now,
+            Some(now + TimeDelta::hours(3)),
+            true,
+            margin,
+            asleep,
+            min_asleep
+        ),
+        Err(Declined::AlarmPlaying)
+    );
+
+    // Voice clips may be as long as the maximum cue duration
+    let voice = LucidConfig {
+        voice_weight: 1.0,
+        ..config.clone()
+    };
+    assert_eq!(
+        voice.max_cue_duration(100.0),
+        Duration::from_secs(2 * 100 + 20)
+    );
+    assert_eq!(config.max_cue_duration(100.0), Duration::from_secs(100));
 }
 
-async fn should_start_lucid_sounds2(
-    alarm_state: AlarmState,
-    sleeping_start_time_data: &Arc<Mutex<Option<Instant>>>,
-    is_user_in_bed: Arc<SyncedContainer<bool>>,
-    is_significant_movement_in_bed: Arc<SyncedContainer<bool>>,
-    minimum_sleeping_time: Duration,
-    require_movement: bool,
-) -> bool {
-    let sleeping_start_time = *sleeping_start_time_data.lock().unwrap();
+/// What [`LucidScheduler`] knows about the user and the alarm when it decides what to do.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LucidInputs {
+    pub now: DateTime<Local>,
+    /// Why the sleep or the alarm do not allow a cue, see [`should_start_lucid_sounds`]. The rest is up to the scheduler.
+    pub declined: Option<Declined>,
+    /// There is significant movement in bed.
+    pub moving: bool,
+    /// Whether the sleep stages look like REM sleep, see [`is_rem_window`]. `None` if they are unknown, or not used.
+    pub rem_window: Option<bool>,
+    /// Cues which may still be played tonight, see [`CueBudget`].
+    pub remaining_cues: u32,
+}
 
-    let is_user_in_bed = is_user_in_bed.get().unwrap_or(false);
+async fn lucid_inputs(
+    alarm_state: &AlarmState,
+    presence_confidence: &Container<Option<Confidence>>,
+    is_significant_movement_in_bed: &Container<bool>,
+    config: &LucidConfig,
+) -> LucidInputs {
+    let asleep_since = *alarm_state.asleep_since.borrow();
+
+    let presence_confidence = get_presence_confidence(presence_confidence);
     let is_significant_movement = is_significant_movement_in_bed.get().unwrap_or(false);
 
-    let alarm_is_active = alarm_state
-        .should_start_alarm_soon(TimeDelta::hours(12))
-        .is_some();
-    let user_is_waking_up_soon = alarm_state
-        .should_start_alarm_soon(TimeDelta::minutes(50))
-        .is_some();
+    let next_alarm = alarm_state.should_start_alarm_soon(TimeDelta::hours(12));
+    let alarm_is_playing = alarm_state.is_playing.get().unwrap_or(false);
+    let alarm_margin = alarm_margin(
+        config,
+        alarm_state.config.get().playback.max_lucid_cue_seconds,
+        alarm_state.wake_window().earliest_minutes,
+    );
 
-    println!("Evaluating lucid effects");
+    debug!("Evaluating lucid effects with {:?}", config);
 
-    let sleeping_time = sleeping_start_time.map(|t| t.elapsed());
-    dbg!(
-        is_user_in_bed,
+    let sleeping_time = asleep_since.map(|t| (Utc::now() - t).to_std().unwrap_or_default());
+    debug!(
+        "Presence confidence {}, moving {}, next alarm {:?}, alarm playing {}, alarm margin {}, asleep for {:?}",
+        presence_confidence,
         is_significant_movement,
-        alarm_is_active,
-        user_is_waking_up_soon,
+        next_alarm,
+        alarm_is_playing,
+        alarm_margin,
         sleeping_time
     );
 
-    if require_movement && !is_significant_movement {
-        return false;
+    let declined = if config.enabled {
+        should_start_lucid_sounds(
+            presence_confidence,
+            MIN_PRESENCE_CONFIDENCE,
+            chrono::Utc::now(),
+            next_alarm,
+            alarm_is_playing,
+            alarm_margin,
+            sleeping_time,
+            Duration::from_secs_f32(config.min_sleep_minutes * 60.0),
+        )
+        .err()
+    } else {
+        Some(Declined::Disabled)
+    };
+    let rem_window = if config.timing == LucidTiming::RemTargeted {
+        sleep_minutes(alarm_state)
+            .await
+            .map(|minutes| is_rem_window(&minutes, config.rem_quiet_minutes as usize))
+    } else {
+        None
+    };
+
+    LucidInputs {
+        now: Local::now(),
+        declined,
+        moving: is_significant_movement,
+        rem_window,
+        remaining_cues: alarm_state
+            .lucid_budget
+            .lock()
+            .unwrap()
+            .remaining(config.max_cues_per_night),
     }
+}
 
-    should_start_lucid_sounds(
-        is_user_in_bed,
-        alarm_is_active,
-        user_is_waking_up_soon,
-        sleeping_time,
-        minimum_sleeping_time,
-    )
+/// What the scheduler decided to do next.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LucidAction {
+    Wait(Duration),
+    Play(LucidCue, LucidTiming),
+    /// The cue would have been played, but the budget of the night is used up.
+    OverBudget(LucidCue, LucidTiming),
 }
 
-fn play_lucid_sounds(
-    rng: &mut StdRng,
-    lucid_music_volume: &SyncedContainer<i32>,
-    lucid_sfx_volume: &SyncedContainer<i32>,
-) {
-    if rng.gen_bool(0.2) {
-        let duration = 150.0 * rng.gen::<f32>();
-        let fadeout_duration = 10.0;
-        let fadein_duration = 5.0;
-        println!(
-            "Starting lucid music. Duration={duration} at {}",
-            chrono::Local::now(),
-        );
-        match random_alarm_sound(Path::new("./sounds/lucid")) {
-            Ok(path) => {
-                dbg!(&path);
-                crate::alarm::play_audio(
-                    &path,
-                    |t| {
-                        let volume = lucid_music_volume.get().unwrap() as f32 / 100.0;
-                        let v = volume
-                            * fadein(t, fadein_duration)
-                            * fadeout(t - (duration - fadeout_duration), fadeout_duration);
-                        if t < duration {
-                            Some(v)
-                        } else {
-                            None
-                        }
-                    },
-                    true,
-                );
-            }
-            Err(e) => {
-                eprintln!("Error: {}", e);
-            }
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SchedulePhase {
+    /// At the start of a period.
+    Idle,
+    /// A cue was played during REM sleep, so the rest of the period is skipped.
+    AfterRemCue,
+    /// At the random time in the period. A cue is played when the user moves, or at the last attempt.
+    Trying { attempt: u32 },
+}
+
+/// Decides when lucid cues are played, and which kinds. Keeps the cues within the window, apart by the spacing, and
+/// within the budget of the night.
+///
+/// It does not read the clock or play anything, so with a fixed seed the same inputs give the same schedule.
+pub struct LucidScheduler {
+    rng: StdRng,
+    phase: SchedulePhase,
+    last_cue_end: Option<DateTime<Local>>,
+    declined: Option<Declined>,
+}
+
+impl LucidScheduler {
+    /// Attempts at the random time in a period, a minute apart.
+    const RANDOM_ATTEMPTS: u32 = 20;
+
+    pub fn new(seed: u64) -> LucidScheduler {
+        LucidScheduler {
+            rng: StdRng::seed_from_u64(seed),
+            phase: SchedulePhase::Idle,
+            last_cue_end: None,
+            declined: None,
         }
-    } else {
-        let duration = 500.0;
-        println!("Starting lucid effects at {}.", chrono::Local::now());
-        match random_alarm_sound(Path::new("./sounds/lucid_sfx")) {
-            Ok(path) => {
-                dbg!(&path);
-                crate::alarm::play_audio(
-                    &path,
-                    |t| {
-                        let volume = lucid_sfx_volume.get().unwrap() as f32 / 100.0;
-                        let v = volume;
-                        if t < duration {
-                            Some(v)
-                        } else {
-                            None
+    }
+
+    /// Why the last call to [`Self::next`] did not play a cue. `None` if it played one, or was not about to.
+    pub fn declined(&self) -> Option<Declined> {
+        self.declined
+    }
+
+    /// Must be called when a cue has ended, for the spacing.
+    pub fn cue_finished(&mut self, at: DateTime<Local>) {
+        self.last_cue_end = Some(at);
+    }
+
+    /// The next action, given the inputs at the current time. Waits must be completed before the next call.
+    pub fn next(&mut self, config: &LucidConfig, inputs: LucidInputs) -> LucidAction {
+        self.declined = None;
+        let period = Duration::from_secs_f64(config.period_minutes as f64 * 60.0);
+        match self.phase {
+            SchedulePhase::AfterRemCue => {
+                // At most one cue per REM period
+                self.phase = SchedulePhase::Idle;
+                LucidAction::Wait(period)
+            }
+            SchedulePhase::Idle => {
+                if config.timing == LucidTiming::RemTargeted {
+                    if let Some(rem_window) = inputs.rem_window {
+                        let allowed = self
+                            .check(config, &inputs)
+                            .and(rem_window.then_some(()).ok_or(Declined::NotRem));
+                        if let Err(declined) = allowed {
+                            self.declined = Some(declined);
+                            return LucidAction::Wait(REM_POLL_INTERVAL);
                         }
-                    },
-                    false,
+                        self.phase = SchedulePhase::AfterRemCue;
+                        return self.play(config, inputs, LucidTiming::RemTargeted);
+                    }
+                    warn!("The sleep stages are unknown, so the lucid cues are timed randomly");
+                }
+                self.phase = SchedulePhase::Trying { attempt: 0 };
+                LucidAction::Wait(period.mul_f64(self.rng.gen::<f64>()))
+            }
+            SchedulePhase::Trying { attempt } => {
+                let last = attempt + 1 >= Self::RANDOM_ATTEMPTS;
+                let allowed = self.check(config, &inputs).and(
+                    (inputs.moving || last)
+                        .then_some(())
+                        .ok_or(Declined::NoMovement),
                 );
+                match allowed {
+                    Ok(()) => {
+                        self.phase = SchedulePhase::Idle;
+                        return self.play(config, inputs, LucidTiming::Random);
+                    }
+                    Err(declined) => self.declined = Some(declined),
+                }
+                self.phase = if last {
+                    SchedulePhase::Idle
+                } else {
+                    SchedulePhase::Trying {
+                        attempt: attempt + 1,
+                    }
+                };
+                LucidAction::Wait(period.min(Duration::from_secs(60)))
             }
-            Err(e) => {
-                eprintln!("Error: {}", e);
+        }
+    }
+
+    /// Whether a cue may be played, apart from the movement, the sleep stages and the budget.
+    fn check(&self, config: &LucidConfig, inputs: &LucidInputs) -> Result<(), Declined> {
+        if let Some(declined) = inputs.declined {
+            return Err(declined);
+        }
+        if config
+            .window
+            .is_some_and(|w| !w.contains(inputs.now.time()))
+        {
+            return Err(Declined::OutsideWindow);
+        }
+        let spacing = TimeDelta::milliseconds((config.min_cue_spacing_minutes * 60_000.0) as i64);
+        if self
+            .last_cue_end
+            .is_some_and(|end| inputs.now - end < spacing)
+        {
+            return Err(Declined::TooSoonAfterLastCue);
+        }
+        Ok(())
+    }
+
+    fn play(
+        &mut self,
+        config: &LucidConfig,
+        inputs: LucidInputs,
+        timing: LucidTiming,
+    ) -> LucidAction {
+        let cue = config.choose_cue(&mut self.rng);
+        if inputs.remaining_cues == 0 {
+            self.declined = Some(Declined::OverBudget);
+            LucidAction::OverBudget(cue, timing)
+        } else {
+            LucidAction::Play(cue, timing)
+        }
+    }
+}
+
+/// Plays the cues which [`LucidScheduler`] decides on.
+pub trait CuePlayer {
+    /// Plays one cue. Returns false if it could not be started, e.g. since the alarm is playing.
+    fn play(&mut self, cue: LucidCue, timing: LucidTiming, config: &LucidConfig) -> bool;
+}
+
+/// Plays the cues on the speakers.
+struct AudioCuePlayer {
+    alarm_state: AlarmState,
+    volumes: LucidVolumes,
+    rng: StdRng,
+}
+
+impl CuePlayer for AudioCuePlayer {
+    fn play(&mut self, cue: LucidCue, timing: LucidTiming, config: &LucidConfig) -> bool {
+        play_lucid_sounds(
+            &self.alarm_state,
+            &mut self.rng,
+            &self.volumes,
+            config,
+            cue,
+            timing,
+        )
+    }
+}
+
+/// Volume containers of the lucid cues, from 0 to 100.
+#[derive(Clone)]
+pub struct LucidVolumes {
+    pub music: Arc<Container<i32>>,
+    pub sfx: Arc<Container<i32>>,
+    pub voice: Arc<Container<i32>>,
+    pub binaural: Arc<Container<i32>>,
+}
+
+/// Logs whether the cues will be played, and how. Called at startup, and when the cues are enabled or disabled.
+fn log_lucid_state(config: &LucidConfig, volumes: &LucidVolumes) {
+    if !config.enabled {
+        info!("Lucid effects are disabled. Set `enabled` in alarm/lucid_config to turn them on.");
+        return;
+    }
+    info!(
+        "Lucid effects are active, with volumes music {:?}, sfx {:?}, voice {:?}, binaural {:?} and {:?}",
+        volumes.music.get(),
+        volumes.sfx.get(),
+        volumes.voice.get(),
+        volumes.binaural.get(),
+        config
+    );
+}
+
+/// The containers which the lucid cues are played with. Also managed by rocket, for [`trigger_lucid_cue`].
+#[derive(Clone)]
+pub struct LucidSettings {
+    pub volumes: LucidVolumes,
+    pub config: Arc<Container<LucidConfig>>,
+}
+
+/// Plays one cue. Returns false if it was not played, e.g. since the alarm is playing or the playback failed.
+fn play_lucid_sounds(
+    alarm_state: &AlarmState,
+    rng: &mut StdRng,
+    volumes: &LucidVolumes,
+    config: &LucidConfig,
+    cue: LucidCue,
+    timing: LucidTiming,
+) -> bool {
+    let sounds_config = alarm_state.config.get().sounds;
+    if cue == LucidCue::Haptic {
+        return play_haptic_cue(alarm_state, config, timing);
+    }
+    let Some(directory) = cue.directory(&sounds_config) else {
+        return play_binaural_beats(alarm_state, volumes, config, timing);
+    };
+    let Some(lease) = alarm_state.playback.request(PlaybackPriority::LucidCue) else {
+        info!("Not playing lucid sounds, since the alarm is playing");
+        return false;
+    };
+
+    let path = match random_alarm_sound(directory, &sounds_config, None, &[], &[]) {
+        Ok(path) => path,
+        Err(e) => {
+            error!("Could not pick a lucid {:?} sound: {}", cue, e);
+            return false;
+        }
+    };
+    let volume_container = match cue {
+        LucidCue::Music => &volumes.music,
+        LucidCue::Sfx => &volumes.sfx,
+        LucidCue::Voice => &volumes.voice,
+        LucidCue::Binaural => &volumes.binaural,
+        LucidCue::Haptic => unreachable!("haptic cues have no volume"),
+    };
+    let multiplier = scheduled_volume_multiplier(alarm_state, config, cue);
+    let volume = || volume_container.get().unwrap() as f32 / 100.0 * multiplier;
+    let playing = start_cue(
+        alarm_state,
+        cue,
+        timing,
+        crate::sounds::sound_name(&sounds_config.directory, &path),
+        None,
+        volume(),
+    );
+
+    let max_seconds = alarm_state.config.get().playback.max_lucid_cue_seconds;
+    let max_duration = Some(Duration::from_secs_f32(max_seconds));
+    let play = |vol: &mut dyn FnMut(f32) -> Option<f32>, lowpass| {
+        play_cue_file(
+            &path,
+            vol,
+            lowpass,
+            max_duration,
+            &alarm_state.config.get(),
+            &lease,
+        )
+    };
+    let played = with_haptics(alarm_state, config, &lease, || match cue {
+        // Faded out before the maximum duration, rather than cut by it
+        LucidCue::Music => {
+            let duration = (config.max_music_seconds * rng.gen::<f32>()).min(max_seconds);
+            debug!("Playing lucid music for {duration} s");
+            play(
+                &mut |t| cue_envelope(t, duration, config.music_fade).map(|v| volume() * v),
+                true,
+            )
+        }
+        LucidCue::Sfx => {
+            let duration = config.sfx_seconds.min(max_seconds);
+            play(
+                &mut |t| cue_envelope(t, duration, config.sfx_fade).map(|v| volume() * v),
+                false,
+            )
+        }
+        // Never lowpass filtered, so that the words stay intelligible
+        LucidCue::Voice => (0..config.voice_repetitions).all(|i| {
+            if i > 0 {
+                let gap_end = Instant::now() + Duration::from_secs_f32(config.voice_gap_seconds);
+                while Instant::now() < gap_end && !lease.is_cancelled() {
+                    std::thread::sleep(VOICE_GAP_POLL_INTERVAL);
+                }
             }
+            !lease.is_cancelled() && play(&mut |_| Some(volume()), false)
+        }),
+        LucidCue::Binaural | LucidCue::Haptic => {
+            unreachable!("{:?} cues are not played from files", cue)
+        }
+    });
+    finish_cue(
+        alarm_state,
+        playing,
+        !played || lease.is_cancelled(),
+        config,
+    );
+    played
+}
+
+/// Plays the sound file of a cue. Returns false if the playback failed.
+#[cfg(feature = "audio")]
+fn play_cue_file(
+    path: &Path,
+    vol: &mut dyn FnMut(f32) -> Option<f32>,
+    lowpass: bool,
+    max_duration: Option<Duration>,
+    config: &Config,
+    lease: &PlaybackLease,
+) -> bool {
+    crate::alarm::play_audio(
+        path,
+        vol,
+        lowpass,
+        max_duration,
+        config,
+        &mut LatencyTrace::new("lucid cue"),
+        lease,
+    )
+    .map_err(|e| error!("Could not play the lucid cue: {}", e))
+    .is_ok()
+}
+
+/// Sounds cannot be played without audio support, so the cue always fails.
+#[cfg(not(feature = "audio"))]
+fn play_cue_file(
+    path: &Path,
+    _vol: &mut dyn FnMut(f32) -> Option<f32>,
+    _lowpass: bool,
+    _max_duration: Option<Duration>,
+    _config: &Config,
+    _lease: &PlaybackLease,
+) -> bool {
+    error!(
+        "Could not play the lucid cue {}, since the audio feature is disabled",
+        path.display()
+    );
+    false
+}
+
+/// Pulses the vibration motor. Stops when the alarm starts. Returns false if there is no motor or the alarm is playing.
+fn play_haptic_cue(alarm_state: &AlarmState, config: &LucidConfig, timing: LucidTiming) -> bool {
+    let Some(haptics) = &alarm_state.haptics else {
+        warn!("Not playing a haptic cue, since there is no vibration motor. Set haptic.pin in the config.");
+        return false;
+    };
+    let Some(lease) = alarm_state.playback.request(PlaybackPriority::LucidCue) else {
+        info!("Not playing lucid sounds, since the alarm is playing");
+        return false;
+    };
+    let pattern = &config.haptic_pattern;
+    let playing = start_cue(
+        alarm_state,
+        LucidCue::Haptic,
+        timing,
+        format!("{} haptic pulses", pattern.pulses),
+        None,
+        1.0,
+    );
+    let finished = haptics.play(pattern, &|| lease.is_cancelled());
+    finish_cue(alarm_state, playing, !finished, config);
+    true
+}
+
+/// Runs `play`, while also pulsing the vibration motor if [`LucidConfig::haptic_with_audio`] is set.
+///
+/// The pulses stop when `lease` is cancelled, and are waited for before returning.
+fn with_haptics<R>(
+    alarm_state: &AlarmState,
+    config: &LucidConfig,
+    lease: &PlaybackLease,
+    play: impl FnOnce() -> R,
+) -> R {
+    std::thread::scope(|scope| {
+        if let Some(haptics) = alarm_state
+            .haptics
+            .as_deref()
+            .filter(|_| config.haptic_with_audio)
+        {
+            scope.spawn(|| haptics.play(&config.haptic_pattern, &|| lease.is_cancelled()));
+        }
+        play()
+    })
+}
+
+/// Plays binaural beats. They are skipped if the playback is mono, since the beat needs the channels to be apart.
+/// Returns false if they were not played.
+fn play_binaural_beats(
+    alarm_state: &AlarmState,
+    volumes: &LucidVolumes,
+    config: &LucidConfig,
+    timing: LucidTiming,
+) -> bool {
+    let alarm_config = alarm_state.config.get();
+    if alarm_config.playback.mono {
+        warn!("Not playing binaural beats, since the playback is mono");
+        return false;
+    }
+    let Some(lease) = alarm_state.playback.request(PlaybackPriority::LucidCue) else {
+        info!("Not playing lucid sounds, since the alarm is playing");
+        return false;
+    };
+    let multiplier = scheduled_volume_multiplier(alarm_state, config, LucidCue::Binaural);
+    let volume = || volumes.binaural.get().unwrap() as f32 / 100.0 * multiplier;
+    let playing = start_cue(
+        alarm_state,
+        LucidCue::Binaural,
+        timing,
+        format!(
+            "binaural {} + {} Hz",
+            config.binaural_carrier_hz, config.binaural_beat_hz
+        ),
+        Some(BinauralParams {
+            carrier_hz: config.binaural_carrier_hz,
+            beat_hz: config.binaural_beat_hz,
+        }),
+        volume(),
+    );
+    let played = with_haptics(alarm_state, config, &lease, || {
+        play_binaural_source(config, &alarm_config, &volume, &lease)
+    });
+    finish_cue(
+        alarm_state,
+        playing,
+        !played || lease.is_cancelled(),
+        config,
+    );
+    played
+}
+
+/// Generates and plays the beats of [`play_binaural_beats`]. Returns false if the playback failed.
+#[cfg(feature = "audio")]
+fn play_binaural_source(
+    config: &LucidConfig,
+    alarm_config: &Config,
+    volume: &dyn Fn() -> f32,
+    lease: &PlaybackLease,
+) -> bool {
+    let source = BinauralSource::new(
+        config.binaural_carrier_hz,
+        config.binaural_beat_hz,
+        Duration::from_secs_f32(
+            config
+                .binaural_seconds
+                .min(alarm_config.playback.max_lucid_cue_seconds),
+        ),
+        config.binaural_fade,
+    );
+    crate::alarm::play_source(
+        source,
+        |_| Some(volume()),
+        crate::alarm::cutoff_curve(false, &alarm_config.lowpass),
+        Some(Duration::from_secs_f32(
+            alarm_config.playback.max_lucid_cue_seconds,
+        )),
+        alarm_config,
+        &mut LatencyTrace::new("lucid cue"),
+        lease,
+    )
+    .map_err(|e| error!("Could not play the binaural beats: {}", e))
+    .is_ok()
+}
+
+/// Binaural beats cannot be played without audio support, so the cue always fails.
+#[cfg(not(feature = "audio"))]
+fn play_binaural_source(
+    _config: &LucidConfig,
+    _alarm_config: &Config,
+    _volume: &dyn Fn() -> f32,
+    _lease: &PlaybackLease,
+) -> bool {
+    error!("Could not play the binaural beats, since the audio feature is disabled");
+    false
+}
+
+/// Plays one cue right away, whatever the sleep state. The kind is chosen by the weights if `cue` is `None`.
+pub fn play_manual_cue(alarm_state: &AlarmState, settings: &LucidSettings, cue: Option<LucidCue>) {
+    let config = match settings.config.get() {
+        Some(config) if config.validate().is_ok() => config,
+        _ => LucidConfig::default(),
+    };
+    let mut rng = StdRng::from_entropy();
+    let cue = cue.unwrap_or_else(|| config.choose_cue(&mut rng));
+    play_lucid_sounds(
+        alarm_state,
+        &mut rng,
+        &settings.volumes,
+        &config,
+        cue,
+        LucidTiming::Manual,
+    );
+}
+
+/// Plays one lucid cue right away, whatever the sleep state, e.g. to try out the volumes. It is logged as a manual cue.
+///
+/// The kind is chosen by the weights if `cue` is not given. Fails with 409 Conflict while the alarm is playing.
+#[post("/lucid/trigger?<cue>")]
+pub fn trigger_lucid_cue(
+    state: &State<AlarmState>,
+    settings: &State<LucidSettings>,
+    cue: Option<&str>,
+) -> Result<(), (Status, String)> {
+    let cue = cue
+        .map(LucidCue::from_str)
+        .transpose()
+        .map_err(|e| (Status::BadRequest, e))?;
+    if state.is_playing.get().unwrap_or(false) {
+        return Err((Status::Conflict, "The alarm is playing".to_owned()));
+    }
+    let (state, settings) = (state.inner().clone(), settings.inner().clone());
+    tokio::task::spawn_blocking(move || play_manual_cue(&state, &settings, cue));
+    Ok(())
+}
+
+#[derive(Serialize, Debug)]
+pub struct LucidConfigInfo {
+    #[serde(flatten)]
+    pub config: LucidConfig,
+    /// Cues which may still be played tonight, see [`CueBudget`].
+    pub remaining_cues: u32,
+}
+
+/// The lucid config which is in use, and how much of the budget of the night is left.
+#[get("/lucid/config")]
+pub fn get_lucid_config(
+    state: &State<AlarmState>,
+    settings: &State<LucidSettings>,
+) -> Json<LucidConfigInfo> {
+    let config = match settings.config.get() {
+        Some(config) if config.validate().is_ok() => config,
+        _ => LucidConfig::default(),
+    };
+    let remaining_cues = state
+        .lucid_budget
+        .lock()
+        .unwrap()
+        .remaining(config.max_cues_per_night);
+    Json(LucidConfigInfo {
+        config,
+        remaining_cues,
+    })
+}
+
+/// The time of [`run_lucid_loop`], so that a night can be run in tests without waiting for it.
+trait Clock {
+    fn now(&self) -> DateTime<Local>;
+    async fn sleep(&mut self, duration: Duration);
+}
+
+struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+
+    async fn sleep(&mut self, duration: Duration) {
+        tokio::time::sleep(duration).await;
+    }
+}
+
+/// Where [`run_lucid_loop`] gets its config and inputs from, and what it reports to.
+trait LucidEnvironment {
+    /// The config to evaluate the next cue with. `None` stops the loop.
+    async fn config(&mut self) -> Option<LucidConfig>;
+    /// Called at the start, and when the cues are enabled or disabled.
+    fn enabled_changed(&mut self, config: &LucidConfig);
+    /// What the scheduler knows at `now`. Only called while the cues are enabled.
+    async fn inputs(&mut self, config: &LucidConfig, now: DateTime<Local>) -> LucidInputs;
+    /// See [`LucidStatus::evaluated`].
+    fn evaluated(
+        &mut self,
+        now: DateTime<Local>,
+        decision: LucidDecision,
+        declined: Option<Declined>,
+        wait: Duration,
+    );
+    /// A cue was played, see [`CueBudget::spend`].
+    fn spend(&mut self);
+    /// A cue was not played, since the budget of the night is used up.
+    fn over_budget(&mut self, cue: LucidCue, timing: LucidTiming, config: &LucidConfig);
+}
+
+/// The environment of the lucid loop on the device.
+struct AlarmEnvironment {
+    alarm_state: AlarmState,
+    settings: LucidSettings,
+    presence_confidence: Arc<Container<Option<Confidence>>>,
+    is_significant_movement_in_bed: Arc<Container<bool>>,
+    config: LucidConfig,
+    monitor: Option<tokio::task::JoinHandle<()>>,
+}
+
+impl LucidEnvironment for AlarmEnvironment {
+    async fn config(&mut self) -> Option<LucidConfig> {
+        Some(read_lucid_config(&self.settings.config, &mut self.config).await)
+    }
+
+    fn enabled_changed(&mut self, config: &LucidConfig) {
+        log_lucid_state(config, &self.settings.volumes);
+        self.alarm_state.lucid_status.lock().unwrap().enabled = config.enabled;
+    }
+
+    async fn inputs(&mut self, config: &LucidConfig, now: DateTime<Local>) -> LucidInputs {
+        // Stops by itself when the cues are disabled, and is started again when they are enabled
+        if self.monitor.as_ref().is_none_or(|m| m.is_finished()) {
+            self.monitor = Some(tokio::spawn(monitor_sleeping_duration(
+                self.alarm_state.clone(),
+                self.settings.config.clone(),
+                self.presence_confidence.clone(),
+                self.is_significant_movement_in_bed.clone(),
+            )));
+        }
+        LucidInputs {
+            now,
+            ..lucid_inputs(
+                &self.alarm_state,
+                &self.presence_confidence,
+                &self.is_significant_movement_in_bed,
+                config,
+            )
+            .await
         }
-        println!("Lucid effects ended");
+    }
+
+    fn evaluated(
+        &mut self,
+        now: DateTime<Local>,
+        decision: LucidDecision,
+        declined: Option<Declined>,
+        wait: Duration,
+    ) {
+        self.alarm_state.lucid_status.lock().unwrap().evaluated(
+            now.with_timezone(&Utc),
+            decision,
+            declined,
+            wait,
+        );
+    }
+
+    fn spend(&mut self) {
+        self.alarm_state.lucid_budget.lock().unwrap().spend();
+    }
+
+    fn over_budget(&mut self, cue: LucidCue, timing: LucidTiming, config: &LucidConfig) {
+        info!(
+            "Not playing a {} cue, since {} cues have been played tonight",
+            cue.name(),
+            config.max_cues_per_night
+        );
+        self.alarm_state.lucid_log.record_skipped(
+            cue,
+            timing,
+            format!(
+                "the budget of {} cues per night was used up",
+                config.max_cues_per_night
+            ),
+        );
     }
 }
 
+/// Plays lucid cues through the night. The cues are reproducible with the same `seed`, given the same inputs.
 pub async fn start_lucid_effects(
     alarm_state: AlarmState,
-    force_start: bool,
-    lucid_music_volume: Arc<SyncedContainer<i32>>,
-    lucid_sfx_volume: Arc<SyncedContainer<i32>>,
-    is_user_in_bed: Arc<SyncedContainer<bool>>,
-    is_significant_movement_in_bed: Arc<SyncedContainer<bool>>,
+    settings: LucidSettings,
+    presence_confidence: Arc<Container<Option<Confidence>>>,
+    is_significant_movement_in_bed: Arc<Container<bool>>,
+    seed: Option<u64>,
 ) {
-    let sleeping_start_time_data = Arc::new(Mutex::new(None));
-    tokio::spawn(monitor_sleeping_duration(
-        alarm_state.clone(),
-        sleeping_start_time_data.clone(),
-        is_user_in_bed.clone(),
-        is_significant_movement_in_bed.clone(),
+    let seed = seed.unwrap_or_else(rand::random);
+    info!("Scheduling lucid cues with seed {}", seed);
+    let player = AudioCuePlayer {
+        alarm_state: alarm_state.clone(),
+        volumes: settings.volumes.clone(),
+        rng: StdRng::seed_from_u64(seed.wrapping_add(1)),
+    };
+    alarm_state.lucid_status.lock().unwrap().running = true;
+    let mut environment = AlarmEnvironment {
+        alarm_state,
+        settings,
+        presence_confidence,
+        is_significant_movement_in_bed,
+        config: LucidConfig::default(),
+        monitor: None,
+    };
+    run_lucid_loop(
+        LucidScheduler::new(seed),
+        player,
+        &mut SystemClock,
+        &mut environment,
+    )
+    .await;
+}
+
+/// Evaluates the scheduler and plays its cues, until `environment` has no config. The cues are played on the
+/// blocking thread pool, since they block until they are done.
+async fn run_lucid_loop<P: CuePlayer + Send + 'static>(
+    mut scheduler: LucidScheduler,
+    mut player: P,
+    clock: &mut impl Clock,
+    environment: &mut impl LucidEnvironment,
+) {
+    let mut enabled = None;
+    while let Some(current) = environment.config().await {
+        if enabled != Some(current.enabled) {
+            enabled = Some(current.enabled);
+            environment.enabled_changed(&current);
+        }
+        if !current.enabled {
+            environment.evaluated(
+                clock.now(),
+                LucidDecision::Decline,
+                Some(Declined::Disabled),
+                DISABLED_POLL_INTERVAL,
+            );
+            clock.sleep(DISABLED_POLL_INTERVAL).await;
+            continue;
+        }
+
+        let inputs = environment.inputs(&current, clock.now()).await;
+        let action = scheduler.next(&current, inputs);
+        let (decision, wait) = match action {
+            LucidAction::Wait(duration) if scheduler.declined().is_some() => {
+                (LucidDecision::Decline, duration)
+            }
+            LucidAction::Wait(duration) => (LucidDecision::Wait, duration),
+            LucidAction::Play(..) => (LucidDecision::Play, Duration::ZERO),
+            LucidAction::OverBudget(..) => (LucidDecision::Decline, Duration::ZERO),
+        };
+        environment.evaluated(clock.now(), decision, scheduler.declined(), wait);
+        match action {
+            LucidAction::Wait(duration) => clock.sleep(duration).await,
+            LucidAction::Play(cue, timing) => {
+                let config = current.clone();
+                let played;
+                (player, played) = tokio::task::spawn_blocking(move || {
+                    let played = player.play(cue, timing, &config);
+                    (player, played)
+                })
+                .await
+                .expect("Playing the lucid cue panicked");
+                // A cue which could not be started, e.g. since the alarm is playing, does not count
+                if played {
+                    environment.spend();
+                }
+                scheduler.cue_finished(clock.now());
+            }
+            LucidAction::OverBudget(cue, timing) => {
+                environment.over_budget(cue, timing, &current);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_lucid_config() {
+    assert!(LucidConfig::default().validate().is_ok());
+    let invalid = [
+        LucidConfig {
+            voice_weight: -1.0,
+            ..Default::default()
+        },
+        LucidConfig {
+            music_weight: f32::NAN,
+            ..Default::default()
+        },
+        LucidConfig {
+            music_weight: 0.0,
+            sfx_weight: 0.0,
+            ..Default::default()
+        },
+        LucidConfig {
+            voice_repetitions: 0,
+            ..Default::default()
+        },
+        LucidConfig {
+            binaural_beat_hz: 0.0,
+            ..Default::default()
+        },
+        LucidConfig {
+            sfx_fade: Fade {
+                in_seconds: -1.0,
+                out_seconds: 10.0,
+            },
+            ..Default::default()
+        },
+        LucidConfig {
+            sfx_seconds: 0.0,
+            ..Default::default()
+        },
+        LucidConfig {
+            period_minutes: f32::INFINITY,
+            ..Default::default()
+        },
+        LucidConfig {
+            min_sleep_minutes: -1.0,
+            ..Default::default()
+        },
+        LucidConfig {
+            timing: LucidTiming::Manual,
+            ..Default::default()
+        },
+    ];
+    for config in invalid {
+        assert!(config.validate().is_err(), "{config:?}");
+    }
+}
+
+#[test]
+fn test_choose_cue() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let config = LucidConfig {
+        music_weight: 1.0,
+        sfx_weight: 0.0,
+        voice_weight: 3.0,
+        ..Default::default()
+    };
+    let voice = (0..1000)
+        .map(|_| config.choose_cue(&mut rng))
+        .inspect(|cue| assert_ne!(*cue, LucidCue::Sfx))
+        .filter(|cue| *cue == LucidCue::Voice)
+        .count();
+    assert!((700..800).contains(&voice), "{voice}");
+
+    assert_eq!("SFX".parse(), Ok(LucidCue::Sfx));
+    assert!("noise".parse::<LucidCue>().is_err());
+}
+
+#[test]
+fn test_is_rem_window() {
+    let quiet = SleepMinute {
+        asleep: true,
+        moving: false,
+    };
+    let twitch = SleepMinute {
+        asleep: true,
+        moving: true,
+    };
+    let awake = SleepMinute {
+        asleep: false,
+        moving: true,
+    };
+    let night = |parts: &[(SleepMinute, usize)]| {
+        parts
+            .iter()
+            .flat_map(|&(m, n)| std::iter::repeat_n(m, n))
+            .collect::<Vec<_>>()
+    };
+
+    // Small movements after an hour of quiet sleep
+    let mut rem = night(&[
+        (quiet, 30),
+        (twitch, 1),
+        (quiet, 30),
+        (twitch, 2),
+        (quiet, 1),
+        (twitch, 1),
+    ]);
+    assert!(is_rem_window(&rem, 60));
+    // A single movement is turning over
+    assert!(!is_rem_window(&night(&[(quiet, 64), (twitch, 1)]), 60));
+    // Not long enough asleep
+    assert!(!is_rem_window(&rem, 62));
+    // Not if the quiet sleep was restless, or the user is awake
+    assert!(!is_rem_window(
+        &night(&[(quiet, 50), (twitch, 10), (twitch, 5)]),
+        60
     ));
+    rem.push(awake);
+    assert!(!is_rem_window(&rem, 60));
+    assert!(!is_rem_window(&[], 60));
+}
 
-    let minimum_sleeping_time = Duration::from_secs(60 * 90);
-    let period_secs = 60.0 * 60.0;
-    let mut rng = rand::rngs::StdRng::from_entropy();
+#[test]
+fn test_time_window() {
+    let time = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+    let early = TimeWindow {
+        start: time(3, 0),
+        end: time(6, 30),
+    };
+    assert!(!early.contains(time(2, 59)));
+    assert!(early.contains(time(3, 0)));
+    assert!(early.contains(time(6, 29)));
+    assert!(!early.contains(time(6, 30)));
+    assert!(!early.contains(time(23, 30)));
 
-    loop {
-        let time = Duration::from_secs_f64(rng.gen::<f64>() * period_secs);
+    let overnight = TimeWindow {
+        start: time(23, 0),
+        end: time(2, 0),
+    };
+    assert!(overnight.contains(time(23, 30)));
+    assert!(overnight.contains(time(0, 0)));
+    assert!(overnight.contains(time(1, 59)));
+    assert!(!overnight.contains(time(2, 0)));
+    assert!(!overnight.contains(time(12, 0)));
 
-        if !force_start {
-            tokio::time::sleep(time).await;
-        }
+    let empty = TimeWindow {
+        start: time(4, 0),
+        end: time(4, 0),
+    };
+    assert!(empty.contains(time(12, 0)));
+    assert_eq!(early.duration(), TimeDelta::minutes(210));
+    assert_eq!(overnight.duration(), TimeDelta::hours(3));
+    assert_eq!(empty.duration(), TimeDelta::hours(24));
 
-        let tries = 20;
-        for i in 0..tries {
-            let should_start = should_start_lucid_sounds2(
-                alarm_state.clone(),
-                &sleeping_start_time_data,
-                is_user_in_bed.clone(),
-                is_significant_movement_in_bed.clone(),
-                minimum_sleeping_time,
-                i < tries - 1, // Require movement, unless it's the last try
-            )
-            .await;
-            dbg!(should_start);
+    let config: LucidConfig =
+        serde_json::from_str(r#"{"window": {"start": "03:00", "end": "06:30"}}"#).unwrap();
+    assert_eq!(config.window, Some(early));
+}
 
-            if should_start || force_start {
-                play_lucid_sounds(&mut rng, &lucid_music_volume, &lucid_sfx_volume);
-                break;
-            }
+#[test]
+fn test_cue_envelope() {
+    let fade = Fade {
+        in_seconds: 5.0,
+        out_seconds: 10.0,
+    };
+    assert_eq!(cue_envelope(0.0, 100.0, fade), Some(0.0));
+    assert_eq!(cue_envelope(2.5, 100.0, fade), Some(0.5));
+    assert_eq!(cue_envelope(5.0, 100.0, fade), Some(1.0));
+    assert_eq!(cue_envelope(90.0, 100.0, fade), Some(1.0));
+    assert_eq!(cue_envelope(95.0, 100.0, fade), Some(0.5));
+    assert!(cue_envelope(99.99, 100.0, fade).unwrap() < 1e-4);
+    assert_eq!(cue_envelope(100.0, 100.0, fade), None);
+    // Rises, and then falls, smoothly
+    let volumes = (0..1000)
+        .map(|i| cue_envelope(i as f32 * 0.1, 100.0, fade).unwrap())
+        .collect::<Vec<_>>();
+    assert!(volumes[..50].windows(2).all(|w| w[0] <= w[1]));
+    assert!(volumes[900..].windows(2).all(|w| w[0] >= w[1]));
+
+    // Without fades, the volume is constant until the end
+    let none = Fade {
+        in_seconds: 0.0,
+        out_seconds: 0.0,
+    };
+    assert_eq!(cue_envelope(0.0, 10.0, none), Some(1.0));
+    assert_eq!(cue_envelope(9.99, 10.0, none), Some(1.0));
+    assert_eq!(cue_envelope(10.0, 10.0, none), None);
+}
+
+#[test]
+fn test_volume_multiplier() {
+    let point = |hours, multiplier| VolumePoint { hours, multiplier };
+    let schedule = [point(2.0, 0.5), point(4.0, 1.0), point(6.0, 0.8)];
+    assert_eq!(volume_multiplier(&schedule, None), 1.0);
+    assert_eq!(volume_multiplier(&[], Some(3.0)), 1.0);
+    assert_eq!(volume_multiplier(&schedule, Some(0.0)), 0.5);
+    assert_eq!(volume_multiplier(&schedule, Some(2.0)), 0.5);
+    assert_eq!(volume_multiplier(&schedule, Some(3.0)), 0.75);
+    assert_eq!(volume_multiplier(&schedule, Some(4.0)), 1.0);
+    assert!((volume_multiplier(&schedule, Some(5.0)) - 0.9).abs() < 1e-6);
+    assert_eq!(volume_multiplier(&schedule, Some(9.0)), 0.8);
+    assert_eq!(volume_multiplier(&[point(1.0, 0.3)], Some(5.0)), 0.3);
 
-            tokio::time::sleep(Duration::from_secs_f64(60.0f64.min(period_secs))).await;
+    let unsorted = LucidConfig {
+        volume_schedule: vec![point(4.0, 1.0), point(2.0, 0.5)],
+        ..Default::default()
+    };
+    assert!(unsorted.validate().is_err());
+}
+
+#[test]
+fn test_lucid_scheduler() {
+    use chrono::TimeZone;
+
+    /// Records the cues, and when they were played by the mock clock.
+    #[derive(Default)]
+    struct Recorder {
+        now: Duration,
+        played: Vec<(Duration, LucidCue, LucidTiming)>,
+    }
+    impl CuePlayer for Recorder {
+        fn play(&mut self, cue: LucidCue, timing: LucidTiming, _config: &LucidConfig) -> bool {
+            self.played.push((self.now, cue, timing));
+            // Every cue lasts 5 minutes
+            self.now += Duration::from_secs(5 * 60);
+            true
         }
     }
+    let minutes = |m: u64| Duration::from_secs(m * 60);
+    // A scripted night, from midnight to 08:00
+    let midnight = Local.with_ymd_and_hms(2024, 3, 2, 0, 0, 0).unwrap();
+    let run = |seed, config: &LucidConfig, inputs: &dyn Fn(Duration) -> LucidInputs| {
+        let mut scheduler = LucidScheduler::new(seed);
+        let mut budget = CueBudget::default();
+        let mut player = Recorder::default();
+        let at = |t| midnight + TimeDelta::from_std(t).unwrap();
+        while player.now < minutes(8 * 60) {
+            let inputs = LucidInputs {
+                now: at(player.now),
+                remaining_cues: budget.remaining(config.max_cues_per_night),
+                ..inputs(player.now)
+            };
+            match scheduler.next(config, inputs) {
+                LucidAction::Wait(duration) => player.now += duration,
+                LucidAction::Play(cue, timing) => {
+                    if player.play(cue, timing, config) {
+                        budget.spend();
+                    }
+                    scheduler.cue_finished(at(player.now));
+                }
+                LucidAction::OverBudget(..) => {}
+            }
+        }
+        player.played
+    };
+    let inputs = |allowed: bool, moving, rem_window| LucidInputs {
+        now: midnight,
+        declined: (!allowed).then_some(Declined::NotInBed),
+        moving,
+        rem_window,
+        remaining_cues: 0,
+    };
+    let starts = |played: &[(Duration, LucidCue, LucidTiming)]| {
+        played.iter().map(|p| p.0).collect::<Vec<_>>()
+    };
+
+    // REM sleep from 30 to 40 and from 120 to 130 minutes. One cue per REM period.
+    let config = LucidConfig::default();
+    let rem = |t: Duration| {
+        let m = t.as_secs() / 60;
+        inputs(
+            true,
+            false,
+            Some((30..40).contains(&m) || (120..130).contains(&m)),
+        )
+    };
+    let played = run(0, &config, &rem);
+    assert_eq!(starts(&played), [minutes(30), minutes(120)]);
+    assert!(played.iter().all(|p| p.2 == LucidTiming::RemTargeted));
+
+    // Random times, without movement the cue is played at the last attempt
+    let config = LucidConfig {
+        timing: LucidTiming::Random,
+        period_minutes: 60.0,
+        max_cues_per_night: 100,
+        ..Default::default()
+    };
+    let still = |_| inputs(true, false, None);
+    let played = run(1, &config, &still);
+    assert!(played.len() >= 4, "{played:?}");
+    assert!(played[0].0 >= minutes(19) && played[0].0 < minutes(60 + 19));
+    // The spacing holds, however the random times fall
+    assert!(starts(&played)
+        .windows(2)
+        .all(|w| w[1] - w[0] >= minutes(5 + 45)));
+    assert!(played.iter().all(|p| p.2 == LucidTiming::Random));
+    // The same seed gives the same schedule, and another seed a different one
+    assert_eq!(run(1, &config, &still), played);
+    assert_ne!(run(2, &config, &still), played);
+    // With movement it is played right away
+    let moving = |_| inputs(true, true, None);
+    let played_moving = run(1, &config, &moving);
+    assert_ne!(played_moving, played);
+    assert!(starts(&played_moving)
+        .windows(2)
+        .all(|w| w[1] - w[0] >= minutes(5 + 45)));
+
+    // Nothing is played when it's not allowed, or when there is no budget
+    assert!(run(1, &config, &|_| inputs(false, true, Some(true))).is_empty());
+    let no_budget = LucidConfig {
+        max_cues_per_night: 0,
+        ..config.clone()
+    };
+    assert!(run(1, &no_budget, &moving).is_empty());
+
+    // The window, the spacing and the budget together
+    let config = LucidConfig {
+        timing: LucidTiming::Random,
+        period_minutes: 30.0,
+        window: Some(TimeWindow {
+            start: NaiveTime::from_hms_opt(1, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(5, 0, 0).unwrap(),
+        }),
+        ..Default::default()
+    };
+    for seed in 0..20 {
+        let played = starts(&run(seed, &config, &moving));
+        assert_eq!(played.len(), 3, "{played:?}");
+        assert!(played
+            .iter()
+            .all(|t| (minutes(60)..minutes(5 * 60)).contains(t)));
+        assert!(played.windows(2).all(|w| w[1] - w[0] >= minutes(5 + 45)));
+    }
+
+    // Tells why it declined
+    let mut scheduler = LucidScheduler::new(0);
+    let config = LucidConfig::default();
+    let rem = |rem, remaining_cues| LucidInputs {
+        remaining_cues,
+        ..inputs(true, false, Some(rem))
+    };
+    assert_eq!(
+        scheduler.next(&config, rem(false, 1)),
+        LucidAction::Wait(REM_POLL_INTERVAL)
+    );
+    assert_eq!(scheduler.declined(), Some(Declined::NotRem));
+    assert!(matches!(
+        scheduler.next(&config, rem(true, 0)),
+        LucidAction::OverBudget(_, LucidTiming::RemTargeted)
+    ));
+    assert_eq!(scheduler.declined(), Some(Declined::OverBudget));
+    assert_eq!(
+        scheduler.next(&config, rem(true, 1)),
+        LucidAction::Wait(Duration::from_secs(60 * 60))
+    );
+    assert_eq!(scheduler.declined(), None);
+
+    let start = Utc::now();
+    let at = |m| start + TimeDelta::minutes(m);
+    let mut budget = CueBudget::default();
+    budget.spend();
+    budget.spend();
+    assert_eq!(budget.remaining(3), 1);
+    // A short time out of bed, like a bathroom visit, is still the same night
+    budget.update(false, at(0));
+    budget.update(false, at(10));
+    budget.update(true, at(11));
+    budget.update(false, at(40));
+    assert_eq!(budget.remaining(3), 1);
+    budget.update(false, at(70));
+    assert_eq!(budget.remaining(3), 3);
 }
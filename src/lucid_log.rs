@@ -0,0 +1,389 @@
+//! Every lucid cue which was played, with the notes the user adds in the morning, to correlate the cues with dream recall.
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chrono::{DateTime, Local, TimeDelta, TimeZone, Utc};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+
+use crate::json_log::JsonLog;
+use crate::lucid::{LucidCue, LucidTiming};
+use crate::AlarmState;
+
+pub const LUCID_LOG_FILE: &str = "./lucid_log.jsonl";
+pub const LUCID_NOTES_FILE: &str = "./lucid_notes.jsonl";
+pub const LUCID_DISRUPTIONS_FILE: &str = "./lucid_disruptions.jsonl";
+
+/// Frequencies of generated binaural beats.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct BinauralParams {
+    pub carrier_hz: f32,
+    pub beat_hz: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AwakeningKind {
+    /// A significant movement event, see [`crate::movement_events`].
+    Movement,
+    BedExit,
+}
+
+/// Something which suggests that the user woke up.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Awakening {
+    pub kind: AwakeningKind,
+    pub time: DateTime<Utc>,
+}
+
+/// The first awakening while a cue played from `start` to `end`, or within `window` after it.
+pub fn find_disruption(
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    window: TimeDelta,
+    awakenings: &[Awakening],
+) -> Option<Awakening> {
+    awakenings
+        .iter()
+        .filter(|a| start <= a.time && a.time <= end + window)
+        .min_by_key(|a| a.time)
+        .copied()
+}
+
+/// One lucid cue which was played.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LucidCueRecord {
+    /// Increases by one for every cue, also across restarts.
+    pub id: u64,
+    pub time: DateTime<Utc>,
+    pub cue: LucidCue,
+    pub timing: LucidTiming,
+    /// The sound, relative to the alarm sound directory if it is inside it. `None` for generated cues.
+    pub sound: Option<String>,
+    #[serde(default)]
+    pub binaural: Option<BinauralParams>,
+    /// How long the cue played, including the gaps between repetitions.
+    pub duration_seconds: f32,
+    /// Volume from 0 to 1 when the cue started.
+    pub volume: f32,
+    /// Stopped early, because something more important started playing or the playback failed.
+    pub aborted: bool,
+    /// Why the cue was not played at all, like when the budget of the night was used up.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skipped: Option<String>,
+    /// The last note which was added in the morning. Stored separately, see [`LucidNote`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    /// What suggested that the cue woke the user up. Stored separately, see [`LucidDisruption`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disruption: Option<Awakening>,
+}
+
+/// A note about how a cue went, like "became lucid", "woke up" or "no effect".
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LucidNote {
+    pub id: u64,
+    pub time: DateTime<Utc>,
+    pub note: String,
+}
+
+/// Found some time after the cue, once the window for awakenings has passed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct LucidDisruption {
+    pub id: u64,
+    pub awakening: Awakening,
+}
+
+/// The cues, the notes and the disruptions, in separate logs, so that all can be appended to.
+pub struct LucidLog {
+    cues: JsonLog<LucidCueRecord>,
+    notes: JsonLog<LucidNote>,
+    disruptions: JsonLog<LucidDisruption>,
+    next_id: AtomicU64,
+}
+
+impl LucidLog {
+    /// Continues with the ids after the cues in `cues`.
+    pub fn new(cues: &Path, notes: &Path, disruptions: &Path) -> LucidLog {
+        let cues = JsonLog::new(cues);
+        let next_id = cues
+            .entries()
+            .iter()
+            .map(|c: &LucidCueRecord| c.id + 1)
+            .max()
+            .unwrap_or(0);
+        LucidLog {
+            cues,
+            notes: JsonLog::new(notes),
+            disruptions: JsonLog::new(disruptions),
+            next_id: AtomicU64::new(next_id),
+        }
+    }
+
+    /// Reserves the id of a cue which is starting.
+    pub fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn record(&self, cue: &LucidCueRecord) {
+        self.cues.record(cue);
+    }
+
+    /// Records a cue which would have been played at the current time, and why it was not.
+    pub fn record_skipped(&self, cue: LucidCue, timing: LucidTiming, reason: String) {
+        self.record(&LucidCueRecord {
+            id: self.next_id(),
+            time: Utc::now(),
+            cue,
+            timing,
+            sound: None,
+            binaural: None,
+            duration_seconds: 0.0,
+            volume: 0.0,
+            aborted: false,
+            skipped: Some(reason),
+            note: None,
+            disruption: None,
+        });
+    }
+
+    /// The cues since `since`, oldest first, with their notes and disruptions.
+    pub fn cues(&self, since: DateTime<Utc>) -> Vec<LucidCueRecord> {
+        let mut cues = self.cues.entries();
+        cues.retain(|c| c.time >= since);
+        for note in self.notes.entries() {
+            if let Some(cue) = cues.iter_mut().find(|c| c.id == note.id) {
+                cue.note = Some(note.note);
+            }
+        }
+        for disruption in self.disruptions.entries() {
+            if let Some(cue) = cues.iter_mut().find(|c| c.id == disruption.id) {
+                cue.disruption = Some(disruption.awakening);
+            }
+        }
+        cues
+    }
+
+    /// Marks the cue with the id as having woken the user up.
+    pub fn mark_disruptive(&self, id: u64, awakening: Awakening) {
+        self.disruptions.record(&LucidDisruption { id, awakening });
+    }
+
+    /// Adds a note to the cue with the id. Returns false if there is no such cue.
+    pub fn annotate(&self, id: u64, note: &str) -> bool {
+        if !self.cues.entries().iter().any(|c| c.id == id) {
+            return false;
+        }
+        self.notes.record(&LucidNote {
+            id,
+            time: Utc::now(),
+            note: note.to_owned(),
+        });
+        true
+    }
+}
+
+/// The noon at which the earliest of the last `nights` nights started. Nights go from noon to noon in local time.
+fn first_night_start<Tz: TimeZone>(now: DateTime<Utc>, nights: u32, tz: &Tz) -> DateTime<Utc> {
+    let today = (now.with_timezone(tz).naive_local() - TimeDelta::hours(12)).date();
+    let noon = (today - TimeDelta::days(nights.max(1) as i64 - 1))
+        .and_hms_opt(12, 0, 0)
+        .unwrap();
+    tz.from_local_datetime(&noon)
+        .earliest()
+        .map(|t| t.with_timezone(&Utc))
+        .unwrap_or_else(|| noon.and_utc())
+}
+
+/// The cues of the last `nights` nights, 7 by default, oldest first.
+#[get("/lucid/log?<nights>")]
+pub fn get_lucid_log(state: &State<AlarmState>, nights: Option<u32>) -> Json<Vec<LucidCueRecord>> {
+    let nights = nights.unwrap_or(7).min(100 * 365);
+    Json(
+        state
+            .lucid_log
+            .cues(first_night_start(Utc::now(), nights, &Local)),
+    )
+}
+
+/// How often the cues of one kind woke the user up.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CueStats {
+    pub cue: LucidCue,
+    pub cues: usize,
+    pub disruptive: usize,
+    /// `None` if there were no cues.
+    pub disruption_rate: Option<f32>,
+}
+
+/// The stats of each kind of cue. Manual cues are left out, since they are usually played while awake, and so are
+/// skipped cues.
+pub fn cue_stats(cues: &[LucidCueRecord]) -> Vec<CueStats> {
+    LucidCue::ALL
+        .into_iter()
+        .map(|kind| {
+            let of_kind = cues
+                .iter()
+                .filter(|c| c.cue == kind && c.timing != LucidTiming::Manual && c.skipped.is_none())
+                .collect::<Vec<_>>();
+            let disruptive = of_kind.iter().filter(|c| c.disruption.is_some()).count();
+            CueStats {
+                cue: kind,
+                cues: of_kind.len(),
+                disruptive,
+                disruption_rate: (!of_kind.is_empty())
+                    .then(|| disruptive as f32 / of_kind.len() as f32),
+            }
+        })
+        .collect()
+}
+
+/// How often each kind of cue woke the user up during the last `nights` nights, 30 by default.
+#[get("/lucid/stats?<nights>")]
+pub fn get_lucid_stats(state: &State<AlarmState>, nights: Option<u32>) -> Json<Vec<CueStats>> {
+    let nights = nights.unwrap_or(30).min(100 * 365);
+    Json(cue_stats(&state.lucid_log.cues(first_night_start(
+        Utc::now(),
+        nights,
+        &Local,
+    ))))
+}
+
+/// Adds a note about how the cue went. A later note replaces the earlier ones.
+#[post("/lucid/log/<id>/annotate", data = "<note>")]
+pub fn annotate_lucid_cue(
+    state: &State<AlarmState>,
+    id: u64,
+    note: Json<String>,
+) -> Result<(), (Status, String)> {
+    if state.lucid_log.annotate(id, &note) {
+        Ok(())
+    } else {
+        Err((Status::NotFound, format!("There is no lucid cue {id}")))
+    }
+}
+
+#[test]
+fn test_lucid_log() {
+    let dir = std::env::temp_dir();
+    let path = |name| dir.join(format!("lucid-{name}-test-{}.jsonl", std::process::id()));
+    let (cues_path, notes_path, disruptions_path) =
+        (path("log"), path("notes"), path("disruptions"));
+    let start = Utc.with_ymd_and_hms(2024, 3, 2, 3, 0, 0).unwrap();
+
+    let log = LucidLog::new(&cues_path, &notes_path, &disruptions_path);
+    let cue = |id, minutes| LucidCueRecord {
+        id,
+        time: start + TimeDelta::minutes(minutes),
+        cue: LucidCue::Voice,
+        timing: LucidTiming::RemTargeted,
+        sound: Some("lucid_voice/reality_check.mp3".to_owned()),
+        binaural: None,
+        duration_seconds: 25.0,
+        volume: 0.2,
+        aborted: false,
+        skipped: None,
+        note: None,
+        disruption: None,
+    };
+    for minutes in [0, 90] {
+        let id = log.next_id();
+        log.record(&cue(id, minutes));
+    }
+    assert!(log.annotate(1, "no effect"));
+    assert!(log.annotate(1, "became lucid"));
+    assert!(!log.annotate(2, "woke up"));
+    let awakening = Awakening {
+        kind: AwakeningKind::BedExit,
+        time: start + TimeDelta::minutes(92),
+    };
+    log.mark_disruptive(1, awakening);
+    assert_eq!(
+        log.cues(start + TimeDelta::minutes(1)),
+        [LucidCueRecord {
+            note: Some("became lucid".to_owned()),
+            disruption: Some(awakening),
+            ..cue(1, 90)
+        }]
+    );
+
+    // Continues with the next id after a restart
+    assert_eq!(
+        LucidLog::new(&cues_path, &notes_path, &disruptions_path).next_id(),
+        2
+    );
+    std::fs::remove_file(&cues_path).unwrap();
+    std::fs::remove_file(&notes_path).unwrap();
+    std::fs::remove_file(&disruptions_path).unwrap();
+
+    let tz = chrono::FixedOffset::east_opt(3600).unwrap();
+    // 04:00 local time belongs to the night which started at noon the day before
+    assert_eq!(
+        first_night_start(start, 2, &tz),
+        Utc.with_ymd_and_hms(2024, 2, 29, 11, 0, 0).unwrap()
+    );
+}
+
+#[test]
+fn test_disruptions() {
+    let start = Utc.with_ymd_and_hms(2024, 3, 2, 3, 0, 0).unwrap();
+    let at = |seconds| start + TimeDelta::seconds(seconds);
+    let awakening = |kind, seconds| Awakening {
+        kind,
+        time: at(seconds),
+    };
+    let window = TimeDelta::minutes(3);
+    // The cue plays from 0 to 100 seconds
+    let find = |awakenings: &[Awakening]| find_disruption(at(0), at(100), window, awakenings);
+
+    assert_eq!(find(&[]), None);
+    // Before the cue, and after the window
+    assert_eq!(
+        find(&[
+            awakening(AwakeningKind::Movement, -10),
+            awakening(AwakeningKind::BedExit, 281)
+        ]),
+        None
+    );
+    // During the cue, and at the end of the window
+    let exit = awakening(AwakeningKind::BedExit, 280);
+    assert_eq!(find(&[exit]), Some(exit));
+    let moved = awakening(AwakeningKind::Movement, 50);
+    assert_eq!(find(&[exit, moved]), Some(moved));
+
+    let cue = |cue, timing, disruption| LucidCueRecord {
+        id: 0,
+        time: start,
+        cue,
+        timing,
+        sound: None,
+        binaural: None,
+        duration_seconds: 100.0,
+        volume: 0.2,
+        aborted: false,
+        skipped: None,
+        note: None,
+        disruption,
+    };
+    let stats = cue_stats(&[
+        cue(LucidCue::Sfx, LucidTiming::Random, Some(moved)),
+        cue(LucidCue::Sfx, LucidTiming::RemTargeted, None),
+        cue(LucidCue::Sfx, LucidTiming::Manual, Some(moved)),
+        LucidCueRecord {
+            skipped: Some("over budget".to_owned()),
+            ..cue(LucidCue::Sfx, LucidTiming::Random, None)
+        },
+    ]);
+    assert_eq!(
+        stats[1],
+        CueStats {
+            cue: LucidCue::Sfx,
+            cues: 2,
+            disruptive: 1,
+            disruption_rate: Some(0.5),
+        }
+    );
+    assert_eq!(stats[0].disruption_rate, None);
+}
@@ -1,14 +1,15 @@
-use brevduva::{SyncStorage, SyncedContainer};
 use machineid_rs::HWIDComponent;
+use rocket::http::Status;
 use rocket::serde::json::Json;
 use rocket::State;
 use serde::{Deserialize, Serialize};
 
 use std::fmt::Debug;
+use std::path::Path;
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
-use tokio::sync::Mutex;
+use tokio::sync::{watch, Mutex};
 
 use chrono::{DateTime, Utc};
 use chrono::{Duration as DateDuration, NaiveDateTime};
@@ -19,29 +20,137 @@ mod filtered_source;
 #[cfg(feature = "audio")]
 mod alarm;
 #[cfg(feature = "audio")]
+mod binaural_source;
+#[cfg(feature = "audio")]
+mod convolution;
+#[cfg(feature = "audio")]
+mod crossfade_source;
+#[cfg(feature = "audio")]
+mod decode_cache;
+#[cfg(feature = "audio")]
+mod downmix_source;
+#[cfg(feature = "audio")]
+mod output;
+#[cfg(feature = "audio")]
 mod precalculated_source;
 
+mod analysis;
+#[cfg(feature = "motion")]
+mod archive;
+#[cfg(feature = "motion")]
+mod autotune;
+mod config;
+mod equalizer;
+mod events;
+#[cfg(feature = "parquet")]
+mod export;
+mod fade;
+mod haptic;
+mod health;
+#[cfg(feature = "motion")]
+mod histogram;
+mod history;
+#[cfg(feature = "motion")]
+mod imu;
+mod json_log;
+mod latency;
+mod local_state;
 pub mod lucid;
+mod lucid_log;
+mod metrics;
+#[cfg(feature = "motion")]
+mod motion_log;
+#[cfg(feature = "motion")]
+mod movement_events;
+mod mqtt;
+#[cfg(feature = "motion")]
+mod nights;
+mod offline;
+mod playback;
+mod reality_check;
+#[cfg(feature = "motion")]
+mod respiration;
+mod shutdown;
+#[cfg(feature = "sqlite")]
+mod sleep_db;
 #[cfg(feature = "motion")]
 mod sleep_monitor;
+mod sounds;
+mod sync_health;
+mod wake;
+mod watchdog;
 
 #[macro_use]
 extern crate rocket;
 
 #[derive(Clone)]
 pub struct AlarmState {
-    inner: Arc<SyncedContainer<InnerAlarmState>>,
-    last_played: Arc<SyncedContainer<LastPlayed>>,
+    inner: Arc<offline::Container<InnerAlarmState>>,
+    last_played: Arc<offline::Container<LastPlayed>>,
     #[cfg(feature = "motion")]
     sleep_monitor: Arc<Mutex<SleepMonitorState>>,
-    #[allow(dead_code)]
-    storage: SyncStorage,
-    is_playing: Arc<SyncedContainer<bool>>,
-    #[allow(dead_code)]
-    is_user_in_bed: Arc<SyncedContainer<bool>>,
+    #[cfg(feature = "motion")]
+    bed_log: Arc<json_log::JsonLog<nights::BedTransition>>,
+    #[cfg(feature = "motion")]
+    sleep_summaries: Arc<json_log::JsonLog<nights::SleepSummary>>,
+    /// Score of the last night, from 0 to 100. See [`nights::summarize`].
+    #[cfg(feature = "motion")]
+    sleep_score: Arc<offline::Container<Option<u8>>>,
+    is_playing: Arc<offline::Container<bool>>,
+    /// False while it is unknown, see [`AlarmState::presence_known`].
+    is_user_in_bed: Arc<offline::Container<bool>>,
+    /// False while the presence is unknown, like when the sleep monitor is paused or there is no sensor.
+    presence_known: Arc<offline::Container<bool>>,
+    /// See [`config::DismissalPolicy::MustLeaveBed`].
+    bed_exit: Arc<std::sync::Mutex<wake::BedExit>>,
+    /// The alarm which is ringing again after it was snoozed, see [`wake::snooze_decision`].
+    #[cfg(feature = "motion")]
+    snooze_chain: Arc<std::sync::Mutex<Option<wake::SnoozeChain>>>,
+    /// Smoothed temperature in °C, from the accelerometer.
+    room_temperature: Arc<offline::Container<Option<Celsius>>>,
+    config: Arc<config::ConfigStore>,
+    history: Arc<history::AlarmHistory>,
+    lucid_log: Arc<lucid_log::LucidLog>,
+    /// When the user fell asleep, as estimated for the lucid cues. `None` while awake, or while the cues are disabled.
+    asleep_since: Arc<watch::Sender<Option<DateTime<Utc>>>>,
+    /// See [`lucid::LucidConfig::max_cues_per_night`].
+    lucid_budget: Arc<std::sync::Mutex<lucid::CueBudget>>,
+    lucid_status: Arc<std::sync::Mutex<lucid::LucidStatus>>,
+    /// See [`sync_health`].
+    sync_health: Arc<std::sync::Mutex<sync_health::SyncHealth>>,
+    /// See [`offline`].
+    local_state: local_state::LocalState,
+    /// `None` if there is no vibration motor, see [`config::HapticConfig`].
+    haptics: Option<Arc<haptic::Haptics>>,
+    reality_checks: Arc<reality_check::RealityCheckLog>,
+    events: events::PlaybackEvents,
+    playback: playback::PlaybackCoordinator,
+}
+
+/// A temperature in °C. Serialized as a plain number.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(transparent)]
+pub struct Celsius(pub f32);
+
+// Containers must be hashable
+impl std::hash::Hash for Celsius {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.to_bits().hash(state);
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Hash)]
+/// A probability from 0 to 1. Serialized as a plain number.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(transparent)]
+pub struct Confidence(pub f32);
+
+impl std::hash::Hash for Confidence {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.to_bits().hash(state);
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default, Hash)]
 pub struct LastPlayed {
     last_played_time: Option<DateTime<Utc>>,
 }
@@ -49,9 +158,28 @@ pub struct LastPlayed {
 #[cfg(feature = "motion")]
 struct SleepMonitorState {
     sleep_monitor: sleep_monitor::SleepMonitor,
-    accelerometer: sleep_monitor::Accelerometer,
+    /// `None` if motion sensing was disabled with `--no-motion`.
+    accelerometer: Option<Box<dyn sleep_monitor::AccelerometerSource>>,
     alarm_is_playing: bool,
-    error_status: Arc<SyncedContainer<Option<String>>>,
+    /// Published by [`sleep_monitor::publish_changes`].
+    error_status: watch::Sender<Option<String>>,
+    /// See [`health::SensorHealth`]. Published by [`sleep_monitor::publish_changes`].
+    sensor_health: watch::Sender<Option<health::SensorHealth>>,
+    temperature: sleep_monitor::RoomTemperature,
+    /// Published by [`sleep_monitor::publish_changes`].
+    room_temperature: watch::Sender<Option<Celsius>>,
+    /// Published by [`sleep_monitor::publish_changes`].
+    movement_intensity: watch::Sender<Option<sleep_monitor::MovementIntensity>>,
+    /// See [`sleep_monitor::SleepMonitor::presence_confidence`]. Published by [`sleep_monitor::publish_changes`].
+    presence_confidence: watch::Sender<Option<Confidence>>,
+    /// Notified when the bed is tapped twice while the alarm is playing.
+    double_tap: Arc<tokio::sync::Notify>,
+    /// The sensor on the partner's side of the bed, if configured. Only logged and published, the alarm ignores it.
+    partner: Option<PartnerSide>,
+    /// See [`sleep_monitor::pause`].
+    paused: Arc<offline::Container<bool>>,
+    autotune: autotune::AutoTune,
+    movement_events: movement_events::MovementEvents,
 }
 
 impl AlarmState {
@@ -78,6 +206,22 @@ impl AlarmState {
         }
     }
 
+    /// The alarm which should start now, and why. See [`wake::decide`].
+    #[cfg(feature = "audio")]
+    fn should_wake(
+        &self,
+        signals: Option<&wake::SleepSignals>,
+    ) -> Option<(DateTime<Utc>, wake::WakeReason)> {
+        let window = self.wake_window();
+        let alarm =
+            self.should_start_alarm_soon(DateDuration::minutes(window.earliest_minutes as i64))?;
+        wake::decide(Utc::now(), alarm, &window, signals).map(|reason| (alarm, reason))
+    }
+
+    fn wake_window(&self) -> wake::WakeWindow {
+        self.inner.get().map(|s| s.wake_window).unwrap_or_default()
+    }
+
     fn is_trigger_time(&self, time: DateTime<Utc>) -> bool {
         self.inner
             .get()
@@ -86,12 +230,100 @@ impl AlarmState {
             .is_trigger_time(time, self.last_played.get().as_ref().unwrap())
     }
 
+    /// Whether the user is in bed, or `None` while it is unknown.
+    fn user_in_bed(&self) -> Option<bool> {
+        self.presence_known
+            .get()
+            .unwrap_or(false)
+            .then(|| self.is_user_in_bed.get().unwrap_or(false))
+    }
+
+    /// Stops the alarm if it is playing, the same way as disabling it does. Returns false if nothing was playing.
+    ///
+    /// With [`config::DismissalPolicy::MustLeaveBed`], the alarm only turns down. See [`AlarmState::update_bed_exit`].
+    async fn stop_alarm(&self) -> bool {
+        if !self.is_playing.get().unwrap_or(false) {
+            return false;
+        }
+        let alarm = self.config.get().alarm;
+        if alarm.dismissal == config::DismissalPolicy::MustLeaveBed && self.user_in_bed().is_some()
+        {
+            let mut bed_exit = self.bed_exit.lock().unwrap();
+            if *bed_exit == wake::BedExit::Ringing {
+                info!(
+                    "Turning the alarm down until the user has been out of bed for {} minutes",
+                    alarm.out_of_bed_minutes
+                );
+                *bed_exit = wake::BedExit::Dismissed { absent_since: None };
+            }
+            return true;
+        }
+        self.inner.update(|s| s.enabled = false).await;
+        true
+    }
+
+    /// Whether the alarm was stopped, and plays quietly until the user has been out of bed for `out_of_bed`.
+    ///
+    /// Disables the alarm once they have been, which finishes it the same way as stopping it does without the policy.
+    /// Called regularly while the alarm is playing.
+    fn update_bed_exit(&self, out_of_bed: Duration) -> bool {
+        let in_bed = self.user_in_bed();
+        let (prev, bed_exit) = {
+            let mut bed_exit = self.bed_exit.lock().unwrap();
+            let prev = *bed_exit;
+            *bed_exit = prev.update(Utc::now(), in_bed, out_of_bed);
+            (prev, *bed_exit)
+        };
+        match (prev, bed_exit) {
+            (wake::BedExit::Dismissed { .. }, wake::BedExit::Ringing) => {
+                info!("The user got back into bed. Turning the alarm up again.");
+            }
+            (wake::BedExit::Dismissed { .. }, wake::BedExit::Done) => {
+                if in_bed.is_some() {
+                    info!("The user is out of bed. Dismissing the alarm.");
+                } else {
+                    info!(
+                        "It is no longer known whether the user is in bed. Dismissing the alarm."
+                    );
+                }
+                futures::executor::block_on(self.inner.update(|s| s.enabled = false));
+            }
+            _ => {}
+        }
+        matches!(bed_exit, wake::BedExit::Dismissed { .. })
+    }
+
     async fn on_alarm_finished(&self, time: DateTime<Utc>) {
         self.last_played
             .update(|data| {
                 data.last_played_time = Some(time);
             })
             .await;
+        #[cfg(feature = "motion")]
+        self.summarize_night().await;
+    }
+
+    /// Summarizes the night which is ending, unless nobody was in bed.
+    #[cfg(feature = "motion")]
+    async fn summarize_night(&self) {
+        let date = nights::sleep_date(Utc::now(), &chrono::Local);
+        let minutes = self
+            .sleep_monitor
+            .lock()
+            .await
+            .sleep_monitor
+            .night_minutes(date, &chrono::Local);
+        if !minutes.iter().any(|m| m.in_bed) {
+            return;
+        }
+        let onset_minutes = self.config.get().sleep_monitor.sleep_onset_minutes;
+        let summary = nights::summarize(date, &minutes, onset_minutes as usize);
+        info!(
+            "Slept {} minutes, with a score of {}",
+            summary.asleep_minutes, summary.score
+        );
+        self.sleep_summaries.record(&summary);
+        self.sleep_score.set(Some(summary.score)).await;
     }
 }
 
@@ -99,9 +331,28 @@ impl AlarmState {
 struct InnerAlarmState {
     next_alarm: DateTime<Utc>,
     enabled: bool,
+    /// Subdirectory of the sound directory to pick the alarm sound from.
+    #[serde(default)]
+    category: Option<String>,
+    /// When the alarm may start, relative to `next_alarm`.
+    #[serde(default)]
+    wake_window: wake::WakeWindow,
 }
 
 impl InnerAlarmState {
+    /// The alarm which was playing when the program stopped, if it started less than `window` before `now` and never finished.
+    fn interrupted_alarm(
+        &self,
+        last_played: &LastPlayed,
+        now: DateTime<Utc>,
+        window: DateDuration,
+    ) -> Option<DateTime<Utc>> {
+        (self.is_trigger_time(self.next_alarm, last_played)
+            && self.next_alarm <= now
+            && now - self.next_alarm < window)
+            .then_some(self.next_alarm)
+    }
+
     fn is_trigger_time(&self, time: DateTime<Utc>, last_played: &LastPlayed) -> bool {
         self.enabled
             && self.next_alarm == time
@@ -130,6 +381,26 @@ fn get_info_compat(state: &State<AlarmState>) -> Json<AlarmInfo> {
     get_info(state)
 }
 
+#[derive(Serialize)]
+struct StatusInfo {
+    room_temperature: Option<Celsius>,
+    /// See [`AlarmState::asleep_since`].
+    asleep_since: Option<DateTime<Utc>>,
+    lucid: lucid::LucidStatus,
+    /// Not synced if the broker could not be reached, see [`offline`].
+    sync: sync_health::Connection,
+}
+
+#[get("/status")]
+fn get_status(state: &State<AlarmState>) -> Json<StatusInfo> {
+    Json(StatusInfo {
+        room_temperature: state.room_temperature.get().flatten(),
+        asleep_since: *state.asleep_since.borrow(),
+        lucid: lucid::lucid_status(state),
+        sync: state.sync_health.lock().unwrap().connection,
+    })
+}
+
 #[get("/state")]
 fn get_state(state: &State<AlarmState>) -> Json<InnerAlarmState> {
     let state = state.inner.get().clone().unwrap();
@@ -156,15 +427,29 @@ fn state_to_info(state: &InnerAlarmState, last_played: &LastPlayed) -> AlarmInfo
     }
 }
 
+/// Stops the alarm which is playing.
+#[post("/stop")]
+async fn stop(state: &State<AlarmState>) -> Status {
+    if state.stop_alarm().await {
+        Status::Ok
+    } else {
+        Status::NotFound
+    }
+}
+
 #[post("/store", data = "<info>")]
 async fn store_compat(info: Json<AlarmInfo>, state: &State<AlarmState>) -> Json<AlarmInfo> {
     let naive_datetime = NaiveDateTime::parse_from_str(&info.time, "%Y-%m-%dT%H:%M:%S%.f")
         .expect("Could not parse date");
     let next_alarm = DateTime::<Utc>::from_naive_utc_and_offset(naive_datetime, chrono::Utc);
     let new_state = {
+        // The compat API does not know about categories or wake windows, so keep the current ones
+        let current = state.inner.get();
         InnerAlarmState {
             next_alarm,
             enabled: info.enabled,
+            category: current.as_ref().and_then(|s| s.category.clone()),
+            wake_window: current.map(|s| s.wake_window).unwrap_or_default(),
         }
     };
 
@@ -208,97 +493,514 @@ async fn store_inner(state: &AlarmState, new_state: InnerAlarmState) {
         .await;
 }
 
+/// Consecutive failed reads after which the accelerometer is initialized again.
+#[cfg(feature = "motion")]
+const SENSOR_REINIT_AFTER_FAILURES: u32 = 5;
+/// How long the accelerometer may be unreadable before the presence data is considered stale.
 #[cfg(feature = "motion")]
-fn monitor_sleep(state: Arc<Mutex<SleepMonitorState>>) {
-    use std::{io::Write, thread};
+const SENSOR_STALE_AFTER: Duration = Duration::from_secs(60);
 
-    
+/// Time to wait before reading the accelerometer again after `failures` consecutive failed reads.
+#[cfg(feature = "motion")]
+fn sensor_retry_backoff(failures: u32) -> Duration {
+    Duration::from_millis(100 << failures.saturating_sub(1).min(5)).min(Duration::from_secs(2))
+}
 
-    let mut file = std::fs::OpenOptions::new()
-        .append(true)
-        .create(true)
-        .open("accelerometer.csv")
-        .unwrap();
+/// Prints the gyro bias model learned for the calibration in `path`, and how well it fits the estimates it was fitted to.
+#[cfg(feature = "motion")]
+fn print_gyro_bias(path: &Path) {
+    let calibration = sleep_monitor::Calibration::load(path);
+    let model = calibration.gyro_bias;
+    match model {
+        Some(model) => println!(
+            "Gyro bias in rad/s = {:?} + {:?} * °C",
+            model.intercept, model.slope
+        ),
+        None => println!(
+            "No gyro bias model yet. The constant offset {:?} is used.",
+            calibration.gyro_offset
+        ),
+    }
+    println!("°C\tsamples\tbias x, y, z\tresidual x, y, z");
+    for estimate in &calibration.gyro_bias_estimates {
+        let residual = model.map(|m| m.residual(estimate));
+        println!(
+            "{:.1}\t{}\t{:?}\t{:?}",
+            estimate.temp, estimate.samples, estimate.gyro, residual
+        );
+    }
+}
+
+/// The partner's sensor, and a sleep monitor of its own.
+#[cfg(feature = "motion")]
+struct PartnerSide {
+    accelerometer: Box<dyn sleep_monitor::AccelerometerSource>,
+    sleep_monitor: sleep_monitor::SleepMonitor,
+    /// Samples of the current reading.
+    samples: Vec<sleep_monitor::AccelerometerData>,
+    failures: u32,
+    last_success: std::time::Instant,
+    freeze: sleep_monitor::FreezeDetector,
+}
+
+#[cfg(feature = "motion")]
+impl PartnerSide {
+    fn new(
+        accelerometer: Box<dyn sleep_monitor::AccelerometerSource>,
+        sleep_monitor: sleep_monitor::SleepMonitor,
+    ) -> PartnerSide {
+        PartnerSide {
+            accelerometer,
+            sleep_monitor,
+            samples: vec![],
+            failures: 0,
+            last_success: std::time::Instant::now(),
+            freeze: sleep_monitor::FreezeDetector::default(),
+        }
+    }
+
+    /// Takes one sample. Failures only mark the partner's data as stale, and never affect the user's sensor.
+    fn sample(&mut self, frozen_after: Duration) {
+        let now = std::time::Instant::now();
+        match self.accelerometer.sample() {
+            Ok(data) => {
+                self.failures = 0;
+                self.last_success = now;
+                let frozen = self.freeze.update(&data, now, frozen_after);
+                if !frozen {
+                    self.samples.push(data);
+                }
+                self.sleep_monitor.set_stale(frozen);
+            }
+            Err(e) => {
+                self.failures += 1;
+                warn!("Partner's sensor: {}", e);
+                if self.failures.is_multiple_of(SENSOR_REINIT_AFTER_FAILURES) {
+                    if let Err(e) = self.accelerometer.reinit() {
+                        warn!("Partner's sensor: {}", e);
+                    }
+                }
+                self.sleep_monitor
+                    .set_stale(now.duration_since(self.last_success) > SENSOR_STALE_AFTER);
+            }
+        }
+    }
+
+    fn set_paused(&mut self, paused: bool) {
+        // The time before the pause should not count as failing or frozen
+        self.last_success = std::time::Instant::now();
+        self.freeze = sleep_monitor::FreezeDetector::default();
+        self.samples.clear();
+        self.sleep_monitor.set_paused(paused);
+    }
+
+    /// Pushes the mean of the samples since the last reading to the sleep monitor, and returns it for the log.
+    fn finish_reading(
+        &mut self,
+        now: DateTime<Utc>,
+        alarm_playing: bool,
+    ) -> Option<motion_log::LogSample> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let mean = sleep_monitor::AccelerometerData::mean(&self.samples);
+        let movement = self.sleep_monitor.push(mean.clone(), now);
+        let sample = motion_log::LogSample {
+            time: now,
+            sensor: motion_log::PARTNER_SENSOR,
+            samples: self.samples.len(),
+            alarm_playing,
+            present: Some(self.sleep_monitor.is_present()),
+            movement,
+            data: mean,
+            respiration: self.sleep_monitor.take_respiration(),
+        };
+        self.samples.clear();
+        Some(sample)
+    }
+}
+
+#[cfg(feature = "motion")]
+fn monitor_sleep(
+    state: Arc<Mutex<SleepMonitorState>>,
+    config: Arc<config::ConfigStore>,
+    log: motion_log::MotionLog,
+    #[cfg(feature = "sqlite")] mut db: Option<sleep_db::SleepDb>,
+) {
+    use health::SensorHealth;
+    use std::thread;
+    use std::time::Instant;
 
-    loop {
-        if !state.blocking_lock().sleep_monitor.is_present() {
-            // Don't collect as much data when the user is not in bed
+    let mut failures: u32 = 0;
+    let mut last_success = Instant::now();
+    let mut sensor_health = None;
+    let mut reading_start: Option<Instant> = None;
+    let mut intensity_published: Option<Instant> = None;
+    let mut taps = sleep_monitor::TapDetector::default();
+    let mut freeze = sleep_monitor::FreezeDetector::default();
+    let sampling = config.get().motion;
+    // Turned off after a write error, so that a full disk only logs a single error
+    let mut log = Some(log);
+    // `event` flushes the log immediately, so that a significant event is not lost on a power cut
+    let mut write = |sample: motion_log::LogSample, event: bool| {
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &mut db {
+            if let Err(e) = db.add(sample) {
+                error!("Failed to write the accelerometer readings: {}", e);
+            }
+            return;
+        }
+        let Some(l) = &mut log else {
+            return;
+        };
+        let mut result = l.write(sample.time, &sample.to_csv());
+        if event && result.is_ok() {
+            result = l.flush();
+        }
+        if let Err(e) = result {
+            error!(
+                "Failed to write the accelerometer log, turning it off: {:?}",
+                e
+            );
+            health::set_motion_log_failed();
+            log = None;
+        }
+    };
+    let mut last_event_state = None;
+    while !shutdown::requested() {
+        watchdog::beat(watchdog::Subsystem::SleepMonitor);
+        // Checked on every reading, so that it can also be toggled over MQTT
+        let paused = {
+            let mut guard = state.blocking_lock();
+            let s = &mut *guard;
+            let paused = s.paused.get().unwrap_or(false);
+            if paused != s.sleep_monitor.is_paused() {
+                info!(
+                    "{} the sleep monitor",
+                    if paused { "Pausing" } else { "Resuming" }
+                );
+                s.sleep_monitor.set_paused(paused);
+                if let Some(partner) = &mut s.partner {
+                    partner.set_paused(paused);
+                }
+                sleep_monitor::send_if_changed(&s.movement_intensity, None);
+                sleep_monitor::send_if_changed(&s.presence_confidence, None);
+                // Nothing is known about the movement while paused
+                s.movement_events.update(Utc::now(), false, None);
+                if let Err(e) =
+                    sleep_monitor::save_paused(Path::new(sleep_monitor::PAUSED_FILE), paused)
+                {
+                    warn!("Could not save that the sleep monitor is paused: {}", e);
+                }
+                last_success = Instant::now();
+                freeze = sleep_monitor::FreezeDetector::default();
+            }
+            paused
+        };
+        if paused {
+            // The sensor stays initialized, so that resuming is instant
+            reading_start = None;
+            last_event_state = None;
+            thread::sleep(Duration::from_secs(1));
+            continue;
+        }
+        let anyone_in_bed = {
+            let s = state.blocking_lock();
+            s.sleep_monitor.is_present()
+                || s.partner
+                    .as_ref()
+                    .is_some_and(|p| p.sleep_monitor.is_present())
+        };
+        if !anyone_in_bed {
+            // Don't collect as much data when nobody is in bed
             thread::sleep(Duration::from_secs(1));
         }
 
-        // Take 10 samples every 100 ms and average them
-        const SAMPLES: usize = 10;
-        const PERIOD_MS: u64 = 10;
+        if let Some(start) = reading_start {
+            thread::sleep(
+                Duration::from_millis(sampling.reading_interval_ms).saturating_sub(start.elapsed()),
+            );
+        }
+        reading_start = Some(Instant::now());
+
+        // Take a few samples for each reading and average them
         let mut samples = vec![];
-        for _ in 0..SAMPLES {
-            let sample = {
-                let mut s = state.blocking_lock();
-                match s.accelerometer.get_data() {
-                    Ok(data) => {
-                        futures::executor::block_on(s.error_status.set(None));
-                        Ok(data)
+        let tap_config = config.get().tap_to_dismiss;
+        let frozen_after = Duration::from_secs_f32(config.get().motion.frozen_after_seconds);
+        for _ in 0..sampling.samples_per_reading {
+            let mut guard = state.blocking_lock();
+            let s = &mut *guard;
+            let Some(accelerometer) = &mut s.accelerometer else {
+                return;
+            };
+            let health = match accelerometer.sample() {
+                Ok(data) => {
+                    failures = 0;
+                    last_success = Instant::now();
+                    if freeze.update(&data, Instant::now(), frozen_after) {
+                        SensorHealth::Frozen
+                    } else {
+                        // Taps are only used to stop the alarm, so they are ignored while nothing is playing
+                        if tap_config.enabled && s.alarm_is_playing {
+                            if taps.update(&data, Instant::now(), &tap_config) {
+                                info!("Double tap detected. Stopping the alarm.");
+                                s.double_tap.notify_one();
+                            }
+                        } else {
+                            taps = sleep_monitor::TapDetector::default();
+                        }
+                        samples.push(data);
+                        SensorHealth::Ok
+                    }
+                }
+                Err(sleep_monitor::SensorError::Ended) => {
+                    info!("The replayed recording has ended");
+                    return;
+                }
+                Err(e) => {
+                    failures += 1;
+                    error!("{}", e);
+                    if failures.is_multiple_of(SENSOR_REINIT_AFTER_FAILURES) {
+                        warn!("Initializing the accelerometer again after {failures} failed reads");
+                        if let Err(e) = accelerometer.reinit() {
+                            error!("{}", e);
+                        }
+                    }
+                    if sensor_health != Some(SensorHealth::Failing) {
+                        sleep_monitor::send_if_changed(&s.error_status, Some(e.to_string()));
                     }
-                    Err(e) => {
-                        error!("Failed to get accelerometer data: {:?}", e);
-                        futures::executor::block_on(s.error_status.set(Some(format!("{:?}", e))));
-                        Err(e)
+                    if last_success.elapsed() > SENSOR_STALE_AFTER {
+                        SensorHealth::Stale
+                    } else {
+                        SensorHealth::Failing
                     }
                 }
             };
 
-            match sample {
-                Ok(data) => {
-                    samples.push(data);
-                    thread::sleep(Duration::from_millis(PERIOD_MS));
+            if sensor_health != Some(health) {
+                match health {
+                    SensorHealth::Ok => {
+                        sleep_monitor::send_if_changed(&s.error_status, None);
+                    }
+                    SensorHealth::Stale => {
+                        warn!("The accelerometer has been unreadable for too long. Ignoring the presence data.");
+                    }
+                    SensorHealth::Frozen => {
+                        let message = format!(
+                            "The accelerometer readings have not changed for {} seconds. It may be disconnected.",
+                            frozen_after.as_secs_f32()
+                        );
+                        warn!("{message} Ignoring the presence data.");
+                        sleep_monitor::send_if_changed(&s.error_status, Some(message));
+                    }
+                    SensorHealth::Failing => {}
                 }
-                Err(_) => {
-                    thread::sleep(Duration::from_millis(2000));
+                if sensor_health.is_some_and(SensorHealth::is_stale) && !health.is_stale() {
+                    info!("The accelerometer has recovered");
                 }
+                s.sleep_monitor.set_stale(health.is_stale());
+                health::set_sensor_health(health);
+                sleep_monitor::send_if_changed(&s.sensor_health, Some(health));
+                sensor_health = Some(health);
             }
+            // Sampled right after the user's sensor, so that both get the same number of samples per reading
+            if let Some(partner) = &mut s.partner {
+                partner.sample(frozen_after);
+            }
+            drop(guard);
+
+            if failures == 0 {
+                thread::sleep(Duration::from_millis(sampling.sample_interval_ms));
+            } else {
+                thread::sleep(sensor_retry_backoff(failures));
+            }
+        }
+        // The same time for both sensors, in the sleep monitors and in the logs
+        let now = Utc::now();
+        // Also when the user's sensor could not be read
+        let partner_sample = {
+            let mut s = state.blocking_lock();
+            let alarm_is_playing = s.alarm_is_playing;
+            s.partner
+                .as_mut()
+                .and_then(|p| p.finish_reading(now, alarm_is_playing))
+        };
+        if let Some(sample) = partner_sample {
+            write(sample, false);
         }
         if samples.is_empty() {
-            samples.push(sleep_monitor::AccelerometerData::default());
+            continue;
         }
         let mean = sleep_monitor::AccelerometerData::mean(&samples);
-        let alarm_is_playing = {
+        let (alarm_is_playing, present, movement, respiration) = {
             let mut s = state.blocking_lock();
-            s.sleep_monitor.push(mean.clone());
-            s.alarm_is_playing
+            let movement = s.sleep_monitor.push(mean.clone(), now);
+            if let Some(temperature) = s.temperature.update(mean.temp, Instant::now()) {
+                sleep_monitor::send_if_changed(&s.room_temperature, Some(Celsius(temperature)));
+            }
+            if intensity_published
+                .is_none_or(|t| t.elapsed() >= sleep_monitor::INTENSITY_PUBLISH_INTERVAL)
+            {
+                intensity_published = Some(Instant::now());
+                let intensity = s.sleep_monitor.movement_intensity();
+                sleep_monitor::send_if_changed(&s.movement_intensity, intensity);
+                // Rounded, so that it does not change all the time
+                let confidence = s
+                    .sleep_monitor
+                    .presence_confidence()
+                    .map(|c| Confidence((c * 100.0).round() / 100.0));
+                sleep_monitor::send_if_changed(&s.presence_confidence, confidence);
+            }
+            if let Some(movement) = movement {
+                s.autotune.add(movement);
+            }
+            let significant = s.sleep_monitor.is_significant_movement();
+            s.movement_events.update(now, significant, movement);
+            // The gyro bias is learned while the bed is empty and still
+            let at_rest = !s.sleep_monitor.is_stale()
+                && !s.sleep_monitor.is_present()
+                && movement.is_some_and(|m| m < config.get().sleep_monitor.noise_threshold);
+            if let Some(accelerometer) = &mut s.accelerometer {
+                accelerometer.set_at_rest(at_rest);
+            }
+            (
+                s.alarm_is_playing,
+                s.sleep_monitor.is_present(),
+                movement,
+                s.sleep_monitor.take_respiration(),
+            )
         };
-        let time = Utc::now();
-        let line = format!(
-            "{},{},{},{},{},{},{},{},{},{}\n",
-            // YYYY-MM-DD HH:MM:SS.SSS
-            time.format("%Y-%m-%d %H:%M:%S%.3f"),
-            SAMPLES,
-            alarm_is_playing as u32,
-            mean.acc.0,
-            mean.acc.1,
-            mean.acc.2,
-            mean.gyro.0,
-            mean.gyro.1,
-            mean.gyro.2,
-            mean.temp,
-        );
-        file.write_all(line.as_bytes()).unwrap();
+        let sample = motion_log::LogSample {
+            time: now,
+            sensor: motion_log::MY_SENSOR,
+            samples: samples.len(),
+            alarm_playing: alarm_is_playing,
+            present: Some(present),
+            movement,
+            data: mean,
+            respiration,
+        };
+        // The alarm starting or stopping, or the user getting in or out of bed
+        let event_state = Some((alarm_is_playing, present));
+        let event = last_event_state.is_some() && last_event_state != event_state;
+        last_event_state = event_state;
+        write(sample, event);
     }
-}
 
-const MQTT_HOST: &str = "mqtt://arongranberg.com:1883";
-const MQTT_CLIENT_ID: &str = "alarm";
-const MQTT_USERNAME: &str = "wakeup_alarm";
-const MQTT_PASSWORD: &str = "xafzz25nomehasff";
+    // Nothing buffered is lost when the process exits
+    if let Some(log) = log {
+        match log.close() {
+            Ok(()) => info!("Closed the accelerometer log"),
+            Err(e) => error!("Failed to close the accelerometer log: {:?}", e),
+        }
+    }
+}
 
 #[rocket::main]
 async fn main() -> Result<(), rocket::Error> {
     env_logger::init();
 
+    let config = Arc::new(config::ConfigStore::load(Path::new(config::CONFIG_FILE)));
+
+    #[cfg(feature = "parquet")]
+    if std::env::args().nth(1).as_deref() == Some("export") {
+        if let Err(e) = export::run(
+            &config.get().motion,
+            &std::env::args().skip(2).collect::<Vec<_>>(),
+        ) {
+            error!("Failed to export: {}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    #[cfg(not(feature = "parquet"))]
+    if std::env::args().nth(1).as_deref() == Some("export") {
+        error!("Exporting requires the parquet feature");
+        std::process::exit(1);
+    }
+    #[cfg(feature = "sqlite")]
+    if let Some(csv) = std::env::args().skip_while(|x| x != "--import-csv").nth(1) {
+        let Some(database) = config.get().motion.database.clone() else {
+            error!("Set motion.database in the config to import {}", csv);
+            std::process::exit(1);
+        };
+        match sleep_db::import_csv(&database, Path::new(&csv)) {
+            Ok(count) => info!("Imported {} readings from {}", count, csv),
+            Err(e) => {
+                error!("Failed to import {}: {}", csv, e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
     #[cfg(feature = "motion")]
-    let acc = match sleep_monitor::Accelerometer::new() {
-        Ok(acc) => acc,
-        Err(e) => {
-            panic!("Failed to initialize accelerometer: {:?}", e);
+    if let Some(csv) = std::env::args().skip_while(|x| x != "--archive").nth(1) {
+        match archive::backfill(&config.get().motion.log_dir, Path::new(&csv)) {
+            Ok(days) => info!("Archived {} days from {}", days, csv),
+            Err(e) => {
+                error!("Failed to archive {}: {}", csv, e);
+                std::process::exit(1);
+            }
         }
+        return Ok(());
+    }
+    #[cfg(feature = "motion")]
+    if std::env::args().any(|x| x == "--gyro-bias") {
+        print_gyro_bias(&config.get().motion.calibration_file);
+        return Ok(());
+    }
+    #[cfg(not(feature = "sqlite"))]
+    if config.get().motion.database.is_some() {
+        warn!("motion.database is set, but the sqlite feature is not enabled. Using the CSV logs.");
+    }
+
+    #[cfg(feature = "motion")]
+    let acc: Option<Box<dyn sleep_monitor::AccelerometerSource>> =
+        if std::env::args().any(|x| x == "--no-motion") {
+            info!("Motion sensing is disabled");
+            None
+        } else if let Some(path) = std::env::args().skip_while(|x| x != "--replay").nth(1) {
+            match sleep_monitor::ReplaySource::open(Path::new(&path), Some(1.0)) {
+                Ok(replay) => {
+                    info!("Replaying the accelerometer readings in {}", path);
+                    Some(Box::new(replay))
+                }
+                Err(e) => {
+                    error!("Could not read {}: {}", path, e);
+                    std::process::exit(1);
+                }
+            }
+        } else {
+            match imu::open(&config.get().motion) {
+                Ok(acc) => Some(acc),
+                Err(e) => {
+                    error!("{}. Use --no-motion to run without it.", e);
+                    std::process::exit(1);
+                }
+            }
+        };
+    // Only used next to the user's sensor, and not when replaying
+    #[cfg(feature = "motion")]
+    let partner_acc = if std::env::args().any(|x| x == "--no-motion" || x == "--replay") {
+        None
+    } else {
+        config
+            .get()
+            .motion
+            .partner_config()
+            .and_then(|partner| match imu::open(&partner) {
+                Ok(acc) => {
+                    info!(
+                        "Using the partner's sensor on {}",
+                        partner.i2c_bus.display()
+                    );
+                    Some((acc, partner.calibration_file))
+                }
+                Err(e) => {
+                    error!("{}. Continuing without the partner's sensor.", e);
+                    None
+                }
+            })
     };
 
     let machine_id = machineid_rs::IdBuilder::new(machineid_rs::Encryption::SHA256)
@@ -306,91 +1008,394 @@ async fn main() -> Result<(), rocket::Error> {
         .build("somekey")
         .unwrap();
 
-    let storage = SyncStorage::new(
-        &format!("{MQTT_CLIENT_ID} {machine_id}"),
-        MQTT_HOST,
-        MQTT_USERNAME,
-        MQTT_PASSWORD,
-    )
-    .await;
+    let mqtt_config = std::env::args().skip_while(|x| x != "--mqtt-config").nth(1);
+    let mqtt = mqtt::load(mqtt_config.as_deref().map(Path::new), |var| {
+        std::env::var(var).ok()
+    })
+    .unwrap_or_else(|e| {
+        error!("{}", e);
+        std::process::exit(1);
+    });
+    info!("Syncing through {} as {}", mqtt.host, mqtt.username);
 
-    let inner_state = storage
-        .add_container(
+    let mut containers = offline::Containers::default();
+
+    // Starts from the state saved by the last run, in case the broker can not be reached
+    let mut local_state = local_state::LocalState::load(Path::new(local_state::LOCAL_STATE_FILE));
+    let inner_state = containers.add(
+        "alarm/state",
+        local_state.initial(
             "alarm/state",
             InnerAlarmState {
                 next_alarm: Utc::now(),
                 enabled: false,
+                category: None,
+                wake_window: wake::WakeWindow::default(),
             },
-        )
-        .await
-        .unwrap();
+        ),
+    );
+    local_state.add("alarm/state", &inner_state);
 
-    let last_played = storage
-        .add_container(
+    let last_played = containers.add(
+        "alarm/last_played",
+        local_state.initial(
             "alarm/last_played",
             LastPlayed {
                 last_played_time: None,
             },
+        ),
+    );
+    local_state.add("alarm/last_played", &last_played);
+
+    let is_playing = containers.add("alarm/is_playing", false);
+    let is_user_in_bed = containers.add("alarm/is_user_in_bed", false);
+    let is_significant_movement_in_bed =
+        containers.add("alarm/is_significant_movement_in_bed", false);
+    let presence_known = containers.add("alarm/presence_known", false);
+    let presence_confidence = containers.add("alarm/presence_confidence", None::<Confidence>);
+    // Only with a partner's sensor, so that nothing changes for a single sensor
+    #[cfg(feature = "motion")]
+    let partner_containers = partner_acc.as_ref().map(|_| {
+        (
+            containers.add("alarm/partner_is_user_in_bed", false),
+            containers.add("alarm/partner_is_significant_movement_in_bed", false),
+            containers.add("alarm/partner_presence_known", false),
         )
-        .await
-        .unwrap();
+    });
+    let room_temperature = containers.add("alarm/room_temperature", None::<Celsius>);
+    #[cfg(feature = "motion")]
+    let movement_intensity = containers.add(
+        "alarm/movement_intensity",
+        None::<sleep_monitor::MovementIntensity>,
+    );
+    #[cfg(feature = "motion")]
+    let sleep_score = containers.add("alarm/sleep_score", None::<u8>);
+    // Also saved locally, so that it is kept across restarts without a connection
+    #[cfg(feature = "motion")]
+    let sleep_monitor_paused = containers.add(
+        "alarm/sleep_monitor_paused",
+        sleep_monitor::load_paused(Path::new(sleep_monitor::PAUSED_FILE)),
+    );
+    #[cfg(feature = "motion")]
+    let sensor_health = containers.add("alarm/sensor_health", None::<health::SensorHealth>);
+    let sleep_monitor_err = containers.add("alarm/sleep_monitor_error", None::<String>);
 
-    let is_playing = storage
-        .add_container("alarm/is_playing", false)
-        .await
-        .unwrap();
-    let is_user_in_bed = storage
-        .add_container("alarm/is_user_in_bed", false)
-        .await
-        .unwrap();
-    let is_significant_movement_in_bed = storage
-        .add_container("alarm/is_significant_movement_in_bed", false)
-        .await
-        .unwrap();
-    let sleep_monitor_err = storage
-        .add_container("alarm/sleep_monitor_error", None::<String>)
-        .await
-        .unwrap();
+    let lucid_music_volume = containers.add(
+        "alarm/lucid_music_volume",
+        local_state.initial("alarm/lucid_music_volume", 30),
+    );
+    local_state.add("alarm/lucid_music_volume", &lucid_music_volume);
+    let lucid_sfx_volume = containers.add(
+        "alarm/lucid_sfx_volume",
+        local_state.initial("alarm/lucid_sfx_volume", 50),
+    );
+    local_state.add("alarm/lucid_sfx_volume", &lucid_sfx_volume);
+    let lucid_voice_volume = containers.add(
+        "alarm/lucid_voice_volume",
+        local_state.initial("alarm/lucid_voice_volume", 20),
+    );
+    local_state.add("alarm/lucid_voice_volume", &lucid_voice_volume);
+    let lucid_binaural_volume = containers.add(
+        "alarm/lucid_binaural_volume",
+        local_state.initial("alarm/lucid_binaural_volume", 10),
+    );
+    local_state.add("alarm/lucid_binaural_volume", &lucid_binaural_volume);
+    let lucid_config = containers.add(
+        "alarm/lucid_config",
+        local_state.initial("alarm/lucid_config", lucid::LucidConfig::default()),
+    );
+    local_state.add("alarm/lucid_config", &lucid_config);
+    let reality_check_volume = containers.add(
+        "alarm/reality_check_volume",
+        local_state.initial("alarm/reality_check_volume", 30),
+    );
+    local_state.add("alarm/reality_check_volume", &reality_check_volume);
 
-    let lucid_mucic_volume = storage
-        .add_container("alarm/lucid_music_volume", 30)
-        .await
-        .unwrap();
-    let lucid_sfx_volume = storage
-        .add_container("alarm/lucid_sfx_volume", 50)
-        .await
-        .unwrap();
+    let now_playing = containers.add("alarm/now_playing", events::NOT_PLAYING.to_owned());
+    let last_event = containers.add("alarm/last_event", None::<events::PlaybackEvent>);
+    // Not restored, only saved to see that they were left idle at shutdown
+    local_state.add("alarm/is_playing", &is_playing);
+    local_state.add("alarm/now_playing", &now_playing);
 
-    storage.wait_for_sync().await;
+    let sync_health = Arc::new(std::sync::Mutex::new(sync_health::SyncHealth::new(
+        Utc::now(),
+    )));
+    let mut watched = sync_health::Watched::default();
+    watched.add(sync_health::STATE_CONTAINER, &inner_state);
+    watched.add("alarm/last_played", &last_played);
+    watched.add("alarm/is_playing", &is_playing);
+    watched.add("alarm/is_user_in_bed", &is_user_in_bed);
+    watched.add(
+        "alarm/is_significant_movement_in_bed",
+        &is_significant_movement_in_bed,
+    );
+    watched.add("alarm/presence_confidence", &presence_confidence);
+    watched.add("alarm/room_temperature", &room_temperature);
+    watched.add("alarm/lucid_music_volume", &lucid_music_volume);
+    watched.add("alarm/lucid_sfx_volume", &lucid_sfx_volume);
+    watched.add("alarm/lucid_voice_volume", &lucid_voice_volume);
+    watched.add("alarm/lucid_binaural_volume", &lucid_binaural_volume);
+    watched.add("alarm/lucid_config", &lucid_config);
+    watched.add("alarm/reality_check_volume", &reality_check_volume);
+    watched.add("alarm/now_playing", &now_playing);
+    tokio::spawn(sync_health::watch(
+        sync_health.clone(),
+        watched,
+        mqtt.stale_state_after,
+    ));
+    let client_id = format!("{} {machine_id}", mqtt.client_id);
+    offline::start(
+        mqtt,
+        client_id,
+        containers,
+        local_state.clone(),
+        sync_health.clone(),
+    )
+    .await;
+
+    sounds::check_directories(&config.get().sounds);
+
+    let haptics = config
+        .get()
+        .haptic
+        .pin
+        .and_then(|pin| match haptic::Haptics::open(pin) {
+            Ok(haptics) => {
+                info!("Haptic cues are played on GPIO pin {}", pin);
+                Some(Arc::new(haptics))
+            }
+            Err(e) => {
+                error!("{}", e);
+                None
+            }
+        });
 
     let play_immediately = std::env::args().any(|x| x == "--play");
-    let play_lucid_immediately = std::env::args().any(|x| x == "--play-lucid");
+    // Plays one lucid cue at startup, of the given kind or a random one
+    let lucid_test = {
+        let mut args = std::env::args().skip_while(|x| x != "--lucid-test");
+        args.next().map(|_| {
+            args.next().filter(|x| !x.starts_with("--")).map(|cue| {
+                cue.parse::<lucid::LucidCue>().unwrap_or_else(|e| {
+                    error!("{}", e);
+                    std::process::exit(1);
+                })
+            })
+        })
+    };
 
     let alarm_state = AlarmState {
-        storage,
         inner: inner_state,
         last_played,
         is_playing,
         is_user_in_bed: is_user_in_bed.clone(),
+        presence_known: presence_known.clone(),
+        bed_exit: Arc::default(),
+        #[cfg(feature = "motion")]
+        snooze_chain: Arc::default(),
+        room_temperature: room_temperature.clone(),
+        config: config.clone(),
+        history: Arc::new(history::AlarmHistory::new(Path::new(history::HISTORY_FILE))),
+        lucid_log: Arc::new(lucid_log::LucidLog::new(
+            Path::new(lucid_log::LUCID_LOG_FILE),
+            Path::new(lucid_log::LUCID_NOTES_FILE),
+            Path::new(lucid_log::LUCID_DISRUPTIONS_FILE),
+        )),
+        asleep_since: Arc::new(watch::Sender::new(None)),
+        lucid_budget: Arc::default(),
+        lucid_status: Arc::default(),
+        sync_health,
+        local_state,
+        haptics,
+        reality_checks: Arc::new(reality_check::RealityCheckLog::new(
+            Path::new(reality_check::REALITY_CHECK_LOG_FILE),
+            Path::new(reality_check::REALITY_CHECK_NOTICED_FILE),
+        )),
+        events: events::PlaybackEvents::new(now_playing, last_event),
+        playback: playback::PlaybackCoordinator::default(),
         #[cfg(feature = "motion")]
         sleep_monitor: Arc::new(Mutex::new(SleepMonitorState {
             accelerometer: acc,
-            sleep_monitor: sleep_monitor::SleepMonitor::new(
-                Duration::from_secs(18 * 60),
-                is_user_in_bed.clone(),
-                is_significant_movement_in_bed.clone(),
-            ),
+            sleep_monitor: {
+                let mut monitor = sleep_monitor::SleepMonitor::new(
+                    Duration::from_secs(18 * 60),
+                    config.get().motion.reading_period(),
+                    config.clone(),
+                );
+                let calibration =
+                    sleep_monitor::Calibration::load(&config.get().motion.calibration_file);
+                monitor.set_reference_gravity(calibration.gravity);
+                monitor
+            },
             alarm_is_playing: false,
-            error_status: sleep_monitor_err,
+            error_status: watch::Sender::new(None),
+            sensor_health: watch::Sender::new(None),
+            temperature: sleep_monitor::RoomTemperature::new(
+                config.get().motion.temperature_offset,
+            ),
+            room_temperature: watch::Sender::new(None),
+            movement_intensity: watch::Sender::new(None),
+            presence_confidence: watch::Sender::new(None),
+            double_tap: Arc::new(tokio::sync::Notify::new()),
+            partner: partner_acc.map(|(acc, calibration_file)| {
+                let mut monitor = sleep_monitor::SleepMonitor::new(
+                    Duration::from_secs(18 * 60),
+                    config.get().motion.reading_period(),
+                    config.clone(),
+                );
+                let calibration = sleep_monitor::Calibration::load(&calibration_file);
+                monitor.set_reference_gravity(calibration.gravity);
+                PartnerSide::new(acc, monitor)
+            }),
+            paused: sleep_monitor_paused,
+            autotune: autotune::AutoTune::load(Path::new(autotune::AUTOTUNE_FILE)),
+            movement_events: movement_events::MovementEvents::load(
+                Path::new(movement_events::MOVEMENT_EVENTS_FILE),
+                config.get().motion.movement_event_retention_days,
+            ),
         })),
+        #[cfg(feature = "motion")]
+        bed_log: Arc::new(json_log::JsonLog::new(Path::new(nights::BED_LOG_FILE))),
+        #[cfg(feature = "motion")]
+        sleep_summaries: Arc::new(json_log::JsonLog::new(Path::new(nights::SUMMARY_FILE))),
+        #[cfg(feature = "motion")]
+        sleep_score,
     };
 
+    // Joined at shutdown, once it has closed the sleep log
     #[cfg(feature = "motion")]
-    {
+    let sleep_monitor_thread = {
+        let presence = alarm_state
+            .sleep_monitor
+            .lock()
+            .await
+            .sleep_monitor
+            .subscribe();
+        tokio::spawn(nights::record_transitions(
+            presence.clone(),
+            alarm_state.bed_log.clone(),
+        ));
+        tokio::spawn(sleep_monitor::publish_presence(
+            presence,
+            is_user_in_bed.clone(),
+            is_significant_movement_in_bed.clone(),
+            presence_known,
+        ));
+        tokio::spawn(sleep_monitor::publish_changes(
+            alarm_state
+                .sleep_monitor
+                .lock()
+                .await
+                .room_temperature
+                .subscribe(),
+            room_temperature,
+        ));
+        tokio::spawn(sleep_monitor::publish_changes(
+            alarm_state
+                .sleep_monitor
+                .lock()
+                .await
+                .movement_intensity
+                .subscribe(),
+            movement_intensity,
+        ));
+        tokio::spawn(sleep_monitor::publish_changes(
+            alarm_state
+                .sleep_monitor
+                .lock()
+                .await
+                .presence_confidence
+                .subscribe(),
+            presence_confidence.clone(),
+        ));
+        tokio::spawn(sleep_monitor::publish_changes(
+            alarm_state
+                .sleep_monitor
+                .lock()
+                .await
+                .sensor_health
+                .subscribe(),
+            sensor_health,
+        ));
+        tokio::spawn(sleep_monitor::publish_changes(
+            alarm_state
+                .sleep_monitor
+                .lock()
+                .await
+                .error_status
+                .subscribe(),
+            sleep_monitor_err,
+        ));
+        if let Some(partner) = &alarm_state.sleep_monitor.lock().await.partner {
+            let (in_bed, movement, known) = partner_containers.unwrap();
+            tokio::spawn(sleep_monitor::publish_presence(
+                partner.sleep_monitor.subscribe(),
+                in_bed,
+                movement,
+                known,
+            ));
+        }
+        let double_tap = alarm_state.sleep_monitor.lock().await.double_tap.clone();
+        let state = alarm_state.clone();
+        tokio::spawn(async move {
+            loop {
+                double_tap.notified().await;
+                state.stop_alarm().await;
+            }
+        });
         let sm = alarm_state.sleep_monitor.clone();
-        thread::spawn(move || monitor_sleep(sm));
+        if sm.lock().await.accelerometer.is_some() {
+            let motion_config = alarm_state.config.get().motion.clone();
+            let log = motion_log::MotionLog::new(
+                &motion_config.log_dir,
+                motion_config.log_retention_days,
+            );
+            #[cfg(feature = "sqlite")]
+            let db = motion_config.database.as_ref().and_then(|path| {
+                sleep_db::SleepDb::open(path, motion_config.log_retention_days)
+                    .map_err(|e| error!("Could not open {}: {}", path.display(), e))
+                    .ok()
+            });
+            let config = alarm_state.config.clone();
+            Some(thread::spawn(move || {
+                monitor_sleep(
+                    sm,
+                    config,
+                    log,
+                    #[cfg(feature = "sqlite")]
+                    db,
+                )
+            }))
+        } else {
+            None
+        }
+    };
+    #[cfg(not(feature = "motion"))]
+    let sleep_monitor_thread = None;
+
+    let resume_window = DateDuration::milliseconds(
+        (alarm_state.config.get().alarm.resume_window_minutes as f64 * 60_000.0) as i64,
+    );
+    let interrupted_alarm = alarm_state.inner.get().unwrap().interrupted_alarm(
+        alarm_state.last_played.get().as_ref().unwrap(),
+        Utc::now(),
+        resume_window,
+    );
+    if let Some(time) = interrupted_alarm {
+        info!("Resuming the alarm at {} which was interrupted", time);
     }
 
+    #[cfg(feature = "audio")]
+    let lucid = lucid::LucidSettings {
+        volumes: lucid::LucidVolumes {
+            music: lucid_music_volume,
+            sfx: lucid_sfx_volume,
+            voice: lucid_voice_volume,
+            binaural: lucid_binaural_volume,
+        },
+        config: lucid_config,
+    };
+
     if play_immediately {
         info!("Playing alarm immediately");
         alarm_state
@@ -404,33 +1409,189 @@ async fn main() -> Result<(), rocket::Error> {
 
     #[cfg(feature = "audio")]
     {
-        tokio::spawn(alarm::start_alarm_thread(alarm_state.clone()));
+        tokio::spawn(alarm::start_alarm_thread(
+            alarm_state.clone(),
+            interrupted_alarm,
+        ));
+        tokio::spawn(alarm::keep_speaker_awake(alarm_state.clone()));
 
+        if let Some(cue) = lucid_test {
+            info!("Playing a lucid cue to test it");
+            let (alarm_state, lucid) = (alarm_state.clone(), lucid.clone());
+            tokio::task::spawn_blocking(move || lucid::play_manual_cue(&alarm_state, &lucid, cue));
+        }
+        tokio::spawn(reality_check::start_reality_checks(
+            alarm_state.clone(),
+            lucid.config.clone(),
+            reality_check_volume,
+        ));
+        // Makes the times and kinds of the lucid cues reproducible
+        let lucid_seed = std::env::args()
+            .skip_while(|x| x != "--lucid-seed")
+            .nth(1)
+            .map(|seed| {
+                seed.parse::<u64>().unwrap_or_else(|e| {
+                    error!("Invalid lucid seed `{}`: {}", seed, e);
+                    std::process::exit(1);
+                })
+            });
         tokio::spawn(lucid::start_lucid_effects(
             alarm_state.clone(),
-            play_lucid_immediately,
-            lucid_mucic_volume,
-            lucid_sfx_volume,
-            is_user_in_bed.clone(),
+            lucid.clone(),
+            presence_confidence,
             is_significant_movement_in_bed.clone(),
+            lucid_seed,
         ));
     }
+    #[cfg(not(feature = "audio"))]
+    info!("Lucid effects are not available, since the audio feature is disabled");
 
-    rocket::build()
-        .manage(alarm_state.clone())
-        .mount(
-            "/",
-            routes![
-                get_info,
-                get_info_compat,
-                store_compat,
-                get_state,
-                put_state
-            ],
-        )
-        .launch()
-        .await
-        .unwrap();
+    // Signals are handled in `shutdown`, so that the server stops last
+    let figment = rocket::Config::figment()
+        .merge(("shutdown.ctrlc", false))
+        .merge(("shutdown.signals", Vec::<String>::new()));
+    let rocket = rocket::custom(figment).manage(alarm_state.clone()).mount(
+        "/",
+        routes![
+            get_info,
+            get_info_compat,
+            store_compat,
+            get_state,
+            get_status,
+            put_state,
+            stop,
+            config::get_config,
+            metrics::get_metrics,
+            config::put_config,
+            health::get_health,
+            sounds::get_sounds,
+            sounds::put_sound_weight,
+            sounds::put_sound_trim,
+            lucid_log::get_lucid_log,
+            lucid_log::annotate_lucid_cue,
+            lucid_log::get_lucid_stats,
+            reality_check::get_reality_checks,
+            reality_check::mark_reality_check_noticed
+        ],
+    );
+
+    #[cfg(feature = "audio")]
+    let rocket = rocket.manage(lucid).mount(
+        "/",
+        routes![
+            lucid::trigger_lucid_cue,
+            lucid::get_lucid_config,
+            alarm::put_cutoff_override,
+            alarm::get_playback,
+            alarm::preview_sound,
+            alarm::stop_preview,
+            alarm::play_tone
+        ],
+    );
+
+    #[cfg(feature = "motion")]
+    let rocket = rocket.mount(
+        "/",
+        routes![
+            sleep_monitor::calibrate,
+            sleep_monitor::pause,
+            sleep_monitor::resume,
+            sleep_monitor::get_sleep_stages,
+            sleep_monitor::get_live,
+            sleep_monitor::put_sleep_config,
+            autotune::get_autotune,
+            autotune::put_autotune_label,
+            autotune::reset_autotune,
+            autotune::accept_autotune,
+            motion_log::get_raw,
+            motion_log::get_stats,
+            nights::get_nights,
+            movement_events::get_movement_events,
+            histogram::get_histogram
+        ],
+    );
+
+    // The state has been synced, or restored from the local state, by now
+    let rocket = rocket.attach(rocket::fairing::AdHoc::on_liftoff("systemd", |_| {
+        Box::pin(async {
+            watchdog::ready();
+            tokio::spawn(watchdog::supervise());
+        })
+    }));
+    let rocket = rocket.ignite().await?;
+    tokio::spawn(shutdown::on_signal(
+        alarm_state.clone(),
+        sleep_monitor_thread,
+        rocket.shutdown(),
+    ));
+    rocket.launch().await?;
 
     Ok(())
 }
+
+#[test]
+fn test_interrupted_alarm() {
+    let alarm = Utc::now() - DateDuration::minutes(3);
+    let state = InnerAlarmState {
+        next_alarm: alarm,
+        enabled: true,
+        category: None,
+        wake_window: wake::WakeWindow::default(),
+    };
+    let window = DateDuration::minutes(10);
+    let never_played = LastPlayed {
+        last_played_time: None,
+    };
+    let now = Utc::now();
+    assert_eq!(
+        state.interrupted_alarm(&never_played, now, window),
+        Some(alarm)
+    );
+
+    // Already finished, at the alarm time or later
+    for finished in [alarm, alarm + DateDuration::minutes(1)] {
+        let last_played = LastPlayed {
+            last_played_time: Some(finished),
+        };
+        assert_eq!(state.interrupted_alarm(&last_played, now, window), None);
+    }
+    // Only an earlier alarm finished
+    let last_played = LastPlayed {
+        last_played_time: Some(alarm - DateDuration::days(1)),
+    };
+    assert_eq!(
+        state.interrupted_alarm(&last_played, now, window),
+        Some(alarm)
+    );
+
+    // At the alarm time, and at the end of the window
+    assert_eq!(
+        state.interrupted_alarm(&never_played, alarm, window),
+        Some(alarm)
+    );
+    assert_eq!(
+        state.interrupted_alarm(&never_played, alarm + window, window),
+        None
+    );
+    // The alarm has not started yet
+    assert_eq!(
+        state.interrupted_alarm(&never_played, alarm - DateDuration::seconds(1), window),
+        None
+    );
+
+    let disabled = InnerAlarmState {
+        enabled: false,
+        ..state
+    };
+    assert_eq!(disabled.interrupted_alarm(&never_played, now, window), None);
+}
+
+#[cfg(feature = "motion")]
+#[test]
+fn test_sensor_retry_backoff() {
+    assert_eq!(sensor_retry_backoff(1), Duration::from_millis(100));
+    assert_eq!(sensor_retry_backoff(2), Duration::from_millis(200));
+    assert_eq!(sensor_retry_backoff(5), Duration::from_millis(1600));
+    assert_eq!(sensor_retry_backoff(6), Duration::from_secs(2));
+    assert_eq!(sensor_retry_backoff(1000), Duration::from_secs(2));
+}
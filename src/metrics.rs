@@ -0,0 +1,120 @@
+use std::fmt::Write;
+
+/// Metrics in the Prometheus text format.
+#[get("/metrics")]
+pub fn get_metrics() -> String {
+    let mut out = String::new();
+
+    #[cfg(feature = "audio")]
+    {
+        let cache = crate::decode_cache::stats();
+        gauge(
+            &mut out,
+            "alarm_decode_cache_bytes",
+            "Memory used by cached decoded sounds",
+            cache.bytes as f64,
+        );
+        gauge(
+            &mut out,
+            "alarm_decode_cache_entries",
+            "Number of cached decoded sounds",
+            cache.entries as f64,
+        );
+        counter(
+            &mut out,
+            "alarm_decode_cache_hits_total",
+            "Sounds that could be played without decoding them again",
+            cache.hits as f64,
+        );
+        counter(
+            &mut out,
+            "alarm_decode_cache_misses_total",
+            "Sounds that had to be decoded",
+            cache.misses as f64,
+        );
+
+        counter(
+            &mut out,
+            "alarm_non_finite_samples_total",
+            "NaN or infinite samples that were replaced by silence",
+            crate::filtered_source::non_finite_samples() as f64,
+        );
+
+        let output = crate::output::stats();
+        counter(
+            &mut out,
+            "alarm_output_underruns_total",
+            "Intervals where the audio output played fewer samples than expected",
+            output.underruns as f64,
+        );
+        counter(
+            &mut out,
+            "alarm_output_recoveries_total",
+            "Times the audio output was reopened because it stalled",
+            output.recoveries as f64,
+        );
+    }
+
+    let sync = crate::sync_health::stats();
+    counter(
+        &mut out,
+        "alarm_sync_offline_total",
+        "Times the alarm continued offline, since the MQTT broker could not be reached",
+        sync.offline as f64,
+    );
+    counter(
+        &mut out,
+        "alarm_sync_resyncs_total",
+        "Times the state was synced again after having been offline",
+        sync.resyncs as f64,
+    );
+    counter(
+        &mut out,
+        "alarm_sync_container_updates_total",
+        "Changes of the values of the synced containers",
+        sync.updates as f64,
+    );
+    counter(
+        &mut out,
+        "alarm_sync_suspicious_total",
+        "Times the alarm state looked stale while the other containers were updating",
+        sync.suspicious as f64,
+    );
+
+    if let Some(latency) = crate::latency::last_alarm() {
+        let steps = [
+            ("decoded", latency.decoded_ms, "decoded"),
+            ("sink", latency.sink_ms, "sent to the output"),
+            ("first_sound", latency.first_sound_ms, "heard"),
+        ];
+        for (name, ms, description) in steps {
+            if let Some(ms) = ms {
+                gauge(
+                    &mut out,
+                    &format!("alarm_last_{name}_latency_seconds"),
+                    &format!("Time from the last alarm trigger until its sound was {description}"),
+                    ms as f64 / 1000.0,
+                );
+            }
+        }
+    }
+
+    out
+}
+
+#[allow(unused)]
+fn gauge(out: &mut String, name: &str, help: &str, value: f64) {
+    metric(out, name, "gauge", help, value);
+}
+
+#[allow(unused)]
+fn counter(out: &mut String, name: &str, help: &str, value: f64) {
+    metric(out, name, "counter", help, value);
+}
+
+#[allow(unused)]
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: f64) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} {kind}").unwrap();
+    writeln!(out, "{name} {value}").unwrap();
+}
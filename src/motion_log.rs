@@ -0,0 +1,541 @@
+//! Daily accelerometer logs, named `accelerometer-YYYY-MM-DD.csv`.
+//!
+//! Files from previous days are gzipped and archived as per-minute aggregates (see [`crate::archive`]).
+//! Files older than the retention period are deleted once they are archived.
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeDelta, Timelike, Utc};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::archive;
+use crate::config::SleepMonitorConfig;
+use crate::nights;
+use crate::respiration::Respiration;
+use crate::sleep_monitor::{AccelerometerData, MovementFilter};
+use crate::AlarmState;
+
+const PREFIX: &str = "accelerometer-";
+const DATE_FORMAT: &str = "%Y-%m-%d";
+/// Format of the timestamp in the first column.
+pub const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.3f";
+
+/// Id of the sensor on the user's side of the bed, which is the only one in single-sensor setups.
+pub const MY_SENSOR: u8 = 0;
+/// Id of the sensor on the partner's side. See [`crate::config::PartnerSensorConfig`].
+pub const PARTNER_SENSOR: u8 = 1;
+
+/// Buffered lines are written to disk at least this often.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+/// The file is synced to the storage at most this often, to spare the SD card.
+const SYNC_INTERVAL: Duration = Duration::from_secs(60);
+
+/// One logged reading, which is the mean of a few samples from the sensor.
+#[derive(Debug, Clone)]
+pub struct LogSample {
+    pub time: DateTime<Utc>,
+    /// [`MY_SENSOR`] or [`PARTNER_SENSOR`].
+    pub sensor: u8,
+    /// Number of sensor samples in the mean.
+    pub samples: usize,
+    pub alarm_playing: bool,
+    /// Unknown in CSV logs from before it was logged.
+    pub present: Option<bool>,
+    /// Change in acceleration since the previous reading, in g. Not stored in the CSV logs.
+    pub movement: Option<f32>,
+    pub data: AccelerometerData,
+    /// Breathing rate during the minute before the one of this reading. Only logged with the first reading after that
+    /// minute, see [`crate::sleep_monitor::SleepMonitor::take_respiration`].
+    pub respiration: Option<Respiration>,
+}
+
+impl LogSample {
+    pub fn to_csv(&self) -> String {
+        let d = &self.data;
+        format!(
+            "{},{},{},{},{},{},{},{},{},{},{},{},{},{}\n",
+            // YYYY-MM-DD HH:MM:SS.SSS
+            self.time.format(TIME_FORMAT),
+            self.samples,
+            self.alarm_playing as u32,
+            d.acc.0,
+            d.acc.1,
+            d.acc.2,
+            d.gyro.0,
+            d.gyro.1,
+            d.gyro.2,
+            d.temp,
+            self.present.map_or("", |p| if p { "1" } else { "0" }),
+            self.sensor,
+            self.respiration
+                .map_or(String::new(), |r| r.breaths_per_minute.to_string()),
+            self.respiration
+                .map_or(String::new(), |r| r.confidence.to_string()),
+        )
+    }
+
+    pub fn parse_csv(line: &str) -> Option<LogSample> {
+        let mut fields = line.trim_end().split(',');
+        let time = NaiveDateTime::parse_from_str(fields.next()?, TIME_FORMAT)
+            .ok()?
+            .and_utc();
+        let samples = fields.next()?.parse().ok()?;
+        let alarm_playing = fields.next()? != "0";
+        let mut values = [0.0f32; 7];
+        for v in &mut values {
+            *v = fields.next()?.parse().ok()?;
+        }
+        // Added later, so older logs don't have them
+        let present = match fields.next() {
+            Some("1") => Some(true),
+            Some("0") => Some(false),
+            _ => None,
+        };
+        let sensor = match fields.next() {
+            Some(sensor) => sensor.parse().ok()?,
+            None => MY_SENSOR,
+        };
+        let respiration = match (fields.next(), fields.next()) {
+            (Some(rate), Some(confidence)) if !rate.is_empty() => Some(Respiration {
+                breaths_per_minute: rate.parse().ok()?,
+                confidence: confidence.parse().ok()?,
+            }),
+            _ => None,
+        };
+        Some(LogSample {
+            time,
+            sensor,
+            samples,
+            alarm_playing,
+            present,
+            movement: None,
+            data: AccelerometerData {
+                acc: (values[0], values[1], values[2]),
+                gyro: (values[3], values[4], values[5]),
+                temp: values[6],
+            },
+            respiration,
+        })
+    }
+}
+
+/// Computes the movement of logged samples like the sleep monitor does, separately for each sensor.
+///
+/// Uses the default drift time constant, since the logs don't record which one was configured.
+#[derive(Debug, Default)]
+pub struct MovementTracker {
+    sensors: HashMap<u8, (MovementFilter, DateTime<Utc>)>,
+}
+
+impl MovementTracker {
+    /// Adds a sample, which must not be older than the previous one of the same sensor. Returns its movement.
+    pub fn update(&mut self, sample: &LogSample) -> Option<f32> {
+        let time_constant =
+            Duration::from_secs_f32(SleepMonitorConfig::default().drift_time_constant_seconds);
+        let (filter, prev) = self
+            .sensors
+            .entry(sample.sensor)
+            .or_insert_with(|| (MovementFilter::default(), sample.time));
+        let elapsed = (sample.time - *prev).to_std().unwrap_or_default();
+        *prev = sample.time;
+        filter.update(sample.data.acc, elapsed, time_constant)
+    }
+}
+
+/// Fills in the movement of samples from the CSV logs, see [`MovementTracker`].
+pub fn with_movement(samples: Vec<LogSample>) -> Vec<LogSample> {
+    let mut tracker = MovementTracker::default();
+    samples
+        .into_iter()
+        .map(|mut sample| {
+            sample.movement = tracker.update(&sample);
+            sample
+        })
+        .collect()
+}
+
+/// Parses the lines of the user's sensor, and fills in their movement. Lines from the partner's sensor are left out.
+pub fn parse_my_side<S: AsRef<str>>(lines: impl IntoIterator<Item = S>) -> Vec<LogSample> {
+    with_movement(
+        lines
+            .into_iter()
+            .filter_map(|l| LogSample::parse_csv(l.as_ref()))
+            .filter(|s| s.sensor == MY_SENSOR)
+            .collect(),
+    )
+}
+
+/// True if the local time of day of `time` is in the range of hours. The range wraps around midnight if `start_hour > end_hour`.
+pub fn in_hours(time: DateTime<Utc>, start_hour: u32, end_hour: u32) -> bool {
+    let hour = time.with_timezone(&Local).hour();
+    if start_hour <= end_hour {
+        start_hour <= hour && hour < end_hour
+    } else {
+        hour >= start_hour || hour < end_hour
+    }
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct SleepStats {
+    pub samples: u64,
+    /// Mean change in acceleration between readings, in g.
+    pub mean_movement: Option<f32>,
+    /// Fraction of the time when someone was in bed. Unknown for CSV logs from before it was logged.
+    pub present_fraction: Option<f32>,
+    /// Mean sleep onset latency during the last [`nights::ONSET_AVERAGE_NIGHTS`] nights, regardless of `days`.
+    /// `None` if the user did not fall asleep during any of them.
+    pub mean_onset_latency_minutes: Option<f32>,
+    /// Number of those nights during which the user never fell asleep. They are not included in the mean.
+    pub nights_without_onset: u32,
+}
+
+pub struct MotionLog {
+    dir: PathBuf,
+    retention_days: u32,
+    current: Option<(NaiveDate, BufWriter<File>)>,
+    last_flush: Instant,
+    last_sync: Instant,
+}
+
+fn log_path(dir: &Path, date: NaiveDate, gzipped: bool) -> PathBuf {
+    let extension = if gzipped { "csv.gz" } else { "csv" };
+    dir.join(format!("{PREFIX}{}.{extension}", date.format(DATE_FORMAT)))
+}
+
+/// Parses the date and whether the file is gzipped from the name of a log file.
+fn parse_log_name(name: &str) -> Option<(NaiveDate, bool)> {
+    let rest = name.strip_prefix(PREFIX)?;
+    let (date, gzipped) = if let Some(date) = rest.strip_suffix(".csv.gz") {
+        (date, true)
+    } else {
+        (rest.strip_suffix(".csv")?, false)
+    };
+    Some((NaiveDate::parse_from_str(date, DATE_FORMAT).ok()?, gzipped))
+}
+
+/// True if the raw log of `date` has not been deleted yet.
+pub fn has_log(dir: &Path, date: NaiveDate) -> bool {
+    log_path(dir, date, false).exists() || log_path(dir, date, true).exists()
+}
+
+fn gzip(path: &Path, destination: &Path) -> io::Result<()> {
+    let tmp = destination.with_extension("gz.tmp");
+    let mut encoder = GzEncoder::new(File::create(&tmp)?, Compression::default());
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::rename(&tmp, destination)?;
+    fs::remove_file(path)
+}
+
+impl MotionLog {
+    pub fn new(dir: &Path, retention_days: u32) -> MotionLog {
+        MotionLog {
+            dir: dir.to_owned(),
+            retention_days,
+            current: None,
+            last_flush: Instant::now(),
+            last_sync: Instant::now(),
+        }
+    }
+
+    /// Appends a line to the file of the day of `time`.
+    pub fn write(&mut self, time: DateTime<Utc>, line: &str) -> io::Result<()> {
+        let date = time.date_naive();
+        if self.current.as_ref().map(|(d, _)| *d) != Some(date) {
+            if let Some((_, file)) = self.current.take() {
+                file.into_inner()?.sync_all()?;
+            }
+            fs::create_dir_all(&self.dir)?;
+            let file = fs::OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(log_path(&self.dir, date, false))?;
+            self.current = Some((date, BufWriter::new(file)));
+            self.clean_up(date);
+        }
+
+        let (_, file) = self.current.as_mut().unwrap();
+        file.write_all(line.as_bytes())?;
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the buffered lines to the file. Also syncs the file if it has not been synced for [`SYNC_INTERVAL`].
+    ///
+    /// Called on significant events, like the alarm starting, so that they are not lost on a power cut.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.last_flush = Instant::now();
+        let Some((_, file)) = &mut self.current else {
+            return Ok(());
+        };
+        file.flush()?;
+        if self.last_sync.elapsed() >= SYNC_INTERVAL {
+            self.last_sync = Instant::now();
+            file.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the buffered lines, and syncs the file, like at shutdown.
+    pub fn close(self) -> io::Result<()> {
+        if let Some((_, file)) = self.current {
+            file.into_inner()?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Gzips and archives the files from before `today`, and deletes the ones older than the retention period.
+    ///
+    /// Files which could not be archived are kept, so that the data is not lost.
+    fn clean_up(&self, today: NaiveDate) {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Could not list {}: {}", self.dir.display(), e);
+                return;
+            }
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Some((date, gzipped)) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(parse_log_name)
+            else {
+                continue;
+            };
+            let archived = date < today
+                && (archive::is_archived(&self.dir, date)
+                    || match archive::archive_log(&self.dir, date, &path) {
+                        Ok(()) => {
+                            info!("Archived the accelerometer log {}", path.display());
+                            true
+                        }
+                        Err(e) => {
+                            warn!("Could not archive {}: {}", path.display(), e);
+                            false
+                        }
+                    });
+            let result = if archived && (today - date).num_days() > self.retention_days as i64 {
+                info!("Deleting old accelerometer log {}", path.display());
+                fs::remove_file(&path)
+            } else if !gzipped && date < today {
+                gzip(&path, &log_path(&self.dir, date, true))
+            } else {
+                Ok(())
+            };
+            if let Err(e) = result {
+                warn!("Could not clean up {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Opens a log file, decompressing it if it is gzipped.
+pub fn open(path: &Path) -> io::Result<Box<dyn BufRead>> {
+    let file = File::open(path)?;
+    if path.extension().is_some_and(|e| e == "gz") {
+        Ok(Box::new(BufReader::new(GzDecoder::new(file))))
+    } else {
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+/// Reads the lines logged between `from` and `to`, from both plain and gzipped files.
+pub fn read(dir: &Path, from: DateTime<Utc>, to: DateTime<Utc>) -> io::Result<Vec<String>> {
+    let (from, to) = (from.naive_utc(), to.naive_utc());
+    let mut lines = vec![];
+    let mut date = from.date();
+    while date <= to.date() {
+        let reader = match open(&log_path(dir, date, false)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                match open(&log_path(dir, date, true)) {
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                        date = date.succ_opt().unwrap();
+                        continue;
+                    }
+                    reader => reader?,
+                }
+            }
+            reader => reader?,
+        };
+        for line in reader.lines() {
+            let line = line?;
+            let time = line
+                .split(',')
+                .next()
+                .and_then(|t| NaiveDateTime::parse_from_str(t, TIME_FORMAT).ok());
+            if time.is_some_and(|t| from <= t && t <= to) {
+                lines.push(line);
+            }
+        }
+        date = date.succ_opt().unwrap();
+    }
+    Ok(lines)
+}
+
+/// The raw accelerometer data between two RFC 3339 timestamps, as CSV.
+#[get("/sleep/raw?<from>&<to>")]
+pub async fn get_raw(
+    state: &State<AlarmState>,
+    from: &str,
+    to: &str,
+) -> Result<String, (Status, String)> {
+    let parse = |t: &str| {
+        DateTime::parse_from_rfc3339(t)
+            .map(|t| t.with_timezone(&Utc))
+            .map_err(|e| (Status::BadRequest, format!("Invalid time `{t}`: {e}")))
+    };
+    let (from, to) = (parse(from)?, parse(to)?);
+    let config = state.config.get().motion.clone();
+    tokio::task::spawn_blocking(move || {
+        #[cfg(feature = "sqlite")]
+        if let Some(database) = &config.database {
+            let samples = crate::sleep_db::read(database, from, to).map_err(|e| e.to_string())?;
+            return Ok(samples.iter().map(LogSample::to_csv).collect());
+        }
+        let lines = read(&config.log_dir, from, to).map_err(|e| e.to_string())?;
+        Ok(lines.into_iter().map(|l| l + "\n").collect())
+    })
+    .await
+    .unwrap()
+    .map_err(|e: String| (Status::InternalServerError, e))
+}
+
+/// Statistics of the last `days`, only including the local hours from `start_hour` up to `end_hour`.
+///
+/// Uses the raw readings while they are kept, and the per-minute archive after that. For example `?days=30&start_hour=3&end_hour=4` gives the movement between 3 and 4 am during the last month.
+#[get("/sleep/stats?<days>&<start_hour>&<end_hour>")]
+pub async fn get_stats(
+    state: &State<AlarmState>,
+    days: u32,
+    start_hour: Option<u32>,
+    end_hour: Option<u32>,
+) -> Result<Json<SleepStats>, (Status, String)> {
+    let (start_hour, end_hour) = (start_hour.unwrap_or(0), end_hour.unwrap_or(24));
+    if start_hour >= 24 || end_hour > 24 {
+        return Err((Status::BadRequest, "Hours must be at most 24".to_owned()));
+    }
+    let to = Utc::now();
+    let from = to - TimeDelta::days(days as i64);
+    let config = state.config.get().motion.clone();
+    let first_night =
+        nights::sleep_date(to, &Local) - TimeDelta::days(nights::ONSET_AVERAGE_NIGHTS - 1);
+    let (mean_onset_latency_minutes, nights_without_onset) =
+        nights::mean_onset_latency(&state.sleep_summaries.entries(), first_night);
+    tokio::task::spawn_blocking(move || {
+        #[cfg(feature = "sqlite")]
+        if let Some(database) = &config.database {
+            return crate::sleep_db::stats(database, from, to, start_hour, end_hour)
+                .map_err(|e| e.to_string());
+        }
+        let minutes = archive::read(&config.log_dir, from, to).map_err(|e| e.to_string())?;
+        Ok(archive::stats(&minutes, start_hour, end_hour))
+    })
+    .await
+    .unwrap()
+    .map(|stats| {
+        Json(SleepStats {
+            mean_onset_latency_minutes,
+            nights_without_onset,
+            ..stats
+        })
+    })
+    .map_err(|e: String| (Status::InternalServerError, e))
+}
+
+#[test]
+fn test_rotation() {
+    use chrono::TimeZone;
+    let dir = std::env::temp_dir().join(format!("motion-log-test-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let time = |day, hour| Utc.with_ymd_and_hms(2024, 3, day, hour, 0, 0).unwrap();
+    let line = |t: DateTime<Utc>| format!("{},10,0\n", t.format(TIME_FORMAT));
+
+    let mut log = MotionLog::new(&dir, 1);
+    for t in [time(1, 22), time(2, 3), time(2, 23), time(3, 1)] {
+        log.write(t, &line(t)).unwrap();
+    }
+    log.flush().unwrap();
+
+    let date = |day| NaiveDate::from_ymd_opt(2024, 3, day).unwrap();
+    assert!(!log_path(&dir, date(1), false).exists());
+    assert!(!log_path(&dir, date(1), true).exists());
+    assert!(!log_path(&dir, date(2), false).exists());
+    assert!(log_path(&dir, date(2), true).exists());
+    assert!(log_path(&dir, date(3), false).exists());
+
+    // Reads across the gzipped and the current file
+    assert_eq!(
+        read(&dir, time(2, 12), time(3, 12)).unwrap(),
+        vec![line(time(2, 23)).trim_end(), line(time(3, 1)).trim_end()]
+    );
+    assert!(read(&dir, time(5, 0), time(6, 0)).unwrap().is_empty());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_csv_stats() {
+    use chrono::TimeZone;
+    let sample = |minute, acc| LogSample {
+        sensor: MY_SENSOR,
+        time: Local
+            .with_ymd_and_hms(2024, 3, 1, 3, minute, 0)
+            .unwrap()
+            .with_timezone(&Utc),
+        samples: 10,
+        alarm_playing: false,
+        present: None,
+        movement: None,
+        data: AccelerometerData {
+            acc: (acc, 0.0, -1.0),
+            gyro: (0.5, 0.25, 0.0),
+            temp: 21.5,
+        },
+        respiration: None,
+    };
+    let mut samples = vec![sample(0, 0.0), sample(1, 0.5), sample(2, 0.5)];
+    samples[2].respiration = Some(Respiration {
+        breaths_per_minute: 14.5,
+        confidence: 0.75,
+    });
+    for s in &samples {
+        let parsed = LogSample::parse_csv(&s.to_csv()).unwrap();
+        assert_eq!(parsed.time, s.time);
+        assert_eq!(parsed.data.acc, s.data.acc);
+        assert_eq!(parsed.data.gyro, s.data.gyro);
+        assert_eq!(parsed.sensor, MY_SENSOR);
+        assert_eq!(parsed.respiration, s.respiration);
+    }
+    // Logs from before the presence and the sensor were logged
+    let old = LogSample::parse_csv("2024-03-01 03:00:00.000,10,0,0,0,-1,0,0,0,21.5").unwrap();
+    assert_eq!((old.present, old.sensor), (None, MY_SENSOR));
+    let partner = LogSample {
+        sensor: PARTNER_SENSOR,
+        present: Some(true),
+        ..samples[0].clone()
+    };
+    let parsed = LogSample::parse_csv(&partner.to_csv()).unwrap();
+    assert_eq!(
+        (parsed.present, parsed.sensor),
+        (Some(true), PARTNER_SENSOR)
+    );
+
+    let minutes = archive::aggregate(&with_movement(samples));
+    let stats = archive::stats(&minutes, 3, 4);
+    assert_eq!((stats.samples, stats.present_fraction), (3, None));
+    // The readings are a minute apart, so most of the step is removed as drift
+    assert!((stats.mean_movement.unwrap() - 0.0631).abs() < 1e-4);
+    assert_eq!(archive::stats(&minutes, 4, 3).samples, 0);
+    assert_eq!(archive::stats(&minutes, 22, 4).samples, 3);
+}
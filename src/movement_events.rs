@@ -0,0 +1,229 @@
+//! Discrete events of significant movement, so that it can be seen afterwards what the alarm acted on.
+//!
+//! An event lasts from the first to the last reading for which [`crate::sleep_monitor::SleepMonitor::is_significant_movement`] is true.
+//! Events older than [`crate::config::MotionConfig::movement_event_retention_days`] are deleted from the log once a day.
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::Arc;
+
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+
+use crate::json_log::JsonLog;
+use crate::nights;
+use crate::AlarmState;
+
+pub const MOVEMENT_EVENTS_FILE: &str = "./movement_events.jsonl";
+/// Number of events kept in memory, about a few nights.
+const MAX_RECENT: usize = 500;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct MovementEvent {
+    /// Increases by one for every event, also across restarts.
+    pub id: u64,
+    pub start: DateTime<Utc>,
+    /// Time from the first to the last reading of the event.
+    pub duration_seconds: f32,
+    /// Largest change in acceleration during the event, in g.
+    pub peak: f32,
+}
+
+impl MovementEvent {
+    pub fn end(&self) -> DateTime<Utc> {
+        self.start + TimeDelta::milliseconds((self.duration_seconds * 1000.0) as i64)
+    }
+}
+
+/// The event which is going on, the recent ones, and the log of the finished events within the retention period.
+pub struct MovementEvents {
+    next_id: u64,
+    current: Option<MovementEvent>,
+    recent: VecDeque<MovementEvent>,
+    log: Arc<JsonLog<MovementEvent>>,
+    retention: TimeDelta,
+    /// The day on which the old events were last deleted.
+    pruned: Option<NaiveDate>,
+}
+
+impl MovementEvents {
+    /// Continues from the events in `log`, after deleting the ones older than `retention_days`.
+    pub fn new(log: Arc<JsonLog<MovementEvent>>, retention_days: u32) -> MovementEvents {
+        let retention = TimeDelta::days(retention_days as i64);
+        let now = Utc::now();
+        let mut entries = log.entries();
+        let next_id = entries.last().map_or(0, |e| e.id + 1);
+        if entries.first().is_some_and(|e| e.start < now - retention) {
+            prune(&log, now - retention, next_id);
+            entries.retain(|e| e.start >= now - retention || e.id + 1 == next_id);
+        }
+        let recent = entries
+            .iter()
+            .skip(entries.len().saturating_sub(MAX_RECENT))
+            .copied()
+            .collect::<VecDeque<_>>();
+        MovementEvents {
+            next_id,
+            current: None,
+            recent,
+            log,
+            retention,
+            pruned: Some(now.date_naive()),
+        }
+    }
+
+    pub fn load(path: &Path, retention_days: u32) -> MovementEvents {
+        MovementEvents::new(Arc::new(JsonLog::new(path)), retention_days)
+    }
+
+    /// Updates with the reading at `now`, whose change in acceleration was `delta`. Returns the event if it finished.
+    pub fn update(
+        &mut self,
+        now: DateTime<Utc>,
+        significant: bool,
+        delta: Option<f32>,
+    ) -> Option<MovementEvent> {
+        if !significant {
+            let event = self.current.take()?;
+            if self.pruned != Some(now.date_naive()) {
+                self.pruned = Some(now.date_naive());
+                prune(&self.log, now - self.retention, self.next_id);
+            }
+            self.log.record(&event);
+            if self.recent.len() == MAX_RECENT {
+                self.recent.pop_front();
+            }
+            self.recent.push_back(event);
+            info!(
+                "Movement event {} ended after {:.0} s, with a peak of {} g",
+                event.id, event.duration_seconds, event.peak
+            );
+            return Some(event);
+        }
+        let event = self.current.get_or_insert_with(|| {
+            let event = MovementEvent {
+                id: self.next_id,
+                start: now,
+                duration_seconds: 0.0,
+                peak: 0.0,
+            };
+            self.next_id += 1;
+            event
+        });
+        event.duration_seconds = (now - event.start).num_milliseconds().max(0) as f32 / 1000.0;
+        event.peak = event.peak.max(delta.unwrap_or(0.0));
+        None
+    }
+
+    /// The event which is going on.
+    pub fn current(&self) -> Option<&MovementEvent> {
+        self.current.as_ref()
+    }
+
+    /// The recent events which overlap `from..=to`, including the current one.
+    pub fn overlapping(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> impl Iterator<Item = &MovementEvent> {
+        self.recent
+            .iter()
+            .chain(&self.current)
+            .filter(move |e| e.start <= to && e.end() >= from)
+    }
+
+    /// The ids of the recent events which overlap `from..=to`, including the current one.
+    pub fn between(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<u64> {
+        self.overlapping(from, to).map(|e| e.id).collect()
+    }
+}
+
+/// Deletes the events which started before `before` from `log`. The last event before `next_id` is always kept, so
+/// that the ids continue from it after a restart.
+fn prune(log: &JsonLog<MovementEvent>, before: DateTime<Utc>, next_id: u64) {
+    let deleted = log.retain(|e| e.start >= before || e.id + 1 == next_id);
+    if deleted > 0 {
+        info!("Deleted {} movement events from before {}", deleted, before);
+    }
+}
+
+/// The movement events during the night of `date`, which goes from noon to noon in local time.
+#[get("/sleep/events?<date>")]
+pub async fn get_movement_events(
+    state: &State<AlarmState>,
+    date: &str,
+) -> Result<Json<Vec<MovementEvent>>, (Status, String)> {
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|e| (Status::BadRequest, format!("Invalid date `{date}`: {e}")))?;
+    let to = nights::end_of_night(date, &chrono::Local);
+    let from = to - TimeDelta::days(1);
+    let (log, current) = {
+        let s = state.sleep_monitor.lock().await;
+        (s.movement_events.log.clone(), s.movement_events.current)
+    };
+    let mut events = log.entries();
+    events.extend(current);
+    events.retain(|e| from <= e.start && e.start < to);
+    Ok(Json(events))
+}
+
+#[test]
+fn test_movement_events() {
+    let path =
+        std::env::temp_dir().join(format!("movement-events-test-{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let start = DateTime::parse_from_rfc3339("2024-03-02T05:00:00Z")
+        .unwrap()
+        .with_timezone(&Utc);
+    let at = |seconds| start + TimeDelta::seconds(seconds);
+
+    let mut events = MovementEvents::load(&path, 30);
+    assert_eq!(events.update(at(0), false, Some(0.01)), None);
+    assert_eq!(events.update(at(1), true, Some(0.05)), None);
+    assert_eq!(events.update(at(2), true, Some(0.2)), None);
+    assert_eq!(events.current().map(|e| e.id), Some(0));
+    assert_eq!(events.between(at(0), at(1)), [0]);
+    assert_eq!(events.update(at(4), true, Some(0.1)), None);
+    assert_eq!(
+        events.update(at(5), false, None),
+        Some(MovementEvent {
+            id: 0,
+            start: at(1),
+            duration_seconds: 3.0,
+            peak: 0.2,
+        })
+    );
+    assert_eq!(events.current(), None);
+
+    // Continues with the next id after a restart
+    let mut events = MovementEvents::load(&path, 30);
+    events.update(at(10), true, None);
+    assert_eq!(events.between(at(0), at(20)), [0, 1]);
+    assert_eq!(events.between(at(6), at(20)), [1]);
+
+    // Old events are deleted once a day, when an event finishes
+    std::fs::remove_file(&path).unwrap();
+    let now = Utc::now();
+    let log = Arc::new(JsonLog::new(&path));
+    let mut events = MovementEvents::new(log.clone(), 30);
+    assert!(log.entries().is_empty());
+    for days in [40, 20] {
+        events.update(now - TimeDelta::days(days), true, None);
+        events.update(now - TimeDelta::days(days), false, None);
+    }
+    assert_eq!(log.entries().len(), 2);
+    events.update(now + TimeDelta::days(1), true, None);
+    events.update(now + TimeDelta::days(1), false, None);
+    assert_eq!(
+        log.entries().iter().map(|e| e.id).collect::<Vec<_>>(),
+        [1, 2]
+    );
+    // The ids continue after the old events are gone, also if all of them are
+    assert_eq!(MovementEvents::load(&path, 30).next_id, 3);
+    let events = MovementEvents::new(log.clone(), 0);
+    assert_eq!(log.entries().iter().map(|e| e.id).collect::<Vec<_>>(), [2]);
+    assert_eq!(events.next_id, 3);
+    std::fs::remove_file(&path).unwrap();
+}
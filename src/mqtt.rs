@@ -0,0 +1,199 @@
+//! The MQTT broker which the state is synced through.
+//!
+//! Read at startup from [`MQTT_CONFIG_FILE`] (or the file given with `--mqtt-config`), and from the environment. The
+//! file takes precedence. It is kept out of [`crate::config::Config`], so that the password is never served by `/config`.
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+use thiserror::Error;
+
+pub const MQTT_CONFIG_FILE: &str = "./mqtt.json";
+const DEFAULT_CLIENT_ID: &str = "alarm";
+const DEFAULT_SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_STALE_STATE_HOURS: f32 = 48.0;
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct MqttFile {
+    host: Option<String>,
+    client_id: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    sync_timeout_seconds: Option<f32>,
+    stale_state_hours: Option<f32>,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct MqttConfig {
+    /// Like `mqtt://example.com:1883`.
+    pub host: String,
+    /// The machine id is appended, so that several alarms can use the same one.
+    pub client_id: String,
+    pub username: String,
+    pub password: String,
+    /// How long to wait for the initial sync before continuing offline, see [`crate::offline`].
+    pub sync_timeout: Duration,
+    /// How long the alarm state may go without updates while the other containers are updating, before it is flagged
+    /// as suspicious. See [`crate::sync_health`].
+    pub stale_state_after: Duration,
+}
+
+// Keeps the password out of the logs
+impl std::fmt::Debug for MqttConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MqttConfig")
+            .field("host", &self.host)
+            .field("client_id", &self.client_id)
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .field("sync_timeout", &self.sync_timeout)
+            .field("stale_state_after", &self.stale_state_after)
+            .finish()
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum MqttConfigError {
+    #[error("Could not read the MQTT config `{0}`: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Invalid MQTT config `{0}`: {1}")]
+    Parse(PathBuf, serde_json::Error),
+    #[error("The MQTT {0} is not set. Set `{0}` in {1}, or the {2} environment variable.")]
+    Missing(&'static str, String, &'static str),
+    #[error("Invalid MQTT {0} {1}, it must be positive")]
+    InvalidDuration(&'static str, f32),
+}
+
+/// Loads the config from `path`, or from [`MQTT_CONFIG_FILE`] if it exists, and then from the environment.
+///
+/// `env` looks up an environment variable.
+pub fn load(
+    path: Option<&Path>,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<MqttConfig, MqttConfigError> {
+    load_or_default(path, Path::new(MQTT_CONFIG_FILE), env)
+}
+
+/// Like [`load`], but with `default` instead of [`MQTT_CONFIG_FILE`].
+fn load_or_default(
+    path: Option<&Path>,
+    default: &Path,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<MqttConfig, MqttConfigError> {
+    let (path, required) = match path {
+        Some(path) => (path, true),
+        None => (default, false),
+    };
+    let file = match std::fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str::<MqttFile>(&contents)
+            .map_err(|e| MqttConfigError::Parse(path.to_owned(), e))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && !required => MqttFile::default(),
+        Err(e) => return Err(MqttConfigError::Read(path.to_owned(), e)),
+    };
+
+    let get = |field: &'static str, value: Option<String>, var: &'static str| {
+        value
+            .or_else(|| env(var))
+            .filter(|v| !v.is_empty())
+            .ok_or_else(|| MqttConfigError::Missing(field, path.display().to_string(), var))
+    };
+    Ok(MqttConfig {
+        host: get("host", file.host, "ALARM_MQTT_HOST")?,
+        client_id: get("client_id", file.client_id, "ALARM_MQTT_CLIENT_ID")
+            .unwrap_or_else(|_| DEFAULT_CLIENT_ID.to_owned()),
+        username: get("username", file.username, "ALARM_MQTT_USERNAME")?,
+        password: get("password", file.password, "ALARM_MQTT_PASSWORD")?,
+        sync_timeout: match file.sync_timeout_seconds {
+            Some(seconds) => duration("sync_timeout_seconds", seconds, 1.0)?,
+            None => DEFAULT_SYNC_TIMEOUT,
+        },
+        stale_state_after: duration(
+            "stale_state_hours",
+            file.stale_state_hours.unwrap_or(DEFAULT_STALE_STATE_HOURS),
+            3600.0,
+        )?,
+    })
+}
+
+/// `value` in units of `unit` seconds.
+fn duration(field: &'static str, value: f32, unit: f32) -> Result<Duration, MqttConfigError> {
+    Duration::try_from_secs_f32(value * unit)
+        .ok()
+        .filter(|t| !t.is_zero())
+        .ok_or(MqttConfigError::InvalidDuration(field, value))
+}
+
+#[test]
+fn test_load_mqtt_config() {
+    let path = std::env::temp_dir().join(format!("mqtt-test-{}.json", std::process::id()));
+    let env = |var: &str| {
+        match var {
+            "ALARM_MQTT_HOST" => Some("mqtt://env:1883"),
+            "ALARM_MQTT_USERNAME" => Some("env_user"),
+            "ALARM_MQTT_PASSWORD" => Some("env_password"),
+            _ => None,
+        }
+        .map(str::to_owned)
+    };
+
+    // A missing file is an error only if it was given explicitly
+    let config = load_or_default(None, &path, env).unwrap();
+    assert_eq!(config.host, "mqtt://env:1883");
+    assert_eq!(config.client_id, "alarm");
+    assert_eq!(config.username, "env_user");
+    assert_eq!(config.password, "env_password");
+    assert_eq!(config.sync_timeout, DEFAULT_SYNC_TIMEOUT);
+    assert!(matches!(
+        load(Some(&path), env),
+        Err(MqttConfigError::Read(..))
+    ));
+    assert!(matches!(
+        load(Some(&path), |_| None),
+        Err(MqttConfigError::Read(..))
+    ));
+
+    // The file takes precedence over the environment
+    std::fs::write(
+        &path,
+        r#"{"host": "mqtt://file:1883", "password": "file_password"}"#,
+    )
+    .unwrap();
+    let config = load(Some(&path), env).unwrap();
+    assert_eq!(
+        config,
+        MqttConfig {
+            host: "mqtt://file:1883".to_owned(),
+            client_id: "alarm".to_owned(),
+            username: "env_user".to_owned(),
+            password: "file_password".to_owned(),
+            sync_timeout: DEFAULT_SYNC_TIMEOUT,
+            stale_state_after: Duration::from_secs(48 * 3600),
+        }
+    );
+    assert!(!format!("{config:?}").contains("file_password"));
+
+    let error = load(Some(&path), |_| None).unwrap_err();
+    assert!(matches!(
+        error,
+        MqttConfigError::Missing("username", _, "ALARM_MQTT_USERNAME")
+    ));
+
+    std::fs::write(&path, r#"{"sync_timeout_seconds": 5}"#).unwrap();
+    assert_eq!(
+        load(Some(&path), env).unwrap().sync_timeout,
+        Duration::from_secs(5)
+    );
+    std::fs::write(&path, r#"{"sync_timeout_seconds": -1}"#).unwrap();
+    assert!(matches!(
+        load(Some(&path), env),
+        Err(MqttConfigError::InvalidDuration("sync_timeout_seconds", _))
+    ));
+
+    std::fs::write(&path, r#"{"hots": "mqtt://file:1883"}"#).unwrap();
+    assert!(matches!(
+        load(Some(&path), env),
+        Err(MqttConfigError::Parse(..))
+    ));
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,440 @@
+//! Time in bed per night, from the debounced presence of the sleep monitor, and a summary of how well each night was slept.
+use std::sync::Arc;
+
+use chrono::{DateTime, NaiveDate, TimeDelta, TimeZone, Utc};
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::json_log::JsonLog;
+use crate::respiration::Respiration;
+use crate::sleep_monitor::Presence;
+use crate::AlarmState;
+
+pub const BED_LOG_FILE: &str = "./bed_log.jsonl";
+pub const SUMMARY_FILE: &str = "./sleep_summaries.jsonl";
+
+/// Someone got into or out of bed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct BedTransition {
+    pub time: DateTime<Utc>,
+    pub in_bed: bool,
+}
+
+/// Time in bed during one night.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Night {
+    /// The date on which the night started. Nights go from noon to noon in local time.
+    pub date: NaiveDate,
+    /// When the first time in bed started.
+    pub entered: DateTime<Utc>,
+    /// When the last time in bed ended. The current time if still in bed.
+    pub left: DateTime<Utc>,
+    /// Total time in bed, excluding the times out of bed in between.
+    pub in_bed_minutes: f32,
+    /// Number of times in bed.
+    pub segments: usize,
+    /// Made when the morning alarm finishes.
+    pub summary: Option<SleepSummary>,
+}
+
+/// One minute of a night, from the sleep monitor.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct NightMinute {
+    pub in_bed: bool,
+    pub asleep: bool,
+    /// Number of samples with movement.
+    pub movement: u32,
+    pub respiration: Option<Respiration>,
+}
+
+/// How well a night was slept.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SleepSummary {
+    /// The night, as in [`Night::date`].
+    pub date: NaiveDate,
+    pub in_bed_minutes: u32,
+    pub asleep_minutes: u32,
+    /// Minutes from first getting into bed until falling asleep, which is the first time asleep for
+    /// [`crate::config::SleepMonitorConfig::sleep_onset_minutes`] in a row. `None` if never asleep that long.
+    pub onset_latency_minutes: Option<u32>,
+    /// Number of times awake or out of bed for at least [`WAKE_EPISODE_MINUTES`] after falling asleep.
+    pub wake_episodes: u32,
+    /// Percentage of the minutes in bed with any movement.
+    pub movement_index: f32,
+    /// Mean breathing rate while asleep, of the minutes with a confident estimate. `None` if there were none.
+    #[serde(default)]
+    pub breaths_per_minute: Option<f32>,
+    /// From 0 to 100. See [`summarize`].
+    pub score: u8,
+}
+
+/// Shorter times awake are turning over in the sleep, and do not count as waking up.
+pub const WAKE_EPISODE_MINUTES: usize = 2;
+/// Sleeping at least this long gives the full points for the duration.
+const TARGET_SLEEP_MINUTES: f32 = 8.0 * 60.0;
+/// Falling asleep after this long, or waking up this many times, gives no points for it.
+const MAX_ONSET_LATENCY_MINUTES: f32 = 60.0;
+const MAX_WAKE_EPISODES: f32 = 5.0;
+/// Breathing rate estimates below this confidence are mostly noise, and are left out of the summary.
+const MIN_RESPIRATION_CONFIDENCE: f32 = 0.3;
+/// The stats include the mean onset latency of this many nights.
+pub const ONSET_AVERAGE_NIGHTS: i64 = 14;
+
+/// Summarizes the minutes of one night, oldest first. The user fell asleep at the start of the first `onset_minutes` asleep in a row.
+///
+/// The score is the sum of
+/// - 40 × the time asleep relative to 8 hours, at most 1,
+/// - 30 × the sleep efficiency, the time asleep relative to the time in bed,
+/// - 10 × (1 − the onset latency relative to 60 minutes), at least 0, or 0 if the user never fell asleep,
+/// - 10 × (1 − the wake episodes relative to 5), at least 0,
+/// - 10 × (1 − the movement index / 100).
+///
+/// A night without any sleep scores 0.
+pub fn summarize(date: NaiveDate, minutes: &[NightMinute], onset_minutes: usize) -> SleepSummary {
+    let in_bed_minutes = minutes.iter().filter(|m| m.in_bed).count() as u32;
+    let asleep_minutes = minutes.iter().filter(|m| m.asleep).count() as u32;
+    let first_in_bed = minutes.iter().position(|m| m.in_bed);
+    let first_asleep = minutes.iter().position(|m| m.asleep);
+    let last_asleep = minutes.iter().rposition(|m| m.asleep);
+    let onset = minutes
+        .windows(onset_minutes.max(1))
+        .position(|w| w.iter().all(|m| m.asleep));
+    let onset_latency_minutes = onset.map(|i| (i - first_in_bed.unwrap_or(i)) as u32);
+
+    let mut wake_episodes = 0;
+    if let (Some(first), Some(last)) = (first_asleep, last_asleep) {
+        let mut awake = 0;
+        for m in &minutes[first..=last] {
+            if m.asleep {
+                awake = 0;
+            } else {
+                awake += 1;
+                if awake == WAKE_EPISODE_MINUTES {
+                    wake_episodes += 1;
+                }
+            }
+        }
+    }
+
+    let moving = minutes
+        .iter()
+        .filter(|m| m.in_bed && m.movement > 0)
+        .count();
+    let movement_index = if in_bed_minutes > 0 {
+        100.0 * moving as f32 / in_bed_minutes as f32
+    } else {
+        0.0
+    };
+
+    let breaths = minutes
+        .iter()
+        .filter(|m| m.asleep)
+        .filter_map(|m| m.respiration)
+        .filter(|r| r.confidence >= MIN_RESPIRATION_CONFIDENCE)
+        .map(|r| r.breaths_per_minute)
+        .collect::<Vec<_>>();
+    let breaths_per_minute = (!breaths.is_empty())
+        .then(|| (breaths.iter().sum::<f32>() / breaths.len() as f32 * 10.0).round() / 10.0);
+
+    let score = if asleep_minutes > 0 {
+        let duration = (asleep_minutes as f32 / TARGET_SLEEP_MINUTES).min(1.0);
+        let efficiency = asleep_minutes as f32 / in_bed_minutes.max(asleep_minutes) as f32;
+        let onset = onset_latency_minutes.map_or(0.0, |latency| {
+            1.0 - (latency as f32 / MAX_ONSET_LATENCY_MINUTES).min(1.0)
+        });
+        let episodes = 1.0 - (wake_episodes as f32 / MAX_WAKE_EPISODES).min(1.0);
+        let stillness = 1.0 - movement_index / 100.0;
+        (40.0 * duration + 30.0 * efficiency + 10.0 * onset + 10.0 * episodes + 10.0 * stillness)
+            .round() as u8
+    } else {
+        0
+    };
+
+    SleepSummary {
+        date,
+        in_bed_minutes,
+        asleep_minutes,
+        onset_latency_minutes,
+        wake_episodes,
+        movement_index: (movement_index * 10.0).round() / 10.0,
+        breaths_per_minute,
+        score,
+    }
+}
+
+/// The mean onset latency of the nights from `first` on, and the number of those nights during which the user never fell asleep.
+///
+/// If a night was summarized more than once, the last summary is used.
+pub fn mean_onset_latency(summaries: &[SleepSummary], first: NaiveDate) -> (Option<f32>, u32) {
+    let mut nights = summaries
+        .iter()
+        .rev()
+        .filter(|s| s.date >= first)
+        .collect::<Vec<_>>();
+    nights.sort_by_key(|s| s.date);
+    nights.dedup_by_key(|s| s.date);
+    let latencies = nights
+        .iter()
+        .filter_map(|s| s.onset_latency_minutes)
+        .collect::<Vec<_>>();
+    let mean = (!latencies.is_empty())
+        .then(|| latencies.iter().sum::<u32>() as f32 / latencies.len() as f32);
+    (mean, (nights.len() - latencies.len()) as u32)
+}
+
+/// Pairs up the transitions into times in bed. A time in bed which has not ended yet ends at `now`.
+///
+/// Repeated transitions in the same direction are ignored. They happen if the program is restarted while someone is in bed,
+/// in which case the time in between counts as in bed.
+fn segments(
+    transitions: &[BedTransition],
+    now: DateTime<Utc>,
+) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+    let mut segments = vec![];
+    let mut entered = None;
+    for t in transitions {
+        match (t.in_bed, entered) {
+            (true, None) => entered = Some(t.time),
+            (false, Some(start)) => {
+                segments.push((start, t.time));
+                entered = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(start) = entered {
+        segments.push((start, now));
+    }
+    segments
+}
+
+/// The night that `time` belongs to.
+pub(crate) fn sleep_date<Tz: TimeZone>(time: DateTime<Utc>, tz: &Tz) -> NaiveDate {
+    (time.with_timezone(tz).naive_local() - TimeDelta::hours(12)).date()
+}
+
+/// The noon at which the night of `date` ends.
+pub(crate) fn end_of_night<Tz: TimeZone>(date: NaiveDate, tz: &Tz) -> DateTime<Utc> {
+    let noon = (date + TimeDelta::days(1)).and_hms_opt(12, 0, 0).unwrap();
+    tz.from_local_datetime(&noon)
+        .earliest()
+        .map(|t| t.with_timezone(&Utc))
+        .unwrap_or_else(|| noon.and_utc())
+}
+
+/// Sums up the times in bed per night, oldest first. Times in bed which span noon are split between the nights.
+fn nights<Tz: TimeZone>(segments: &[(DateTime<Utc>, DateTime<Utc>)], tz: &Tz) -> Vec<Night> {
+    let mut nights: Vec<Night> = vec![];
+    for &(mut start, end) in segments {
+        while start < end {
+            let date = sleep_date(start, tz);
+            let part_end = end.min(end_of_night(date, tz));
+            let minutes = (part_end - start).num_milliseconds() as f32 / 60_000.0;
+            match nights.last_mut() {
+                Some(night) if night.date == date => {
+                    night.left = part_end;
+                    night.in_bed_minutes += minutes;
+                    night.segments += 1;
+                }
+                _ => nights.push(Night {
+                    date,
+                    entered: start,
+                    left: part_end,
+                    in_bed_minutes: minutes,
+                    segments: 1,
+                    summary: None,
+                }),
+            }
+            start = part_end;
+        }
+    }
+    nights
+}
+
+/// Records the changes of the presence from [`crate::sleep_monitor::SleepMonitor::subscribe`].
+pub async fn record_transitions(
+    mut presence: watch::Receiver<Presence>,
+    log: Arc<JsonLog<BedTransition>>,
+) {
+    // Nothing is recorded while the presence is unknown
+    let mut in_bed = presence.borrow_and_update().in_bed.unwrap_or(false);
+    while presence.changed().await.is_ok() {
+        let Some(current) = presence.borrow_and_update().in_bed else {
+            continue;
+        };
+        if current != in_bed {
+            in_bed = current;
+            log.record(&BedTransition {
+                time: Utc::now(),
+                in_bed,
+            });
+        }
+    }
+}
+
+/// Time in bed during the last `days` nights, oldest first, with the summaries of the nights which have one.
+#[get("/sleep/nights?<days>")]
+pub async fn get_nights(state: &State<AlarmState>, days: Option<u32>) -> Json<Vec<Night>> {
+    let now = Utc::now();
+    let days = days.unwrap_or(14).min(100 * 365) as i64;
+    let first = sleep_date(now, &chrono::Local) - TimeDelta::days(days - 1);
+    let mut nights = nights(&segments(&state.bed_log.entries(), now), &chrono::Local);
+    nights.retain(|n| n.date >= first);
+    // A night is summarized again if another alarm finishes during it. The last summary wins.
+    for summary in state.sleep_summaries.entries() {
+        if let Some(night) = nights.iter_mut().find(|n| n.date == summary.date) {
+            night.summary = Some(summary);
+        }
+    }
+    Json(nights)
+}
+
+#[test]
+fn test_nights() {
+    let tz = chrono::FixedOffset::east_opt(3600).unwrap();
+    let at = |d, h, m| {
+        tz.with_ymd_and_hms(2024, 3, d, h, m, 0)
+            .unwrap()
+            .with_timezone(&Utc)
+    };
+    let transition = |time, in_bed| BedTransition { time, in_bed };
+    let transitions = [
+        // A night with a bathroom break
+        transition(at(1, 23, 0), true),
+        transition(at(2, 3, 0), false),
+        transition(at(2, 3, 10), true),
+        // Restarted while in bed
+        transition(at(2, 5, 0), true),
+        transition(at(2, 7, 0), false),
+        // A nap across noon
+        transition(at(2, 11, 30), true),
+        transition(at(2, 12, 30), false),
+        // Still in bed
+        transition(at(2, 22, 0), true),
+    ];
+    let nights = nights(&segments(&transitions, at(3, 1, 0)), &tz);
+    let date = |d| NaiveDate::from_ymd_opt(2024, 3, d).unwrap();
+    assert_eq!(
+        nights,
+        [
+            Night {
+                date: date(1),
+                entered: at(1, 23, 0),
+                left: at(2, 12, 0),
+                in_bed_minutes: 8.0 * 60.0 - 10.0 + 30.0,
+                segments: 3,
+                summary: None,
+            },
+            Night {
+                date: date(2),
+                entered: at(2, 12, 0),
+                left: at(3, 1, 0),
+                in_bed_minutes: 30.0 + 3.0 * 60.0,
+                segments: 2,
+                summary: None,
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_summarize() {
+    let date = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap();
+    let awake = NightMinute {
+        in_bed: true,
+        asleep: false,
+        movement: 5,
+        respiration: None,
+    };
+    let asleep = NightMinute {
+        in_bed: true,
+        asleep: true,
+        movement: 0,
+        respiration: Some(Respiration {
+            breaths_per_minute: 14.0,
+            confidence: 0.8,
+        }),
+    };
+    let turning = NightMinute {
+        movement: 2,
+        ..asleep
+    };
+    let out_of_bed = NightMinute::default();
+    let night = |parts: &[(NightMinute, usize)]| {
+        let minutes = parts
+            .iter()
+            .flat_map(|&(m, n)| std::iter::repeat_n(m, n))
+            .collect::<Vec<_>>();
+        summarize(date, &minutes, 10)
+    };
+
+    // Falls asleep quickly, and sleeps through the night
+    let solid = night(&[(awake, 10), (asleep, 470), (turning, 10)]);
+    assert_eq!(solid.in_bed_minutes, 490);
+    assert_eq!(solid.asleep_minutes, 480);
+    assert_eq!(solid.onset_latency_minutes, Some(10));
+    assert_eq!(solid.wake_episodes, 0);
+    assert_eq!(solid.movement_index, 4.1);
+    assert_eq!(solid.breaths_per_minute, Some(14.0));
+    // 40 + 30 * 480 / 490 + 10 * 50 / 60 + 10 + 10 * (1 - 20 / 490)
+    assert_eq!(solid.score, 97);
+
+    // Lies awake for long, and wakes up all the time. Turning over for a single minute is not an episode.
+    let mut parts = vec![(awake, 60)];
+    for _ in 0..8 {
+        parts.extend([(asleep, 30), (awake, 1), (turning, 10), (awake, 3)]);
+    }
+    let restless = night(&parts);
+    assert_eq!(restless.onset_latency_minutes, Some(60));
+    assert_eq!(restless.wake_episodes, 7);
+    assert!(restless.score < 60, "{restless:?}");
+
+    // Got up in the middle of the night. Also counts as waking up.
+    let interrupted = night(&[(awake, 10), (asleep, 200), (out_of_bed, 30), (asleep, 200)]);
+    assert_eq!(interrupted.in_bed_minutes, 410);
+    assert_eq!(interrupted.wake_episodes, 1);
+    assert!(interrupted.score < solid.score && interrupted.score > restless.score);
+
+    // Dozed off now and then, but never for long enough to count as falling asleep
+    let dozing = night(&[
+        (awake, 30),
+        (asleep, 5),
+        (awake, 20),
+        (asleep, 9),
+        (awake, 10),
+    ]);
+    assert_eq!(dozing.asleep_minutes, 14);
+    assert_eq!(dozing.onset_latency_minutes, None);
+    assert!(dozing.score > 0);
+    let dozed_off = night(&[(awake, 30), (asleep, 5), (awake, 20), (asleep, 10)]);
+    assert_eq!(dozed_off.onset_latency_minutes, Some(55));
+
+    // Never fell asleep
+    let sleepless = night(&[(awake, 120)]);
+    assert_eq!(sleepless.onset_latency_minutes, None);
+    assert_eq!(sleepless.breaths_per_minute, None);
+    assert_eq!(sleepless.score, 0);
+
+    // The nights without sleep onset are counted instead of averaged
+    let summary = |day, onset_latency_minutes| SleepSummary {
+        date: NaiveDate::from_ymd_opt(2024, 3, day).unwrap(),
+        onset_latency_minutes,
+        ..solid.clone()
+    };
+    let summaries = [
+        summary(1, Some(60)),
+        summary(2, Some(10)),
+        summary(3, None),
+        summary(4, Some(30)),
+        // Summarized again after a second alarm
+        summary(4, Some(20)),
+    ];
+    assert_eq!(
+        mean_onset_latency(&summaries, summaries[1].date),
+        (Some(15.0), 1)
+    );
+    assert_eq!(mean_onset_latency(&summaries[2..3], date), (None, 1));
+    assert_eq!(mean_onset_latency(&[], date), (None, 0));
+}
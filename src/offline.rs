@@ -0,0 +1,281 @@
+//! Offline mode, for when the MQTT broker can not be reached at startup.
+//!
+//! The containers are created locally with the values from [`crate::local_state`], and are registered with the broker
+//! in the background, so that neither connecting nor registering holds up the startup. If that and the initial sync do
+//! not finish within [`crate::mqtt::MqttConfig::sync_timeout`], the alarm continues with the local values, and the
+//! client keeps connecting in the background. Once the broker is back, the values which were changed while offline are
+//! published again, so that they win over the ones from the broker. The others take the broker's values.
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use brevduva::{SyncStorage, SyncedContainer};
+use chrono::Utc;
+use futures::future::BoxFuture;
+use log::{error, info, warn};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+use crate::local_state::{LocalState, Snapshot};
+use crate::mqtt::MqttConfig;
+use crate::sync_health::{Connection, SyncHealth};
+
+/// How often the containers are checked for changes to save.
+///
+/// Changes made offline within this long before the broker comes back are lost to the broker's values.
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The values in `latest` which differ from the ones in `restored`, that is, which were changed while offline.
+fn offline_changes(restored: &Snapshot, latest: &Snapshot) -> Snapshot {
+    latest
+        .iter()
+        .filter(|(name, value)| restored.get(*name) != Some(value))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect()
+}
+
+/// A synced container, which can be used before (and whether or not) the broker has been reached.
+///
+/// Until it has been registered with the broker, the value is only kept here.
+pub struct Container<T> {
+    name: &'static str,
+    offline: Mutex<T>,
+    synced: OnceLock<Arc<SyncedContainer<T>>>,
+    /// Fingerprint of the value which this alarm set last, so that it can be told apart from the broker's values.
+    set_locally: AtomicU64,
+}
+
+impl<T> Container<T>
+where
+    T: Serialize + DeserializeOwned + Clone + Hash + Send + Sync + 'static,
+{
+    pub fn get(&self) -> Option<T> {
+        match self.synced.get() {
+            Some(synced) => synced.get(),
+            None => Some(self.offline.lock().unwrap().clone()),
+        }
+    }
+
+    pub async fn set(&self, value: T) {
+        self.set_locally
+            .store(fingerprint(&Some(value.clone())), Ordering::Relaxed);
+        match self.synced.get() {
+            Some(synced) => {
+                synced.set(value).await;
+            }
+            None => *self.offline.lock().unwrap() = value,
+        }
+    }
+
+    pub async fn update<F: FnOnce(&mut T) + Send>(&self, f: F) {
+        match self.synced.get() {
+            Some(synced) => {
+                synced.update(f).await;
+            }
+            None => f(&mut *self.offline.lock().unwrap()),
+        }
+        self.set_locally
+            .store(fingerprint(&self.get()), Ordering::Relaxed);
+    }
+
+    /// Fingerprint of the current value, and whether it is the value which this alarm set last.
+    pub fn fingerprint(&self) -> (u64, bool) {
+        let current = fingerprint(&self.get());
+        (current, current == self.set_locally.load(Ordering::Relaxed))
+    }
+}
+
+fn fingerprint<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// A [`Container`] of any type, so that they can all be registered by [`sync`].
+trait Register: Send + Sync {
+    fn register<'a>(&'a self, storage: &'a SyncStorage) -> BoxFuture<'a, ()>;
+}
+
+impl<T> Register for Container<T>
+where
+    T: Serialize + DeserializeOwned + Clone + Hash + Send + Sync + 'static,
+{
+    fn register<'a>(&'a self, storage: &'a SyncStorage) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let value = self.offline.lock().unwrap().clone();
+            match storage.add_container(self.name, value.clone()).await {
+                Ok(synced) => {
+                    let _ = self.synced.set(synced.clone());
+                    // Set while it was being registered
+                    let latest = self.offline.lock().unwrap().clone();
+                    if fingerprint(&latest) != fingerprint(&value) {
+                        synced.set(latest).await;
+                    }
+                }
+                Err(e) => error!(
+                    "Could not register {} with the MQTT broker, it is only kept locally: {:?}",
+                    self.name, e
+                ),
+            }
+        })
+    }
+}
+
+/// Creates the containers, which are registered with the broker by [`start`].
+#[derive(Default)]
+pub struct Containers(Vec<Arc<dyn Register>>);
+
+impl Containers {
+    /// Creates the container `name`, which has the value `initial` until the broker has been reached.
+    pub fn add<T>(&mut self, name: &'static str, initial: T) -> Arc<Container<T>>
+    where
+        T: Serialize + DeserializeOwned + Clone + Hash + Send + Sync + 'static,
+    {
+        let container = Arc::new(Container {
+            name,
+            set_locally: AtomicU64::new(fingerprint(&Some(initial.clone()))),
+            offline: Mutex::new(initial),
+            synced: OnceLock::new(),
+        });
+        self.0.push(container.clone());
+        container
+    }
+}
+
+/// Connects to the broker, registers the containers, and sends `synced` after the initial sync. Takes as long as it
+/// takes for the broker to be reached.
+async fn sync(
+    mqtt: MqttConfig,
+    client_id: String,
+    containers: Containers,
+    synced: oneshot::Sender<()>,
+) {
+    let storage = SyncStorage::new(&client_id, &mqtt.host, &mqtt.username, &mqtt.password).await;
+    for container in &containers.0 {
+        container.register(&storage).await;
+    }
+    storage.wait_for_sync().await;
+    let _ = synced.send(());
+    // Kept, so that the containers stay synced
+    let _storage = storage;
+    std::future::pending::<()>().await;
+}
+
+/// Connects to the broker in the background. Waits for the initial sync for at most
+/// [`MqttConfig::sync_timeout`], and continues offline with the local state if it does not finish in time. Then keeps
+/// saving the state in the background.
+pub async fn start(
+    mqtt: MqttConfig,
+    client_id: String,
+    containers: Containers,
+    local: LocalState,
+    health: Arc<Mutex<SyncHealth>>,
+) {
+    let timeout = mqtt.sync_timeout;
+    let (synced_tx, mut synced) = oneshot::channel();
+    tokio::spawn(sync(mqtt, client_id, containers, synced_tx));
+    let (restored, synced) = match tokio::time::timeout(timeout, &mut synced).await {
+        Ok(Ok(())) => {
+            health
+                .lock()
+                .unwrap()
+                .set_connection(Connection::Synced, Utc::now());
+            (None, None)
+        }
+        result => {
+            warn!("No sync with the MQTT broker within {timeout:?}, continuing offline with the local state");
+            health
+                .lock()
+                .unwrap()
+                .set_connection(Connection::Offline, Utc::now());
+            // Unless the connection failed for good
+            (Some(local.snapshot()), result.is_err().then_some(synced))
+        }
+    };
+    tokio::spawn(keep_saved(synced, local, restored, health));
+}
+
+/// Saves the state whenever it changes. If offline, that is if `restored` is set, also merges the state once `synced`
+/// is received.
+async fn keep_saved(
+    mut synced: Option<oneshot::Receiver<()>>,
+    local: LocalState,
+    mut restored: Option<Snapshot>,
+    health: Arc<Mutex<SyncHealth>>,
+) {
+    let mut save_failed = false;
+    loop {
+        match (&restored, &mut synced) {
+            (Some(before), Some(receiver)) => {
+                match tokio::time::timeout(SAVE_INTERVAL, receiver).await {
+                    Ok(Ok(())) => {
+                        // The broker's values have replaced the local ones by now, but the saved ones are still there
+                        let changes = offline_changes(before, &local.saved());
+                        info!(
+                            "Synced with the MQTT broker again, keeping {} values changed while offline",
+                            changes.len()
+                        );
+                        local.set(&changes).await;
+                        health
+                            .lock()
+                            .unwrap()
+                            .set_connection(Connection::Synced, Utc::now());
+                        restored = None;
+                        synced = None;
+                    }
+                    Ok(Err(_)) => {
+                        error!("The connection to the MQTT broker failed, staying offline");
+                        synced = None;
+                    }
+                    Err(_) => {}
+                }
+            }
+            _ => tokio::time::sleep(SAVE_INTERVAL).await,
+        }
+
+        match local.save_changes() {
+            Ok(_) => save_failed = false,
+            // Retried every time, but only logged once
+            Err(e) if !save_failed => {
+                warn!("Could not save the local state to {:?}: {e}", local.path());
+                save_failed = true;
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+#[test]
+fn test_offline_changes() {
+    use serde_json::json;
+
+    let restored = Snapshot::from([
+        ("alarm/state".to_owned(), json!({"enabled": true})),
+        ("alarm/lucid_sfx_volume".to_owned(), json!(50)),
+    ]);
+    // Only what was changed while offline is kept over the broker's values
+    let mut latest = restored.clone();
+    latest.insert("alarm/state".to_owned(), json!({"enabled": false}));
+    latest.insert("alarm/last_played".to_owned(), json!(null));
+    assert_eq!(
+        offline_changes(&restored, &latest),
+        Snapshot::from([
+            ("alarm/last_played".to_owned(), json!(null)),
+            ("alarm/state".to_owned(), json!({"enabled": false})),
+        ])
+    );
+    assert_eq!(offline_changes(&restored, &restored), Snapshot::new());
+}
+
+#[test]
+fn test_container_offline() {
+    let mut containers = Containers::default();
+    let volume = containers.add("alarm/lucid_sfx_volume", 50);
+    // Usable before the broker has been reached
+    assert_eq!(volume.get(), Some(50));
+    futures::executor::block_on(volume.set(20));
+    futures::executor::block_on(volume.update(|v| *v += 1));
+    assert_eq!(volume.get(), Some(21));
+}
@@ -0,0 +1,374 @@
+use log::warn;
+use rodio::{Device, DeviceTrait, Sink, Source};
+
+use std::f32::consts::PI;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time;
+use time::{Duration, Instant};
+
+/// Finds an output device whose name contains `name`, or the default output device if `name` is `None`.
+pub fn find_output_device(name: Option<&str>) -> Option<Device> {
+    match name {
+        Some(name) => rodio::output_devices()
+            .ok()?
+            .find(|device| device.name().map(|n| n.contains(name)).unwrap_or(false)),
+        None => rodio::default_output_device(),
+    }
+}
+
+/// Starts playing `source` on `device` at the given volume.
+///
+/// Returns `None` if the device could not be opened.
+pub fn open_sink<S>(device: &Device, source: S, volume: f32) -> Option<Sink>
+where
+    S: Source<Item = f32> + Send + 'static,
+{
+    // rodio panics if the device has gone away while we try to open it
+    let sink = std::panic::catch_unwind(AssertUnwindSafe(|| Sink::new(device))).ok()?;
+    sink.set_volume(volume);
+    sink.append(source);
+    Some(sink)
+}
+
+/// A source which can be played by several sinks in turn.
+///
+/// If the output device stops working, playback can be moved to a new sink using [`ResumableSource::resume`],
+/// and it will continue from the same position.
+pub struct ResumableSource<S> {
+    inner: Arc<Mutex<S>>,
+    samples_read: Arc<AtomicUsize>,
+    /// Set once the inner source has ended.
+    exhausted: Arc<AtomicBool>,
+}
+
+impl<S> ResumableSource<S>
+where
+    S: Source<Item = f32>,
+{
+    pub fn new(inner: S) -> Self {
+        ResumableSource {
+            inner: Arc::new(Mutex::new(inner)),
+            samples_read: Arc::new(AtomicUsize::new(0)),
+            exhausted: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// A new handle to the same source, which continues where this one is.
+    pub fn resume(&self) -> Self {
+        ResumableSource {
+            inner: self.inner.clone(),
+            samples_read: self.samples_read.clone(),
+            exhausted: self.exhausted.clone(),
+        }
+    }
+
+    /// Total number of samples that have been played through any of the handles.
+    pub fn samples_read(&self) -> usize {
+        self.samples_read.load(Ordering::Relaxed)
+    }
+
+    /// The source has ended, so no more samples will be read.
+    pub fn is_exhausted(&self) -> bool {
+        self.exhausted.load(Ordering::Relaxed)
+    }
+}
+
+impl<S> Iterator for ResumableSource<S>
+where
+    S: Source<Item = f32>,
+{
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let sample = self.inner.lock().unwrap().next();
+        if sample.is_some() {
+            self.samples_read.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.exhausted.store(true, Ordering::Relaxed);
+        }
+        sample
+    }
+}
+
+impl<S> Source for ResumableSource<S>
+where
+    S: Source<Item = f32>,
+{
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.lock().unwrap().current_frame_len()
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.inner.lock().unwrap().channels()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.inner.lock().unwrap().sample_rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.lock().unwrap().total_duration()
+    }
+}
+
+/// How often the output consumption rate is measured.
+const MONITOR_WINDOW: Duration = Duration::from_secs(1);
+
+/// Windows where less than this fraction of the expected samples were consumed count as an underrun.
+const UNDERRUN_RATIO: f32 = 0.9;
+
+/// If less than this fraction of the expected samples are consumed for a long time, the output is considered stalled.
+const STALL_RATIO: f32 = 0.5;
+
+static UNDERRUNS: AtomicU64 = AtomicU64::new(0);
+static RECOVERIES: AtomicU64 = AtomicU64::new(0);
+
+pub struct OutputStats {
+    pub underruns: u64,
+    pub recoveries: u64,
+}
+
+pub fn stats() -> OutputStats {
+    OutputStats {
+        underruns: UNDERRUNS.load(Ordering::Relaxed),
+        recoveries: RECOVERIES.load(Ordering::Relaxed),
+    }
+}
+
+/// Counts a sink which had to be recreated because the output stalled.
+pub fn record_recovery() {
+    RECOVERIES.fetch_add(1, Ordering::Relaxed);
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum OutputHealth {
+    Ok,
+    /// The output has consumed samples too slowly for longer than the stall timeout.
+    Stalled,
+}
+
+/// Compares how many samples the output has consumed with how many it should have consumed in the same wall time.
+///
+/// Underruns show up as windows where too few samples were consumed.
+/// If the output keeps falling behind (or stops completely), it is reported as stalled.
+pub struct OutputMonitor {
+    stall_timeout: Duration,
+    window_start: Instant,
+    window_samples: usize,
+    /// Samples per second, which changes e.g. when going from the wakeup tone to the actual sound.
+    window_rate: f32,
+    behind_since: Option<Instant>,
+}
+
+impl OutputMonitor {
+    pub fn new(stall_timeout: Duration, samples_read: usize, now: Instant) -> Self {
+        OutputMonitor {
+            stall_timeout,
+            window_start: now,
+            window_samples: samples_read,
+            window_rate: 0.0,
+            behind_since: None,
+        }
+    }
+
+    /// Starts over, e.g. after the sink has been recreated.
+    pub fn reset(&mut self, samples_read: usize, now: Instant) {
+        *self = OutputMonitor::new(self.stall_timeout, samples_read, now);
+    }
+
+    /// Should be called regularly with the total number of samples read and the current samples per second.
+    ///
+    /// Once the source is `exhausted`, nothing more is consumed, which is not a stall.
+    pub fn update(
+        &mut self,
+        samples_read: usize,
+        rate: f32,
+        exhausted: bool,
+        now: Instant,
+    ) -> OutputHealth {
+        if exhausted {
+            self.behind_since = None;
+            return OutputHealth::Ok;
+        }
+        if rate != self.window_rate {
+            // Measuring across a rate change would be misleading
+            self.window_start = now;
+            self.window_samples = samples_read;
+            self.window_rate = rate;
+        }
+
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed >= MONITOR_WINDOW {
+            let expected = rate * elapsed.as_secs_f32();
+            let ratio = (samples_read - self.window_samples) as f32 / expected;
+            if ratio < UNDERRUN_RATIO {
+                UNDERRUNS.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    "Audio output underrun: {:.0}% of the expected samples were played in the last {:.1} seconds",
+                    ratio * 100.0,
+                    elapsed.as_secs_f32()
+                );
+            }
+            if ratio < STALL_RATIO {
+                self.behind_since.get_or_insert(self.window_start);
+            } else {
+                self.behind_since = None;
+            }
+            self.window_start = now;
+            self.window_samples = samples_read;
+        }
+
+        match self.behind_since {
+            Some(since) if now.duration_since(since) > self.stall_timeout => OutputHealth::Stalled,
+            _ => OutputHealth::Ok,
+        }
+    }
+}
+
+/// An endless series of beeps, used when the normal audio output fails.
+pub struct Beeper {
+    sample_index: u64,
+    frequency: f32,
+    beep_duration: f32,
+    period: f32,
+}
+
+impl Beeper {
+    const SAMPLE_RATE: u32 = 44100;
+
+    pub fn new(frequency: f32) -> Self {
+        Beeper {
+            sample_index: 0,
+            frequency,
+            beep_duration: 0.5,
+            period: 1.0,
+        }
+    }
+}
+
+impl Iterator for Beeper {
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let t = self.sample_index as f32 / Self::SAMPLE_RATE as f32;
+        self.sample_index += 1;
+        if t % self.period < self.beep_duration {
+            Some(0.5 * (2.0 * PI * self.frequency * t).sin())
+        } else {
+            Some(0.0)
+        }
+    }
+}
+
+impl Source for Beeper {
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        Self::SAMPLE_RATE
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[test]
+fn test_resumable_source() {
+    let source = ResumableSource::new(rodio::buffer::SamplesBuffer::new(
+        1,
+        100,
+        vec![1.0f32, 2.0, 3.0, 4.0],
+    ));
+    let mut first = source.resume();
+    assert_eq!(first.next(), Some(1.0));
+    assert_eq!(first.next(), Some(2.0));
+    drop(first);
+
+    let mut second = source.resume();
+    assert_eq!(second.next(), Some(3.0));
+    assert_eq!(source.samples_read(), 3);
+    assert!(!source.is_exhausted());
+    assert_eq!(second.next(), Some(4.0));
+    assert_eq!(second.next(), None);
+    assert_eq!(source.samples_read(), 4);
+    assert!(source.is_exhausted());
+}
+
+#[test]
+fn test_output_monitor() {
+    let t0 = Instant::now();
+    let at = |ms| t0 + Duration::from_millis(ms);
+    let mut monitor = OutputMonitor::new(Duration::from_secs(3), 0, t0);
+
+    // Playing at the expected rate
+    assert_eq!(monitor.update(0, 1000.0, false, at(0)), OutputHealth::Ok);
+    assert_eq!(
+        monitor.update(1000, 1000.0, false, at(1000)),
+        OutputHealth::Ok
+    );
+
+    // A short hiccup is only an underrun
+    let underruns = stats().underruns;
+    assert_eq!(
+        monitor.update(1200, 1000.0, false, at(2000)),
+        OutputHealth::Ok
+    );
+    assert_eq!(
+        monitor.update(2200, 1000.0, false, at(3000)),
+        OutputHealth::Ok
+    );
+    assert!(stats().underruns > underruns);
+
+    // Playing too slowly for a long time is a stall
+    assert_eq!(
+        monitor.update(2300, 1000.0, false, at(4000)),
+        OutputHealth::Ok
+    );
+    assert_eq!(
+        monitor.update(2400, 1000.0, false, at(5000)),
+        OutputHealth::Ok
+    );
+    assert_eq!(
+        monitor.update(2500, 1000.0, false, at(6000)),
+        OutputHealth::Ok
+    );
+    assert_eq!(
+        monitor.update(2500, 1000.0, false, at(7100)),
+        OutputHealth::Stalled
+    );
+
+    monitor.reset(2500, at(7100));
+    assert_eq!(
+        monitor.update(2500, 1000.0, false, at(7100)),
+        OutputHealth::Ok
+    );
+
+    // A sound which ends normally is not a stall, however long the output has been idle since
+    let mut monitor = OutputMonitor::new(Duration::from_secs(3), 0, t0);
+    assert_eq!(monitor.update(0, 1000.0, false, at(0)), OutputHealth::Ok);
+    assert_eq!(
+        monitor.update(1000, 1000.0, false, at(1000)),
+        OutputHealth::Ok
+    );
+    for ms in (2000..=10_000).step_by(1000) {
+        assert_eq!(monitor.update(1500, 1000.0, true, at(ms)), OutputHealth::Ok);
+    }
+}
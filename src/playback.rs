@@ -0,0 +1,178 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+/// What a sound is played for. Starting to play something stops everything with a lower or equal priority.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PlaybackPriority {
+    /// The inaudible tone which keeps the speakers awake.
+    KeepAlive,
+    /// Sound previews and test tones.
+    Preview,
+    LucidCue,
+    Alarm,
+}
+
+/// How long to wait for lower priority playback to fade out, before starting anyway.
+const HANDOVER_TIMEOUT: Duration = Duration::from_secs(2);
+
+struct Active {
+    id: u64,
+    priority: PlaybackPriority,
+    cancelled: Arc<AtomicBool>,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    active: Vec<Active>,
+}
+
+#[derive(Default)]
+struct Inner {
+    state: Mutex<State>,
+    released: Condvar,
+}
+
+/// Makes sure that only one thing plays at a time, and that the most important one wins.
+#[derive(Clone, Default)]
+pub struct PlaybackCoordinator {
+    inner: Arc<Inner>,
+}
+
+impl PlaybackCoordinator {
+    /// Asks to start playing. Returns `None` if something with a higher priority is playing.
+    ///
+    /// Anything else that is playing is told to stop, and this blocks until it has faded out (for at most [`HANDOVER_TIMEOUT`]).
+    /// Playback may continue until the returned lease is dropped, or [`PlaybackLease::is_cancelled`] is set.
+    pub fn request(&self, priority: PlaybackPriority) -> Option<PlaybackLease> {
+        let deadline = Instant::now() + HANDOVER_TIMEOUT;
+        let mut state = self.inner.state.lock().unwrap();
+        loop {
+            if state.active.iter().any(|a| a.priority > priority) {
+                return None;
+            }
+            for active in &state.active {
+                if !active.cancelled.swap(true, Ordering::SeqCst) {
+                    info!(
+                        "Stopping {:?} playback to play {:?}",
+                        active.priority, priority
+                    );
+                }
+            }
+
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if state.active.is_empty() {
+                break;
+            } else if timeout.is_zero() {
+                warn!("Other playback did not stop in time. Playing {priority:?} anyway.");
+                break;
+            }
+            state = self.inner.released.wait_timeout(state, timeout).unwrap().0;
+        }
+
+        let id = state.next_id;
+        state.next_id += 1;
+        let cancelled = Arc::new(AtomicBool::new(false));
+        state.active.push(Active {
+            id,
+            priority,
+            cancelled: cancelled.clone(),
+        });
+        Some(PlaybackLease {
+            id,
+            cancelled,
+            inner: self.inner.clone(),
+        })
+    }
+
+    /// Tells any playback with the given priority to stop.
+    pub fn cancel(&self, priority: PlaybackPriority) {
+        let state = self.inner.state.lock().unwrap();
+        for active in state.active.iter().filter(|a| a.priority == priority) {
+            active.cancelled.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Tells all playback to stop, and blocks until it has faded out (for at most [`HANDOVER_TIMEOUT`]).
+    ///
+    /// Returns false if something did not stop in time.
+    pub fn stop_all(&self) -> bool {
+        let deadline = Instant::now() + HANDOVER_TIMEOUT;
+        let mut state = self.inner.state.lock().unwrap();
+        for active in &state.active {
+            active.cancelled.store(true, Ordering::SeqCst);
+        }
+        while !state.active.is_empty() {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                return false;
+            }
+            state = self.inner.released.wait_timeout(state, timeout).unwrap().0;
+        }
+        true
+    }
+
+    pub fn is_playing(&self) -> bool {
+        !self.inner.state.lock().unwrap().active.is_empty()
+    }
+}
+
+/// Permission to play, given out by [`PlaybackCoordinator::request`]. Playback ends when it is dropped.
+pub struct PlaybackLease {
+    id: u64,
+    cancelled: Arc<AtomicBool>,
+    inner: Arc<Inner>,
+}
+
+impl PlaybackLease {
+    /// True when something else wants to play. The sound should then fade out and stop.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for PlaybackLease {
+    fn drop(&mut self) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.active.retain(|a| a.id != self.id);
+        self.inner.released.notify_all();
+    }
+}
+
+#[test]
+fn test_playback_priority() {
+    let coordinator = PlaybackCoordinator::default();
+    let preview = coordinator.request(PlaybackPriority::Preview).unwrap();
+    assert!(coordinator.is_playing());
+
+    let alarm = {
+        let coordinator = coordinator.clone();
+        std::thread::spawn(move || coordinator.request(PlaybackPriority::Alarm))
+    };
+    while !preview.is_cancelled() {
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    drop(preview);
+    let alarm = alarm.join().unwrap().unwrap();
+    assert!(!alarm.is_cancelled());
+
+    // Less important sounds have to wait until the alarm is done
+    assert!(coordinator.request(PlaybackPriority::LucidCue).is_none());
+    drop(alarm);
+    assert!(!coordinator.is_playing());
+    assert!(coordinator.request(PlaybackPriority::LucidCue).is_some());
+
+    // Everything fades out at shutdown, also the alarm
+    let alarm = coordinator.request(PlaybackPriority::Alarm).unwrap();
+    let player = std::thread::spawn(move || {
+        while !alarm.is_cancelled() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    });
+    assert!(coordinator.stop_all());
+    assert!(!coordinator.is_playing());
+    player.join().unwrap();
+}
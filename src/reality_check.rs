@@ -0,0 +1,416 @@
+//! Reality checks during the day: short prompts at random times, as reminders to check whether one is dreaming.
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Local, NaiveTime, TimeDelta, TimeZone, Utc};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Config, ConfigError};
+use crate::json_log::JsonLog;
+#[cfg(feature = "audio")]
+use crate::latency::LatencyTrace;
+use crate::lucid::{LucidConfig, TimeWindow};
+use crate::offline::Container;
+use crate::playback::{PlaybackLease, PlaybackPriority};
+use crate::sounds::{self, SoundKind};
+use crate::AlarmState;
+
+pub const REALITY_CHECK_LOG_FILE: &str = "./reality_checks.jsonl";
+pub const REALITY_CHECK_NOTICED_FILE: &str = "./reality_checks_noticed.jsonl";
+/// Prompts are cut off after this long.
+const MAX_PROMPT_SECONDS: f32 = 30.0;
+/// How often the config is checked while the reality checks are disabled.
+const DISABLED_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct RealityCheckConfig {
+    pub enabled: bool,
+    /// Prompts are played during this time of day. A changed window is used from the next day.
+    pub window: TimeWindow,
+    pub prompts_per_day: u32,
+    /// Least time between two prompts.
+    pub min_spacing_minutes: f32,
+}
+
+impl Default for RealityCheckConfig {
+    fn default() -> Self {
+        RealityCheckConfig {
+            enabled: false,
+            window: TimeWindow {
+                start: NaiveTime::from_hms_opt(9, 0, 0).unwrap(),
+                end: NaiveTime::from_hms_opt(21, 0, 0).unwrap(),
+            },
+            prompts_per_day: 5,
+            min_spacing_minutes: 60.0,
+        }
+    }
+}
+
+// Containers must be hashable
+impl std::hash::Hash for RealityCheckConfig {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.enabled.hash(state);
+        self.window.hash(state);
+        self.prompts_per_day.hash(state);
+        self.min_spacing_minutes.to_bits().hash(state);
+    }
+}
+
+impl RealityCheckConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if !self.min_spacing_minutes.is_finite() || self.min_spacing_minutes < 0.0 {
+            return Err(ConfigError::Invalid(
+                "min_spacing_minutes must be a non-negative number".to_owned(),
+            ));
+        }
+        let window_minutes = self.window.duration().num_minutes() as f32;
+        if self.prompts_per_day.saturating_sub(1) as f32 * self.min_spacing_minutes > window_minutes
+        {
+            return Err(ConfigError::Invalid(format!(
+                "{} reality checks, {} minutes apart, do not fit in the window",
+                self.prompts_per_day, self.min_spacing_minutes
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Random times for `count` prompts, in minutes from the start of a window which is `window_minutes` long.
+///
+/// The times are sorted and at least `spacing_minutes` apart. They are placed at random in the window without the
+/// spacing, which is then added after each one.
+fn schedule_prompts(
+    rng: &mut impl Rng,
+    window_minutes: f32,
+    count: u32,
+    spacing_minutes: f32,
+) -> Vec<f32> {
+    let slack = (window_minutes - count.saturating_sub(1) as f32 * spacing_minutes).max(0.0);
+    let mut times = (0..count)
+        .map(|_| rng.gen::<f32>() * slack)
+        .collect::<Vec<_>>();
+    times.sort_by(f32::total_cmp);
+    for (i, time) in times.iter_mut().enumerate() {
+        *time += i as f32 * spacing_minutes;
+    }
+    times
+}
+
+/// The start of the window which is going on at `now`, or of the next one.
+fn window_start<Tz: TimeZone>(window: &TimeWindow, now: DateTime<Utc>, tz: &Tz) -> DateTime<Utc> {
+    let today = now.with_timezone(tz).date_naive();
+    [-1, 0, 1]
+        .into_iter()
+        .filter_map(|days| {
+            let start = (today + TimeDelta::days(days)).and_time(window.start);
+            tz.from_local_datetime(&start)
+                .earliest()
+                .map(|t| t.with_timezone(&Utc))
+        })
+        .find(|start| *start + window.duration() > now)
+        .unwrap_or(now)
+}
+
+/// A reality check prompt, which was played or skipped.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RealityCheckPrompt {
+    /// Increases by one for every prompt, also across restarts.
+    pub id: u64,
+    pub time: DateTime<Utc>,
+    /// The sound, relative to the alarm sound directory if it is inside it. `None` if the prompt was skipped.
+    pub sound: Option<String>,
+    /// Why the prompt was not played.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skipped: Option<String>,
+    /// Set when the user reports that they noticed the prompt. Stored separately, see [`NoticedPrompt`].
+    #[serde(default)]
+    pub noticed: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct NoticedPrompt {
+    id: u64,
+    time: DateTime<Utc>,
+}
+
+/// The prompts, and which of them were noticed, in separate logs so that both can be appended to.
+pub struct RealityCheckLog {
+    prompts: JsonLog<RealityCheckPrompt>,
+    noticed: JsonLog<NoticedPrompt>,
+    next_id: AtomicU64,
+}
+
+impl RealityCheckLog {
+    /// Continues with the ids after the prompts in `prompts`.
+    pub fn new(prompts: &Path, noticed: &Path) -> RealityCheckLog {
+        let prompts = JsonLog::new(prompts);
+        let next_id = prompts
+            .entries()
+            .iter()
+            .map(|p: &RealityCheckPrompt| p.id + 1)
+            .max()
+            .unwrap_or(0);
+        RealityCheckLog {
+            prompts,
+            noticed: JsonLog::new(noticed),
+            next_id: AtomicU64::new(next_id),
+        }
+    }
+
+    /// Records a prompt at the current time, and returns its id.
+    fn record(&self, sound: Option<String>, skipped: Option<String>) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.prompts.record(&RealityCheckPrompt {
+            id,
+            time: Utc::now(),
+            sound,
+            skipped,
+            noticed: false,
+        });
+        id
+    }
+
+    /// The prompts since `since`, oldest first.
+    pub fn prompts(&self, since: DateTime<Utc>) -> Vec<RealityCheckPrompt> {
+        let mut prompts = self.prompts.entries();
+        prompts.retain(|p| p.time >= since);
+        for noticed in self.noticed.entries() {
+            if let Some(prompt) = prompts.iter_mut().find(|p| p.id == noticed.id) {
+                prompt.noticed = true;
+            }
+        }
+        prompts
+    }
+
+    /// Returns false if there is no played prompt with the id.
+    pub fn mark_noticed(&self, id: u64) -> bool {
+        if !self
+            .prompts
+            .entries()
+            .iter()
+            .any(|p| p.id == id && p.skipped.is_none())
+        {
+            return false;
+        }
+        self.noticed.record(&NoticedPrompt {
+            id,
+            time: Utc::now(),
+        });
+        true
+    }
+}
+
+/// Plays a prompt, unless anything else is playing.
+fn play_prompt(alarm_state: &AlarmState, volume: &Container<i32>) {
+    let skip = |reason: &str| {
+        info!("Skipping a reality check, since {}", reason);
+        alarm_state
+            .reality_checks
+            .record(None, Some(reason.to_owned()));
+    };
+    if alarm_state.is_playing.get().unwrap_or(false) {
+        return skip("the alarm is playing");
+    }
+    if alarm_state.playback.is_playing() {
+        return skip("something else is playing");
+    }
+
+    let config = alarm_state.config.get();
+    let dir = SoundKind::RealityCheck.dir(&config.sounds);
+    let path = match sounds::random_alarm_sound(dir, &config.sounds, None, &[], &[]) {
+        Ok(path) => path,
+        Err(e) => {
+            error!("Could not pick a reality check sound: {}", e);
+            return;
+        }
+    };
+    let Some(lease) = alarm_state.playback.request(PlaybackPriority::LucidCue) else {
+        return skip("the alarm is playing");
+    };
+    let name = sounds::sound_name(&config.sounds.directory, &path);
+    let id = alarm_state.reality_checks.record(Some(name.clone()), None);
+    info!("Playing reality check {}: {}", id, name);
+    futures::executor::block_on(alarm_state.events.now_playing(Some(&name)));
+    play_prompt_file(&path, volume, &config, &lease);
+    futures::executor::block_on(alarm_state.events.now_playing(None));
+}
+
+#[cfg(feature = "audio")]
+fn play_prompt_file(path: &Path, volume: &Container<i32>, config: &Config, lease: &PlaybackLease) {
+    if let Err(e) = crate::alarm::play_audio(
+        path,
+        |_| Some(volume.get().unwrap() as f32 / 100.0),
+        false,
+        Some(Duration::from_secs_f32(MAX_PROMPT_SECONDS)),
+        config,
+        &mut LatencyTrace::new("reality check"),
+        lease,
+    ) {
+        error!("Could not play the reality check: {}", e);
+    }
+}
+
+/// Prompts cannot be played without audio support.
+#[cfg(not(feature = "audio"))]
+fn play_prompt_file(
+    path: &Path,
+    _volume: &Container<i32>,
+    _config: &Config,
+    _lease: &PlaybackLease,
+) {
+    error!(
+        "Could not play the reality check {}, since the audio feature is disabled",
+        path.display()
+    );
+}
+
+async fn sleep_until(time: DateTime<Utc>) {
+    tokio::time::sleep((time - Utc::now()).to_std().unwrap_or_default()).await;
+}
+
+/// Plays the prompts of each day, at times which are picked at the start of the day's window.
+pub async fn start_reality_checks(
+    alarm_state: AlarmState,
+    lucid_config: Arc<Container<LucidConfig>>,
+    volume: Arc<Container<i32>>,
+) {
+    let current_config = || {
+        lucid_config
+            .get()
+            .filter(|c| c.validate().is_ok())
+            .unwrap_or_default()
+            .reality_checks
+    };
+    let mut rng = StdRng::from_entropy();
+    // The directory is only checked while the reality checks are enabled, and again when it changes
+    let mut checked_dir = None;
+    loop {
+        let config = current_config();
+        if !config.enabled {
+            checked_dir = None;
+            tokio::time::sleep(DISABLED_POLL_INTERVAL).await;
+            continue;
+        }
+        let sounds_config = alarm_state.config.get().sounds;
+        if checked_dir.as_ref() != Some(&sounds_config.reality_check_dir) {
+            sounds::check_directory(SoundKind::RealityCheck, &sounds_config);
+            checked_dir = Some(sounds_config.reality_check_dir);
+        }
+
+        let now = Utc::now();
+        let start = window_start(&config.window, now, &Local);
+        let times = schedule_prompts(
+            &mut rng,
+            config.window.duration().num_seconds() as f32 / 60.0,
+            config.prompts_per_day,
+            config.min_spacing_minutes,
+        )
+        .into_iter()
+        .map(|minutes| start + TimeDelta::seconds((minutes * 60.0) as i64))
+        .filter(|t| *t >= now)
+        .collect::<Vec<_>>();
+        info!(
+            "Reality checks at {:?}",
+            times
+                .iter()
+                .map(|t| t.with_timezone(&Local).time())
+                .collect::<Vec<_>>()
+        );
+
+        for time in times {
+            sleep_until(time).await;
+            if !current_config().enabled {
+                info!("Reality checks are disabled");
+                break;
+            }
+            let (alarm_state, volume) = (alarm_state.clone(), volume.clone());
+            let _ = tokio::task::spawn_blocking(move || play_prompt(&alarm_state, &volume)).await;
+        }
+        sleep_until(start + config.window.duration()).await;
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct RealityCheckSummary {
+    pub played: usize,
+    pub noticed: usize,
+    pub prompts: Vec<RealityCheckPrompt>,
+}
+
+/// The prompts of the last `days` days, 7 by default, oldest first.
+#[get("/lucid/reality_checks?<days>")]
+pub fn get_reality_checks(
+    state: &State<AlarmState>,
+    days: Option<u32>,
+) -> Json<RealityCheckSummary> {
+    let days = days.unwrap_or(7).clamp(1, 100 * 365);
+    let first_day = Local::now().date_naive() - TimeDelta::days(days as i64 - 1);
+    let since = Local
+        .from_local_datetime(&first_day.and_time(NaiveTime::MIN))
+        .earliest()
+        .map_or(Utc::now(), |t| t.with_timezone(&Utc));
+    let prompts = state.reality_checks.prompts(since);
+    Json(RealityCheckSummary {
+        played: prompts.iter().filter(|p| p.skipped.is_none()).count(),
+        noticed: prompts.iter().filter(|p| p.noticed).count(),
+        prompts,
+    })
+}
+
+/// Records that the prompt was noticed.
+#[post("/lucid/reality_checks/<id>/noticed")]
+pub fn mark_reality_check_noticed(
+    state: &State<AlarmState>,
+    id: u64,
+) -> Result<(), (Status, String)> {
+    if state.reality_checks.mark_noticed(id) {
+        Ok(())
+    } else {
+        Err((
+            Status::NotFound,
+            format!("There is no played reality check {id}"),
+        ))
+    }
+}
+
+#[test]
+fn test_schedule_prompts() {
+    let mut rng = StdRng::seed_from_u64(0);
+    for _ in 0..100 {
+        let times = schedule_prompts(&mut rng, 720.0, 5, 60.0);
+        assert_eq!(times.len(), 5);
+        assert!(times.iter().all(|t| (0.0..720.0).contains(t)), "{times:?}");
+        assert!(times.windows(2).all(|w| w[1] - w[0] >= 60.0), "{times:?}");
+    }
+    // Exactly as many as fit
+    assert_eq!(
+        schedule_prompts(&mut rng, 120.0, 3, 60.0),
+        [0.0, 60.0, 120.0]
+    );
+
+    let tz = chrono::FixedOffset::east_opt(3600).unwrap();
+    let config = RealityCheckConfig::default();
+    let at = |h, m| Utc.with_ymd_and_hms(2024, 3, 2, h, m, 0).unwrap();
+    // 09:00 to 21:00 local time is 08:00 to 20:00 UTC
+    assert_eq!(window_start(&config.window, at(5, 0), &tz), at(8, 0));
+    assert_eq!(window_start(&config.window, at(19, 59), &tz), at(8, 0));
+    assert_eq!(
+        window_start(&config.window, at(20, 0), &tz),
+        at(8, 0) + TimeDelta::days(1)
+    );
+
+    assert!(config.validate().is_ok());
+    assert!(RealityCheckConfig {
+        prompts_per_day: 14,
+        ..config
+    }
+    .validate()
+    .is_err());
+}
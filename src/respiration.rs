@@ -0,0 +1,143 @@
+//! Estimates the breathing rate from the tiny periodic movement of the mattress.
+use serde::Serialize;
+use std::time::Duration;
+
+/// Slowest and fastest breathing that is detected, in breaths per minute (0.1–0.5 Hz).
+const MIN_BREATHS_PER_MINUTE: f32 = 6.0;
+const MAX_BREATHS_PER_MINUTE: f32 = 30.0;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct Respiration {
+    pub breaths_per_minute: f32,
+    /// How periodic the movement is, from 0 to 1. Values below about 0.3 are mostly noise.
+    pub confidence: f32,
+}
+
+/// Centered moving average over `width` samples. The window shrinks at the ends.
+fn moving_average(signal: &[f32], width: usize) -> Vec<f32> {
+    let mut prefix = vec![0.0; signal.len() + 1];
+    for (i, v) in signal.iter().enumerate() {
+        prefix[i + 1] = prefix[i] + v;
+    }
+    let half = width / 2;
+    (0..signal.len())
+        .map(|i| {
+            let start = i.saturating_sub(half);
+            let end = (i + width - half).min(signal.len());
+            (prefix[end] - prefix[start]) / (end - start) as f32
+        })
+        .collect()
+}
+
+/// Keeps roughly the frequencies of breathing.
+///
+/// Subtracting a moving average over the slowest breath removes the drift, while keeping the slowest breathing,
+/// since that average cancels out exactly one period of it. A one second moving average removes the fast sensor noise.
+/// The ends are left out, since the moving averages are skewed there.
+fn band_pass(signal: &[f32], period: f32) -> Vec<f32> {
+    let samples = |seconds: f32| ((seconds / period).round() as usize).max(1);
+    let trend_width = samples(60.0 / MIN_BREATHS_PER_MINUTE);
+    let trend = moving_average(signal, trend_width);
+    let detrended = signal
+        .iter()
+        .zip(trend)
+        .map(|(v, t)| v - t)
+        .collect::<Vec<_>>();
+    let filtered = moving_average(&detrended, samples(1.0));
+    let skewed = (trend_width / 2).min(filtered.len() / 2);
+    filtered[skewed..filtered.len() - skewed].to_vec()
+}
+
+/// Normalized autocorrelation of `signal` with itself shifted by `lag` samples.
+fn autocorrelation(signal: &[f32], lag: usize) -> f32 {
+    let (a, b) = (&signal[..signal.len() - lag], &signal[lag..]);
+    let dot = a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+    let energy = |s: &[f32]| s.iter().map(|x| x * x).sum::<f32>();
+    dot / (energy(a) * energy(b)).sqrt()
+}
+
+/// Estimates the breathing rate from the acceleration, sampled every `period` while the sleeper was lying still.
+///
+/// Uses the axis along which the breathing moves the sensor the most, and the strongest peak of its autocorrelation.
+/// `None` if there are too few samples, or if no breathing-like period is found.
+pub fn estimate(acc: &[(f32, f32, f32)], period: Duration) -> Option<Respiration> {
+    let period = period.as_secs_f32();
+    let lag = |breaths_per_minute: f32| (60.0 / breaths_per_minute / period).round() as usize;
+    let (min_lag, max_lag) = (lag(MAX_BREATHS_PER_MINUTE), lag(MIN_BREATHS_PER_MINUTE));
+    if min_lag < 2 {
+        return None;
+    }
+
+    let energy = |s: &[f32]| s.iter().map(|x| x * x).sum::<f32>();
+    let signal = [
+        acc.iter().map(|a| a.0).collect::<Vec<_>>(),
+        acc.iter().map(|a| a.1).collect(),
+        acc.iter().map(|a| a.2).collect(),
+    ]
+    .map(|axis| band_pass(&axis, period))
+    .into_iter()
+    .max_by(|a, b| energy(a).total_cmp(&energy(b)))?;
+    if signal.len() < 2 * max_lag + 2 {
+        return None;
+    }
+
+    let correlations = (min_lag - 1..=max_lag + 1)
+        .map(|lag| autocorrelation(&signal, lag))
+        .collect::<Vec<_>>();
+    // Multiples of the period correlate almost as well as the period itself, so use the first local maximum which is
+    // nearly as high as the highest one. It is refined by fitting a parabola through it and its neighbours.
+    let peaks = (1..correlations.len() - 1)
+        .filter(|&i| {
+            correlations[i] > correlations[i - 1] && correlations[i] >= correlations[i + 1]
+        })
+        .map(|i| (i, correlations[i]))
+        .collect::<Vec<_>>();
+    let highest = peaks.iter().map(|&(_, r)| r).max_by(f32::total_cmp)?;
+    let (i, r) = peaks.into_iter().find(|&(_, r)| r >= 0.9 * highest)?;
+    let (before, after) = (correlations[i - 1], correlations[i + 1]);
+    let curvature = before - 2.0 * r + after;
+    let offset = if curvature < 0.0 {
+        0.5 * (before - after) / curvature
+    } else {
+        0.0
+    };
+    let lag = (min_lag - 1 + i) as f32 + offset;
+    (r.is_finite() && r > 0.0).then(|| Respiration {
+        breaths_per_minute: 60.0 / (lag * period),
+        confidence: r.min(1.0),
+    })
+}
+
+#[test]
+fn test_estimate() {
+    let period = Duration::from_millis(100);
+    // Breathing along z with a small tilt, on top of gravity, a slow drift and some deterministic noise
+    let breathing = |breaths_per_minute: f32, amplitude: f32| {
+        let mut noise = 12345u32;
+        (0..600)
+            .map(|i| {
+                noise = noise.wrapping_mul(1103515245).wrapping_add(12345);
+                let noise = ((noise >> 16) as f32 / 65536.0 - 0.5) * 0.001;
+                let t = i as f32 * 0.1;
+                let breath =
+                    amplitude * (std::f32::consts::TAU * breaths_per_minute / 60.0 * t).sin();
+                (0.01 + 0.2 * breath, 0.0, -1.0 + breath + 0.0001 * t + noise)
+            })
+            .collect::<Vec<_>>()
+    };
+
+    for expected in [8.0, 12.0, 15.0, 20.0, 27.0] {
+        let estimate = estimate(&breathing(expected, 0.002), period).unwrap();
+        assert!(
+            (estimate.breaths_per_minute - expected).abs() < 0.5,
+            "{expected}: {estimate:?}"
+        );
+        assert!(estimate.confidence > 0.7, "{expected}: {estimate:?}");
+    }
+
+    // Only noise is not periodic
+    let still = breathing(15.0, 0.0);
+    assert!(estimate(&still, period).is_none_or(|e| e.confidence < 0.3));
+    // Too short to see the slowest breathing twice
+    assert_eq!(estimate(&breathing(15.0, 0.002)[..150], period), None);
+}
@@ -0,0 +1,121 @@
+//! Graceful shutdown on SIGTERM or SIGINT.
+//!
+//! Playback fades out, the containers are set to their idle values, the sleep log is closed and the local state is
+//! saved, and only then does the server stop. If that takes longer than [`SHUTDOWN_TIMEOUT`], or another signal
+//! arrives, the process exits right away.
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use futures::future::{select, Either};
+use log::{error, info, warn};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+use crate::{watchdog, AlarmState};
+
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// True once a shutdown has started. Nothing new should be started then, like an alarm or decoding a sound.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// The signals which stop the alarm.
+struct Signals {
+    term: Signal,
+    int: Signal,
+}
+
+impl Signals {
+    fn new() -> std::io::Result<Signals> {
+        Ok(Signals {
+            term: signal(SignalKind::terminate())?,
+            int: signal(SignalKind::interrupt())?,
+        })
+    }
+
+    async fn recv(&mut self) -> &'static str {
+        match select(pin!(self.term.recv()), pin!(self.int.recv())).await {
+            Either::Left(_) => "SIGTERM",
+            Either::Right(_) => "SIGINT",
+        }
+    }
+}
+
+/// Waits for a signal, shuts down, and then stops the server with `server`.
+///
+/// `sleep_monitor` is the thread which writes the sleep log. It returns once it has closed the log.
+pub async fn on_signal(
+    state: AlarmState,
+    sleep_monitor: Option<JoinHandle<()>>,
+    server: rocket::Shutdown,
+) {
+    let mut signals = match Signals::new() {
+        Ok(signals) => signals,
+        Err(e) => {
+            error!("Could not listen for signals, there will be no graceful shutdown: {e}");
+            return;
+        }
+    };
+    let signal = signals.recv().await;
+    info!("Received {signal}, shutting down");
+    REQUESTED.store(true, Ordering::SeqCst);
+    watchdog::stopping();
+
+    let graceful = pin!(tokio::time::timeout(
+        SHUTDOWN_TIMEOUT,
+        shut_down(&state, sleep_monitor)
+    ));
+    match select(graceful, pin!(signals.recv())).await {
+        Either::Left((Ok(()), _)) => {}
+        Either::Left((Err(_), _)) => {
+            error!("Shutting down took longer than {SHUTDOWN_TIMEOUT:?}, exiting right away");
+            std::process::exit(1);
+        }
+        Either::Right((signal, _)) => {
+            warn!("Received {signal} while shutting down, exiting right away");
+            std::process::exit(1);
+        }
+    }
+    server.notify();
+}
+
+async fn shut_down(state: &AlarmState, sleep_monitor: Option<JoinHandle<()>>) {
+    // Fades out like when something more important starts playing, so that it does not pop
+    let playback = state.playback.clone();
+    if !tokio::task::spawn_blocking(move || playback.stop_all())
+        .await
+        .unwrap()
+    {
+        warn!("Playback did not fade out in time");
+    }
+    // Waits for the current pulse of a playing pattern
+    if let Some(haptics) = state.haptics.clone() {
+        tokio::task::spawn_blocking(move || haptics.off())
+            .await
+            .unwrap();
+    }
+    state.is_playing.set(false).await;
+    state.events.now_playing(None).await;
+
+    if let Some(thread) = sleep_monitor {
+        if tokio::task::spawn_blocking(move || thread.join())
+            .await
+            .unwrap()
+            .is_err()
+        {
+            error!("The sleep monitor panicked while closing the sleep log");
+        }
+    }
+
+    if let Err(e) = state.local_state.save_changes() {
+        error!(
+            "Could not save the local state to {:?}: {e}",
+            state.local_state.path()
+        );
+    }
+    info!("Shut down cleanly");
+}
@@ -0,0 +1,390 @@
+//! SQLite storage of the accelerometer readings, as an alternative to the CSV logs.
+//!
+//! Besides the readings themselves, the database keeps per-minute aggregates which are cheap to query over long periods.
+//! The readings are deleted after the retention period, while the aggregates are kept.
+use chrono::{DateTime, NaiveDate, TimeDelta, TimeZone, Utc};
+use rusqlite::{params, Connection, Transaction};
+use std::{
+    io::BufRead,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::motion_log::{self, LogSample, SleepStats};
+use crate::respiration::Respiration;
+use crate::sleep_monitor::AccelerometerData;
+
+/// Readings are buffered and inserted in a single transaction this often, to reduce wear on the SD card.
+const COMMIT_INTERVAL: Duration = Duration::from_secs(30);
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS samples (
+    -- Milliseconds since the unix epoch
+    time INTEGER NOT NULL,
+    samples INTEGER NOT NULL,
+    alarm_playing INTEGER NOT NULL,
+    present INTEGER,
+    acc_x REAL NOT NULL,
+    acc_y REAL NOT NULL,
+    acc_z REAL NOT NULL,
+    gyro_x REAL NOT NULL,
+    gyro_y REAL NOT NULL,
+    gyro_z REAL NOT NULL,
+    temp REAL NOT NULL,
+    acc_magnitude REAL NOT NULL,
+    gyro_magnitude REAL NOT NULL,
+    movement REAL,
+    -- See motion_log::MY_SENSOR
+    sensor INTEGER NOT NULL DEFAULT 0,
+    -- Of the minute before, see motion_log::LogSample::respiration
+    breaths_per_minute REAL,
+    respiration_confidence REAL
+);
+CREATE INDEX IF NOT EXISTS samples_time ON samples (time);
+
+-- Only of the user's sensor
+CREATE TABLE IF NOT EXISTS minutes (
+    -- Start of the minute, in seconds since the unix epoch
+    time INTEGER PRIMARY KEY,
+    samples INTEGER NOT NULL,
+    mean_acc_magnitude REAL NOT NULL,
+    mean_gyro_magnitude REAL NOT NULL,
+    mean_movement REAL,
+    present INTEGER,
+    alarm_playing INTEGER NOT NULL,
+    max_movement REAL,
+    mean_temp REAL,
+    breaths_per_minute REAL,
+    respiration_confidence REAL
+);
+";
+
+/// Columns which were added later, by table. Older databases get them when they are opened.
+const ADDED_COLUMNS: [(&str, &str, &str); 7] = [
+    ("minutes", "max_movement", "REAL"),
+    ("minutes", "mean_temp", "REAL"),
+    ("samples", "sensor", "INTEGER NOT NULL DEFAULT 0"),
+    ("samples", "breaths_per_minute", "REAL"),
+    ("samples", "respiration_confidence", "REAL"),
+    ("minutes", "breaths_per_minute", "REAL"),
+    ("minutes", "respiration_confidence", "REAL"),
+];
+
+fn magnitude(v: (f32, f32, f32)) -> f32 {
+    (v.0.powi(2) + v.1.powi(2) + v.2.powi(2)).sqrt()
+}
+
+pub fn open(path: &Path) -> rusqlite::Result<Connection> {
+    let conn = Connection::open(path)?;
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    conn.execute_batch(SCHEMA)?;
+    for (table, name, column_type) in ADDED_COLUMNS {
+        let exists: bool = conn.query_row(
+            "SELECT count(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+            params![table, name],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            conn.execute_batch(&format!(
+                "ALTER TABLE {table} ADD COLUMN {name} {column_type}"
+            ))?;
+        }
+    }
+    Ok(conn)
+}
+
+/// Inserts the samples, which must be in order, and updates the aggregates of the minutes they are in.
+///
+/// The breathing rate of a minute is logged with the first reading after it, so it is set on the minute before.
+/// That minute is never aggregated again by a later batch, which would clear it.
+fn insert(tx: &Transaction, samples: &[LogSample]) -> rusqlite::Result<()> {
+    let (Some(first), Some(last)) = (samples.first(), samples.last()) else {
+        return Ok(());
+    };
+    let mut stmt = tx.prepare_cached(
+        "INSERT INTO samples VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)",
+    )?;
+    for s in samples {
+        let d = &s.data;
+        stmt.execute(params![
+            s.time.timestamp_millis(),
+            s.samples,
+            s.alarm_playing,
+            s.present,
+            d.acc.0,
+            d.acc.1,
+            d.acc.2,
+            d.gyro.0,
+            d.gyro.1,
+            d.gyro.2,
+            d.temp,
+            magnitude(d.acc),
+            magnitude(d.gyro),
+            s.movement,
+            s.sensor,
+            s.respiration.map(|r| r.breaths_per_minute),
+            s.respiration.map(|r| r.confidence),
+        ])?;
+    }
+    tx.execute(
+        "INSERT OR REPLACE INTO minutes (time, samples, mean_acc_magnitude, mean_gyro_magnitude, mean_movement, present, alarm_playing, max_movement, mean_temp)
+        SELECT time / 60000 * 60, sum(samples), avg(acc_magnitude), avg(gyro_magnitude), avg(movement), max(present), max(alarm_playing), max(movement), avg(temp)
+        FROM samples WHERE sensor = ?3 AND time >= ?1 / 60000 * 60000 AND time <= ?2 GROUP BY time / 60000",
+        params![
+            first.time.timestamp_millis(),
+            last.time.timestamp_millis(),
+            motion_log::MY_SENSOR
+        ],
+    )?;
+    let mut respiration = tx.prepare_cached(
+        "UPDATE minutes SET breaths_per_minute = ?1, respiration_confidence = ?2 WHERE time = ?3",
+    )?;
+    for s in samples.iter().filter(|s| s.sensor == motion_log::MY_SENSOR) {
+        if let Some(r) = s.respiration {
+            let minute = s.time.timestamp().div_euclid(60) * 60 - 60;
+            respiration.execute(params![r.breaths_per_minute, r.confidence, minute])?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes readings to the database in batches.
+pub struct SleepDb {
+    conn: Connection,
+    pending: Vec<LogSample>,
+    last_commit: Instant,
+    retention_days: u32,
+    /// The day when the old readings were last deleted.
+    pruned: Option<NaiveDate>,
+}
+
+impl SleepDb {
+    pub fn open(path: &Path, retention_days: u32) -> rusqlite::Result<SleepDb> {
+        Ok(SleepDb {
+            conn: open(path)?,
+            pending: vec![],
+            last_commit: Instant::now(),
+            retention_days,
+            pruned: None,
+        })
+    }
+
+    pub fn add(&mut self, sample: LogSample) -> rusqlite::Result<()> {
+        self.pending.push(sample);
+        if self.last_commit.elapsed() >= COMMIT_INTERVAL {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    pub fn commit(&mut self) -> rusqlite::Result<()> {
+        self.last_commit = Instant::now();
+        let tx = self.conn.transaction()?;
+        insert(&tx, &self.pending)?;
+        tx.commit()?;
+        self.pending.clear();
+
+        let now = Utc::now();
+        if self.pruned != Some(now.date_naive()) {
+            self.pruned = Some(now.date_naive());
+            if let Some(before) =
+                now.checked_sub_signed(TimeDelta::days(self.retention_days as i64))
+            {
+                let deleted = self.conn.execute(
+                    "DELETE FROM samples WHERE time < ?1",
+                    params![before.timestamp_millis()],
+                )?;
+                if deleted > 0 {
+                    info!("Deleted {} readings from before {}", deleted, before);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SleepDb {
+    fn drop(&mut self) {
+        if let Err(e) = self.commit() {
+            error!("Failed to write the accelerometer readings: {}", e);
+        }
+    }
+}
+
+/// Reads the samples of all sensors between `from` and `to`.
+pub fn read(
+    path: &Path,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> rusqlite::Result<Vec<LogSample>> {
+    let conn = open(path)?;
+    let mut stmt = conn.prepare(
+        "SELECT time, samples, alarm_playing, present, acc_x, acc_y, acc_z, gyro_x, gyro_y, gyro_z, temp, movement, sensor,
+            breaths_per_minute, respiration_confidence
+        FROM samples WHERE time >= ?1 AND time <= ?2 ORDER BY time",
+    )?;
+    let samples = stmt
+        .query_map(
+            params![from.timestamp_millis(), to.timestamp_millis()],
+            |row| {
+                Ok(LogSample {
+                    time: Utc
+                        .timestamp_millis_opt(row.get(0)?)
+                        .single()
+                        .unwrap_or_default(),
+                    samples: row.get(1)?,
+                    alarm_playing: row.get(2)?,
+                    present: row.get(3)?,
+                    data: AccelerometerData {
+                        acc: (row.get(4)?, row.get(5)?, row.get(6)?),
+                        gyro: (row.get(7)?, row.get(8)?, row.get(9)?),
+                        temp: row.get(10)?,
+                    },
+                    movement: row.get(11)?,
+                    sensor: row.get(12)?,
+                    respiration: match (row.get(13)?, row.get(14)?) {
+                        (Some(breaths_per_minute), Some(confidence)) => Some(Respiration {
+                            breaths_per_minute,
+                            confidence,
+                        }),
+                        _ => None,
+                    },
+                })
+            },
+        )?
+        .collect();
+    samples
+}
+
+/// Statistics of the minutes between `from` and `to`, which are within the local hours `start_hour..end_hour`.
+pub fn stats(
+    path: &Path,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    start_hour: u32,
+    end_hour: u32,
+) -> rusqlite::Result<SleepStats> {
+    let conn = open(path)?;
+    conn.query_row(
+        "SELECT sum(samples), sum(mean_movement * samples) / sum(samples), avg(present)
+        FROM (SELECT *, CAST(strftime('%H', time, 'unixepoch', 'localtime') AS INTEGER) AS hour FROM minutes)
+        WHERE time >= ?1 AND time <= ?2
+            AND ((?3 <= ?4 AND hour >= ?3 AND hour < ?4) OR (?3 > ?4 AND (hour >= ?3 OR hour < ?4)))",
+        params![from.timestamp(), to.timestamp(), start_hour, end_hour],
+        |row| {
+            Ok(SleepStats {
+                samples: row.get::<_, Option<u64>>(0)?.unwrap_or(0),
+                mean_movement: row.get::<_, Option<f64>>(1)?.map(|v| v as f32),
+                present_fraction: row.get::<_, Option<f64>>(2)?.map(|v| v as f32),
+                ..Default::default()
+            })
+        },
+    )
+}
+
+/// Imports a CSV log, which may be gzipped. Returns the number of imported readings.
+pub fn import_csv(database: &Path, csv: &Path) -> Result<usize, String> {
+    const BATCH_SIZE: usize = 10_000;
+    let mut conn = open(database).map_err(|e| e.to_string())?;
+    let mut insert_batch = |batch: &mut Vec<LogSample>| -> rusqlite::Result<()> {
+        let tx = conn.transaction()?;
+        insert(&tx, batch)?;
+        tx.commit()?;
+        batch.clear();
+        Ok(())
+    };
+
+    let mut count = 0;
+    let mut batch = vec![];
+    let mut tracker = motion_log::MovementTracker::default();
+    for line in motion_log::open(csv).map_err(|e| e.to_string())?.lines() {
+        let line = line.map_err(|e| e.to_string())?;
+        let Some(mut sample) = LogSample::parse_csv(&line) else {
+            warn!("Skipping invalid line in {}: {}", csv.display(), line);
+            continue;
+        };
+        sample.movement = tracker.update(&sample);
+        batch.push(sample);
+        count += 1;
+        if batch.len() >= BATCH_SIZE {
+            insert_batch(&mut batch).map_err(|e| e.to_string())?;
+        }
+    }
+    insert_batch(&mut batch).map_err(|e| e.to_string())?;
+    Ok(count)
+}
+
+#[test]
+fn test_sleep_db() {
+    let path = std::env::temp_dir().join(format!("sleep-db-test-{}.sqlite", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let start = Utc.with_ymd_and_hms(2024, 3, 1, 3, 0, 0).unwrap();
+    let sample = |seconds, movement| LogSample {
+        sensor: motion_log::MY_SENSOR,
+        time: start + chrono::TimeDelta::seconds(seconds),
+        samples: 10,
+        alarm_playing: false,
+        present: Some(movement > 0.0),
+        movement: Some(movement),
+        data: AccelerometerData {
+            acc: (0.0, 0.0, -1.0),
+            ..AccelerometerData::default()
+        },
+        respiration: None,
+    };
+
+    let mut db = SleepDb::open(&path, 100 * 365).unwrap();
+    // Two batches which both have samples in the second minute
+    for (seconds, movement) in [(0, 0.0), (30, 0.0)] {
+        db.add(sample(seconds, movement)).unwrap();
+    }
+    // Completes the first minute, in which the user was breathing calmly
+    let breathing = Respiration {
+        breaths_per_minute: 12.5,
+        confidence: 0.8,
+    };
+    db.add(LogSample {
+        respiration: Some(breathing),
+        ..sample(60, 0.1)
+    })
+    .unwrap();
+    // The partner's sensor is stored, but not in the statistics
+    db.add(LogSample {
+        sensor: motion_log::PARTNER_SENSOR,
+        ..sample(61, 1.0)
+    })
+    .unwrap();
+    db.commit().unwrap();
+    db.add(sample(90, 0.3)).unwrap();
+    drop(db);
+
+    let samples = read(&path, start, start + chrono::TimeDelta::minutes(10)).unwrap();
+    assert_eq!(samples.len(), 5);
+    assert_eq!(samples[3].sensor, motion_log::PARTNER_SENSOR);
+    assert_eq!(samples[4].time, start + chrono::TimeDelta::seconds(90));
+    assert_eq!(samples[4].data.acc, (0.0, 0.0, -1.0));
+    assert_eq!(samples[2].respiration, Some(breathing));
+    let minute_respiration = |minute: i64| -> Option<f64> {
+        open(&path)
+            .unwrap()
+            .query_row(
+                "SELECT breaths_per_minute FROM minutes WHERE time = ?1",
+                params![start.timestamp() + 60 * minute],
+                |row| row.get(0),
+            )
+            .unwrap()
+    };
+    // Set on the minute before the reading it was logged with
+    assert_eq!(minute_respiration(0), Some(12.5));
+    assert_eq!(minute_respiration(1), None);
+
+    let all = stats(&path, start, start + chrono::TimeDelta::minutes(10), 0, 24).unwrap();
+    assert_eq!(all.samples, 40);
+    assert!((all.mean_movement.unwrap() - 0.1).abs() < 1e-6);
+    assert_eq!(all.present_fraction, Some(0.5));
+    assert_eq!(
+        stats(&path, start, start, 12, 13).unwrap(),
+        SleepStats::default()
+    );
+    std::fs::remove_file(&path).unwrap();
+}
@@ -1,15 +1,448 @@
-use brevduva::SyncedContainer;
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
 use linux_embedded_hal::{Delay, I2CError, I2cdev};
+use log::{debug, warn};
 use mpu6050::*;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
+    collections::VecDeque,
+    hash::Hash,
+    io::BufRead,
+    path::{Path, PathBuf},
     sync::Arc,
     time::{Duration, Instant},
 };
+use thiserror::Error;
+use tokio::sync::watch;
 
+use crate::config::{self, ConfigStore, MotionConfig, SleepMonitorConfig, TapConfig};
+use crate::histogram;
+use crate::motion_log::{self, LogSample};
+use crate::nights::{self, NightMinute};
+use crate::offline::Container;
+use crate::respiration::{self, Respiration};
+use crate::AlarmState;
+
+/// See [`load_paused`].
+pub const PAUSED_FILE: &str = "sleep_monitor_paused.json";
+
+/// How long to sample for when calibrating.
+const CALIBRATION_DURATION: Duration = Duration::from_secs(30);
+const CALIBRATION_PERIOD: Duration = Duration::from_millis(50);
+
+/// Changes in acceleration (in g) between consecutive samples that are larger than this mean that the bed moved while calibrating.
+const NOISE_THRESHOLD: f32 = 0.015;
+
+/// How many minutes of activity to keep for the sleep stage estimation.
+const STAGE_HISTORY_MINUTES: usize = 12 * 60;
+
+/// Weights of the activity in the minutes around the scored minute (4 before, the minute itself, and 2 after),
+/// from the Cole-Kripke algorithm for 1 minute epochs.
+const STAGE_WEIGHTS: [f32; 7] = [106.0, 54.0, 58.0, 76.0, 230.0, 74.0, 67.0];
+const STAGE_SCALE: f32 = 0.001;
+
+/// Sleep scores below this are deep sleep. Scores of 1 or more are wake.
+const DEEP_SLEEP_SCORE: f32 = 0.1;
+
+/// Minutes of light sleep required before the alarm may start early.
+const LIGHT_SLEEP_MINUTES: usize = 3;
+/// How recent the deep sleep before the light sleep must be, in minutes.
+const DEEP_SLEEP_LOOKBACK_MINUTES: usize = 30;
+
+/// Minimum time between publishing changes of the presence, so that flickering values are coalesced.
+const PRESENCE_PUBLISH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The movement intensity is measured over this long.
+const INTENSITY_WINDOW: Duration = Duration::from_secs(60);
+/// The breathing rate is estimated from this much data.
+const RESPIRATION_WINDOW: Duration = Duration::from_secs(60);
+
+/// The direction of gravity is averaged over this long, so that movement does not count as tilting.
+const ORIENTATION_WINDOW: Duration = Duration::from_secs(30);
+
+/// Without [`crate::config::MotionConfig::max_window_readings`], the window keeps this many times the readings expected in it.
+const WINDOW_SAFETY_FACTOR: f32 = 2.0;
+/// The movement intensity is published at most this often.
+pub const INTENSITY_PUBLISH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How much the bed moves, as the 95th percentile of the changes in acceleration (in g) during the last minute.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(transparent)]
+pub struct MovementIntensity(pub f32);
+
+// Containers must be hashable
+impl std::hash::Hash for MovementIntensity {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.to_bits().hash(state);
+    }
+}
+
+/// Something which measures the movement of the bed.
+pub trait AccelerometerSource: Send {
+    /// Reads the sensor, with any calibration applied.
+    fn sample(&mut self) -> Result<AccelerometerData, SensorError>;
+
+    /// Initializes the sensor again, after repeated failed reads.
+    fn reinit(&mut self) -> Result<(), SensorError> {
+        Ok(())
+    }
+
+    /// Measures new calibration offsets, and uses them from now on. The bed must be empty.
+    fn calibrate(&mut self) -> Result<Calibration, CalibrationError> {
+        Err(CalibrationError::Unsupported)
+    }
+
+    /// Tells the sensor whether the bed is empty and still, so that it can learn how its gyro bias depends on the temperature.
+    fn set_at_rest(&mut self, _at_rest: bool) {}
+}
+
+#[derive(Error, Debug)]
+pub enum SensorError {
+    #[error("Failed to read the accelerometer: {0}")]
+    Read(String),
+    #[error(transparent)]
+    Init(#[from] AccelerometerInitError),
+    /// A replayed recording has no more readings.
+    #[error("The recording has ended")]
+    Ended,
+}
+
+impl From<Mpu6050Error<I2CError>> for SensorError {
+    fn from(e: Mpu6050Error<I2CError>) -> Self {
+        SensorError::Read(format!("{:?}", e))
+    }
+}
+
+/// An MPU6050 connected over I2C.
 pub struct Accelerometer {
     mpu: Mpu6050<I2cdev>,
+    calibration: Calibration,
+    gyro_bias: GyroBiasLearner,
+    config: MotionConfig,
+}
+
+#[derive(Error, Debug)]
+#[error("Failed to initialize the {} on {} at {}: {}", .sensor, .bus.display(), .address.map_or("any known address".to_owned(), |a| format!("address {a:#04x}")), .cause)]
+pub struct AccelerometerInitError {
+    pub sensor: String,
+    pub bus: PathBuf,
+    pub address: Option<u8>,
+    pub cause: String,
+}
+
+/// Width of the temperature ranges which the gyro bias is estimated for, in °C.
+const GYRO_BIAS_BIN: f32 = 0.5;
+/// The gyro bias model is only fitted once the estimates span this many °C.
+const GYRO_BIAS_MIN_SPAN: f32 = 2.0;
+/// An estimate is a mean over at most this many samples, so that it follows the sensor as it ages.
+const GYRO_BIAS_MAX_SAMPLES: u32 = 100_000;
+/// The learned gyro bias is saved at most this often while the bed is empty.
+const GYRO_BIAS_SAVE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Biases of the sensor, which are subtracted from every reading.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Calibration {
+    pub acc_offset: (f32, f32, f32),
+    /// Used when there is no `gyro_bias`.
+    pub gyro_offset: (f32, f32, f32),
+    /// Direction of gravity in the corrected readings while the bed was empty. Missing in calibrations from older versions.
+    #[serde(default)]
+    pub gravity: Option<(f32, f32, f32)>,
+    /// Gyro bias as a function of the temperature, fitted to `gyro_bias_estimates`.
+    #[serde(default)]
+    pub gyro_bias: Option<GyroBiasModel>,
+    /// Mean raw gyro readings while the bed was empty, per temperature, ordered by the temperature.
+    #[serde(default)]
+    pub gyro_bias_estimates: Vec<GyroBiasEstimate>,
+}
+
+/// The mean raw gyro reading within [`GYRO_BIAS_BIN`] of `temp`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct GyroBiasEstimate {
+    pub temp: f32,
+    pub gyro: (f32, f32, f32),
+    pub samples: u32,
+}
+
+/// Gyro bias which changes linearly with the temperature, per axis: `intercept + slope * temp`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct GyroBiasModel {
+    pub intercept: (f32, f32, f32),
+    pub slope: (f32, f32, f32),
+}
+
+impl GyroBiasModel {
+    /// Least squares fit, with every temperature weighted the same. `None` if the temperatures are too close together.
+    pub fn fit(estimates: &[GyroBiasEstimate]) -> Option<GyroBiasModel> {
+        let temps = estimates.iter().map(|e| e.temp);
+        let span = temps.clone().fold(f32::NEG_INFINITY, f32::max)
+            - temps.clone().fold(f32::INFINITY, f32::min);
+        if estimates.len() < 2 || span < GYRO_BIAS_MIN_SPAN {
+            return None;
+        }
+        let n = estimates.len() as f32;
+        let mean_temp = temps.sum::<f32>() / n;
+        let variance = estimates
+            .iter()
+            .map(|e| (e.temp - mean_temp).powi(2))
+            .sum::<f32>();
+        let axis = |value: fn(&GyroBiasEstimate) -> f32| {
+            let mean = estimates.iter().map(value).sum::<f32>() / n;
+            let slope = estimates
+                .iter()
+                .map(|e| (e.temp - mean_temp) * (value(e) - mean))
+                .sum::<f32>()
+                / variance;
+            (mean - slope * mean_temp, slope)
+        };
+        let (x, y, z) = (axis(|e| e.gyro.0), axis(|e| e.gyro.1), axis(|e| e.gyro.2));
+        Some(GyroBiasModel {
+            intercept: (x.0, y.0, z.0),
+            slope: (x.1, y.1, z.1),
+        })
+    }
+
+    pub fn bias(&self, temp: f32) -> (f32, f32, f32) {
+        let (i, s) = (self.intercept, self.slope);
+        (i.0 + s.0 * temp, i.1 + s.1 * temp, i.2 + s.2 * temp)
+    }
+
+    /// How far each estimate is from the model, for checking the fit.
+    pub fn residual(&self, estimate: &GyroBiasEstimate) -> (f32, f32, f32) {
+        let bias = self.bias(estimate.temp);
+        let g = estimate.gyro;
+        (g.0 - bias.0, g.1 - bias.1, g.2 - bias.2)
+    }
+}
+
+impl Calibration {
+    /// Loads the calibration from `path`. Without a calibration file, nothing is subtracted.
+    pub fn load(path: &Path) -> Calibration {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                warn!("Could not parse {}: {}", path.display(), e);
+                Calibration::default()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Calibration::default(),
+            Err(e) => {
+                warn!("Could not read {}: {}", path.display(), e);
+                Calibration::default()
+            }
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)
+    }
+
+    /// Computes the offsets from samples taken while the sensor was at rest.
+    ///
+    /// At rest, the accelerometer should only measure 1 g of gravity along the axis which points the most downwards.
+    /// Fails if the samples show any movement.
+    pub fn from_samples(samples: &[AccelerometerData]) -> Result<Calibration, CalibrationError> {
+        if samples.is_empty() {
+            return Err(CalibrationError::NoSamples);
+        }
+        if samples
+            .windows(2)
+            .any(|w| delta_magnitude(&w[0], &w[1]) > NOISE_THRESHOLD)
+        {
+            return Err(CalibrationError::Movement);
+        }
+
+        let mean = AccelerometerData::mean(samples);
+        let (x, y, z) = mean.acc;
+        let gravity = |v: f32, max: f32| {
+            if v.abs() == max {
+                v.signum()
+            } else {
+                0.0
+            }
+        };
+        let max = x.abs().max(y.abs()).max(z.abs());
+        let down = (gravity(x, max), gravity(y, max), gravity(z, max));
+        Ok(Calibration {
+            acc_offset: (x - down.0, y - down.1, z - down.2),
+            gyro_offset: mean.gyro,
+            gravity: Some(down),
+            ..Calibration::default()
+        })
+    }
+
+    /// Samples a sensor at rest for a while, and saves the resulting calibration to `path`. Keeps the gyro bias learned in `previous`.
+    pub fn measure(
+        path: &Path,
+        previous: &Calibration,
+        mut read_raw: impl FnMut() -> Result<AccelerometerData, SensorError>,
+    ) -> Result<Calibration, CalibrationError> {
+        let start = Instant::now();
+        let mut samples = vec![];
+        while start.elapsed() < CALIBRATION_DURATION {
+            match read_raw() {
+                Ok(data) => samples.push(data),
+                Err(e) => warn!("{}", e),
+            }
+            std::thread::sleep(CALIBRATION_PERIOD);
+        }
+
+        let calibration = Calibration {
+            gyro_bias: previous.gyro_bias,
+            gyro_bias_estimates: previous.gyro_bias_estimates.clone(),
+            ..Calibration::from_samples(&samples)?
+        };
+        calibration.save(path)?;
+        Ok(calibration)
+    }
+
+    /// Adds a raw reading from while the bed is empty to the gyro bias estimate of its temperature.
+    pub fn add_gyro_bias_sample(&mut self, raw: &AccelerometerData) {
+        let temp = (raw.temp / GYRO_BIAS_BIN).round() * GYRO_BIAS_BIN;
+        let estimates = &mut self.gyro_bias_estimates;
+        let index = estimates.partition_point(|e| e.temp < temp);
+        if estimates.get(index).is_none_or(|e| e.temp != temp) {
+            estimates.insert(
+                index,
+                GyroBiasEstimate {
+                    temp,
+                    gyro: (0.0, 0.0, 0.0),
+                    samples: 0,
+                },
+            );
+        }
+        let estimate = &mut estimates[index];
+        estimate.samples = (estimate.samples + 1).min(GYRO_BIAS_MAX_SAMPLES);
+        let weight = 1.0 / estimate.samples as f32;
+        let (mean, g) = (&mut estimate.gyro, raw.gyro);
+        mean.0 += weight * (g.0 - mean.0);
+        mean.1 += weight * (g.1 - mean.1);
+        mean.2 += weight * (g.2 - mean.2);
+    }
+
+    pub fn apply(&self, data: AccelerometerData) -> AccelerometerData {
+        let acc = self.acc_offset;
+        let gyro = self
+            .gyro_bias
+            .map_or(self.gyro_offset, |model| model.bias(data.temp));
+        AccelerometerData {
+            acc: (data.acc.0 - acc.0, data.acc.1 - acc.1, data.acc.2 - acc.2),
+            gyro: (
+                data.gyro.0 - gyro.0,
+                data.gyro.1 - gyro.1,
+                data.gyro.2 - gyro.2,
+            ),
+            temp: data.temp,
+        }
+    }
+}
+
+/// Learns the gyro bias of a sensor while the bed is empty. See [`AccelerometerSource::set_at_rest`].
+#[derive(Debug)]
+pub struct GyroBiasLearner {
+    at_rest: bool,
+    last_saved: Instant,
+}
+
+impl Default for GyroBiasLearner {
+    fn default() -> Self {
+        GyroBiasLearner {
+            at_rest: false,
+            last_saved: Instant::now(),
+        }
+    }
+}
+
+impl GyroBiasLearner {
+    /// Adds a raw reading to the estimates if the bed is at rest. The calibration is saved to `path` now and then.
+    pub fn add(&mut self, calibration: &mut Calibration, path: &Path, raw: &AccelerometerData) {
+        if !self.at_rest {
+            return;
+        }
+        calibration.add_gyro_bias_sample(raw);
+        if self.last_saved.elapsed() >= GYRO_BIAS_SAVE_INTERVAL {
+            self.save(calibration, path);
+        }
+    }
+
+    pub fn set_at_rest(&mut self, calibration: &mut Calibration, path: &Path, at_rest: bool) {
+        if self.at_rest && !at_rest {
+            self.save(calibration, path);
+        }
+        self.at_rest = at_rest;
+    }
+
+    /// Fits the model again, and saves it together with the calibration.
+    fn save(&mut self, calibration: &mut Calibration, path: &Path) {
+        self.last_saved = Instant::now();
+        calibration.gyro_bias = GyroBiasModel::fit(&calibration.gyro_bias_estimates);
+        if let Err(e) = calibration.save(path) {
+            warn!("Could not save the gyro bias: {}", e);
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum CalibrationError {
+    #[error("Someone seems to be in bed. Calibrate while the bed is empty.")]
+    Occupied,
+    #[error("The bed moved while calibrating. Calibrate while the bed is empty.")]
+    Movement,
+    #[error("The accelerometer could not be read")]
+    NoSamples,
+    #[error("There is no accelerometer")]
+    NoSensor,
+    #[error("This accelerometer cannot be calibrated")]
+    Unsupported,
+    #[error("Could not save the calibration: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+pub fn delta_magnitude(a: &AccelerometerData, b: &AccelerometerData) -> f32 {
+    let delta = (b.acc.0 - a.acc.0, b.acc.1 - a.acc.1, b.acc.2 - a.acc.2);
+    (delta.0.powi(2) + delta.1.powi(2) + delta.2.powi(2)).sqrt()
 }
 
+/// Change in acceleration between readings, after removing gravity and the slow drift of the sensor.
+///
+/// The temperature dependent bias of the sensor drifts over the night. Measured directly, the drift would add to every change
+/// and make the noise floor wander. It is removed by subtracting an exponential moving average of each axis.
+#[derive(Debug, Clone, Default)]
+pub struct MovementFilter {
+    /// Moving average of each axis. `None` before the first reading.
+    mean: Option<(f32, f32, f32)>,
+    /// The acceleration minus the moving average, at the previous reading.
+    prev: (f32, f32, f32),
+}
+
+impl MovementFilter {
+    /// Adds a reading, `elapsed` after the previous one. Returns the change since the previous reading, or `None` for the first one.
+    pub fn update(
+        &mut self,
+        acc: (f32, f32, f32),
+        elapsed: Duration,
+        time_constant: Duration,
+    ) -> Option<f32> {
+        let Some(mean) = &mut self.mean else {
+            self.mean = Some(acc);
+            return None;
+        };
+        let alpha = 1.0 - (-elapsed.as_secs_f32() / time_constant.as_secs_f32()).exp();
+        mean.0 += alpha * (acc.0 - mean.0);
+        mean.1 += alpha * (acc.1 - mean.1);
+        mean.2 += alpha * (acc.2 - mean.2);
+        let filtered = (acc.0 - mean.0, acc.1 - mean.1, acc.2 - mean.2);
+        let prev = std::mem::replace(&mut self.prev, filtered);
+        let delta = (
+            filtered.0 - prev.0,
+            filtered.1 - prev.1,
+            filtered.2 - prev.2,
+        );
+        Some((delta.0.powi(2) + delta.1.powi(2) + delta.2.powi(2)).sqrt())
+    }
+}
+
+/// Acceleration in g, rotation in rad/s and temperature in °C.
 #[derive(Debug, Clone)]
 pub struct AccelerometerData {
     pub acc: (f32, f32, f32),
@@ -51,16 +484,32 @@ impl Default for AccelerometerData {
 }
 
 impl Accelerometer {
-    pub fn new() -> Result<Self, Mpu6050Error<I2CError>> {
-        let i2c = I2cdev::new("/dev/i2c-1").map_err(|e| Mpu6050Error::I2c(I2CError::from(e)))?;
-        let mut delay = Delay;
-        let mut mpu = Mpu6050::new(i2c);
-        mpu.init(&mut delay)?;
-        mpu.set_clock_source(device::CLKSEL::GXAXIS)?;
-        Ok(Accelerometer { mpu })
+    pub fn new(config: &MotionConfig) -> Result<Self, AccelerometerInitError> {
+        let address = config.i2c_address.unwrap_or(device::DEFAULT_SLAVE_ADDR);
+        let init = || -> Result<Mpu6050<I2cdev>, Mpu6050Error<I2CError>> {
+            let i2c =
+                I2cdev::new(&config.i2c_bus).map_err(|e| Mpu6050Error::I2c(I2CError::from(e)))?;
+            let mut delay = Delay;
+            let mut mpu = Mpu6050::new_with_addr(i2c, address);
+            mpu.init(&mut delay)?;
+            mpu.set_clock_source(device::CLKSEL::GXAXIS)?;
+            Ok(mpu)
+        };
+        let mpu = init().map_err(|e| AccelerometerInitError {
+            sensor: "MPU6050".to_owned(),
+            bus: config.i2c_bus.clone(),
+            address: Some(address),
+            cause: format!("{:?}", e),
+        })?;
+        Ok(Accelerometer {
+            mpu,
+            calibration: Calibration::load(&config.calibration_file),
+            gyro_bias: GyroBiasLearner::default(),
+            config: config.clone(),
+        })
     }
 
-    pub fn get_data(&mut self) -> Result<AccelerometerData, Mpu6050Error<I2CError>> {
+    fn get_raw_data(&mut self) -> Result<AccelerometerData, Mpu6050Error<I2CError>> {
         // get accelerometer data, scaled with sensitivity
         let acc = self.mpu.get_acc()?;
 
@@ -78,88 +527,1708 @@ impl Accelerometer {
     }
 }
 
+impl AccelerometerSource for Accelerometer {
+    /// Reads the sensor, with the calibration offsets subtracted.
+    fn sample(&mut self) -> Result<AccelerometerData, SensorError> {
+        let data = self.get_raw_data()?;
+        self.gyro_bias
+            .add(&mut self.calibration, &self.config.calibration_file, &data);
+        Ok(self.calibration.apply(data))
+    }
+
+    /// Initializes the sensor again, with the same settings. Keeps the gyro bias which has not been saved yet.
+    fn reinit(&mut self) -> Result<(), SensorError> {
+        let calibration = std::mem::take(&mut self.calibration);
+        let gyro_bias = std::mem::take(&mut self.gyro_bias);
+        *self = Accelerometer::new(&self.config)?;
+        self.calibration = calibration;
+        self.gyro_bias = gyro_bias;
+        Ok(())
+    }
+
+    fn calibrate(&mut self) -> Result<Calibration, CalibrationError> {
+        let previous = self.calibration.clone();
+        let path = self.config.calibration_file.clone();
+        let calibration = Calibration::measure(&path, &previous, || Ok(self.get_raw_data()?))?;
+        self.calibration = calibration.clone();
+        Ok(calibration)
+    }
+
+    fn set_at_rest(&mut self, at_rest: bool) {
+        self.gyro_bias.set_at_rest(
+            &mut self.calibration,
+            &self.config.calibration_file,
+            at_rest,
+        );
+    }
+}
+
+/// Plays back the readings of an accelerometer log, to run without the sensor.
+///
+/// Every logged reading of the user's sensor is returned as one sample.
+pub struct ReplaySource {
+    readings: std::vec::IntoIter<LogSample>,
+    /// How much faster than real time to replay. `None` returns the readings immediately.
+    speed: Option<f32>,
+    prev_time: Option<DateTime<Utc>>,
+}
+
+impl ReplaySource {
+    pub fn new(log: impl BufRead, speed: Option<f32>) -> std::io::Result<ReplaySource> {
+        let mut readings = vec![];
+        for line in log.lines() {
+            readings
+                .extend(LogSample::parse_csv(&line?).filter(|s| s.sensor == motion_log::MY_SENSOR));
+        }
+        Ok(ReplaySource {
+            readings: readings.into_iter(),
+            speed,
+            prev_time: None,
+        })
+    }
+
+    /// Opens a log file, which may be gzipped.
+    pub fn open(path: &Path, speed: Option<f32>) -> std::io::Result<ReplaySource> {
+        ReplaySource::new(motion_log::open(path)?, speed)
+    }
+}
+
+impl AccelerometerSource for ReplaySource {
+    fn sample(&mut self) -> Result<AccelerometerData, SensorError> {
+        let reading = self.readings.next().ok_or(SensorError::Ended)?;
+        if let (Some(speed), Some(prev_time)) = (self.speed, self.prev_time) {
+            if let Ok(delay) = (reading.time - prev_time).to_std() {
+                std::thread::sleep(delay.div_f32(speed));
+            }
+        }
+        self.prev_time = Some(reading.time);
+        Ok(reading.data)
+    }
+}
+
+/// Smooths the temperature readings of the sensor into the room temperature.
+///
+/// The readings are already in °C, using the formula from the datasheet (`raw / 340 + 36.53`).
+/// That is the temperature of the chip though, so it needs a per-device offset to match the room.
+pub struct RoomTemperature {
+    offset: f32,
+    average: Option<(f32, Instant)>,
+    last_published: Option<Instant>,
+}
+
+impl RoomTemperature {
+    pub fn new(offset: f32) -> Self {
+        RoomTemperature {
+            offset,
+            average: None,
+            last_published: None,
+        }
+    }
+
+    /// Adds a reading. Returns the smoothed temperature, rounded to 0.1 °C, when it is time to publish it.
+    pub fn update(&mut self, temp: f32, now: Instant) -> Option<f32> {
+        let temp = temp + self.offset;
+        let average = match self.average {
+            Some((average, time)) => {
+                let dt = now.duration_since(time).as_secs_f32();
+                let alpha = 1.0 - (-dt / TEMPERATURE_SMOOTHING.as_secs_f32()).exp();
+                average + alpha * (temp - average)
+            }
+            None => temp,
+        };
+        self.average = Some((average, now));
+
+        if self
+            .last_published
+            .is_some_and(|t| now.duration_since(t) < TEMPERATURE_PUBLISH_INTERVAL)
+        {
+            return None;
+        }
+        self.last_published = Some(now);
+        Some((average * 10.0).round() / 10.0)
+    }
+}
+
+/// How the sensor under the mattress is tilted compared to when the bed was empty.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BedOrientation {
+    /// The mattress is level, as when lying down.
+    Level,
+    /// The edge of the mattress is compressed, as when sitting on it.
+    Tilted,
+}
+
+/// What the sleep monitor currently thinks about the user. `None` while the recording is paused, since it is unknown then.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Presence {
+    pub in_bed: Option<bool>,
+    pub significant_movement: Option<bool>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SleepStage {
+    Wake,
+    Light,
+    Deep,
+}
+
+/// Activity during one minute.
+#[derive(Debug, Clone, Copy, Default)]
+struct MinuteActivity {
+    /// Number of samples with movement.
+    count: u32,
+    present: bool,
+    /// The debounced presence, at any time during the minute.
+    in_bed: bool,
+    /// Estimated at the end of the minute, if there was no movement during it.
+    respiration: Option<Respiration>,
+}
+
+/// Classifies each minute using a Cole-Kripke style weighted window of the activity.
+///
+/// Minutes when nobody is in bed are always wake. The last minutes have no activity after them yet, which is scored as no activity.
+fn score_stages(activity: &[MinuteActivity]) -> Vec<SleepStage> {
+    (0..activity.len())
+        .map(|i| {
+            if !activity[i].present {
+                return SleepStage::Wake;
+            }
+            let weighted: f32 = STAGE_WEIGHTS
+                .iter()
+                .enumerate()
+                .filter_map(|(k, w)| {
+                    let j = (i + k).checked_sub(4)?;
+                    activity.get(j).map(|a| w * a.count as f32)
+                })
+                .sum();
+            let score = STAGE_SCALE * weighted;
+            if score >= 1.0 {
+                SleepStage::Wake
+            } else if score < DEEP_SLEEP_SCORE {
+                SleepStage::Deep
+            } else {
+                SleepStage::Light
+            }
+        })
+        .collect()
+}
+
+/// True if the stages end with a few minutes of light sleep which came after deep sleep. That is a good time to wake up.
+fn is_light_sleep_transition(stages: &[SleepStage]) -> bool {
+    let Some(light_start) = stages.len().checked_sub(LIGHT_SLEEP_MINUTES) else {
+        return false;
+    };
+    let (before, light) = stages.split_at(light_start);
+    light.iter().all(|&s| s == SleepStage::Light)
+        && before
+            .iter()
+            .rev()
+            .take(DEEP_SLEEP_LOOKBACK_MINUTES)
+            .any(|&s| s == SleepStage::Deep)
+}
+
+/// Time constant of the moving average of the room temperature.
+const TEMPERATURE_SMOOTHING: Duration = Duration::from_secs(10 * 60);
+/// The room temperature is published at most this often.
+const TEMPERATURE_PUBLISH_INTERVAL: Duration = Duration::from_secs(60);
+
+struct RollingSample {
+    data: AccelerometerData,
+    /// Wall-clock time, the same as in the logs.
+    time: DateTime<Utc>,
+    /// Change since the previous sample. `None` for the first sample.
+    delta: Option<f32>,
+}
+
+/// Detects two taps on the bed in quick succession, from the individual samples of the sensor.
+#[derive(Debug, Default)]
+pub struct TapDetector {
+    prev: Option<AccelerometerData>,
+    /// Set while the sensor readings are above the thresholds, so that a single tap is only counted once.
+    in_spike: bool,
+    /// When the last tap that may be the first of a double tap started.
+    first_tap: Option<Instant>,
+}
+
+impl TapDetector {
+    /// Adds a sample. Returns true if it completed a double tap.
+    pub fn update(&mut self, data: &AccelerometerData, now: Instant, config: &TapConfig) -> bool {
+        let acc = self
+            .prev
+            .as_ref()
+            .map_or(0.0, |prev| delta_magnitude(prev, data));
+        let (x, y, z) = data.gyro;
+        let gyro = (x * x + y * y + z * z).sqrt();
+        self.prev = Some(data.clone());
+
+        let is_spike = acc > config.acc_threshold || gyro > config.gyro_threshold;
+        let is_tap = is_spike && !self.in_spike;
+        self.in_spike = is_spike;
+        if !is_tap {
+            return false;
+        }
+
+        debug!(
+            "Tap spike: acceleration change {:.3} g, rotation {:.3} rad/s",
+            acc, gyro
+        );
+        match self.first_tap.map(|t| now.duration_since(t).as_secs_f32()) {
+            // Still the same tap, bouncing
+            Some(gap) if gap < config.min_gap_seconds => false,
+            Some(gap) if gap <= config.max_gap_seconds => {
+                self.first_tap = None;
+                true
+            }
+            _ => {
+                self.first_tap = Some(now);
+                false
+            }
+        }
+    }
+}
+
+/// Detects a sensor which keeps returning exactly the same values.
+#[derive(Debug, Default)]
+pub struct FreezeDetector {
+    /// The bits of the last values, and since when they have been the same.
+    last: Option<([u32; 7], Instant)>,
+}
+
+impl FreezeDetector {
+    /// Adds a sample. Returns true if the values have not changed for more than `limit`.
+    pub fn update(&mut self, data: &AccelerometerData, now: Instant, limit: Duration) -> bool {
+        let (a, g) = (data.acc, data.gyro);
+        let bits = [a.0, a.1, a.2, g.0, g.1, g.2, data.temp].map(f32::to_bits);
+        match self.last {
+            Some((last, since)) if last == bits => now.duration_since(since) > limit,
+            _ => {
+                self.last = Some((bits, now));
+                false
+            }
+        }
+    }
+}
+
+/// Only changes the presence after the raw presence has disagreed with it for a while.
+#[derive(Debug, Default)]
+struct PresenceDebouncer {
+    present: bool,
+    /// Since when the raw presence has disagreed with `present`.
+    changing_since: Option<DateTime<Utc>>,
+}
+
+impl PresenceDebouncer {
+    /// `raw` is `None` when the movement is between the thresholds for presence and absence.
+    fn update(
+        &mut self,
+        raw: Option<bool>,
+        now: DateTime<Utc>,
+        config: &SleepMonitorConfig,
+    ) -> bool {
+        match raw {
+            Some(raw) if raw != self.present => {
+                let since = *self.changing_since.get_or_insert(now);
+                let required = if raw {
+                    config.present_after_seconds
+                } else {
+                    config.absent_after_seconds
+                };
+                if elapsed(since, now).as_secs_f32() >= required {
+                    self.present = raw;
+                    self.changing_since = None;
+                }
+            }
+            _ => self.changing_since = None,
+        }
+        self.present
+    }
+}
+
+/// Time from `from` to `to`. Zero if the clock was set back in between.
+fn elapsed(from: DateTime<Utc>, to: DateTime<Utc>) -> Duration {
+    (to - from).to_std().unwrap_or_default()
+}
+
+/// Probability from 0 to 1 that someone is in bed.
+///
+/// A logistic function of the time within the window with changes above the noise threshold, which is 0.5 at `noise_threshold_seconds`,
+/// decayed exponentially over the time since the last such change, with `absent_after_seconds` as the time constant.
+/// `since_last` is `None` if there was no such change within the window.
+fn estimate_presence_confidence(
+    active: Duration,
+    since_last: Option<Duration>,
+    reading_period: Duration,
+    config: &SleepMonitorConfig,
+) -> f32 {
+    let Some(since_last) = since_last else {
+        return 0.0;
+    };
+    // One reading changes the evidence by a noticeable step, but not all the way
+    let width = config
+        .noise_threshold_seconds
+        .max(reading_period.as_secs_f32());
+    let x = (active.as_secs_f32() - config.noise_threshold_seconds) / width;
+    let evidence = 1.0 / (1.0 + (-x).exp());
+    let decay = (-since_last.as_secs_f32() / config.absent_after_seconds.max(1.0)).exp();
+    evidence * decay
+}
+
 pub struct SleepMonitor {
-    rolling_data: Vec<AccelerometerData>,
-    times: Vec<Instant>,
-    rolling_delta_magn: Vec<f32>,
+    /// Samples from the last `max_memory`, oldest first.
+    samples: VecDeque<RollingSample>,
     max_memory: Duration,
-    is_user_in_bed: Arc<SyncedContainer<bool>>,
-    is_significant_movement_in_bed: Arc<SyncedContainer<bool>>,
+    /// Approximate time between the readings.
+    reading_period: Duration,
+    /// The thresholds are read from the config every time they are used, so that changes apply immediately.
+    config: Arc<ConfigStore>,
+    /// See [`SleepMonitor::max_readings`]. Computed again when the generation of the config changes.
+    max_readings: Option<(u64, usize)>,
+    presence: watch::Sender<Presence>,
+    presence_debouncer: PresenceDebouncer,
+    /// Set while the sensor cannot be read.
+    stale: bool,
+    /// Set while the recording is paused. See [`pause`].
+    paused: bool,
+    /// Activity of the minute which is currently being recorded, and when it started.
+    current_minute: Option<(DateTime<Utc>, MinuteActivity)>,
+    /// Completed minutes, oldest first. Minutes when the sensor could not be read are skipped.
+    minutes: VecDeque<(DateTime<Utc>, MinuteActivity)>,
+    /// Breathing rate of the minute which was completed last, until it is taken for the log.
+    completed_respiration: Option<Respiration>,
+    /// See [`Calibration::gravity`].
+    reference_gravity: Option<(f32, f32, f32)>,
+    movement: MovementFilter,
 }
 
 impl SleepMonitor {
-    pub fn new(
-        max_memory: Duration,
-        is_user_in_bed: Arc<SyncedContainer<bool>>,
-        is_significant_movement_in_bed: Arc<SyncedContainer<bool>>,
-    ) -> Self {
+    pub fn new(max_memory: Duration, reading_period: Duration, config: Arc<ConfigStore>) -> Self {
+        let capacity = (max_memory.as_secs_f32() / reading_period.as_secs_f32()).ceil() as usize;
         SleepMonitor {
-            rolling_data: vec![],
-            times: vec![],
-            rolling_delta_magn: vec![],
+            samples: VecDeque::with_capacity(capacity + 1),
             max_memory,
-            is_user_in_bed,
-            is_significant_movement_in_bed,
+            reading_period,
+            config,
+            max_readings: None,
+            presence_debouncer: PresenceDebouncer::default(),
+            presence: watch::Sender::new(Presence::default()),
+            stale: false,
+            paused: false,
+            current_minute: None,
+            minutes: VecDeque::new(),
+            completed_respiration: None,
+            reference_gravity: None,
+            movement: MovementFilter::default(),
         }
     }
 
-    pub fn push(&mut self, data: AccelerometerData) {
-        let prev = self.rolling_data.last().cloned();
-        self.rolling_data.push(data.clone());
-        self.times.push(Instant::now());
+    /// Sets the direction of gravity when the bed is empty, which the orientation is measured against.
+    pub fn set_reference_gravity(&mut self, gravity: Option<(f32, f32, f32)>) {
+        self.reference_gravity = gravity;
+    }
 
-        if let Some(prev) = prev {
-            let delta = (
-                data.acc.0 - prev.acc.0,
-                data.acc.1 - prev.acc.1,
-                data.acc.2 - prev.acc.2,
-            );
-            let delta_magn = (delta.0.powi(2) + delta.1.powi(2) + delta.2.powi(2)).sqrt();
-            self.rolling_delta_magn.push(delta_magn);
+    /// Marks the data as stale while the sensor cannot be read. Stale data never counts as presence or movement.
+    pub fn set_stale(&mut self, stale: bool) {
+        if stale && !self.stale {
+            self.forget();
+        }
+        self.stale = stale;
+        self.publish();
+    }
+
+    /// Pauses the recording. Nothing is pushed while paused, and the presence is unknown.
+    pub fn set_paused(&mut self, paused: bool) {
+        if paused && !self.paused {
+            self.forget();
         }
+        self.paused = paused;
+        self.publish();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// The next sample should not be compared to one from long ago
+    fn forget(&mut self) {
+        self.samples.clear();
+        self.presence_debouncer = PresenceDebouncer::default();
+        self.current_minute = None;
+    }
 
-        if self.times.first().unwrap().elapsed() > self.max_memory {
-            self.rolling_data.remove(0);
-            self.times.remove(0);
-            if self.rolling_delta_magn.len() > self.times.len() {
-                self.rolling_delta_magn.remove(0);
+    /// True while the sensor is unreadable or frozen, or the recording is paused.
+    /// Motion-dependent features should behave as if there was no sensor.
+    pub fn is_stale(&self) -> bool {
+        self.stale || self.paused
+    }
+
+    /// Receives the presence whenever it changes.
+    pub fn subscribe(&self) -> watch::Receiver<Presence> {
+        self.presence.subscribe()
+    }
+
+    /// Adds a reading taken at `now`. Returns the change since the previous reading, which is what the thresholds are compared to.
+    pub fn push(&mut self, data: AccelerometerData, now: DateTime<Utc>) -> Option<f32> {
+        // After the clock has been set back, the readings from after `now` cannot be put in order with the new ones
+        while self.samples.back().is_some_and(|s| s.time > now) {
+            self.samples.pop_back();
+        }
+        let since_prev = match self.samples.back() {
+            Some(prev) => elapsed(prev.time, now),
+            None => {
+                // Not comparable to the readings from before a gap
+                self.movement = MovementFilter::default();
+                Duration::ZERO
             }
+        };
+        let time_constant = Duration::from_secs_f32(self.thresholds().drift_time_constant_seconds);
+        let delta = self.movement.update(data.acc, since_prev, time_constant);
+        if let Some(delta) = delta {
+            self.record_activity(now, delta);
         }
+        self.samples.push_back(RollingSample {
+            data,
+            time: now,
+            delta,
+        });
+
+        // The samples are in order, so everything before the first one within the window is too old
+        let expired = self
+            .samples
+            .partition_point(|s| elapsed(s.time, now) > self.max_memory);
+        let excess = self.samples.len().saturating_sub(self.max_readings());
+        self.samples.drain(..expired.max(excess));
 
-        futures::executor::block_on(async {
-            self.is_user_in_bed.set(self.is_present()).await;
-            self.is_significant_movement_in_bed
-                .set(self.is_significant_movement())
-                .await;
+        let raw_presence = self.raw_presence();
+        self.presence_debouncer
+            .update(raw_presence, now, &self.thresholds());
+        self.publish();
+        delta
+    }
+
+    /// Presence according to only the current window. `None` if it is between the thresholds for presence and absence.
+    fn raw_presence(&self) -> Option<bool> {
+        let thresholds = self.thresholds();
+        let count = self.count_deltas_above(thresholds.noise_threshold);
+        if count > self.readings_in(thresholds.noise_threshold_seconds) {
+            Some(true)
+        } else if count <= self.readings_in(thresholds.absent_threshold_seconds) {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    /// Number of samples which changed more than `threshold` since the previous sample.
+    fn count_deltas_above(&self, threshold: f32) -> usize {
+        self.samples
+            .iter()
+            .filter(|s| s.delta.is_some_and(|d| d > threshold))
+            .count()
+    }
+
+    /// Most readings to keep in the window, see [`crate::config::MotionConfig::max_window_readings`].
+    fn max_readings(&mut self) -> usize {
+        let generation = self.config.generation();
+        match self.max_readings {
+            Some((cached, max_readings)) if cached == generation => max_readings,
+            _ => {
+                let max_readings = self
+                    .config
+                    .get()
+                    .motion
+                    .max_window_readings
+                    .unwrap_or_else(|| {
+                        let expected =
+                            self.max_memory.as_secs_f32() / self.reading_period.as_secs_f32();
+                        (expected * WINDOW_SAFETY_FACTOR).ceil() as usize
+                    })
+                    .max(1);
+                self.max_readings = Some((generation, max_readings));
+                max_readings
+            }
+        }
+    }
+
+    /// Number of readings in `seconds`.
+    fn readings_in(&self, seconds: f32) -> usize {
+        (seconds / self.reading_period.as_secs_f32()).round() as usize
+    }
+
+    fn thresholds(&self) -> SleepMonitorConfig {
+        self.config.get().sleep_monitor
+    }
+
+    fn record_activity(&mut self, now: DateTime<Utc>, delta: f32) {
+        let thresholds = self.thresholds();
+        let in_bed = self.is_present();
+        // The same minutes as in the histogram and the archive
+        let minute = histogram::bucket_start(now, chrono::TimeDelta::minutes(1), &Utc);
+        // A minute from before the clock was set back continues until the clock has caught up
+        let minute_done = self.current_minute.is_some_and(|(start, _)| minute > start);
+        let respiration = if minute_done {
+            self.respiration()
+        } else {
+            None
+        };
+        let (start, activity) = self
+            .current_minute
+            .get_or_insert_with(|| (minute, MinuteActivity::default()));
+        if minute_done {
+            activity.respiration = respiration;
+            self.completed_respiration = respiration;
+            self.minutes.push_back((*start, *activity));
+            if self.minutes.len() > STAGE_HISTORY_MINUTES {
+                self.minutes.pop_front();
+            }
+            *start = minute;
+            *activity = MinuteActivity::default();
+        }
+        if delta > thresholds.movement_threshold {
+            activity.count += 1;
+        }
+        activity.present |= delta > thresholds.noise_threshold;
+        activity.in_bed |= in_bed;
+    }
+
+    /// Breathing rate during the last minute. `None` if there was any movement, or too little data.
+    fn respiration(&self) -> Option<Respiration> {
+        let newest = self.samples.back()?.time;
+        let movement_threshold = self.thresholds().movement_threshold;
+        let mut acc = vec![];
+        let mut oldest = newest;
+        for s in self
+            .samples
+            .iter()
+            .rev()
+            .take_while(|s| elapsed(s.time, newest) < RESPIRATION_WINDOW)
+        {
+            if s.delta.is_some_and(|d| d > movement_threshold) {
+                return None;
+            }
+            acc.push(s.data.acc);
+            oldest = s.time;
+        }
+        // Not if the sensor was unreadable for a part of the window
+        if elapsed(oldest, newest) < RESPIRATION_WINDOW.mul_f32(0.9) {
+            return None;
+        }
+        acc.reverse();
+        respiration::estimate(&acc, self.reading_period)
+    }
+
+    /// Breathing rate of the minute before the current one, once, right after that minute has been completed.
+    ///
+    /// Logged with the reading that completed it, see [`crate::motion_log::LogSample::respiration`].
+    pub fn take_respiration(&mut self) -> Option<Respiration> {
+        self.completed_respiration.take()
+    }
+
+    /// Estimated breathing rate of each of the recorded minutes, oldest first. Matches [`SleepMonitor::stages`].
+    pub fn respiration_history(&self) -> Vec<Option<Respiration>> {
+        self.minutes.iter().map(|(_, a)| a.respiration).collect()
+    }
+
+    /// Estimated sleep stage of each of the recorded minutes, oldest first.
+    pub fn stages(&self) -> Vec<(DateTime<Utc>, SleepStage)> {
+        let activity = self.minutes.iter().map(|&(_, a)| a).collect::<Vec<_>>();
+        self.minutes
+            .iter()
+            .map(|&(time, _)| time)
+            .zip(score_stages(&activity))
+            .collect()
+    }
+
+    /// Estimated sleep stage and number of samples with movement of each of the recorded minutes, oldest first.
+    /// `None` while the sensor is stale.
+    pub fn staged_movement(&self) -> Option<Vec<(SleepStage, u32)>> {
+        if self.is_stale() {
+            return None;
+        }
+        let activity = self.minutes.iter().map(|&(_, a)| a).collect::<Vec<_>>();
+        Some(
+            score_stages(&activity)
+                .into_iter()
+                .zip(activity.iter().map(|a| a.count))
+                .collect(),
+        )
+    }
+
+    /// The recorded minutes of the night of `date`, oldest first. See [`nights::summarize`].
+    ///
+    /// Minutes in bed without any noticeable movement count as asleep, even though the stage is wake.
+    pub fn night_minutes<Tz: TimeZone>(&self, date: NaiveDate, tz: &Tz) -> Vec<NightMinute> {
+        let activity = self.minutes.iter().map(|&(_, a)| a).collect::<Vec<_>>();
+        self.minutes
+            .iter()
+            .zip(score_stages(&activity))
+            .filter(|((time, _), _)| nights::sleep_date(*time, tz) == date)
+            .map(|(&(_, a), stage)| NightMinute {
+                in_bed: a.in_bed,
+                asleep: a.in_bed && (stage != SleepStage::Wake || !a.present),
+                movement: a.count,
+                respiration: a.respiration,
+            })
+            .collect()
+    }
+
+    /// Estimated sleep stage of the last complete minute.
+    pub fn current_stage(&self) -> Option<SleepStage> {
+        if self.is_stale() {
+            return None;
+        }
+        self.stages().last().map(|&(_, stage)| stage)
+    }
+
+    /// True if the user seems to be in light sleep after a period of deep sleep.
+    pub fn is_light_sleep_transition(&self) -> bool {
+        let stages = self
+            .stages()
+            .into_iter()
+            .map(|(_, s)| s)
+            .collect::<Vec<_>>();
+        !self.is_stale() && is_light_sleep_transition(&stages)
+    }
+
+    /// Never blocks, so that a slow connection cannot delay the sampling.
+    fn publish(&self) {
+        let known = !self.paused;
+        let presence = Presence {
+            in_bed: known.then(|| self.is_present()),
+            significant_movement: known.then(|| self.is_significant_movement()),
+        };
+        self.presence.send_if_modified(|p| {
+            let modified = *p != presence;
+            *p = presence;
+            modified
         });
     }
 
     pub fn is_significant_movement(&self) -> bool {
-        const MOVEMENT_THRESHOLD: f32 = 0.02;
-        const MOVEMENT_THRESHOLD_SAMPLES: i32 = 2;
+        if self.is_stale() {
+            return false;
+        }
 
-        let mut cnt = 0;
-        for &v in &self.rolling_delta_magn {
-            if v > MOVEMENT_THRESHOLD {
-                cnt += 1;
+        let thresholds = self.thresholds();
+        self.count_deltas_above(thresholds.movement_threshold)
+            > self.readings_in(thresholds.movement_threshold_seconds)
+    }
+
+    /// True if the user is present in bed. Short changes of the movement are ignored.
+    pub fn is_present(&self) -> bool {
+        !self.is_stale() && self.presence_debouncer.present
+    }
+
+    /// Probability from 0 to 1 that someone is in bed, see [`estimate_presence_confidence`]. Unlike [`SleepMonitor::is_present`],
+    /// it is not debounced, so it shows how certain the presence is. `None` while the data is stale.
+    pub fn presence_confidence(&self) -> Option<f32> {
+        if self.is_stale() {
+            return None;
+        }
+        let newest = self.samples.back()?.time;
+        let thresholds = self.thresholds();
+        let active = self.count_deltas_above(thresholds.noise_threshold);
+        let last = self
+            .samples
+            .iter()
+            .rev()
+            .find(|s| s.delta.is_some_and(|d| d > thresholds.noise_threshold));
+        Some(estimate_presence_confidence(
+            self.reading_period * active as u32,
+            last.map(|s| elapsed(s.time, newest)),
+            self.reading_period,
+            &thresholds,
+        ))
+    }
+
+    /// Angle in degrees between gravity now and when the bed was empty. `None` without a reference from the calibration.
+    pub fn tilt_degrees(&self) -> Option<f32> {
+        let reference = self.reference_gravity?;
+        if self.is_stale() {
+            return None;
+        }
+        let newest = self.samples.back()?.time;
+        let recent = self
+            .samples
+            .iter()
+            .rev()
+            .take_while(|s| elapsed(s.time, newest) <= ORIENTATION_WINDOW)
+            .map(|s| s.data.clone())
+            .collect::<Vec<_>>();
+        let gravity = AccelerometerData::mean(&recent).acc;
+        let dot = gravity.0 * reference.0 + gravity.1 * reference.1 + gravity.2 * reference.2;
+        let length = |(x, y, z): (f32, f32, f32)| (x * x + y * y + z * z).sqrt();
+        let cos = dot / (length(gravity) * length(reference));
+        cos.is_finite()
+            .then(|| cos.clamp(-1.0, 1.0).acos().to_degrees())
+    }
+
+    pub fn orientation(&self) -> Option<BedOrientation> {
+        let tilt = self.tilt_degrees()?;
+        Some(if tilt > self.thresholds().tilt_threshold_degrees {
+            BedOrientation::Tilted
+        } else {
+            BedOrientation::Level
+        })
+    }
+
+    /// See [`MovementIntensity`]. Rounded to 0.0001 g, so that it does not change all the time. `None` while the data is stale.
+    pub fn movement_intensity(&self) -> Option<MovementIntensity> {
+        if self.is_stale() {
+            return None;
+        }
+        let newest = self.samples.back()?.time;
+        let mut deltas = self
+            .samples
+            .iter()
+            .rev()
+            .take_while(|s| elapsed(s.time, newest) <= INTENSITY_WINDOW)
+            .filter_map(|s| s.delta)
+            .collect::<Vec<_>>();
+        deltas.sort_by(f32::total_cmp);
+        let p95 = histogram::percentile(&deltas, 0.95)?;
+        Some(MovementIntensity((p95 * 10_000.0).round() / 10_000.0))
+    }
+}
+
+/// Distribution of the changes in acceleration within the window of the sleep monitor.
+#[derive(Serialize)]
+pub struct LiveStats {
+    samples: usize,
+    /// Time of the oldest and the newest reading in the window.
+    window_start: Option<DateTime<Utc>>,
+    window_end: Option<DateTime<Utc>>,
+    min: Option<f32>,
+    median: Option<f32>,
+    max: Option<f32>,
+    in_bed: bool,
+    presence_confidence: Option<f32>,
+    significant_movement: bool,
+    paused: bool,
+    movement_intensity: Option<MovementIntensity>,
+    tilt_degrees: Option<f32>,
+    orientation: Option<BedOrientation>,
+    thresholds: SleepMonitorConfig,
+}
+
+impl SleepMonitor {
+    pub fn live_stats(&self) -> LiveStats {
+        let mut deltas = self
+            .samples
+            .iter()
+            .filter_map(|s| s.delta)
+            .collect::<Vec<_>>();
+        deltas.sort_by(f32::total_cmp);
+        LiveStats {
+            samples: deltas.len(),
+            window_start: self.samples.front().map(|s| s.time),
+            window_end: self.samples.back().map(|s| s.time),
+            min: deltas.first().copied(),
+            median: deltas.get(deltas.len() / 2).copied(),
+            max: deltas.last().copied(),
+            in_bed: self.is_present(),
+            presence_confidence: self.presence_confidence(),
+            significant_movement: self.is_significant_movement(),
+            paused: self.paused,
+            movement_intensity: self.movement_intensity(),
+            tilt_degrees: self.tilt_degrees(),
+            orientation: self.orientation(),
+            thresholds: self.thresholds(),
+        }
+    }
+}
+
+/// For picking the thresholds. Compare the distribution while in bed and while the bed is empty.
+#[get("/sleep/live")]
+pub async fn get_live(state: &State<AlarmState>) -> Json<LiveStats> {
+    Json(state.sleep_monitor.lock().await.sleep_monitor.live_stats())
+}
+
+#[put("/sleep/config", data = "<thresholds>")]
+pub fn put_sleep_config(
+    state: &State<AlarmState>,
+    thresholds: Json<SleepMonitorConfig>,
+) -> Result<Json<SleepMonitorConfig>, (Status, String)> {
+    let mut config = state.config.get();
+    config.sleep_monitor = thresholds.0;
+    match state.config.set(config) {
+        Ok(()) => {
+            info!("Updated the sleep monitor thresholds");
+            Ok(Json(state.config.get().sleep_monitor))
+        }
+        Err(e @ config::ConfigError::Invalid(_)) => Err((Status::BadRequest, e.to_string())),
+        Err(e) => {
+            error!("{}", e);
+            Err((Status::InternalServerError, e.to_string()))
+        }
+    }
+}
+
+/// Measures the offsets of the accelerometer while the bed is empty, and uses them from now on.
+///
+/// Takes about 30 seconds, during which the sleep monitor is paused.
+#[post("/sleep/calibrate")]
+pub async fn calibrate(state: &State<AlarmState>) -> Result<Json<Calibration>, (Status, String)> {
+    let monitor = state.sleep_monitor.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut monitor = monitor.blocking_lock();
+        if monitor.sleep_monitor.is_present() || monitor.sleep_monitor.is_significant_movement() {
+            return Err(CalibrationError::Occupied);
+        }
+        let accelerometer = monitor
+            .accelerometer
+            .as_mut()
+            .ok_or(CalibrationError::NoSensor)?;
+        info!("Calibrating the accelerometer");
+        let calibration = accelerometer.calibrate()?;
+        info!("Calibrated the accelerometer: {:?}", calibration);
+        monitor
+            .sleep_monitor
+            .set_reference_gravity(calibration.gravity);
+        Ok(calibration)
+    })
+    .await
+    .unwrap()
+    .map(Json)
+    .map_err(|e| {
+        let status = match e {
+            CalibrationError::Occupied | CalibrationError::Movement => Status::Conflict,
+            CalibrationError::NoSamples | CalibrationError::Io(_) => Status::InternalServerError,
+            CalibrationError::NoSensor | CalibrationError::Unsupported => {
+                Status::ServiceUnavailable
             }
+        };
+        (status, e.to_string())
+    })
+}
+
+#[derive(Serialize)]
+pub struct StagedMinute {
+    time: DateTime<Utc>,
+    stage: SleepStage,
+    respiration: Option<Respiration>,
+}
+
+#[derive(Serialize)]
+pub struct SleepStages {
+    current: Option<SleepStage>,
+    /// Breathing rate during the last complete minute.
+    respiration: Option<Respiration>,
+    history: Vec<StagedMinute>,
+}
+
+/// The estimated sleep stages of the last hours.
+#[get("/sleep/stages")]
+pub async fn get_sleep_stages(state: &State<AlarmState>) -> Json<SleepStages> {
+    let monitor = &state.sleep_monitor.lock().await.sleep_monitor;
+    let respiration = monitor.respiration_history();
+    Json(SleepStages {
+        current: monitor.current_stage(),
+        respiration: respiration.last().copied().flatten(),
+        history: monitor
+            .stages()
+            .into_iter()
+            .zip(respiration)
+            .map(|((time, stage), respiration)| StagedMinute {
+                time,
+                stage,
+                respiration,
+            })
+            .collect(),
+    })
+}
+
+/// Stops recording and publishing the motion data, for example while guests use the bedroom.
+///
+/// The sensor stays initialized, so that [`resume`] is instant. The paused state is kept across restarts.
+#[post("/sleep/pause")]
+pub async fn pause(state: &State<AlarmState>) {
+    let paused = state.sleep_monitor.lock().await.paused.clone();
+    paused.set(true).await;
+}
+
+#[post("/sleep/resume")]
+pub async fn resume(state: &State<AlarmState>) {
+    let paused = state.sleep_monitor.lock().await.paused.clone();
+    paused.set(false).await;
+}
+
+/// Whether the recording was paused when the program last ran. See [`pause`].
+pub fn load_paused(path: &Path) -> bool {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or(false)
+}
+
+pub fn save_paused(path: &Path, paused: bool) -> std::io::Result<()> {
+    std::fs::write(path, serde_json::to_string(&paused)?)
+}
+
+/// Publishes the presence from [`SleepMonitor::subscribe`] to the synced containers, whenever it changes.
+///
+/// While the presence is unknown, `presence_known` is false and the others are false too. It is marked as unknown
+/// before the others change, and as known after, so that a reader never takes the placeholders for real values.
+/// Changes which happen in quick succession are coalesced, and only the latest one is published.
+pub async fn publish_presence(
+    mut presence: watch::Receiver<Presence>,
+    is_user_in_bed: Arc<Container<bool>>,
+    is_significant_movement_in_bed: Arc<Container<bool>>,
+    presence_known: Arc<Container<bool>>,
+) {
+    let mut published: Option<Presence> = None;
+    loop {
+        let current = *presence.borrow_and_update();
+        let known = current.in_bed.is_some();
+        let was_known = published.map(|p| p.in_bed.is_some());
+        if !known && was_known != Some(false) {
+            presence_known.set(false).await;
+        }
+        let in_bed = current.in_bed.unwrap_or(false);
+        if published.map(|p| p.in_bed.unwrap_or(false)) != Some(in_bed) {
+            is_user_in_bed.set(in_bed).await;
+        }
+        let movement = current.significant_movement.unwrap_or(false);
+        if published.map(|p| p.significant_movement.unwrap_or(false)) != Some(movement) {
+            is_significant_movement_in_bed.set(movement).await;
+        }
+        if known && was_known != Some(true) {
+            presence_known.set(true).await;
+        }
+        published = Some(current);
+
+        tokio::time::sleep(PRESENCE_PUBLISH_INTERVAL).await;
+        if presence.changed().await.is_err() {
+            break;
         }
+    }
+}
+
+/// Sends `value` if it differs from the current one. Never blocks, so that it can be called from the sensor thread.
+pub fn send_if_changed<T: PartialEq>(sender: &watch::Sender<T>, value: T) {
+    sender.send_if_modified(|v| {
+        let modified = *v != value;
+        *v = value;
+        modified
+    });
+}
 
-        cnt > MOVEMENT_THRESHOLD_SAMPLES
+/// Publishes the values sent to `values` to `container`, whenever they change.
+///
+/// Like [`publish_presence`], so that the sensor thread does not wait for the MQTT connection.
+pub async fn publish_changes<T>(mut values: watch::Receiver<T>, container: Arc<Container<T>>)
+where
+    T: Serialize + DeserializeOwned + Clone + Hash + Send + Sync + 'static,
+{
+    while values.changed().await.is_ok() {
+        let value = values.borrow_and_update().clone();
+        container.set(value).await;
     }
+}
 
-    /// True if the user is present in bed
-    pub fn is_present(&self) -> bool {
-        const NOISE_THRESHOLD: f32 = 0.015;
-        const NOISE_THRESHOLD_SAMPLES: i32 = 1;
+#[cfg(test)]
+const TEST_READING_PERIOD: Duration = Duration::from_millis(100);
+
+#[cfg(test)]
+fn test_config(name: &str, sleep_monitor: SleepMonitorConfig) -> Arc<ConfigStore> {
+    let path = std::env::temp_dir().join(format!("{name}-config-{}.json", std::process::id()));
+    let store = ConfigStore::load(&path);
+    let mut config = store.get();
+    config.sleep_monitor = sleep_monitor;
+    store.set(config).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    Arc::new(store)
+}
+
+#[test]
+fn test_presence_changes() {
+    let thresholds = SleepMonitorConfig::default();
+    let config = test_config("presence-changes", thresholds.clone());
+    let mut monitor = SleepMonitor::new(Duration::from_secs(60), TEST_READING_PERIOD, config);
+    let mut presence = monitor.subscribe();
+    let still = AccelerometerData::default();
+    let moved = AccelerometerData {
+        acc: (0.1, 0.0, 0.0),
+        ..AccelerometerData::default()
+    };
+    let start = Utc::now();
+    let at = |ms| start + Duration::from_millis(ms);
+
+    // Unknown until the first reading
+    assert_eq!(presence.borrow_and_update().in_bed, None);
+    monitor.push(still.clone(), at(0));
+    assert_eq!(presence.borrow_and_update().in_bed, Some(false));
+    monitor.push(still.clone(), at(100));
+    assert!(!presence.has_changed().unwrap());
+
+    for i in 0..3 {
+        monitor.push(moved.clone(), at(200 + 200 * i));
+        monitor.push(still.clone(), at(300 + 200 * i));
+    }
+    assert!(presence.has_changed().unwrap());
+    let current = *presence.borrow_and_update();
+    assert_eq!(
+        (current.in_bed, current.significant_movement),
+        (Some(false), Some(true))
+    );
+
+    // Presence is only published once it has lasted for a while
+    monitor.push(still.clone(), at(4000));
+    assert!(!presence.has_changed().unwrap());
+    let after = (thresholds.present_after_seconds * 1000.0) as u64;
+    monitor.push(still.clone(), at(after + 1000));
+    assert!(presence.has_changed().unwrap());
+    assert_eq!(presence.borrow_and_update().in_bed, Some(true));
+
+    monitor.push(still, at(after + 1100));
+    assert!(!presence.has_changed().unwrap());
+
+    // Unknown while paused, and the motion-dependent features fall back to their behavior without a sensor
+    monitor.set_paused(true);
+    assert_eq!(*presence.borrow_and_update(), Presence::default());
+    assert!(monitor.is_stale());
+    monitor.set_paused(false);
+    assert_eq!(presence.borrow_and_update().in_bed, Some(false));
+    assert!(monitor.samples.is_empty());
+}
+
+#[test]
+fn test_calibration() {
+    let sample = |acc, gyro| AccelerometerData {
+        acc,
+        gyro,
+        temp: 20.0,
+    };
+    let samples = vec![
+        sample((0.03, -0.02, -1.01), (0.5, 0.0, -0.2)),
+        sample((0.04, -0.02, -1.0), (0.7, 0.0, -0.2)),
+    ];
+    let calibration = Calibration::from_samples(&samples).unwrap();
+    let corrected = calibration.apply(AccelerometerData::mean(&samples));
+    // Only gravity remains, pointing down along the z axis
+    for (v, expected) in [
+        (corrected.acc.0, 0.0),
+        (corrected.acc.1, 0.0),
+        (corrected.acc.2, -1.0),
+        (corrected.gyro.0, 0.0),
+    ] {
+        assert!((v - expected).abs() < 1e-6, "{corrected:?}");
+    }
+    assert_eq!(calibration.gravity, Some((0.0, 0.0, -1.0)));
+
+    let moving = vec![
+        samples[0].clone(),
+        sample((0.1, 0.0, -1.0), (0.0, 0.0, 0.0)),
+    ];
+    assert!(matches!(
+        Calibration::from_samples(&moving),
+        Err(CalibrationError::Movement)
+    ));
+    assert!(matches!(
+        Calibration::from_samples(&[]),
+        Err(CalibrationError::NoSamples)
+    ));
+}
+
+#[test]
+fn test_gyro_bias() {
+    let raw = |temp: f32, noise: f32| AccelerometerData {
+        acc: (0.0, 0.0, -1.0),
+        // The bias of the x axis grows by 0.002 rad/s per °C
+        gyro: (0.01 + 0.002 * temp + noise, -0.02, 0.0),
+        temp,
+    };
+    let mut calibration = Calibration {
+        gyro_offset: (0.05, -0.02, 0.0),
+        ..Calibration::default()
+    };
+    for i in 0..100 {
+        let noise = if i % 2 == 0 { 0.001 } else { -0.001 };
+        calibration.add_gyro_bias_sample(&raw(20.1, noise));
+    }
+    // Too narrow a range of temperatures to fit a slope
+    assert_eq!(GyroBiasModel::fit(&calibration.gyro_bias_estimates), None);
+    for temp in [17.0, 18.4, 23.0] {
+        calibration.add_gyro_bias_sample(&raw(temp, 0.0));
+    }
+    let temps = calibration
+        .gyro_bias_estimates
+        .iter()
+        .map(|e| e.temp)
+        .collect::<Vec<_>>();
+    assert_eq!(temps, [17.0, 18.5, 20.0, 23.0]);
+
+    let model = GyroBiasModel::fit(&calibration.gyro_bias_estimates).unwrap();
+    // Not exact, since the estimates are at the middle of their ranges of temperatures
+    assert!((model.slope.0 - 0.002).abs() < 1e-4, "{model:?}");
+    assert!(model.slope.1.abs() < 1e-6);
+    for estimate in &calibration.gyro_bias_estimates {
+        assert!(model.residual(estimate).0.abs() < 1e-3);
+    }
+    // Replaces the constant offset, which only matched one temperature
+    calibration.gyro_bias = Some(model);
+    for temp in [15.0, 25.0] {
+        let corrected = calibration.apply(raw(temp, 0.0));
+        assert!(corrected.gyro.0.abs() < 1e-4, "{corrected:?}");
+        assert!(corrected.gyro.1.abs() < 1e-6);
+    }
+}
+
+#[test]
+fn test_score_stages() {
+    use SleepStage::*;
+    let minutes = |counts: &[u32]| {
+        counts
+            .iter()
+            .map(|&count| MinuteActivity {
+                count,
+                present: true,
+                in_bed: true,
+                respiration: None,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // Lying still is deep sleep, tossing and turning is wake, and occasional twitches in between are light sleep
+    let still = minutes(&[0; 10]);
+    assert!(score_stages(&still).iter().all(|&s| s == Deep));
+    let restless = minutes(&[20; 10]);
+    assert!(score_stages(&restless).iter().all(|&s| s == Wake));
+    let twitching = minutes(&[1; 10]);
+    assert!(score_stages(&twitching).iter().all(|&s| s == Light));
+
+    // A single movement only affects the minutes around it
+    let mut single = minutes(&[0; 20]);
+    single[10].count = 5;
+    let stages = score_stages(&single);
+    assert_eq!(stages[10], Wake);
+    assert!(stages[..8].iter().chain(&stages[15..]).all(|&s| s == Deep));
+
+    // An empty bed is wake, even though nothing moves
+    let empty = vec![MinuteActivity::default(); 5];
+    assert!(score_stages(&empty).iter().all(|&s| s == Wake));
+
+    // A night which goes from deep to light sleep
+    let mut night = minutes(&[0; 40]);
+    night.extend(minutes(&[1; 10]));
+    let stages = score_stages(&night);
+    assert!(is_light_sleep_transition(&stages));
+    assert!(!is_light_sleep_transition(&stages[..40]));
+    // Just turning over is not enough
+    let mut turning = minutes(&[0; 40]);
+    turning.extend(minutes(&[20; 2]));
+    assert!(!is_light_sleep_transition(&score_stages(&turning)));
+    // Light sleep without any deep sleep before it
+    assert!(!is_light_sleep_transition(&score_stages(&twitching)));
+}
+
+#[test]
+fn test_rolling_window() {
+    let max_memory = Duration::from_secs(60);
+    // Without debouncing, so that the presence follows the window directly
+    let thresholds = SleepMonitorConfig {
+        present_after_seconds: 0.0,
+        absent_after_seconds: 0.0,
+        ..SleepMonitorConfig::default()
+    };
+    let mut monitor = SleepMonitor::new(
+        max_memory,
+        TEST_READING_PERIOD,
+        test_config("rolling-window", thresholds.clone()),
+    );
+    let noise_readings = monitor.readings_in(thresholds.noise_threshold_seconds);
+    let absent_readings = monitor.readings_in(thresholds.absent_threshold_seconds);
+    let movement_readings = monitor.readings_in(thresholds.movement_threshold_seconds);
+    assert_eq!(
+        (noise_readings, absent_readings, movement_readings),
+        (1, 0, 2)
+    );
+    let start = Utc::now();
+    let at = |i: u32| start + TEST_READING_PERIOD * i;
+    let sample = |x| AccelerometerData {
+        acc: (x, 0.0, -1.0),
+        ..AccelerometerData::default()
+    };
+    let window = (max_memory.as_millis() / TEST_READING_PERIOD.as_millis()) as u32;
+
+    // Ten minutes of samples. Breathing now and then, and a few larger movements at the start.
+    let mut x = 0.0;
+    let mut deltas = vec![];
+    let mut present = false;
+    for i in 0..10 * window {
+        let delta = if i < 5 {
+            0.05
+        } else if i % 100 == 0 {
+            0.016
+        } else {
+            0.0
+        };
+        x += delta;
+        deltas.push(delta);
+        monitor.push(sample(x), at(i));
+
+        // The deltas in the window, except for the one to the first sample, which has nothing before it
+        let first = (i + 1).saturating_sub(window + 1).max(1) as usize;
+        let count = |threshold| deltas[first..].iter().filter(|&&d| d > threshold).count();
+        // Counts between the thresholds keep the presence
+        let active = count(thresholds.noise_threshold);
+        if active > noise_readings {
+            present = true;
+        } else if active <= absent_readings {
+            present = false;
+        }
+        assert_eq!(monitor.is_present(), present, "{i}");
+        assert_eq!(
+            monitor.is_significant_movement(),
+            count(thresholds.movement_threshold) > movement_readings,
+            "{i}"
+        );
+        assert!(monitor.samples.len() <= window as usize + 1);
+    }
+    assert!(!monitor.is_significant_movement());
+    assert!(monitor.is_present());
+
+    // Samples are dropped by age, also when they arrive less often. The drift filter catching up over the gap is the
+    // only change left, which is between the thresholds, so the presence is kept.
+    monitor.push(sample(x), at(10 * window - 1) + max_memory);
+    assert_eq!(monitor.samples.len(), 2);
+    assert_eq!(monitor.count_deltas_above(thresholds.noise_threshold), 1);
+    assert!(monitor.is_present());
+
+    // The clock is set back. The reading from after the new time is dropped, the rest are kept.
+    monitor.push(sample(x), at(10 * window - 1));
+    assert_eq!(monitor.samples.len(), 2);
+    assert_eq!(monitor.live_stats().window_end, Some(at(10 * window - 1)));
+
+    // The same durations span more readings at a higher rate
+    let fast_period = Duration::from_millis(20);
+    let mut fast = SleepMonitor::new(
+        max_memory,
+        fast_period,
+        test_config("rolling-window-fast", thresholds.clone()),
+    );
+    assert_eq!(fast.readings_in(thresholds.movement_threshold_seconds), 10);
+    // And the readings which are older than the window are dropped
+    let fast_window = (max_memory.as_millis() / fast_period.as_millis()) as u32;
+    for i in 0..2 * fast_window {
+        fast.push(sample(0.0), start + fast_period * i);
+    }
+    let end = start + fast_period * (2 * fast_window - 1);
+    assert!(fast
+        .samples
+        .iter()
+        .all(|s| elapsed(s.time, end) <= max_memory));
+    assert_eq!(fast.samples.len(), fast_window as usize + 1);
+}
+
+#[test]
+fn test_presence_hysteresis() {
+    // Without debouncing, so that only the thresholds keep the state
+    let thresholds = SleepMonitorConfig {
+        present_after_seconds: 0.0,
+        absent_after_seconds: 0.0,
+        ..SleepMonitorConfig::default()
+    };
+    let mut monitor = SleepMonitor::new(
+        Duration::from_secs(60),
+        TEST_READING_PERIOD,
+        test_config("presence-hysteresis", thresholds.clone()),
+    );
+    assert!(
+        monitor.readings_in(thresholds.absent_threshold_seconds)
+            < monitor.readings_in(thresholds.noise_threshold_seconds)
+    );
+    let start = Utc::now();
+    let window = 600;
+    let mut i = 0;
+    let mut x = 0.0;
+    // Pushes `readings` readings, of which the first moves by `step`
+    let mut push = |readings: u32, step: f32| {
+        for j in 0..readings {
+            if j == 0 {
+                x += step;
+            }
+            monitor.push(
+                AccelerometerData {
+                    acc: (x, 0.0, -1.0),
+                    ..AccelerometerData::default()
+                },
+                start + TEST_READING_PERIOD * i,
+            );
+            i += 1;
+        }
+        monitor.is_present()
+    };
+
+    assert!(!push(10, 0.0));
+    // Two changes above the noise threshold are presence
+    assert!(!push(10, 0.05));
+    assert!(push(window - 20, 0.05));
+    // The first change has left the window, so only one is left. That is between the thresholds, and keeps the presence.
+    assert!(push(15, 0.0));
+    // None left
+    assert!(!push(window, 0.0));
+    // One change is again between the thresholds, and keeps the absence
+    assert!(!push(10, 0.05));
+}
+
+#[test]
+fn test_presence_debouncer() {
+    let config = SleepMonitorConfig {
+        present_after_seconds: 5.0,
+        absent_after_seconds: 180.0,
+        ..SleepMonitorConfig::default()
+    };
+    let mut debouncer = PresenceDebouncer::default();
+    let start = Utc::now();
+    // Runs a script of (seconds, raw presence) evaluations, and returns the final presence
+    let mut run = |script: &[(u64, Option<bool>)]| {
+        script
+            .iter()
+            .map(|&(t, raw)| debouncer.update(raw, start + Duration::from_secs(t), &config))
+            .last()
+            .unwrap()
+    };
+
+    // Sitting on the bed for a moment
+    assert!(!run(&[(0, Some(true)), (3, Some(true)), (4, Some(false))]));
+    // Movement between the thresholds restarts the wait
+    assert!(!run(&[
+        (10, Some(true)),
+        (13, None),
+        (14, Some(true)),
+        (18, Some(true))
+    ]));
+    // Going to bed
+    assert!(run(&[(20, Some(true)), (26, Some(true))]));
+
+    // Lying still enough to be between the thresholds
+    assert!(run(&[(100, None), (1000, None)]));
+    // A bathroom break
+    assert!(run(&[
+        (2000, Some(false)),
+        (2120, Some(false)),
+        (2121, Some(true))
+    ]));
+    // Getting up in the morning
+    assert!(run(&[(3000, Some(false)), (3100, Some(false))]));
+    assert!(!run(&[(3180, Some(false))]));
+}
+
+#[test]
+fn test_room_temperature() {
+    let mut temperature = RoomTemperature::new(-3.0);
+    let start = Instant::now();
+    let at = |secs| start + Duration::from_secs(secs);
 
-        let mut cnt = 0;
-        for &v in &self.rolling_delta_magn {
-            if v > NOISE_THRESHOLD {
-                cnt += 1;
+    assert_eq!(temperature.update(24.0, at(0)), Some(21.0));
+    // A single outlier barely moves the average, and it is not published until a minute has passed
+    assert_eq!(temperature.update(40.0, at(1)), None);
+    for t in 2..60 {
+        assert_eq!(temperature.update(24.0, at(t)), None);
+    }
+    assert_eq!(temperature.update(24.0, at(60)), Some(21.0));
+
+    // A change in temperature is followed slowly
+    let mut published = vec![];
+    for t in 61..3600 {
+        published.extend(temperature.update(26.0, at(t)));
+    }
+    assert!(published.windows(2).all(|w| w[0] <= w[1]));
+    assert!(published[0] < 21.5);
+    assert!((published.last().unwrap() - 23.0).abs() < 0.05);
+}
+
+#[test]
+fn test_replay_presence() {
+    use chrono::TimeZone;
+    // Ten minutes of an empty bed, followed by someone going to bed, and then getting up again
+    let start = Utc.with_ymd_and_hms(2024, 3, 1, 22, 0, 0).unwrap();
+    let mut log = String::new();
+    let mut x = 0.0;
+    for i in 0..3 * 6000 {
+        let in_bed = (6000..12000).contains(&i);
+        // Breathing every few seconds
+        if in_bed && i % 40 == 0 {
+            x = if x == 0.0 { 0.03 } else { 0.0 };
+        }
+        let reading = LogSample {
+            time: start + chrono::TimeDelta::milliseconds(100 * i),
+            sensor: motion_log::MY_SENSOR,
+            samples: 10,
+            alarm_playing: false,
+            present: None,
+            movement: None,
+            data: AccelerometerData {
+                acc: (x, 0.0, -1.0),
+                ..AccelerometerData::default()
+            },
+            respiration: None,
+        };
+        log += &reading.to_csv();
+    }
+
+    let mut source = ReplaySource::new(std::io::Cursor::new(log), None).unwrap();
+    assert!(matches!(
+        source.calibrate(),
+        Err(CalibrationError::Unsupported)
+    ));
+    let mut monitor = SleepMonitor::new(
+        Duration::from_secs(5 * 60),
+        TEST_READING_PERIOD,
+        test_config("replay", SleepMonitorConfig::default()),
+    );
+    let now = Utc::now();
+    let mut presence = vec![];
+    let mut i = 0;
+    loop {
+        match source.sample() {
+            Ok(data) => {
+                monitor.push(data, now + TEST_READING_PERIOD * i);
             }
+            Err(SensorError::Ended) => break,
+            Err(e) => panic!("{e}"),
+        }
+        presence.push(monitor.is_present());
+        i += 1;
+    }
+    assert_eq!(presence.len(), 3 * 6000);
+    // Noticed within a minute
+    assert!(!presence[..6000].iter().any(|&p| p));
+    assert!(presence[6600..12000].iter().all(|&p| p));
+    // Not until the breathing has left the window, and the bed has been empty for a while
+    assert!(presence[12000..13000].iter().all(|&p| p));
+    assert!(!presence.last().unwrap());
+}
+
+#[test]
+fn test_movement_intensity() {
+    let mut monitor = SleepMonitor::new(
+        Duration::from_secs(5 * 60),
+        TEST_READING_PERIOD,
+        test_config("movement-intensity", SleepMonitorConfig::default()),
+    );
+    assert_eq!(monitor.movement_intensity(), None);
+    let start = Utc::now();
+    let sample = |x| AccelerometerData {
+        acc: (x, 0.0, -1.0),
+        ..AccelerometerData::default()
+    };
+    // A large movement which is more than a minute before the rest
+    monitor.push(sample(0.0), start);
+    monitor.push(sample(1.0), start + TEST_READING_PERIOD);
+    // Changes of 0.001, 0.002, ..., 0.099 g
+    let later = start + Duration::from_secs(120);
+    let mut x = 1.0;
+    for i in 0..100 {
+        x += i as f32 * 0.001;
+        monitor.push(sample(x), later + TEST_READING_PERIOD * i);
+    }
+    // Less than the largest changes, since part of the steady increase is removed as drift
+    assert_eq!(
+        monitor.movement_intensity(),
+        Some(MovementIntensity(0.0812))
+    );
+    monitor.set_stale(true);
+    assert_eq!(monitor.movement_intensity(), None);
+}
+
+#[test]
+fn test_tap_detector() {
+    let config = TapConfig {
+        enabled: true,
+        ..TapConfig::default()
+    };
+    let start = Instant::now();
+    // Samples 10 ms apart. A tap makes the acceleration jump for a few samples.
+    let trace = |taps: &[usize]| {
+        let mut detector = TapDetector::default();
+        (0..300)
+            .filter(|&i| {
+                let x = if taps.iter().any(|&t| (t..t + 3).contains(&i)) {
+                    0.5
+                } else {
+                    0.0
+                };
+                let data = AccelerometerData {
+                    acc: (x, 0.0, -1.0),
+                    ..AccelerometerData::default()
+                };
+                detector.update(&data, start + Duration::from_millis(10 * i as u64), &config)
+            })
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(trace(&[]), Vec::<usize>::new());
+    assert_eq!(trace(&[50]), Vec::<usize>::new());
+    assert_eq!(trace(&[50, 100]), [100]);
+    // Too close together to be separate taps, and too far apart
+    assert_eq!(trace(&[50, 60]), Vec::<usize>::new());
+    assert_eq!(trace(&[50, 200]), Vec::<usize>::new());
+    // After a pause, the next tap starts a new double tap
+    assert_eq!(trace(&[50, 200, 250]), [250]);
+}
+
+#[test]
+fn test_orientation() {
+    let mut monitor = SleepMonitor::new(
+        Duration::from_secs(5 * 60),
+        TEST_READING_PERIOD,
+        test_config("orientation", SleepMonitorConfig::default()),
+    );
+    let start = Utc::now();
+    let tilted = |degrees: f32, i: u32| AccelerometerData {
+        // Some noise, which averages out
+        acc: (
+            degrees.to_radians().sin() + if i.is_multiple_of(2) { 0.01 } else { -0.01 },
+            0.0,
+            -degrees.to_radians().cos(),
+        ),
+        ..AccelerometerData::default()
+    };
+    monitor.push(tilted(0.0, 0), start);
+    assert_eq!(monitor.orientation(), None);
+    monitor.set_reference_gravity(Some((0.0, 0.0, -1.0)));
+
+    let mut i = 0;
+    let mut push_for = |monitor: &mut SleepMonitor, seconds: u32, degrees: f32| {
+        for _ in 0..seconds * 10 {
+            i += 1;
+            monitor.push(tilted(degrees, i), start + TEST_READING_PERIOD * i);
         }
+    };
+    push_for(&mut monitor, 60, 0.0);
+    assert!(monitor.tilt_degrees().unwrap() < 0.1);
+    assert_eq!(monitor.orientation(), Some(BedOrientation::Level));
+    // Sitting down on the edge. It takes a while before the average follows.
+    push_for(&mut monitor, 5, 6.0);
+    assert_eq!(monitor.orientation(), Some(BedOrientation::Level));
+    push_for(&mut monitor, 30, 6.0);
+    assert!((monitor.tilt_degrees().unwrap() - 6.0).abs() < 0.1);
+    assert_eq!(monitor.orientation(), Some(BedOrientation::Tilted));
+}
+
+#[test]
+fn test_freeze_detector() {
+    let start = Instant::now();
+    let limit = Duration::from_secs(30);
+    let at = |seconds| start + Duration::from_secs(seconds);
+    let data = |z| AccelerometerData {
+        acc: (0.0, 0.0, z),
+        ..AccelerometerData::default()
+    };
+    let mut detector = FreezeDetector::default();
+    assert!(!detector.update(&data(-1.0), at(0), limit));
+    assert!(!detector.update(&data(-1.0), at(30), limit));
+    assert!(detector.update(&data(-1.0), at(31), limit));
+    assert!(detector.update(&data(-1.0), at(100), limit));
+    // Recovers as soon as the values change, even by the smallest possible amount
+    let changed = f32::from_bits((-1.0f32).to_bits() + 1);
+    assert!(!detector.update(&data(changed), at(101), limit));
+    assert!(!detector.update(&data(changed), at(131), limit));
+}
+
+#[test]
+fn test_movement_filter() {
+    let config = SleepMonitorConfig::default();
+    let time_constant = Duration::from_secs_f32(config.drift_time_constant_seconds);
+    let reading = Duration::from_millis(100);
+    // Drifts 0.5 g over ten minutes, like the bias does when the sensor warms up
+    let drift = |i: u32| 0.5 * i as f32 / 6000.0;
+    let data = |x: f32| AccelerometerData {
+        acc: (x, 0.0, -1.0),
+        ..AccelerometerData::default()
+    };
+
+    let mut filter = MovementFilter::default();
+    assert_eq!(
+        filter.update(data(drift(0)).acc, reading, time_constant),
+        None
+    );
+    let mut last = 0.0;
+    for i in 1..3000 {
+        last = filter
+            .update(data(drift(i)).acc, reading, time_constant)
+            .unwrap();
+    }
+    // Without the filter, the drift adds to every change. With it, the change settles at zero.
+    let raw = delta_magnitude(&data(drift(2998)), &data(drift(2999)));
+    assert!(raw > 8e-5);
+    assert!(last < raw / 100.0, "{last} vs {raw}");
+
+    // A sharp transient still registers at nearly its full size
+    let transient = filter
+        .update(data(drift(3000) + 0.05).acc, reading, time_constant)
+        .unwrap();
+    assert!(transient > config.movement_threshold);
+    assert!((transient - 0.05).abs() < 1e-3, "{transient}");
+}
+
+#[test]
+fn test_presence_confidence() {
+    let config = SleepMonitorConfig::default();
+    let confidence = |active_readings: u32, since_last_seconds: Option<u64>| {
+        estimate_presence_confidence(
+            TEST_READING_PERIOD * active_readings,
+            since_last_seconds.map(Duration::from_secs),
+            TEST_READING_PERIOD,
+            &config,
+        )
+    };
+    // An empty bed, and someone breathing in it
+    assert_eq!(confidence(0, None), 0.0);
+    assert!(confidence(100, Some(0)) > 0.99);
+    // A single change above the noise is right in the middle, and a few more make it likely
+    assert!((confidence(1, Some(0)) - 0.5).abs() < 1e-6);
+    let few = confidence(3, Some(0));
+    assert!(0.8 < few && few < 0.95, "{few}");
+    // Fades after the last change, reaching 1/e after `absent_after_seconds`
+    let left = confidence(100, Some(180));
+    assert!((left - (-1f32).exp()).abs() < 0.01, "{left}");
+    assert!(confidence(100, Some(60)) > left);
+}
+
+#[test]
+fn test_window_bounds() {
+    let config = test_config("window-bounds", SleepMonitorConfig::default());
+    let mut monitor =
+        SleepMonitor::new(Duration::from_secs(60), TEST_READING_PERIOD, config.clone());
+    let start = Utc::now();
+    let at = |ms| start + Duration::from_millis(ms);
+    let reading = AccelerometerData::default();
 
-        cnt > NOISE_THRESHOLD_SAMPLES
+    // At the expected rate, the window is limited by its duration
+    for i in 0..1000 {
+        monitor.push(reading.clone(), at(100 * i));
     }
+    assert_eq!(monitor.samples.len(), 601);
+
+    // The rate is reconfigured to ten times as fast. The window keeps twice the expected readings, covering less time.
+    for i in 0..2000 {
+        monitor.push(reading.clone(), at(100_000 + 10 * i));
+    }
+    assert_eq!(monitor.samples.len(), 1200);
+    assert_eq!(
+        monitor.samples.front().unwrap().time,
+        at(100_000 + 10 * 800)
+    );
+
+    // A lower limit applies on the next push, in one go
+    let mut c = config.get();
+    c.motion.max_window_readings = Some(100);
+    config.set(c).unwrap();
+    monitor.push(reading.clone(), at(120_000));
+    assert_eq!(monitor.samples.len(), 100);
+
+    // A burst of readings at the same time
+    for _ in 0..500 {
+        monitor.push(reading.clone(), at(120_001));
+    }
+    assert_eq!(monitor.samples.len(), 100);
+    assert!(monitor.samples.iter().all(|s| s.time == at(120_001)));
+
+    // After a gap longer than the window, everything older is evicted at once
+    monitor.push(reading.clone(), at(200_000));
+    assert_eq!(monitor.samples.len(), 1);
 }
@@ -0,0 +1,802 @@
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use log::{info, warn};
+use rand::seq::SliceRandom;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::analysis;
+use crate::config::SoundsConfig;
+use crate::AlarmState;
+
+pub const VALID_EXTENSIONS: [&str; 4] = ["mp3", "ogg", "flac", "wav"];
+
+/// Name of the optional file in a sound directory which maps file names to selection weights.
+pub const WEIGHTS_FILE: &str = "weights.toml";
+
+/// Name of the optional file in a sound directory which maps file names to the part of the sound that should be played.
+pub const TRIMS_FILE: &str = "trims.toml";
+
+fn has_valid_extension(path: &Path) -> bool {
+    path.extension()
+        .and_then(OsStr::to_str)
+        .map(|x| VALID_EXTENSIONS.contains(&x))
+        .unwrap_or_default()
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .and_then(OsStr::to_str)
+        .map(|x| x.starts_with('.'))
+        .unwrap_or_default()
+}
+
+/// True if `path` is a non-empty relative path which cannot escape the directory it is joined to.
+pub fn is_relative_subpath(path: &str) -> bool {
+    let path = Path::new(path);
+    path.components().next().is_some()
+        && path
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)))
+}
+
+/// What the sounds in a directory are played for. Each kind has its own directory in [`SoundsConfig`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SoundKind {
+    Alarm,
+    LucidMusic,
+    LucidSfx,
+    LucidVoice,
+    RealityCheck,
+}
+
+impl SoundKind {
+    pub const ALL: [SoundKind; 5] = [
+        SoundKind::Alarm,
+        SoundKind::LucidMusic,
+        SoundKind::LucidSfx,
+        SoundKind::LucidVoice,
+        SoundKind::RealityCheck,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SoundKind::Alarm => "alarm",
+            SoundKind::LucidMusic => "lucid_music",
+            SoundKind::LucidSfx => "lucid_sfx",
+            SoundKind::LucidVoice => "lucid_voice",
+            SoundKind::RealityCheck => "reality_check",
+        }
+    }
+
+    pub fn dir(self, config: &SoundsConfig) -> &Path {
+        match self {
+            SoundKind::Alarm => &config.directory,
+            SoundKind::LucidMusic => &config.lucid_music_dir,
+            SoundKind::LucidSfx => &config.lucid_sfx_dir,
+            SoundKind::LucidVoice => &config.lucid_voice_dir,
+            SoundKind::RealityCheck => &config.reality_check_dir,
+        }
+    }
+}
+
+impl FromStr for SoundKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        SoundKind::ALL
+            .into_iter()
+            .find(|k| k.name() == s)
+            .ok_or_else(|| {
+                format!(
+                    "Unknown sound kind `{s}`. The kinds are: {}",
+                    SoundKind::ALL.map(SoundKind::name).join(", ")
+                )
+            })
+    }
+}
+
+/// Warns about sound directories which are missing or contain no sounds. They are only read when a sound is needed,
+/// so this is checked at startup and when the config changes.
+///
+/// The reality check directory is left to [`crate::reality_check`], which only needs it while the checks are enabled.
+pub fn check_directories(config: &SoundsConfig) {
+    for kind in SoundKind::ALL {
+        if kind != SoundKind::RealityCheck {
+            check_directory(kind, config);
+        }
+    }
+}
+
+/// Warns if the directory of `kind` is missing or contains no sounds.
+pub fn check_directory(kind: SoundKind, config: &SoundsConfig) {
+    let dir = kind.dir(config);
+    match list_sounds(dir, config) {
+        Ok(sounds) if sounds.is_empty() => {
+            warn!(
+                "The {} sound directory `{}` contains no sounds",
+                kind.name(),
+                dir.display()
+            )
+        }
+        Ok(_) => {}
+        Err(e) => warn!(
+            "Could not read the {} sound directory `{}`: {}",
+            kind.name(),
+            dir.display(),
+            e
+        ),
+    }
+}
+
+/// All sound files inside `root_dir`, sorted by path.
+///
+/// Subdirectories are scanned up to `config.max_scan_depth` levels deep.
+/// Hidden files and directories, `config.excluded_dirs` and the directories of the other kinds of sounds are skipped.
+pub fn list_sounds(root_dir: &Path, config: &SoundsConfig) -> std::io::Result<Vec<PathBuf>> {
+    // Canonical, so that e.g. `./sounds/lucid` and `sounds/lucid` are the same directory.
+    // Directories which do not exist cannot be found while scanning either.
+    let excluded = config
+        .excluded_dirs
+        .iter()
+        .map(|dir| root_dir.join(dir))
+        .chain(
+            SoundKind::ALL[1..]
+                .iter()
+                .map(|kind| kind.dir(config).to_path_buf()),
+        )
+        .filter_map(|dir| dir.canonicalize().ok())
+        .collect::<Vec<_>>();
+
+    let mut sounds = vec![];
+    collect_sounds(root_dir, 0, config.max_scan_depth, &excluded, &mut sounds)?;
+    sounds.sort();
+    Ok(sounds)
+}
+
+fn collect_sounds(
+    dir: &Path,
+    depth: usize,
+    max_depth: usize,
+    excluded: &[PathBuf],
+    sounds: &mut Vec<PathBuf>,
+) -> std::io::Result<()> {
+    for path in dir.read_dir()?.filter_map(|x| x.ok().map(|x| x.path())) {
+        if is_hidden(&path) {
+            continue;
+        }
+
+        if path.is_dir() {
+            let is_excluded = path
+                .canonicalize()
+                .is_ok_and(|path| excluded.contains(&path));
+            if depth < max_depth && !is_excluded {
+                // An unreadable subdirectory should not hide the rest of the library
+                if let Err(e) = collect_sounds(&path, depth + 1, max_depth, excluded, sounds) {
+                    warn!("Could not read directory `{}`: {}", path.display(), e);
+                }
+            }
+        } else if has_valid_extension(&path) {
+            sounds.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// The directory to pick sounds from when the alarm asks for a particular category.
+///
+/// A category is a subdirectory of `root_dir`. Unknown categories fall back to the whole `root_dir`.
+pub fn category_dir(root_dir: &Path, category: Option<&str>) -> PathBuf {
+    match category {
+        Some(category) if is_relative_subpath(category) && root_dir.join(category).is_dir() => {
+            root_dir.join(category)
+        }
+        Some(category) => {
+            warn!(
+                "Unknown sound category `{}`. Picking from all sounds instead.",
+                category
+            );
+            root_dir.to_path_buf()
+        }
+        None => root_dir.to_path_buf(),
+    }
+}
+
+/// Name used to identify a sound in the weights file and in the API.
+pub fn sound_name(root_dir: &Path, path: &Path) -> String {
+    path.strip_prefix(root_dir)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[derive(Error, Debug)]
+pub enum AlarmSoundError {
+    #[error("Could not read directory `{0}`: {1}")]
+    CouldNotReadDir(PathBuf, std::io::Error),
+    #[error("There were no sound files in the sound directory")]
+    NoFiles,
+}
+
+/// Picks a random sound file from `root_dir`, or from its `category` subdirectory if one is given.
+///
+/// Files are picked with probability proportional to their weight in the `weights.toml` of `root_dir`.
+/// Sounds in `recently_played` (named relative to `root_dir`) are avoided, unless nothing else is available.
+/// Sounds in `rejected` are never picked.
+pub fn random_alarm_sound(
+    root_dir: &Path,
+    config: &SoundsConfig,
+    category: Option<&str>,
+    recently_played: &[String],
+    rejected: &[String],
+) -> Result<PathBuf, AlarmSoundError> {
+    let dir = category_dir(root_dir, category);
+    let mut sounds =
+        list_sounds(&dir, config).map_err(|e| AlarmSoundError::CouldNotReadDir(dir.clone(), e))?;
+    sounds.retain(|path| !rejected.contains(&sound_name(root_dir, path)));
+    let weights = SoundWeights::load(root_dir);
+    if category.is_none() {
+        weights.warn_unknown(root_dir, &sounds);
+    }
+
+    let fresh_sounds = sounds
+        .iter()
+        .filter(|path| !recently_played.contains(&sound_name(root_dir, path)))
+        .cloned()
+        .collect::<Vec<_>>();
+    let candidates = if fresh_sounds.is_empty() {
+        if !sounds.is_empty() {
+            info!("All sounds have been played recently. Allowing repeats.");
+        }
+        sounds
+    } else {
+        fresh_sounds
+    };
+
+    candidates
+        .choose_weighted(&mut rand::thread_rng(), |path| {
+            weights.get(&sound_name(root_dir, path))
+        })
+        .cloned()
+        .map_err(|_| AlarmSoundError::NoFiles)
+}
+
+/// Relative selection weights for the sounds in a directory.
+///
+/// Sounds which are not mentioned in the weights file have a weight of 1.0.
+#[derive(Debug, Default, Clone)]
+pub struct SoundWeights {
+    weights: BTreeMap<String, f64>,
+    /// The weights file exists, but could not be read or parsed. It is then never saved over, so that the weights
+    /// of the user are not lost.
+    unreadable: bool,
+}
+
+impl SoundWeights {
+    pub const DEFAULT_WEIGHT: f64 = 1.0;
+
+    /// Loads `weights.toml` from `root_dir`.
+    ///
+    /// A missing or unparseable file, as well as invalid entries, only cause warnings,
+    /// since a broken weights file should never prevent the alarm from playing.
+    pub fn load(root_dir: &Path) -> SoundWeights {
+        let path = root_dir.join(WEIGHTS_FILE);
+        let unreadable = SoundWeights {
+            unreadable: true,
+            ..Default::default()
+        };
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return SoundWeights::default(),
+            Err(e) => {
+                warn!("Could not read {}: {}", path.display(), e);
+                return unreadable;
+            }
+        };
+
+        let weights = match toml::from_str::<BTreeMap<String, f64>>(&contents) {
+            Ok(weights) => weights,
+            Err(e) => {
+                warn!("Could not parse {}: {}", path.display(), e);
+                return unreadable;
+            }
+        };
+
+        SoundWeights {
+            weights: weights
+                .into_iter()
+                .filter(|(name, weight)| {
+                    let valid = is_valid_weight(*weight);
+                    if !valid {
+                        warn!(
+                            "Ignoring invalid weight {} for `{}` in {}. Weights must be positive.",
+                            weight,
+                            name,
+                            path.display()
+                        );
+                    }
+                    valid
+                })
+                .collect(),
+            unreadable: false,
+        }
+    }
+
+    /// Fails without writing anything if the file could not be loaded, see [`SoundWeights::load`].
+    pub fn save(&self, root_dir: &Path) -> std::io::Result<()> {
+        if self.unreadable {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "{} could not be read, so it is not overwritten. Fix or remove it first.",
+                    root_dir.join(WEIGHTS_FILE).display()
+                ),
+            ));
+        }
+        let contents = toml::to_string(&self.weights)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        std::fs::write(root_dir.join(WEIGHTS_FILE), contents)
+    }
+
+    pub fn get(&self, name: &str) -> f64 {
+        self.weights
+            .get(name)
+            .copied()
+            .unwrap_or(Self::DEFAULT_WEIGHT)
+    }
+
+    pub fn set(&mut self, name: &str, weight: f64) {
+        self.weights.insert(name.to_owned(), weight);
+    }
+
+    /// Warns about entries in the weights file which do not correspond to any sound.
+    pub fn warn_unknown(&self, root_dir: &Path, sounds: &[PathBuf]) {
+        for name in self.weights.keys() {
+            if !sounds
+                .iter()
+                .any(|path| sound_name(root_dir, path) == *name)
+            {
+                warn!(
+                    "{} contains a weight for `{}`, but there is no such sound file",
+                    root_dir.join(WEIGHTS_FILE).display(),
+                    name
+                );
+            }
+        }
+    }
+}
+
+/// The part of a sound that should be played, in seconds from the start of the file.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Trim {
+    #[serde(default)]
+    pub start_seconds: f32,
+    /// Plays until the end of the file if not set.
+    #[serde(default)]
+    pub end_seconds: Option<f32>,
+}
+
+impl Trim {
+    /// Checks everything that can be checked without knowing the duration of the sound.
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.start_seconds.is_finite() || self.start_seconds < 0.0 {
+            return Err("start_seconds must be a non-negative number".to_owned());
+        }
+        match self.end_seconds {
+            Some(end) if !end.is_finite() || end <= self.start_seconds => {
+                Err("end_seconds must be after start_seconds".to_owned())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The range of frames to play, out of a sound with `total_frames` frames.
+    pub fn frames(&self, total_frames: usize, sample_rate: u32) -> Result<Range<usize>, String> {
+        self.validate()?;
+        let to_frames = |seconds: f32| (seconds as f64 * sample_rate as f64).round() as usize;
+        let start = to_frames(self.start_seconds);
+        let end = self.end_seconds.map_or(total_frames, to_frames);
+        if end > total_frames || start >= end {
+            return Err(format!(
+                "the sound is only {:.1} seconds long",
+                total_frames as f32 / sample_rate as f32
+            ));
+        }
+        Ok(start..end)
+    }
+
+    /// Seconds which are played out of a sound which is `duration_seconds` long, or `None` if the trim does not fit.
+    pub fn played_seconds(&self, duration_seconds: f32) -> Option<f32> {
+        let end = self.end_seconds.unwrap_or(duration_seconds);
+        (self.validate().is_ok() && end <= duration_seconds && self.start_seconds < end)
+            .then_some(end - self.start_seconds)
+    }
+}
+
+/// Trim points for the sounds in a directory. Sounds which are not mentioned in the trims file are played in full.
+#[derive(Debug, Default, Clone)]
+pub struct SoundTrims {
+    trims: BTreeMap<String, Trim>,
+}
+
+impl SoundTrims {
+    /// Loads `trims.toml` from `root_dir`.
+    ///
+    /// Like for the weights, problems with the file only cause warnings, and invalid entries are ignored.
+    pub fn load(root_dir: &Path) -> SoundTrims {
+        let path = root_dir.join(TRIMS_FILE);
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return SoundTrims::default(),
+            Err(e) => {
+                warn!("Could not read {}: {}", path.display(), e);
+                return SoundTrims::default();
+            }
+        };
+
+        let entries = match toml::from_str::<BTreeMap<String, toml::Value>>(&contents) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Could not parse {}: {}", path.display(), e);
+                return SoundTrims::default();
+            }
+        };
+
+        SoundTrims {
+            trims: entries
+                .into_iter()
+                .filter_map(|(name, value)| {
+                    match value
+                        .try_into::<Trim>()
+                        .map_err(|e| e.to_string())
+                        .and_then(|trim| trim.validate().map(|_| trim))
+                    {
+                        Ok(trim) => Some((name, trim)),
+                        Err(e) => {
+                            warn!(
+                                "Ignoring invalid trim for `{}` in {}: {}",
+                                name,
+                                path.display(),
+                                e
+                            );
+                            None
+                        }
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    pub fn save(&self, root_dir: &Path) -> std::io::Result<()> {
+        let contents = toml::to_string(&self.trims)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        std::fs::write(root_dir.join(TRIMS_FILE), contents)
+    }
+
+    pub fn get(&self, name: &str) -> Option<Trim> {
+        self.trims.get(name).copied()
+    }
+
+    /// Sets the trim of a sound, or removes it if `trim` is `None`.
+    pub fn set(&mut self, name: &str, trim: Option<Trim>) {
+        match trim {
+            Some(trim) => self.trims.insert(name.to_owned(), trim),
+            None => self.trims.remove(name),
+        };
+    }
+}
+
+fn is_valid_weight(weight: f64) -> bool {
+    weight.is_finite() && weight > 0.0
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SoundInfo {
+    /// Relative to the directory of the kind.
+    name: String,
+    kind: SoundKind,
+    weight: f64,
+    /// Seconds of silence skipped at the start of the sound, if it has been played before.
+    leading_silence_seconds: Option<f32>,
+    /// Why the sound cannot be played as an alarm, if it is known to be broken.
+    problem: Option<String>,
+    /// The part of the sound which is played, if not all of it.
+    trim: Option<Trim>,
+}
+
+/// Why the sound at `path` cannot be played as an alarm, if it is known to be broken.
+///
+/// Sounds which have not been played yet are only probed, since decoding every sound whenever they are listed would
+/// be far too slow. Their length is only known if the header of the file contains it. The probe is cached in
+/// `analysis`, see [`analysis::probed_duration`].
+pub fn sound_problem(
+    path: &Path,
+    analysis: &mut analysis::SoundAnalysis,
+    trim: Option<Trim>,
+    min_duration_seconds: f32,
+) -> Option<String> {
+    if std::fs::metadata(path).is_ok_and(|m| m.len() == 0) {
+        return Some("The file is empty".to_owned());
+    }
+    if analysis.undecodable {
+        return Some("The file could not be decoded".to_owned());
+    }
+    let duration = match analysis.duration_seconds {
+        Some(duration) => duration,
+        None => match analysis::probed_duration(path, analysis) {
+            Ok(duration) => duration?,
+            Err(e) => return Some(e),
+        },
+    };
+    let played = trim
+        .and_then(|trim| trim.played_seconds(duration))
+        .unwrap_or(duration)
+        - analysis.leading_silence_seconds;
+    if played >= min_duration_seconds {
+        None
+    } else if played < duration {
+        Some(format!("Only {played:.1} seconds of the sound are played"))
+    } else {
+        Some(format!("The sound is only {duration:.1} seconds long"))
+    }
+}
+
+/// The sounds of one kind, or of all kinds if `kind` is not given. A directory which does not exist has no sounds.
+#[get("/sounds?<kind>")]
+pub fn get_sounds(
+    state: &State<AlarmState>,
+    kind: Option<&str>,
+) -> Result<Json<Vec<SoundInfo>>, (Status, String)> {
+    let kinds = match kind {
+        Some(kind) => vec![kind
+            .parse::<SoundKind>()
+            .map_err(|e| (Status::BadRequest, e))?],
+        None => SoundKind::ALL.to_vec(),
+    };
+    let config = state.config.get();
+    let mut infos = vec![];
+    for kind in kinds {
+        let root_dir = kind.dir(&config.sounds);
+        if !root_dir.exists() {
+            continue;
+        }
+        let sounds = list_sounds(root_dir, &config.sounds).map_err(|e| {
+            error!("Could not read directory `{}`: {}", root_dir.display(), e);
+            (Status::InternalServerError, e.to_string())
+        })?;
+        let weights = SoundWeights::load(root_dir);
+        weights.warn_unknown(root_dir, &sounds);
+        let trims = SoundTrims::load(root_dir);
+        let analysis = analysis::load(root_dir);
+        let mut probed = vec![];
+
+        infos.extend(sounds.iter().map(|path| {
+            let name = sound_name(root_dir, path);
+            let mut sound_analysis = analysis.get(&name).cloned().unwrap_or_default();
+            let problem = sound_problem(
+                path,
+                &mut sound_analysis,
+                trims.get(&name),
+                config.sounds.min_duration_seconds,
+            );
+            let info = SoundInfo {
+                kind,
+                weight: weights.get(&name),
+                leading_silence_seconds: Some(&sound_analysis)
+                    .filter(|a| a.is_decoded())
+                    .map(|a| a.leading_silence_seconds),
+                problem,
+                trim: trims.get(&name),
+                name: name.clone(),
+            };
+            if analysis.get(&name) != Some(&sound_analysis) {
+                probed.push((name, sound_analysis));
+            }
+            info
+        }));
+        analysis::record_all(root_dir, probed);
+    }
+    Ok(Json(infos))
+}
+
+/// Sets the selection weight of a sound.
+///
+/// Sounds in subdirectories are addressed by their relative path, with the `/` percent-encoded.
+#[put("/sounds/<name>/weight", data = "<weight>")]
+pub fn put_sound_weight(
+    state: &State<AlarmState>,
+    name: &str,
+    weight: Json<f64>,
+) -> Result<Json<SoundInfo>, Status> {
+    if !is_valid_weight(weight.0) {
+        return Err(Status::BadRequest);
+    }
+
+    let config = state.config.get();
+    let root_dir = config.sounds.directory.as_path();
+    let sounds = list_sounds(root_dir, &config.sounds).map_err(|_| Status::InternalServerError)?;
+    let Some(path) = sounds
+        .iter()
+        .find(|path| sound_name(root_dir, path) == name)
+    else {
+        return Err(Status::NotFound);
+    };
+
+    let mut weights = SoundWeights::load(root_dir);
+    weights.set(name, weight.0);
+    weights.save(root_dir).map_err(|e| {
+        error!("Could not save sound weights: {}", e);
+        Status::InternalServerError
+    })?;
+    info!("Set weight of `{}` to {}", name, weight.0);
+
+    Ok(Json(sound_info(root_dir, path, &config.sounds)))
+}
+
+/// Sets the part of a sound which is played as an alarm, or plays all of it again if `null`.
+///
+/// Sounds in subdirectories are addressed by their relative path, with the `/` percent-encoded.
+#[put("/sounds/<name>/trim", data = "<trim>")]
+pub fn put_sound_trim(
+    state: &State<AlarmState>,
+    name: &str,
+    trim: Json<Option<Trim>>,
+) -> Result<Json<SoundInfo>, (Status, String)> {
+    if let Some(trim) = &trim.0 {
+        trim.validate().map_err(|e| (Status::BadRequest, e))?;
+    }
+
+    let config = state.config.get();
+    let root_dir = config.sounds.directory.as_path();
+    let sounds = list_sounds(root_dir, &config.sounds)
+        .map_err(|e| (Status::InternalServerError, e.to_string()))?;
+    let Some(path) = sounds
+        .iter()
+        .find(|path| sound_name(root_dir, path) == name)
+    else {
+        return Err((
+            Status::NotFound,
+            format!("There is no sound called `{name}`"),
+        ));
+    };
+
+    let mut trims = SoundTrims::load(root_dir);
+    trims.set(name, trim.0);
+    trims.save(root_dir).map_err(|e| {
+        error!("Could not save sound trims: {}", e);
+        (Status::InternalServerError, e.to_string())
+    })?;
+    info!("Set trim of `{}` to {:?}", name, trim.0);
+
+    Ok(Json(sound_info(root_dir, path, &config.sounds)))
+}
+
+fn sound_info(root_dir: &Path, path: &Path, config: &SoundsConfig) -> SoundInfo {
+    let name = sound_name(root_dir, path);
+    let original = analysis::load(root_dir).remove(&name);
+    let mut analysis = original.clone().unwrap_or_default();
+    let trim = SoundTrims::load(root_dir).get(&name);
+    let problem = sound_problem(path, &mut analysis, trim, config.min_duration_seconds);
+    let info = SoundInfo {
+        kind: SoundKind::Alarm,
+        weight: SoundWeights::load(root_dir).get(&name),
+        leading_silence_seconds: Some(&analysis)
+            .filter(|a| a.is_decoded())
+            .map(|a| a.leading_silence_seconds),
+        problem,
+        trim,
+        name: name.clone(),
+    };
+    if original.as_ref() != Some(&analysis) {
+        analysis::record(root_dir, &name, analysis);
+    }
+    info
+}
+
+#[test]
+fn test_sound_weights() {
+    let mut weights = SoundWeights::default();
+    assert_eq!(weights.get("a.mp3"), 1.0);
+    weights.set("a.mp3", 2.5);
+    assert_eq!(weights.get("a.mp3"), 2.5);
+    assert_eq!(weights.get("b.mp3"), 1.0);
+
+    assert!(!is_valid_weight(0.0));
+    assert!(!is_valid_weight(-1.0));
+    assert!(!is_valid_weight(f64::NAN));
+    assert!(is_valid_weight(0.1));
+
+    // A broken file is not saved over
+    let root_dir = std::env::temp_dir().join(format!("weights-test-{}", std::process::id()));
+    std::fs::create_dir_all(&root_dir).unwrap();
+    weights.save(&root_dir).unwrap();
+    assert_eq!(SoundWeights::load(&root_dir).get("a.mp3"), 2.5);
+    std::fs::write(root_dir.join(WEIGHTS_FILE), "a.mp3 = ").unwrap();
+    let mut weights = SoundWeights::load(&root_dir);
+    assert_eq!(weights.get("a.mp3"), 1.0);
+    weights.set("b.mp3", 2.0);
+    assert!(weights.save(&root_dir).is_err());
+    assert_eq!(
+        std::fs::read_to_string(root_dir.join(WEIGHTS_FILE)).unwrap(),
+        "a.mp3 = "
+    );
+    std::fs::remove_dir_all(&root_dir).unwrap();
+}
+
+#[test]
+fn test_is_relative_subpath() {
+    assert!(is_relative_subpath("calm"));
+    assert!(is_relative_subpath("calm/piano"));
+    assert!(!is_relative_subpath(""));
+    assert!(!is_relative_subpath("/etc"));
+    assert!(!is_relative_subpath("../music"));
+    assert!(!is_relative_subpath("calm/../../music"));
+}
+
+#[test]
+fn test_trim() {
+    let trim = |start_seconds, end_seconds| Trim {
+        start_seconds,
+        end_seconds,
+    };
+    assert_eq!(trim(1.0, Some(2.5)).frames(1000, 100), Ok(100..250));
+    assert_eq!(trim(0.0, None).frames(1000, 100), Ok(0..1000));
+    assert_eq!(trim(2.0, None).frames(1000, 100), Ok(200..1000));
+
+    assert!(trim(-1.0, None).validate().is_err());
+    assert!(trim(f32::NAN, None).validate().is_err());
+    assert!(trim(2.0, Some(2.0)).validate().is_err());
+    assert!(trim(2.0, Some(1.0)).validate().is_err());
+    // Past the end of the sound
+    assert!(trim(1.0, Some(10.1)).frames(1000, 100).is_err());
+    assert!(trim(10.0, None).frames(1000, 100).is_err());
+
+    assert_eq!(trim(1.0, Some(2.5)).played_seconds(10.0), Some(1.5));
+    assert_eq!(trim(2.0, None).played_seconds(10.0), Some(8.0));
+    assert_eq!(trim(1.0, Some(10.1)).played_seconds(10.0), None);
+}
+
+#[test]
+fn test_lucid_dirs_are_not_alarm_sounds() {
+    let root_dir = std::env::temp_dir().join(format!("sounds-test-{}", std::process::id()));
+    for file in ["wake.mp3", "dreams/a.mp3", "voice/b.mp3"] {
+        let path = root_dir.join(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, b"").unwrap();
+    }
+    let config = SoundsConfig {
+        directory: root_dir.clone(),
+        lucid_music_dir: root_dir.join("dreams"),
+        lucid_voice_dir: root_dir.join("voice"),
+        ..Default::default()
+    };
+    assert_eq!(
+        list_sounds(&root_dir, &config).unwrap(),
+        [root_dir.join("wake.mp3")]
+    );
+    assert_eq!(
+        list_sounds(SoundKind::LucidVoice.dir(&config), &config).unwrap(),
+        [root_dir.join("voice/b.mp3")]
+    );
+    // The same directories, written differently
+    let config = SoundsConfig {
+        lucid_music_dir: root_dir.join("voice/../dreams"),
+        lucid_voice_dir: root_dir.join("dreams/../voice/"),
+        ..config
+    };
+    assert_eq!(
+        list_sounds(&root_dir, &config).unwrap(),
+        [root_dir.join("wake.mp3")]
+    );
+    assert_eq!("lucid_sfx".parse(), Ok(SoundKind::LucidSfx));
+    assert!("lucid".parse::<SoundKind>().is_err());
+    std::fs::remove_dir_all(&root_dir).unwrap();
+}
@@ -0,0 +1,259 @@
+//! How the sync with the MQTT broker is doing, so that running on stale data after the broker restarted is noticed.
+//!
+//! brevduva reconnects and resubscribes by itself, and does not report when it loses the connection or resubscribes.
+//! So only the initial sync and the sync after starting offline (see [`crate::offline`]) are tracked as connection
+//! changes, and disconnects are not seen directly. Instead, the containers are watched for updates from the broker. If
+//! [`STATE_CONTAINER`] has not been updated for long while the others have, its subscription was probably lost.
+use std::collections::BTreeMap;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use log::{info, warn};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::offline::Container;
+
+/// The container of [`crate::InnerAlarmState`], which is only updated when the alarm is changed.
+pub const STATE_CONTAINER: &str = "alarm/state";
+/// How often the containers are checked for updates.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Connection {
+    /// Waiting for the initial sync.
+    Connecting,
+    Synced,
+    /// The broker could not be reached at startup, so the locally saved state is used.
+    /// Changes are not seen by the other clients until the broker is back.
+    Offline,
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct ContainerActivity {
+    /// When the value was last changed by a message from the broker. Changes made by this alarm are not counted.
+    pub last_update: Option<DateTime<Utc>>,
+    pub updates: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SyncHealth {
+    pub connection: Connection,
+    /// When the connection last changed.
+    pub since: DateTime<Utc>,
+    /// Times the state was synced again after having been offline.
+    pub resyncs: u64,
+    pub containers: BTreeMap<&'static str, ContainerActivity>,
+    /// True if [`STATE_CONTAINER`] has not been updated for long, while the other containers have.
+    pub suspicious: bool,
+}
+
+static OFFLINE: AtomicU64 = AtomicU64::new(0);
+static RESYNCS: AtomicU64 = AtomicU64::new(0);
+static UPDATES: AtomicU64 = AtomicU64::new(0);
+static SUSPICIOUS: AtomicU64 = AtomicU64::new(0);
+
+pub struct SyncStats {
+    pub offline: u64,
+    pub resyncs: u64,
+    pub updates: u64,
+    pub suspicious: u64,
+}
+
+pub fn stats() -> SyncStats {
+    SyncStats {
+        offline: OFFLINE.load(Ordering::Relaxed),
+        resyncs: RESYNCS.load(Ordering::Relaxed),
+        updates: UPDATES.load(Ordering::Relaxed),
+        suspicious: SUSPICIOUS.load(Ordering::Relaxed),
+    }
+}
+
+impl SyncHealth {
+    pub fn new(now: DateTime<Utc>) -> SyncHealth {
+        SyncHealth {
+            connection: Connection::Connecting,
+            since: now,
+            resyncs: 0,
+            containers: BTreeMap::new(),
+            suspicious: false,
+        }
+    }
+
+    /// False if anything is degraded.
+    pub fn is_ok(&self) -> bool {
+        self.connection == Connection::Synced && !self.suspicious
+    }
+
+    pub fn set_connection(&mut self, connection: Connection, now: DateTime<Utc>) {
+        if connection == self.connection {
+            return;
+        }
+        info!("MQTT sync: {:?} -> {connection:?}", self.connection);
+        match (self.connection, connection) {
+            (_, Connection::Offline) => {
+                OFFLINE.fetch_add(1, Ordering::Relaxed);
+            }
+            (Connection::Offline, Connection::Synced) => {
+                self.resyncs += 1;
+                RESYNCS.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+        self.connection = connection;
+        self.since = now;
+    }
+
+    /// Records that the value of the container `name` changed.
+    pub fn updated(&mut self, name: &'static str, now: DateTime<Utc>) {
+        let activity = self.containers.entry(name).or_default();
+        activity.last_update = Some(now);
+        activity.updates += 1;
+        UPDATES.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Updates [`SyncHealth::suspicious`]. `threshold` is how long [`STATE_CONTAINER`] may go without updates.
+    pub fn check(&mut self, now: DateTime<Utc>, threshold: Duration) {
+        let threshold = TimeDelta::from_std(threshold).unwrap_or(TimeDelta::MAX);
+        // Everything was received when synced
+        let synced_at = (self.connection == Connection::Synced).then_some(self.since);
+        let state_updated = self
+            .containers
+            .get(STATE_CONTAINER)
+            .and_then(|c| c.last_update)
+            .max(synced_at);
+        let others_updating = self.containers.iter().any(|(name, c)| {
+            *name != STATE_CONTAINER && c.last_update.is_some_and(|t| now - t < threshold)
+        });
+        let suspicious = self.connection == Connection::Synced
+            && others_updating
+            && state_updated.is_none_or(|t| now - t > threshold);
+
+        if suspicious && !self.suspicious {
+            warn!(
+                "{STATE_CONTAINER} has not been updated since {state_updated:?}, while other containers have. The subscription may have been lost."
+            );
+            SUSPICIOUS.fetch_add(1, Ordering::Relaxed);
+        } else if !suspicious && self.suspicious {
+            info!("{STATE_CONTAINER} is no longer suspicious");
+        }
+        self.suspicious = suspicious;
+    }
+}
+
+/// Fingerprint of the value of a container, and whether the value was set by this alarm.
+type Fingerprint = Box<dyn Fn() -> (u64, bool) + Send + Sync>;
+
+/// The containers which are watched for updates, with the fingerprints they had when last checked.
+#[derive(Default)]
+pub struct Watched(Vec<(&'static str, Fingerprint, u64)>);
+
+impl Watched {
+    pub fn add<T>(&mut self, name: &'static str, container: &Arc<Container<T>>)
+    where
+        T: Serialize + DeserializeOwned + Clone + Hash + Send + Sync + 'static,
+    {
+        let container = container.clone();
+        let (initial, _) = container.fingerprint();
+        self.0
+            .push((name, Box::new(move || container.fingerprint()), initial));
+    }
+
+    /// Records the containers which were updated by the broker since the last call in `health`.
+    ///
+    /// Values which this alarm set itself say nothing about the subscriptions, so they are not counted.
+    pub fn record(&mut self, health: &mut SyncHealth, now: DateTime<Utc>) {
+        for (name, fingerprint, previous) in &mut self.0 {
+            let (current, set_locally) = fingerprint();
+            if current != *previous {
+                if !set_locally {
+                    health.updated(name, now);
+                }
+                *previous = current;
+            }
+        }
+    }
+}
+
+/// Records the updates of the containers in `health`, and checks whether the state looks stale, forever.
+pub async fn watch(health: Arc<Mutex<SyncHealth>>, mut watched: Watched, threshold: Duration) {
+    loop {
+        tokio::time::sleep(WATCH_INTERVAL).await;
+        let now = Utc::now();
+        let mut health = health.lock().unwrap();
+        watched.record(&mut health, now);
+        health.check(now, threshold);
+    }
+}
+
+#[test]
+fn test_sync_health() {
+    let start = DateTime::parse_from_rfc3339("2024-03-02T05:00:00Z")
+        .unwrap()
+        .with_timezone(&Utc);
+    let at = |hours| start + TimeDelta::hours(hours);
+    let threshold = Duration::from_secs(12 * 3600);
+
+    let mut health = SyncHealth::new(at(0));
+    health.set_connection(Connection::Offline, at(0));
+    health.updated("alarm/is_user_in_bed", at(1));
+    health.check(at(20), threshold);
+    // Nothing is expected from the broker while offline
+    assert!(!health.suspicious);
+    assert!(!health.is_ok());
+
+    health.set_connection(Connection::Synced, at(2));
+    assert_eq!(health.resyncs, 1);
+    health.updated("alarm/is_user_in_bed", at(13));
+    health.check(at(13), threshold);
+    assert!(health.is_ok());
+
+    // The state has not been updated since the sync, but the others have
+    health.check(at(15), threshold);
+    assert!(health.suspicious);
+    assert!(!health.is_ok());
+    health.updated(STATE_CONTAINER, at(16));
+    health.check(at(16), threshold);
+    assert!(!health.suspicious);
+    assert_eq!(
+        health.containers[STATE_CONTAINER],
+        ContainerActivity {
+            last_update: Some(at(16)),
+            updates: 1,
+        }
+    );
+
+    // Nothing is updating at all, so there is nothing to compare with
+    health.check(at(40), threshold);
+    assert!(!health.suspicious);
+}
+
+#[test]
+fn test_local_writes_are_not_updates() {
+    let start = DateTime::parse_from_rfc3339("2024-03-02T05:00:00Z")
+        .unwrap()
+        .with_timezone(&Utc);
+    let at = |hours| start + TimeDelta::hours(hours);
+    let threshold = Duration::from_secs(12 * 3600);
+
+    let mut containers = crate::offline::Containers::default();
+    let state = containers.add(STATE_CONTAINER, 0u32);
+    let is_playing = containers.add("alarm/is_playing", false);
+    let mut watched = Watched::default();
+    watched.add(STATE_CONTAINER, &state);
+    watched.add("alarm/is_playing", &is_playing);
+
+    let mut health = SyncHealth::new(at(0));
+    health.set_connection(Connection::Synced, at(0));
+    for hour in 1..40 {
+        futures::executor::block_on(is_playing.set(hour % 2 == 0));
+        watched.record(&mut health, at(hour));
+        health.check(at(hour), threshold);
+        assert!(!health.suspicious);
+    }
+    assert!(!health.containers.contains_key("alarm/is_playing"));
+}
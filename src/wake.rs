@@ -0,0 +1,367 @@
+//! Decides when to start the alarm within the wake window around the alarm time, when to ring again after it stopped,
+//! and when a stopped alarm may finish if the user must leave the bed to dismiss it.
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::config::SnoozeConfig;
+
+/// The alarm may start up to `earliest_minutes` before the alarm time if the user seems to be sleeping lightly,
+/// and starts at the latest `latest_minutes` after it.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize, Hash)]
+#[serde(default)]
+pub struct WakeWindow {
+    pub earliest_minutes: u32,
+    pub latest_minutes: u32,
+}
+
+impl Default for WakeWindow {
+    fn default() -> Self {
+        WakeWindow {
+            earliest_minutes: 30,
+            latest_minutes: 0,
+        }
+    }
+}
+
+impl WakeWindow {
+    pub fn start(&self, alarm: DateTime<Utc>) -> DateTime<Utc> {
+        alarm - TimeDelta::minutes(self.earliest_minutes as i64)
+    }
+
+    pub fn end(&self, alarm: DateTime<Utc>) -> DateTime<Utc> {
+        alarm + TimeDelta::minutes(self.latest_minutes as i64)
+    }
+}
+
+/// What the sleep monitor currently thinks about the user.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SleepSignals {
+    pub in_bed: bool,
+    /// Light sleep after a period of deep sleep.
+    pub light_sleep_transition: bool,
+    pub significant_movement: bool,
+}
+
+/// Why the alarm started.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WakeReason {
+    /// The user came up from deep sleep.
+    LightSleep,
+    /// The user was moving in bed.
+    Movement,
+    /// Nothing suggested a better moment before the end of the window.
+    EndOfWindow,
+    /// There is no sensor data to find a better moment by, so the alarm started at the start of the window.
+    NoSensorData,
+    /// The alarm was interrupted by a restart, and continued where it left off.
+    Resumed,
+    /// The alarm rang again, because the user was still in bed after it stopped.
+    Snoozed,
+}
+
+/// Whether an alarm at `alarm` should start at `now`. `signals` is `None` if the sleep is not monitored, or the
+/// sensor data is stale, in which case the alarm starts at the start of the window.
+pub fn decide(
+    now: DateTime<Utc>,
+    alarm: DateTime<Utc>,
+    window: &WakeWindow,
+    signals: Option<&SleepSignals>,
+) -> Option<WakeReason> {
+    if now < window.start(alarm) {
+        None
+    } else if now >= window.end(alarm) {
+        Some(WakeReason::EndOfWindow)
+    } else {
+        match signals {
+            None => Some(WakeReason::NoSensorData),
+            Some(s) if s.light_sleep_transition => Some(WakeReason::LightSleep),
+            Some(s) if s.in_bed && s.significant_movement => Some(WakeReason::Movement),
+            _ => None,
+        }
+    }
+}
+
+/// The alarm that is currently snoozed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnoozeChain {
+    pub first_rang: DateTime<Utc>,
+    pub last_rang: DateTime<Utc>,
+    /// The movement events since it stopped the last time, before it rang again.
+    pub movement_events: Vec<u64>,
+}
+
+/// The state of the user at one of the checks while snoozing.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SnoozeCheck {
+    /// Probability from 0 to 1 that the user is in bed.
+    pub presence: f32,
+    pub moving: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnoozeDecision {
+    Wait,
+    /// Ring the alarm again.
+    Ring,
+    /// The user got up, or the alarm has been snoozed for too long. Don't ring again.
+    Stop,
+}
+
+/// Whether to ring again, `elapsed` after the alarm stopped.
+///
+/// `checks` are the checks since the alarm stopped, one every `check_interval`, and `earlier` is the time from when the alarm first rang until it stopped this time.
+/// The user is in bed at a check if the presence is at least `config.present_confidence`, and out of bed if it is at most `config.absent_confidence`.
+/// In between it is unclear, and the decision is put off until it is clear or the snooze is over.
+pub fn snooze_decision(
+    checks: &[SnoozeCheck],
+    check_interval: Duration,
+    elapsed: Duration,
+    earlier: Duration,
+    config: &SnoozeConfig,
+) -> SnoozeDecision {
+    let minutes = |m: f32| Duration::from_secs_f32(m.max(0.0) * 60.0);
+    let remaining = minutes(config.max_total_minutes).saturating_sub(earlier);
+    if remaining.is_zero() {
+        return SnoozeDecision::Stop;
+    }
+    let Some(last) = checks.last() else {
+        return SnoozeDecision::Wait;
+    };
+
+    let movement_checks = ((minutes(config.movement_minutes).as_secs_f32()
+        / check_interval.as_secs_f32())
+    .round() as usize)
+        .max(1);
+    let sustained_movement = checks.len() >= movement_checks
+        && checks[checks.len() - movement_checks..]
+            .iter()
+            .all(|c| c.presence >= config.present_confidence && c.moving);
+
+    if elapsed >= minutes(config.max_minutes).min(remaining) {
+        if last.presence >= config.present_confidence {
+            SnoozeDecision::Ring
+        } else if last.presence <= config.absent_confidence || elapsed >= remaining {
+            SnoozeDecision::Stop
+        } else {
+            SnoozeDecision::Wait
+        }
+    } else if elapsed >= minutes(config.min_minutes) && sustained_movement {
+        SnoozeDecision::Ring
+    } else {
+        SnoozeDecision::Wait
+    }
+}
+
+/// Where a playing alarm is in [`crate::config::DismissalPolicy::MustLeaveBed`] mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BedExit {
+    /// Not stopped.
+    #[default]
+    Ringing,
+    /// Stopped, and playing quietly until the user has been out of bed for long enough. `absent_since` is when they got up.
+    Dismissed { absent_since: Option<DateTime<Utc>> },
+    /// The user has been out of bed for long enough, or it could no longer be known. The alarm finishes.
+    Done,
+}
+
+impl BedExit {
+    /// The state at `now`. `in_bed` is `None` if it is unknown.
+    ///
+    /// Getting back into bed before `out_of_bed` has passed takes back the stop.
+    pub fn update(self, now: DateTime<Utc>, in_bed: Option<bool>, out_of_bed: Duration) -> BedExit {
+        let BedExit::Dismissed { absent_since } = self else {
+            return self;
+        };
+        match in_bed {
+            Some(false) => {
+                let since = absent_since.unwrap_or(now);
+                if (now - since).to_std().unwrap_or_default() >= out_of_bed {
+                    BedExit::Done
+                } else {
+                    BedExit::Dismissed {
+                        absent_since: Some(since),
+                    }
+                }
+            }
+            Some(true) if absent_since.is_some() => BedExit::Ringing,
+            Some(true) => self,
+            None => BedExit::Done,
+        }
+    }
+}
+
+#[test]
+fn test_decide() {
+    let alarm = DateTime::parse_from_rfc3339("2024-03-01T07:00:00Z")
+        .unwrap()
+        .with_timezone(&Utc);
+    let at = |minutes| alarm + TimeDelta::minutes(minutes);
+    let window = WakeWindow {
+        earliest_minutes: 15,
+        latest_minutes: 15,
+    };
+    let sleeping = SleepSignals {
+        in_bed: true,
+        ..SleepSignals::default()
+    };
+    let light = SleepSignals {
+        light_sleep_transition: true,
+        ..sleeping
+    };
+    let moving = SleepSignals {
+        significant_movement: true,
+        ..sleeping
+    };
+    let out_of_bed = SleepSignals {
+        significant_movement: true,
+        in_bed: false,
+        ..sleeping
+    };
+
+    // Nothing happens before the window, whatever the signals are
+    assert_eq!(decide(at(-16), alarm, &window, Some(&light)), None);
+    assert_eq!(decide(at(-16), alarm, &window, Some(&moving)), None);
+    // Within the window, the alarm starts on light sleep or movement
+    assert_eq!(
+        decide(at(-15), alarm, &window, Some(&light)),
+        Some(WakeReason::LightSleep)
+    );
+    assert_eq!(
+        decide(at(-5), alarm, &window, Some(&moving)),
+        Some(WakeReason::Movement)
+    );
+    assert_eq!(
+        decide(at(5), alarm, &window, Some(&light)),
+        Some(WakeReason::LightSleep)
+    );
+    // But not while in deep sleep, or when the movement is not from someone in bed
+    assert_eq!(decide(at(-5), alarm, &window, Some(&sleeping)), None);
+    assert_eq!(decide(at(5), alarm, &window, Some(&out_of_bed)), None);
+    // Without sensor data there is nothing to wait for, so the alarm starts with the window
+    assert_eq!(decide(at(-16), alarm, &window, None), None);
+    assert_eq!(
+        decide(at(-15), alarm, &window, None),
+        Some(WakeReason::NoSensorData)
+    );
+    // Always at the end of the window
+    for signals in [None, Some(&sleeping), Some(&light)] {
+        assert_eq!(
+            decide(at(15), alarm, &window, signals),
+            Some(WakeReason::EndOfWindow)
+        );
+    }
+
+    // Without a window, the alarm starts exactly at the alarm time
+    let exact = WakeWindow {
+        earliest_minutes: 0,
+        latest_minutes: 0,
+    };
+    assert_eq!(decide(at(-1), alarm, &exact, Some(&light)), None);
+    assert_eq!(
+        decide(at(0), alarm, &exact, Some(&sleeping)),
+        Some(WakeReason::EndOfWindow)
+    );
+}
+
+#[test]
+fn test_snooze_decision() {
+    let config = SnoozeConfig::default();
+    let interval = Duration::from_secs(60);
+    // Returns the minute at which the decision is not to wait, or `None` if it never is
+    let run = |trace: &[SnoozeCheck], earlier_minutes: u64| {
+        let earlier = Duration::from_secs(earlier_minutes * 60);
+        (1..=trace.len()).find_map(|n| {
+            match snooze_decision(&trace[..n], interval, interval * n as u32, earlier, &config) {
+                SnoozeDecision::Wait => None,
+                decision => Some((n, decision)),
+            }
+        })
+    };
+    let still = SnoozeCheck {
+        presence: 1.0,
+        moving: false,
+    };
+    let moving = SnoozeCheck {
+        presence: 1.0,
+        moving: true,
+    };
+    let gone = SnoozeCheck {
+        presence: 0.0,
+        moving: false,
+    };
+    let unclear = SnoozeCheck {
+        presence: 0.5,
+        moving: false,
+    };
+
+    // Back in deep sleep. Rings at the latest.
+    assert_eq!(run(&[still; 30], 0), Some((20, SnoozeDecision::Ring)));
+    // Stirring right away. Rings at the earliest.
+    assert_eq!(run(&[moving; 30], 0), Some((5, SnoozeDecision::Ring)));
+    // Turning over once is not enough, but a while of movement is
+    let mut trace = vec![still; 30];
+    trace[3] = moving;
+    trace[10] = moving;
+    trace[11] = moving;
+    assert_eq!(run(&trace, 0), Some((12, SnoozeDecision::Ring)));
+    // Got up
+    let mut trace = vec![moving; 2];
+    trace.extend([gone; 28]);
+    assert_eq!(run(&trace, 0), Some((20, SnoozeDecision::Stop)));
+    // Late in the chain, the snooze is cut short, and then the chain ends
+    assert_eq!(run(&[still; 30], 50), Some((10, SnoozeDecision::Ring)));
+    assert_eq!(run(&[still; 30], 60), Some((1, SnoozeDecision::Stop)));
+
+    // Unclear whether the user is still in bed. Waits until it is clear.
+    let mut trace = vec![unclear; 25];
+    trace.extend([still; 5]);
+    assert_eq!(run(&trace, 0), Some((26, SnoozeDecision::Ring)));
+    let mut trace = vec![unclear; 25];
+    trace.extend([gone; 5]);
+    assert_eq!(run(&trace, 0), Some((26, SnoozeDecision::Stop)));
+    // But not beyond the end of the chain
+    assert_eq!(run(&[unclear; 60], 0), Some((60, SnoozeDecision::Stop)));
+    // Moving while it is unclear is not enough to ring early
+    let moving_unclear = SnoozeCheck {
+        moving: true,
+        ..unclear
+    };
+    assert_eq!(
+        run(&[moving_unclear; 30], 30),
+        Some((30, SnoozeDecision::Stop))
+    );
+}
+
+#[test]
+fn test_bed_exit() {
+    let start = DateTime::parse_from_rfc3339("2024-03-01T07:00:00Z")
+        .unwrap()
+        .with_timezone(&Utc);
+    let at = |seconds| start + TimeDelta::seconds(seconds);
+    let out_of_bed = Duration::from_secs(120);
+    let dismissed = BedExit::Dismissed { absent_since: None };
+
+    // Stays quiet while still lying in bed after stopping it
+    assert_eq!(dismissed.update(at(0), Some(true), out_of_bed), dismissed);
+    // Finishes after two minutes out of bed
+    let up = dismissed.update(at(10), Some(false), out_of_bed);
+    assert_eq!(
+        up,
+        BedExit::Dismissed {
+            absent_since: Some(at(10))
+        }
+    );
+    let up = up.update(at(60), Some(false), out_of_bed);
+    assert_eq!(up.update(at(129), Some(false), out_of_bed), up);
+    assert_eq!(up.update(at(130), Some(false), out_of_bed), BedExit::Done);
+    // Getting back into bed rings again
+    assert_eq!(up.update(at(90), Some(true), out_of_bed), BedExit::Ringing);
+    // Without a sensor, it finishes right away
+    assert_eq!(dismissed.update(at(0), None, out_of_bed), BedExit::Done);
+    assert_eq!(
+        BedExit::Ringing.update(at(0), Some(false), out_of_bed),
+        BedExit::Ringing
+    );
+}
@@ -0,0 +1,141 @@
+//! Readiness notification and watchdog for systemd, to run as a `Type=notify` service with `WatchdogSec=`.
+//!
+//! The notifications are datagrams to the socket in `NOTIFY_SOCKET`, see `sd_notify(3)`, and nothing is sent when not
+//! run by systemd. The watchdog is only pinged while the alarm loop and the sleep monitor keep making progress, so that
+//! systemd restarts the alarm if one of them hangs.
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+
+/// How long a subsystem may go without a heartbeat before it counts as stuck.
+///
+/// Longer than anything the alarm loop waits for, like decoding a long sound.
+const STUCK_AFTER: Duration = Duration::from_secs(5 * 60);
+
+/// A part of the alarm which must keep making progress.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsystem {
+    AlarmLoop,
+    SleepMonitor,
+}
+
+const SUBSYSTEMS: [Subsystem; 2] = [Subsystem::AlarmLoop, Subsystem::SleepMonitor];
+
+type Heartbeats = [Option<Instant>; SUBSYSTEMS.len()];
+
+/// The last heartbeat of each subsystem. `None` until it has started, since not all of them run, like the sleep
+/// monitor without a sensor.
+static HEARTBEATS: Mutex<Heartbeats> = Mutex::new([None; SUBSYSTEMS.len()]);
+
+/// Records that `subsystem` made progress. Called on every iteration of its loop.
+pub fn beat(subsystem: Subsystem) {
+    HEARTBEATS.lock().unwrap()[subsystem as usize] = Some(Instant::now());
+}
+
+/// The subsystems which have not made progress for [`STUCK_AFTER`].
+fn stuck(heartbeats: &Heartbeats, now: Instant) -> Vec<Subsystem> {
+    SUBSYSTEMS
+        .into_iter()
+        .zip(heartbeats)
+        .filter(|(_, beat)| beat.is_some_and(|t| now.duration_since(t) > STUCK_AFTER))
+        .map(|(subsystem, _)| subsystem)
+        .collect()
+}
+
+/// Sends `state` to the notification socket `socket`. Names starting with `@` are in the abstract namespace.
+fn send(socket: &OsStr, state: &str) -> io::Result<()> {
+    let datagram = UnixDatagram::unbound()?;
+    match socket.as_bytes().strip_prefix(b"@") {
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            datagram.send_to_addr(state.as_bytes(), &address)?;
+        }
+        None => {
+            datagram.send_to(state.as_bytes(), Path::new(socket))?;
+        }
+    }
+    Ok(())
+}
+
+fn notify(state: &str) {
+    let Some(socket) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = send(&socket, state) {
+        warn!("Could not notify systemd of {state}: {e}");
+    }
+}
+
+/// Tells systemd that the alarm has started, once the state is synced (or offline) and the server is listening.
+pub fn ready() {
+    notify("READY=1");
+}
+
+/// Tells systemd that the alarm is shutting down.
+pub fn stopping() {
+    notify("STOPPING=1");
+}
+
+/// How often to ping the watchdog, which is half of `WatchdogSec`. `None` if there is no watchdog for this process.
+fn watchdog_interval() -> Option<Duration> {
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    // Set if the watchdog is meant for another process, like one which started this one
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+    Some(Duration::from_micros(usec) / 2)
+}
+
+/// Pings the watchdog for as long as all subsystems keep making progress. Returns right away without a watchdog.
+pub async fn supervise() {
+    let Some(interval) = watchdog_interval() else {
+        return;
+    };
+    info!("Pinging the systemd watchdog every {interval:?}");
+    let mut was_stuck = vec![];
+    loop {
+        let stuck = stuck(&HEARTBEATS.lock().unwrap(), Instant::now());
+        if stuck.is_empty() {
+            if !was_stuck.is_empty() {
+                info!("{was_stuck:?} made progress again, pinging the watchdog");
+            }
+            notify("WATCHDOG=1");
+        } else if stuck != was_stuck {
+            error!(
+                "{stuck:?} made no progress for {STUCK_AFTER:?}. Not pinging the watchdog, so that systemd restarts the alarm."
+            );
+        }
+        was_stuck = stuck;
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[test]
+fn test_watchdog() {
+    let now = Instant::now();
+    let minute = Duration::from_secs(60);
+    // The sleep monitor has not started
+    assert_eq!(stuck(&[Some(now - minute), None], now), []);
+    assert_eq!(
+        stuck(&[Some(now - 10 * minute), Some(now - minute)], now),
+        [Subsystem::AlarmLoop]
+    );
+
+    let path = std::env::temp_dir().join(format!("watchdog-test-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let socket = UnixDatagram::bind(&path).unwrap();
+    send(path.as_os_str(), "READY=1").unwrap();
+    let mut buf = [0; 64];
+    let len = socket.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"READY=1");
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,112 @@
+//! Stops the alarm with SIGTERM, and checks that it leaves a clean state behind.
+//!
+//! Only without the audio feature, so that it runs without a sound card. The broker can not be reached, so the alarm
+//! runs offline.
+#![cfg(all(unix, not(feature = "audio")))]
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+/// Kills the alarm if the test fails before it has stopped.
+struct Alarm(Child);
+
+impl Drop for Alarm {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+impl Alarm {
+    fn wait(&mut self, timeout: Duration) -> Option<ExitStatus> {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if let Some(status) = self.0.try_wait().unwrap() {
+                return Some(status);
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        None
+    }
+}
+
+/// Sends a request, and returns the status code and the body. `None` if the server is not up.
+fn request(port: u16, method: &str, path: &str, body: &str) -> Option<(u16, String)> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).ok()?;
+    write!(
+        stream,
+        "{method} {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+         Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+        body.len()
+    )
+    .ok()?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).ok()?;
+    let status = response.split(' ').nth(1)?.parse().ok()?;
+    let (_, body) = response.split_once("\r\n\r\n")?;
+    Some((status, body.to_owned()))
+}
+
+#[test]
+fn test_sigterm_shutdown() {
+    let dir = std::env::temp_dir().join(format!("alarm-shutdown-test-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    // Nothing listens on the discard port
+    std::fs::write(
+        dir.join("mqtt.json"),
+        r#"{"host": "mqtt://127.0.0.1:9", "username": "test", "password": "test", "sync_timeout_seconds": 1}"#,
+    )
+    .unwrap();
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut alarm = Alarm(
+        Command::new(env!("CARGO_BIN_EXE_alarm"))
+            .current_dir(&dir)
+            .env("ROCKET_ADDRESS", "127.0.0.1")
+            .env("ROCKET_PORT", port.to_string())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while request(port, "GET", "/healthz", "").is_none() {
+        assert!(Instant::now() < deadline, "the server did not start");
+        assert!(alarm.0.try_wait().unwrap().is_none(), "the alarm exited");
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    let state = r#"{"next_alarm": "2030-01-02T06:30:00Z", "enabled": true}"#;
+    assert_eq!(request(port, "PUT", "/state", state).unwrap().0, 200);
+
+    let kill = Command::new("kill")
+        .args(["-TERM", &alarm.0.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(kill.success());
+    let status = alarm
+        .wait(Duration::from_secs(20))
+        .expect("the alarm did not stop");
+    assert!(status.success(), "{status}");
+    assert!(request(port, "GET", "/healthz", "").is_none());
+
+    let file: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(dir.join("local_state.json")).unwrap())
+            .unwrap();
+    let containers = &file["containers"];
+    assert_eq!(
+        containers["alarm/state"]["next_alarm"],
+        "2030-01-02T06:30:00Z"
+    );
+    assert_eq!(containers["alarm/state"]["enabled"], true);
+    assert_eq!(containers["alarm/is_playing"], false);
+    assert_eq!(containers["alarm/now_playing"], "none");
+    assert!(!dir.join("local_state.json.tmp").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}